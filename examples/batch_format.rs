@@ -0,0 +1,88 @@
+//! Compare the cost of receiving metrics as text lines and in the batch format.
+//!
+//! ```text
+//! cargo run --release --example batch_format
+//! ```
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use statsdproxy::batch;
+use statsdproxy::types::Metric;
+
+const LINES: usize = 1_000_000;
+// Roughly what fits into one upstream datagram.
+const LINES_PER_DATAGRAM: usize = 5;
+
+fn main() {
+    let lines: Vec<Vec<u8>> = (0..LINES)
+        .map(|i| {
+            format!(
+                "service.requests.duration:{}|ms|@0.5|#endpoint:/api/{}/,method:GET,status:200",
+                i % 1000,
+                i % 50
+            )
+            .into_bytes()
+        })
+        .collect();
+
+    let text_datagrams: Vec<Vec<u8>> = lines
+        .chunks(LINES_PER_DATAGRAM)
+        .map(|chunk| chunk.join(&b'\n'))
+        .collect();
+    let batch_datagrams: Vec<Vec<u8>> = lines
+        .chunks(LINES_PER_DATAGRAM)
+        .map(|chunk| {
+            let mut datagram = batch::MAGIC.to_vec();
+            for line in chunk {
+                batch::encode(&Metric::new(line.clone()), &mut datagram);
+            }
+            datagram
+        })
+        .collect();
+
+    let text = measure(|| {
+        for datagram in &text_datagrams {
+            for raw in datagram.split(|&x| x == b'\n') {
+                black_box(Metric::new(raw.to_vec()));
+            }
+        }
+    });
+    let batch = measure(|| {
+        for datagram in &batch_datagrams {
+            batch::decode(datagram, |metric| {
+                black_box(metric);
+            })
+            .unwrap();
+        }
+    });
+
+    let bytes = |datagrams: &[Vec<u8>]| datagrams.iter().map(Vec::len).sum::<usize>();
+    for (name, elapsed, datagrams) in [
+        ("text", text, &text_datagrams),
+        ("batch", batch, &batch_datagrams),
+    ] {
+        println!(
+            "{:>5}: {:>8.0} lines/s, {:>6.1} ns/line, {:>9} bytes",
+            name,
+            LINES as f64 / elapsed.as_secs_f64(),
+            elapsed.as_nanos() as f64 / LINES as f64,
+            bytes(datagrams)
+        );
+    }
+}
+
+/// The fastest of several runs of `f`.
+fn measure<F>(mut f: F) -> Duration
+where
+    F: FnMut(),
+{
+    (0..5)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
@@ -0,0 +1,325 @@
+//! A compact binary format for batches of metrics sent between statsdproxy instances, so that the
+//! receiving instance does not need to split text lines and search them for tags.
+//!
+//! A batch is [`MAGIC`] followed by a protobuf-encoded `Batch` message, defined in
+//! `proto/batch.proto`. Since `repeated` fields can be concatenated, a batch can be built by
+//! appending metrics one by one. Text lines never contain NUL bytes, so receivers can tell batches
+//! apart from text.
+//!
+//! A compressed batch is [`COMPRESSED_MAGIC`] followed by a batch, including its magic bytes,
+//! compressed into a zstd frame. Compressing and decompressing requires the `zstd` feature.
+
+#[cfg(feature = "zstd")]
+use std::io::Read;
+
+use anyhow::{anyhow, Error};
+use prost::Message;
+
+use crate::types::Metric;
+
+/// The bytes every batch starts with.
+pub const MAGIC: &[u8] = b"\0SPB";
+/// The bytes every compressed batch starts with.
+pub const COMPRESSED_MAGIC: &[u8] = b"\0SPZ";
+
+// Compressed batches decompressing to more than this are rejected, to bound the memory a single
+// datagram can take.
+#[cfg(feature = "zstd")]
+const MAX_DECOMPRESSED_SIZE: usize = 16 << 20;
+// Statsd metrics typically compress by a factor of 3 to 5, so decompressing into a buffer of this
+// many times the compressed size rarely needs to grow it. A datagram claiming a huge size in its
+// frame header does not get to allocate that much upfront.
+#[cfg(feature = "zstd")]
+const EXPECTED_RATIO: usize = 4;
+
+// The messages in `proto/batch.proto`.
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Batch {
+    #[prost(message, repeated, tag = "1")]
+    metrics: Vec<BatchMetric>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct BatchMetric {
+    #[prost(bytes = "vec", tag = "1")]
+    name: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    value: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    ty: Vec<u8>,
+    #[prost(bytes = "vec", repeated, tag = "4")]
+    tags: Vec<Vec<u8>>,
+    #[prost(bytes = "vec", repeated, tag = "5")]
+    extensions: Vec<Vec<u8>>,
+    #[prost(bytes = "vec", tag = "6")]
+    raw: Vec<u8>,
+}
+
+/// Whether `payload` is a batch rather than text lines.
+pub fn is_batch(payload: &[u8]) -> bool {
+    payload.starts_with(MAGIC)
+}
+
+/// Whether `payload` is a compressed batch.
+pub fn is_compressed(payload: &[u8]) -> bool {
+    payload.starts_with(COMPRESSED_MAGIC)
+}
+
+/// Compress `batch`, unless that does not make it smaller.
+#[cfg(feature = "zstd")]
+pub fn compress(batch: &[u8]) -> Vec<u8> {
+    let mut compressed = COMPRESSED_MAGIC.to_vec();
+    match zstd::stream::copy_encode(batch, &mut compressed, zstd::DEFAULT_COMPRESSION_LEVEL) {
+        Ok(()) if compressed.len() < batch.len() => compressed,
+        _ => batch.to_vec(),
+    }
+}
+
+/// Decompress a compressed batch.
+#[cfg(feature = "zstd")]
+pub fn decompress(payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let compressed = payload
+        .strip_prefix(COMPRESSED_MAGIC)
+        .ok_or_else(|| anyhow!("not a compressed batch"))?;
+    let mut batch =
+        Vec::with_capacity((compressed.len() * EXPECTED_RATIO).min(MAX_DECOMPRESSED_SIZE));
+    zstd::stream::read::Decoder::new(compressed)?
+        .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+        .read_to_end(&mut batch)?;
+    if batch.len() > MAX_DECOMPRESSED_SIZE {
+        return Err(anyhow!(
+            "decompresses to more than {} bytes",
+            MAX_DECOMPRESSED_SIZE
+        ));
+    }
+    if !is_batch(&batch) {
+        return Err(anyhow!("compressed payload is not a batch"));
+    }
+    Ok(batch)
+}
+
+/// Compressed batches are rejected without the `zstd` feature.
+#[cfg(not(feature = "zstd"))]
+pub fn decompress(_payload: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(anyhow!("statsdproxy was built without the zstd feature"))
+}
+
+/// Append `metric` to the batch in `out`, which must already start with [`MAGIC`].
+pub fn encode(metric: &Metric, out: &mut Vec<u8>) {
+    let message = match split(&metric.raw) {
+        Some(parts) => BatchMetric {
+            name: parts.name.to_vec(),
+            value: parts.value.to_vec(),
+            ty: parts.ty.to_vec(),
+            tags: metric.tags_iter().map(|tag| tag.raw.to_vec()).collect(),
+            extensions: parts.extensions.map(<[u8]>::to_vec).collect(),
+            raw: Vec::new(),
+        },
+        None => BatchMetric {
+            raw: metric.raw.clone(),
+            ..Default::default()
+        },
+    };
+    let batch = Batch {
+        metrics: vec![message],
+    };
+    batch.encode(out).expect("a Vec grows as needed");
+}
+
+/// Decode a batch, calling `on_metric` with every metric in it. Invalid batches are rejected as a
+/// whole.
+pub fn decode<F>(payload: &[u8], mut on_metric: F) -> Result<(), Error>
+where
+    F: FnMut(Metric),
+{
+    let payload = payload
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| anyhow!("not a batch"))?;
+    for metric in Batch::decode(payload)?.metrics {
+        on_metric(to_metric(metric));
+    }
+    Ok(())
+}
+
+fn to_metric(metric: BatchMetric) -> Metric {
+    if !metric.raw.is_empty() {
+        return Metric::new(metric.raw);
+    }
+
+    let BatchMetric {
+        name,
+        value,
+        ty,
+        tags,
+        extensions,
+        ..
+    } = metric;
+    let mut raw = Vec::with_capacity(
+        name.len()
+            + value.len()
+            + ty.len()
+            + 3
+            + extensions.iter().map(|x| x.len() + 1).sum::<usize>()
+            + tags.iter().map(|x| x.len() + 1).sum::<usize>(),
+    );
+    raw.extend(name);
+    raw.push(b':');
+    raw.extend(value);
+    raw.push(b'|');
+    raw.extend(ty);
+    for extension in extensions {
+        raw.push(b'|');
+        raw.extend(extension);
+    }
+    let mut tags_pos = None;
+    if !tags.is_empty() {
+        raw.extend(b"|#");
+        let start = raw.len();
+        for (i, tag) in tags.iter().enumerate() {
+            if i > 0 {
+                raw.push(b',');
+            }
+            raw.extend(tag);
+        }
+        tags_pos = Some((start, raw.len()));
+    }
+    Metric::from_raw_parts(raw, tags_pos)
+}
+
+struct Parts<'a, I> {
+    name: &'a [u8],
+    value: &'a [u8],
+    ty: &'a [u8],
+    extensions: I,
+}
+
+/// Split a line into its fields, or None if it has to be sent as is.
+fn split(raw: &[u8]) -> Option<Parts<'_, impl Iterator<Item = &[u8]>>> {
+    let mut sections = raw.split(|&x| x == b'|');
+    let name_value = sections.next()?;
+    let separator = name_value.iter().position(|&x| x == b':')?;
+    let ty = sections.next()?;
+    // The first section starting with `#` holds the tags. Tags are moved to the end of the line,
+    // so the type and other sections must not look like tags.
+    if ty.starts_with(b"#")
+        || raw
+            .split(|&x| x == b'|')
+            .filter(|section| section.starts_with(b"#"))
+            .count()
+            > 1
+    {
+        return None;
+    }
+    let mut seen_tags = false;
+    let extensions = sections.filter(move |section| {
+        let is_tags = !seen_tags && section.starts_with(b"#");
+        seen_tags |= is_tags;
+        !is_tags
+    });
+    Some(Parts {
+        name: &name_value[..separator],
+        value: &name_value[separator + 1..],
+        ty,
+        extensions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(lines: &[&[u8]]) -> Vec<Metric> {
+        let mut batch = MAGIC.to_vec();
+        for line in lines {
+            encode(&Metric::new(line.to_vec()), &mut batch);
+        }
+        assert!(is_batch(&batch));
+        let mut metrics = Vec::new();
+        decode(&batch, |metric| metrics.push(metric)).unwrap();
+        metrics
+    }
+
+    #[test]
+    fn lines() {
+        let metrics = roundtrip(&[
+            b"users.online:1|c",
+            b"users.online:1|c|@0.5|#country:china,,instance:foobar",
+            b"users.online:1|c|#country:china|T1692653389",
+            b"not a metric",
+            b"weird:1|#type",
+            b"weird:1|c|#a|#b",
+        ]);
+        assert_eq!(
+            metrics,
+            [
+                Metric::new(b"users.online:1|c".to_vec()),
+                Metric::new(b"users.online:1|c|@0.5|#country:china,,instance:foobar".to_vec()),
+                // Tags are moved to the end.
+                Metric::new(b"users.online:1|c|T1692653389|#country:china".to_vec()),
+                Metric::new(b"not a metric".to_vec()),
+                Metric::new(b"weird:1|#type".to_vec()),
+                Metric::new(b"weird:1|c|#a|#b".to_vec()),
+            ]
+        );
+        assert_eq!(
+            metrics[1].tags(),
+            Some(&b"country:china,,instance:foobar"[..])
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let mut batch = MAGIC.to_vec();
+        encode(&Metric::new(b"users.online:1|c".to_vec()), &mut batch);
+        // Unknown fields are skipped.
+        batch.extend([7 << 3, 0xac, 0x02]);
+        encode(&Metric::new(b"servers.online:1|c".to_vec()), &mut batch);
+        let mut metrics = Vec::new();
+        decode(&batch, |metric| metrics.push(metric)).unwrap();
+        assert_eq!(
+            metrics,
+            [
+                Metric::new(b"users.online:1|c".to_vec()),
+                Metric::new(b"servers.online:1|c".to_vec()),
+            ]
+        );
+
+        batch.truncate(batch.len() - 1);
+        let mut metrics = Vec::new();
+        assert!(decode(&batch, |metric| metrics.push(metric)).is_err());
+        assert_eq!(metrics, []);
+        assert!(decode(b"users.online:1|c", |_| {}).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed() {
+        let mut batch = MAGIC.to_vec();
+        for i in 0..50 {
+            let line = format!("users.online:{}|c|#country:china,instance:foobar", i);
+            encode(&Metric::new(line.into_bytes()), &mut batch);
+        }
+        let compressed = compress(&batch);
+        assert!(is_compressed(&compressed));
+        assert!(compressed.len() < batch.len() / 2);
+        assert_eq!(decompress(&compressed).unwrap(), batch);
+
+        // Batches that do not get smaller are sent as they are.
+        let mut batch = MAGIC.to_vec();
+        encode(&Metric::new(b"a:1|c".to_vec()), &mut batch);
+        assert_eq!(compress(&batch), batch);
+
+        let mut not_a_batch = COMPRESSED_MAGIC.to_vec();
+        not_a_batch.extend(zstd::encode_all(&b"users.online:1|c"[..], 0).unwrap());
+        assert!(decompress(&not_a_batch).is_err());
+
+        // Payloads decompressing to more than the maximum are rejected.
+        let mut too_large = COMPRESSED_MAGIC.to_vec();
+        let mut batch = MAGIC.to_vec();
+        batch.resize(MAX_DECOMPRESSED_SIZE + 1, b'a');
+        too_large.extend(zstd::encode_all(&batch[..], 0).unwrap());
+        assert!(too_large.len() < 4096);
+        assert!(decompress(&too_large).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::config::OverloadPolicy;
+
+/// A queue holding at most `capacity` items, shared between producers and a consumer.
+pub struct BoundedQueue<T> {
+    capacity: usize,
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+pub enum Pop<T> {
+    Item(T),
+    Timeout,
+    /// The queue is closed and empty.
+    Closed,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        BoundedQueue {
+            capacity: capacity.max(1),
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// Add an item. If the queue is full, `policy` decides whether to wait for space or which
+    /// item to drop. Returns the dropped item, if any.
+    pub fn push(&self, item: T, policy: OverloadPolicy) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let mut dropped = None;
+        if state.items.len() >= self.capacity {
+            match policy {
+                OverloadPolicy::Block => {
+                    state = self
+                        .not_full
+                        .wait_while(state, |state| state.items.len() >= self.capacity)
+                        .unwrap();
+                }
+                OverloadPolicy::DropNewest => return Some(item),
+                OverloadPolicy::DropOldest => dropped = state.items.pop_front(),
+            }
+        }
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+        dropped
+    }
+
+    /// Take the oldest item, waiting at most `timeout` for one.
+    pub fn pop(&self, timeout: Duration) -> Pop<T> {
+        let state = self.state.lock().unwrap();
+        let (mut state, _) = self
+            .not_empty
+            .wait_timeout_while(state, timeout, |state| {
+                state.items.is_empty() && !state.closed
+            })
+            .unwrap();
+        match state.items.pop_front() {
+            Some(item) => {
+                self.not_full.notify_one();
+                Pop::Item(item)
+            }
+            None if state.closed => Pop::Closed,
+            None => Pop::Timeout,
+        }
+    }
+
+    /// Let the consumer know that no more items will be pushed.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(queue: &BoundedQueue<u32>) -> Vec<u32> {
+        let mut items = Vec::new();
+        while let Pop::Item(item) = queue.pop(Duration::ZERO) {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn overload_policies() {
+        let queue = BoundedQueue::new(2);
+        assert_eq!(queue.push(1, OverloadPolicy::DropNewest), None);
+        assert_eq!(queue.push(2, OverloadPolicy::DropNewest), None);
+        assert_eq!(queue.push(3, OverloadPolicy::DropNewest), Some(3));
+        assert_eq!(drain(&queue), [1, 2]);
+
+        queue.push(1, OverloadPolicy::DropOldest);
+        queue.push(2, OverloadPolicy::DropOldest);
+        assert_eq!(queue.push(3, OverloadPolicy::DropOldest), Some(1));
+        assert_eq!(drain(&queue), [2, 3]);
+
+        queue.close();
+        assert!(matches!(queue.pop(Duration::ZERO), Pop::Closed));
+    }
+
+    #[test]
+    fn block() {
+        let queue = BoundedQueue::new(1);
+        queue.push(1, OverloadPolicy::Block);
+        std::thread::scope(|scope| {
+            scope.spawn(|| queue.push(2, OverloadPolicy::Block));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(matches!(queue.pop(Duration::ZERO), Pop::Item(1)));
+        });
+        assert_eq!(drain(&queue), [2]);
+    }
+}
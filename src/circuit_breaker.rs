@@ -0,0 +1,144 @@
+//! A circuit breaker for upstreams, to stop sending to an upstream that fails most sends and give
+//! it time to recover, instead of failing every send in the meantime.
+
+use std::time::{Duration, Instant};
+
+use crate::config::CircuitBreakerConfig;
+
+/// A change of the state of a circuit, to be logged and counted by the caller.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transition {
+    /// Too many sends failed, so sends are rejected until the circuit closes again.
+    Opened { errors: u64, sends: u64 },
+    /// The trial send after the circuit was open failed, so it stays open for another while.
+    Reopened,
+    /// The trial send after the circuit was open succeeded.
+    Closed,
+}
+
+/// Counts sends and failures in windows of `window`. Once at least `min_sends` were attempted in
+/// a window and the share of failures reaches `error_rate`, the circuit opens and sends are
+/// rejected for `open_for`. Then a single trial send decides whether it closes again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    error_rate: f64,
+    min_sends: u64,
+    window: Duration,
+    open_for: Duration,
+    window_started_at: Instant,
+    sends: u64,
+    errors: u64,
+    // While open, when the next trial send is allowed.
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            error_rate: config.error_rate,
+            min_sends: config.min_sends,
+            window: Duration::from_secs(config.window),
+            open_for: Duration::from_secs(config.open_for),
+            window_started_at: Instant::now(),
+            sends: 0,
+            errors: 0,
+            open_until: None,
+        }
+    }
+
+    pub fn open_for(&self) -> Duration {
+        self.open_for
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_until.is_some()
+    }
+
+    /// Whether to attempt a send at `now`. Once the circuit has been open for long enough, the
+    /// next send is allowed as a trial, and its result must be passed to `record`.
+    pub fn allow(&self, now: Instant) -> bool {
+        self.open_until.is_none_or(|until| now >= until)
+    }
+
+    /// Record the result of a send allowed by `allow`.
+    pub fn record(&mut self, ok: bool, now: Instant) -> Option<Transition> {
+        if self.open_until.is_some() {
+            return if ok {
+                self.open_until = None;
+                self.start_window(now);
+                Some(Transition::Closed)
+            } else {
+                self.open_until = Some(now + self.open_for);
+                Some(Transition::Reopened)
+            };
+        }
+
+        if now.saturating_duration_since(self.window_started_at) >= self.window {
+            self.start_window(now);
+        }
+        self.sends += 1;
+        if !ok {
+            self.errors += 1;
+        }
+        if self.sends >= self.min_sends && self.errors as f64 >= self.error_rate * self.sends as f64
+        {
+            let transition = Transition::Opened {
+                errors: self.errors,
+                sends: self.sends,
+            };
+            self.open_until = Some(now + self.open_for);
+            self.start_window(now);
+            return Some(transition);
+        }
+        None
+    }
+
+    fn start_window(&mut self, now: Instant) {
+        self.window_started_at = now;
+        self.sends = 0;
+        self.errors = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let mut breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            error_rate: 0.5,
+            min_sends: 4,
+            window: 10,
+            open_for: 5,
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Failures in an earlier window do not count.
+        assert_eq!(breaker.record(false, at(0)), None);
+        assert_eq!(breaker.record(false, at(0)), None);
+        assert_eq!(breaker.record(false, at(0)), None);
+        assert_eq!(breaker.record(true, at(10)), None);
+        assert_eq!(breaker.record(true, at(10)), None);
+        assert_eq!(breaker.record(false, at(10)), None);
+        assert!(breaker.allow(at(10)));
+        assert_eq!(
+            breaker.record(false, at(11)),
+            Some(Transition::Opened {
+                errors: 2,
+                sends: 4
+            })
+        );
+
+        assert!(breaker.is_open());
+        assert!(!breaker.allow(at(15)));
+        assert!(breaker.allow(at(16)));
+        assert_eq!(breaker.record(false, at(16)), Some(Transition::Reopened));
+        assert!(!breaker.allow(at(20)));
+        assert!(breaker.allow(at(21)));
+        assert_eq!(breaker.record(true, at(21)), Some(Transition::Closed));
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.record(false, at(21)), None);
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Error};
+
+use crate::config::ClientTagConfig;
+
+/// Builds the tag identifying the client that sent a metric, like `client:10.0.3.4`.
+pub struct ClientTag {
+    tag: String,
+    names: HashMap<IpAddr, String>,
+}
+
+impl ClientTag {
+    pub fn new(config: &ClientTagConfig) -> Result<Self, Error> {
+        let names = config
+            .names
+            .iter()
+            .map(|(addr, name)| {
+                let addr = addr
+                    .parse()
+                    .map_err(|_| anyhow!("invalid IP address in client_tag: {}", addr))?;
+                Ok((addr, name.clone()))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(ClientTag {
+            tag: config.tag.clone(),
+            names,
+        })
+    }
+
+    pub fn for_addr(&self, addr: IpAddr) -> Vec<u8> {
+        // Clients connecting over IPv6 sockets may be IPv4 clients in disguise.
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        match self.names.get(&addr) {
+            Some(name) => format!("{}:{}", self.tag, name).into_bytes(),
+            None => format!("{}:{}", self.tag, addr).into_bytes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn basic() {
+        let config = ClientTagConfig {
+            tag: "client".to_string(),
+            names: BTreeMap::from([("10.0.3.4".to_string(), "web-1".to_string())]),
+        };
+        let client_tag = ClientTag::new(&config).unwrap();
+        assert_eq!(
+            client_tag.for_addr("10.0.3.4".parse().unwrap()),
+            b"client:web-1"
+        );
+        assert_eq!(
+            client_tag.for_addr("::ffff:10.0.3.4".parse().unwrap()),
+            b"client:web-1"
+        );
+        assert_eq!(
+            client_tag.for_addr("10.0.3.5".parse().unwrap()),
+            b"client:10.0.3.5"
+        );
+
+        let config = ClientTagConfig {
+            tag: "client".to_string(),
+            names: BTreeMap::from([("web-1".to_string(), "web-1".to_string())]),
+        };
+        assert!(ClientTag::new(&config).is_err());
+    }
+}
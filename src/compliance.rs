@@ -0,0 +1,444 @@
+//! Checking metrics against the DogStatsD datagram format (protocol v1.3), to find clients that
+//! send non-conforming metrics:
+//!
+//! ```text
+//! <name>:<value>[:<value>...]|<type>[|@<sample rate>][|#<tag>,<tag>...][|c:<container id>][|T<timestamp>]
+//! ```
+//!
+//! Violations are counted per client address and violation, and reported in the
+//! `server.compliance_violations` self metric and on the admin endpoint.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::self_metrics;
+use crate::types::Metric;
+
+// Violations of more distinct clients than this are counted under `OTHER_SOURCE`.
+const MAX_SOURCES: usize = 1000;
+const OTHER_SOURCE: &str = "other";
+// The source of metrics received without an address, e.g. on standard input.
+const UNKNOWN_SOURCE: &str = "unknown";
+
+// Names and tags longer than this are rejected by Datadog.
+const MAX_NAME_LENGTH: usize = 200;
+const MAX_TAG_LENGTH: usize = 200;
+// Examples in the report are cut off after this many bytes.
+const MAX_EXAMPLE_LENGTH: usize = 200;
+
+const TYPES: &[&[u8]] = &[b"c", b"g", b"ms", b"h", b"s", b"d"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Violation {
+    /// Not of the form `<name>:<value>|<type>`.
+    Format,
+    /// The name is empty, too long, does not start with a letter, or contains characters other
+    /// than ASCII letters, digits, underscores and periods.
+    Name,
+    /// A value is not a number. Values of sets may be anything but empty.
+    Value,
+    /// Not one of `c`, `g`, `ms`, `h`, `s` and `d`.
+    Type,
+    /// The sample rate is not a number between 0 and 1.
+    SampleRate,
+    /// A tag is empty, too long, does not start with a letter, or contains characters other than
+    /// ASCII letters, digits, underscores, minuses, colons, periods and slashes.
+    Tag,
+    /// The container ID is empty.
+    ContainerId,
+    /// The timestamp is not a number of seconds.
+    Timestamp,
+    /// A section not defined by the format.
+    UnknownField,
+    /// Sample rate, tags, container ID and timestamp are repeated or not in this order.
+    FieldOrder,
+}
+
+impl Violation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Violation::Format => "format",
+            Violation::Name => "name",
+            Violation::Value => "value",
+            Violation::Type => "type",
+            Violation::SampleRate => "sample_rate",
+            Violation::Tag => "tag",
+            Violation::ContainerId => "container_id",
+            Violation::Timestamp => "timestamp",
+            Violation::UnknownField => "unknown_field",
+            Violation::FieldOrder => "field_order",
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Field {
+    SampleRate,
+    Tags,
+    ContainerId,
+    Timestamp,
+    Unknown,
+}
+
+impl Field {
+    fn of(section: &[u8]) -> Field {
+        match section {
+            [b'@', ..] => Field::SampleRate,
+            [b'#', ..] => Field::Tags,
+            [b'c', b':', ..] => Field::ContainerId,
+            [b'T', ..] => Field::Timestamp,
+            _ => Field::Unknown,
+        }
+    }
+}
+
+/// The sections of a metric line.
+struct Line<'a> {
+    name: &'a [u8],
+    values: &'a [u8],
+    ty: &'a [u8],
+    fields: std::slice::Split<'a, u8, fn(&u8) -> bool>,
+}
+
+fn is_separator(x: &u8) -> bool {
+    *x == b'|'
+}
+
+fn parse(raw: &[u8]) -> Option<Line<'_>> {
+    let mut sections = raw.split(is_separator as fn(&u8) -> bool);
+    let (name, values) = split_once(sections.next()?, b':')?;
+    let ty = sections.next()?;
+    Some(Line {
+        name,
+        values,
+        ty,
+        fields: sections,
+    })
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let i = bytes.iter().position(|&x| x == separator)?;
+    Some((&bytes[..i], &bytes[i + 1..]))
+}
+
+fn valid_name(name: &[u8]) -> bool {
+    name.len() <= MAX_NAME_LENGTH
+        && name.first().is_some_and(u8::is_ascii_alphabetic)
+        && name.iter().all(|&x| name_char(x))
+}
+
+fn name_char(x: u8) -> bool {
+    x.is_ascii_alphanumeric() || matches!(x, b'_' | b'.')
+}
+
+fn valid_tag(tag: &[u8]) -> bool {
+    tag.len() <= MAX_TAG_LENGTH
+        && tag.first().is_some_and(u8::is_ascii_alphabetic)
+        && tag.iter().all(|&x| tag_char(x))
+}
+
+fn tag_char(x: u8) -> bool {
+    x.is_ascii_alphanumeric() || matches!(x, b'_' | b'-' | b':' | b'.' | b'/')
+}
+
+fn parse_number(bytes: &[u8]) -> Option<f64> {
+    std::str::from_utf8(bytes)
+        .ok()?
+        .parse::<f64>()
+        .ok()
+        .filter(|x| x.is_finite())
+}
+
+fn valid_values(values: &[u8], ty: &[u8]) -> bool {
+    if ty == b"s" {
+        return !values.is_empty();
+    }
+    values
+        .split(|&x| x == b':')
+        .all(|value| parse_number(value).is_some())
+}
+
+fn valid_field(field: Field, section: &[u8]) -> Result<(), Violation> {
+    let ok = match field {
+        Field::SampleRate => {
+            parse_number(&section[1..]).is_some_and(|rate| rate > 0.0 && rate <= 1.0)
+        }
+        Field::Tags => section[1..].split(|&x| x == b',').all(valid_tag),
+        Field::ContainerId => section.len() > 2,
+        Field::Timestamp => section.len() > 1 && section[1..].iter().all(u8::is_ascii_digit),
+        Field::Unknown => false,
+    };
+    if ok {
+        return Ok(());
+    }
+    Err(match field {
+        Field::SampleRate => Violation::SampleRate,
+        Field::Tags => Violation::Tag,
+        Field::ContainerId => Violation::ContainerId,
+        Field::Timestamp => Violation::Timestamp,
+        Field::Unknown => Violation::UnknownField,
+    })
+}
+
+/// Check `metric`, and return every kind of violation found in it, in the order of `Violation`.
+/// Events and service checks are valid datagrams, but not metrics, so they have none.
+pub fn check(metric: &Metric) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if metric.is_event() || metric.is_service_check() {
+        return violations;
+    }
+    let Some(line) = parse(&metric.raw) else {
+        violations.push(Violation::Format);
+        return violations;
+    };
+
+    if !valid_name(line.name) {
+        violations.push(Violation::Name);
+    }
+    if !valid_values(line.values, line.ty) {
+        violations.push(Violation::Value);
+    }
+    if !TYPES.contains(&line.ty) {
+        violations.push(Violation::Type);
+    }
+    let mut last_field = None;
+    for section in line.fields {
+        let field = Field::of(section);
+        if let Err(violation) = valid_field(field, section) {
+            violations.push(violation);
+        }
+        if field != Field::Unknown {
+            if last_field.is_some_and(|last| last >= field) {
+                violations.push(Violation::FieldOrder);
+            }
+            last_field = Some(field);
+        }
+    }
+    violations.sort_unstable();
+    violations.dedup();
+    violations
+}
+
+/// Fix the violations in `metric` that can be fixed without guessing: characters not allowed in
+/// names and tags are replaced with underscores, empty tags are removed, and the optional fields
+/// are sorted into the documented order. Returns None if `metric` cannot be parsed at all.
+pub fn normalize(metric: &Metric) -> Option<Vec<u8>> {
+    if metric.is_event() || metric.is_service_check() {
+        return Some(metric.raw.clone());
+    }
+    let line = parse(&metric.raw)?;
+    let mut fields: Vec<(Field, &[u8])> = line
+        .fields
+        .map(|section| (Field::of(section), section))
+        .collect();
+    // Stable, so that unknown sections keep their order at the end.
+    fields.sort_by_key(|(field, _)| *field);
+
+    let mut normalized = Vec::with_capacity(metric.raw.len());
+    normalized.extend(line.name.iter().map(|&x| replace_invalid(x, name_char)));
+    normalized.push(b':');
+    normalized.extend(line.values);
+    normalized.push(b'|');
+    normalized.extend(line.ty);
+    for (field, section) in fields {
+        if field != Field::Tags {
+            normalized.push(b'|');
+            normalized.extend(section);
+            continue;
+        }
+        let mut tags = section[1..].split(|&x| x == b',').filter(|x| !x.is_empty());
+        let Some(first) = tags.next() else {
+            continue;
+        };
+        normalized.extend(b"|#");
+        normalized.extend(first.iter().map(|&x| replace_invalid(x, tag_char)));
+        for tag in tags {
+            normalized.push(b',');
+            normalized.extend(tag.iter().map(|&x| replace_invalid(x, tag_char)));
+        }
+    }
+    Some(normalized)
+}
+
+fn replace_invalid(x: u8, valid: fn(u8) -> bool) -> u8 {
+    if valid(x) {
+        x
+    } else {
+        b'_'
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportEntry {
+    pub source: String,
+    pub violation: Violation,
+    pub count: u64,
+    /// The last metric with this violation, cut off after a few hundred bytes.
+    pub example: Vec<u8>,
+}
+
+// The count and last example, keyed by source and violation.
+type Report = BTreeMap<(String, Violation), (u64, Vec<u8>)>;
+static REPORT: Mutex<Report> = Mutex::new(BTreeMap::new());
+
+fn source_name(addr: Option<IpAddr>) -> String {
+    match addr {
+        // Clients connecting over IPv6 sockets may be IPv4 clients in disguise.
+        Some(IpAddr::V6(v6)) => v6
+            .to_ipv4_mapped()
+            .map_or(IpAddr::V6(v6), IpAddr::V4)
+            .to_string(),
+        Some(addr) => addr.to_string(),
+        None => UNKNOWN_SOURCE.to_string(),
+    }
+}
+
+/// Record that the metric `raw`, received from `source`, has `violations`.
+pub fn record(source: Option<IpAddr>, violations: &[Violation], raw: &[u8]) {
+    let mut source = source_name(source);
+    let example = &raw[..raw.len().min(MAX_EXAMPLE_LENGTH)];
+    {
+        let mut report = REPORT.lock().unwrap();
+        let known = report.range((source.clone(), Violation::Format)..).next();
+        if report.len() >= MAX_SOURCES
+            && known.is_none_or(|((known_source, _), _)| *known_source != source)
+        {
+            source = OTHER_SOURCE.to_string();
+        }
+        for violation in violations {
+            let entry = report
+                .entry((source.clone(), *violation))
+                .or_insert((0, Vec::new()));
+            entry.0 += 1;
+            entry.1.clear();
+            entry.1.extend(example);
+        }
+    }
+    for violation in violations {
+        self_metrics::incr(
+            "server.compliance_violations",
+            &[("violation", violation.as_str())],
+            1,
+        );
+    }
+}
+
+/// The number of metrics with each violation per source since startup.
+pub fn report() -> Vec<ReportEntry> {
+    REPORT
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((source, violation), (count, example))| ReportEntry {
+            source: source.clone(),
+            violation: *violation,
+            count: *count,
+            example: example.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(raw: &[u8]) -> Metric {
+        Metric::new(raw.to_vec())
+    }
+
+    #[test]
+    fn conforming() {
+        for raw in [
+            &b"users.online:1|c"[..],
+            b"users.online:1:2.5:-3|ms|@0.5|#country:china,env:prod/eu-1|c:abc123|T1692653389",
+            b"users.seen:john doe|s",
+            b"temperature:20.5|g",
+            b"request.size:512:256|h",
+            b"queue.wait:3|d",
+            b"_e{5,4}:title|text|#a:b",
+            b"_sc|service|0",
+        ] {
+            assert_eq!(check(&metric(raw)), [], "{}", String::from_utf8_lossy(raw));
+        }
+    }
+
+    #[test]
+    fn violations() {
+        for (raw, violations) in [
+            (&b"users.online"[..], &[Violation::Format][..]),
+            (b"users-online:1|c", &[Violation::Name]),
+            (b"1users:1|c", &[Violation::Name]),
+            (b"users.online:one|c", &[Violation::Value]),
+            (b"users.online:1|x", &[Violation::Type]),
+            (b"users.online:1|c|@2", &[Violation::SampleRate]),
+            (b"users.online:1|c|#a,,b", &[Violation::Tag]),
+            (b"users.online:1|c|#country:china;", &[Violation::Tag]),
+            (b"users.online:1|c|c:", &[Violation::ContainerId]),
+            (b"users.online:1|c|T-1", &[Violation::Timestamp]),
+            (b"users.online:1|c|x", &[Violation::UnknownField]),
+            (b"users.online:1|c|#a|@0.5", &[Violation::FieldOrder]),
+            (b"users.online:1|c|#a|#b", &[Violation::FieldOrder]),
+            (
+                b"users online:1|C|#a b",
+                &[Violation::Name, Violation::Type, Violation::Tag],
+            ),
+        ] {
+            assert_eq!(
+                check(&metric(raw)),
+                violations,
+                "{}",
+                String::from_utf8_lossy(raw)
+            );
+        }
+    }
+
+    #[test]
+    fn normalized() {
+        assert_eq!(normalize(&metric(b"users.online")), None);
+        assert_eq!(
+            normalize(&metric(b"users online:1|c|T1692653389|#a b,,c:d;|@0.5|x")).unwrap(),
+            b"users_online:1|c|@0.5|#a_b,c:d_|T1692653389|x"
+        );
+        assert_eq!(
+            normalize(&metric(b"users.online:1|c|#,")).unwrap(),
+            b"users.online:1|c"
+        );
+    }
+
+    #[test]
+    fn reported() {
+        let source = Some("::ffff:10.99.0.1".parse().unwrap());
+        record(source, &[Violation::Name], b"users-online:1|c");
+        record(source, &[Violation::Name, Violation::Tag], b"a-b:1|c|#;");
+
+        let report: Vec<_> = report()
+            .into_iter()
+            .filter(|entry| entry.source == "10.99.0.1")
+            .collect();
+        assert_eq!(
+            report,
+            [
+                ReportEntry {
+                    source: "10.99.0.1".to_string(),
+                    violation: Violation::Name,
+                    count: 2,
+                    example: b"a-b:1|c|#;".to_vec(),
+                },
+                ReportEntry {
+                    source: "10.99.0.1".to_string(),
+                    violation: Violation::Tag,
+                    count: 1,
+                    example: b"a-b:1|c|#;".to_vec(),
+                },
+            ]
+        );
+    }
+}
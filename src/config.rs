@@ -1,9 +1,38 @@
 #[cfg(feature = "cli")]
 use {anyhow::Error, serde::Deserialize, std::fs::File};
 
+use std::collections::BTreeMap;
+
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq, Default)]
+#[derive(Clone, Debug, PartialEq, Default)]
+#[non_exhaustive]
 pub struct Config {
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub server: ServerConfig,
+    /// Metrics that must never be dropped by any middleware.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub exemptions: ExemptionConfig,
+    /// What to do with DogStatsD events and service checks.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub events: EventsConfig,
+    /// Forward metrics through an aggregating relay instead of directly to the upstream.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub relay: Option<RelayConfig>,
+    /// Distribute metrics among several statsdproxy instances by name.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub cluster: Option<ClusterConfig>,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub upstream: UpstreamConfig,
+    /// Only count what the middlewares would change, and forward all metrics unchanged.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub observe_only: bool,
+    /// Also run candidate middlewares on a copy of all metrics, and compare what they emit with
+    /// what the middlewares emit.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub compare: Option<CompareConfig>,
+    /// On reload, roll out the new middlewares gradually instead of switching to them at once.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub canary: Option<CanaryConfig>,
     pub middlewares: Vec<MiddlewareConfig>,
 }
 
@@ -12,72 +41,897 @@ impl Config {
     pub fn new(path: &str) -> Result<Self, Error> {
         let f = File::open(path)?;
         let d: Config = serde_yaml::from_reader(f)?;
+        d.check()?;
         Ok(d)
     }
+
+    /// Validate the whole config, failing with every invalid value at once.
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        let errors = self.validate();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(
+            "invalid config:\n  {}",
+            errors.join("\n  ")
+        ))
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct EventsConfig {
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub action: EventAction,
+    /// The upstream for `EventAction::Upstream`, like the `--upstream` argument.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub upstream: Option<String>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum EventAction {
+    /// Send them to the upstream unchanged, bypassing the middlewares.
+    #[default]
+    Pass,
+    /// Drop them.
+    Drop,
+    /// Send them unchanged to the upstream in `EventsConfig::upstream`.
+    Upstream,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct ClusterConfig {
+    /// The UDP listen addresses of all instances in the cluster, in `host:port` format, in the
+    /// same order on every instance.
+    pub peers: Vec<String>,
+    /// The entry of `peers` that is this instance.
+    pub local_peer: String,
+    /// Send datagrams of up to this many bytes to peers.
+    pub max_datagram_size: usize,
+    /// Send a partially filled datagram to a peer after this many milliseconds, so that forwarded
+    /// metrics arrive in time for the same flush of aggregated metrics.
+    pub max_delay_ms: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            peers: Vec::new(),
+            local_peer: String::new(),
+            max_datagram_size: 1400,
+            max_delay_ms: 100,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct CompareConfig {
+    /// The candidate middlewares, in the same form as `middlewares`.
+    pub middlewares: Vec<MiddlewareConfig>,
+    /// Compare what both chains emitted every this many seconds.
+    pub interval: u64,
+    /// The maximum number of series compared per interval. Further series are not compared.
+    pub max_series: usize,
+}
+
+impl Default for CompareConfig {
+    fn default() -> Self {
+        CompareConfig {
+            middlewares: Vec::new(),
+            interval: 60,
+            max_series: 100000,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+#[non_exhaustive]
+pub struct ServerConfig {
+    /// The number of threads receiving metrics on the listen address. Each thread binds its own
+    /// socket with SO_REUSEPORT and runs its own instance of the middleware chain, so stateful
+    /// middlewares (aggregation, cardinality limits) keep separate state per thread.
+    pub receiver_threads: usize,
+    /// The maximum number of datagrams to receive with a single `recvmmsg` syscall. Values above 1
+    /// enable batched receives on Linux, and are ignored on other platforms.
+    pub recv_batch_size: usize,
+    /// Additionally accept newline-separated metrics over TCP on this address.
+    pub tcp_listen: Option<String>,
+    /// Additionally accept newline-separated metrics over TLS-wrapped TCP. Requires the `tls`
+    /// feature.
+    pub tls: Option<TlsListenConfig>,
+    /// Additionally accept metrics from other statsdproxy instances over QUIC, sent with
+    /// `--upstream quic:host:port`. Requires the `quic` feature.
+    pub quic: Option<TlsListenConfig>,
+    /// The maximum number of open connections per TCP or TLS listener. Further connections are
+    /// closed right after accepting them.
+    pub max_connections: usize,
+    /// Additionally accept POST requests with newline-separated metrics in the body on this
+    /// address. Requires the `http` feature.
+    pub http_listen: Option<String>,
+    /// Additionally accept OTLP/gRPC metric export requests on this address. Requires the `otlp`
+    /// feature.
+    pub otlp_grpc_listen: Option<String>,
+    /// Serve debugging endpoints like `/aggregation` over HTTP on this address. Requires the
+    /// `http` feature.
+    pub admin_listen: Option<String>,
+    /// The size of the socket's receive buffer (SO_RCVBUF) in bytes. Larger buffers absorb
+    /// bursts of traffic without the kernel dropping datagrams. Defaults to the system default.
+    pub recv_buffer_size: Option<usize>,
+    /// Accept the binary batches that other statsdproxy instances send with `upstream.format` set
+    /// to `batch` or `compressed-batch`. Off by default, so that datagrams are always parsed as
+    /// text lines unless the clients are known to be statsdproxy instances.
+    pub accept_batches: bool,
+    /// Periodically emit metrics about statsdproxy itself through the middlewares.
+    pub self_metrics: Option<SelfMetricsConfig>,
+    /// The number of worker threads running the middlewares, per receiver thread. Metrics are
+    /// assigned to workers by name, and each worker runs its own instance of the middleware chain.
+    /// 0 runs the middlewares on the receiver thread.
+    pub worker_threads: usize,
+    /// The number of metrics queued up for each worker.
+    pub worker_queue_size: usize,
+    /// What to do with metrics when a worker's queue is full.
+    pub overload_policy: OverloadPolicy,
+    /// Only receive metrics arriving on this network interface (SO_BINDTODEVICE). Linux only.
+    pub interface: Option<String>,
+    /// Whether sockets listening on an IPv6 address only accept IPv6 (IPV6_V6ONLY). If false,
+    /// `[::]` accepts IPv4 as well. Defaults to the system default.
+    pub ipv6_only: Option<bool>,
+    /// Metrics longer than this many bytes, including the client tag, are handled according to
+    /// `oversized_metric_policy`.
+    pub max_metric_length: Option<usize>,
+    pub oversized_metric_policy: OversizedMetricPolicy,
+    /// Limit the rate of UDP packets, before they are passed to the middlewares.
+    pub packet_rate_limit: Option<PacketRateLimitConfig>,
+    /// Tag every metric with the address of the client that sent it.
+    pub client_tag: Option<ClientTagConfig>,
+    /// Check every metric against the DogStatsD datagram format, and report violations per
+    /// client.
+    pub compliance: Option<ComplianceConfig>,
+    /// Wait for dependencies like the upstream before listening.
+    pub startup: Option<StartupConfig>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct StartupConfig {
+    /// TCP addresses that must accept connections before listening, in `host:port` format. A
+    /// TCP, TLS or Graphite upstream is always waited for.
+    pub wait_for: Vec<String>,
+    /// Stop waiting after this many seconds.
+    pub timeout: u64,
+    pub on_timeout: StartupTimeoutPolicy,
+}
+
+impl Default for StartupConfig {
+    fn default() -> Self {
+        StartupConfig {
+            wait_for: Vec::new(),
+            timeout: 30,
+            on_timeout: StartupTimeoutPolicy::Listen,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum StartupTimeoutPolicy {
+    /// Start listening anyway.
+    #[default]
+    Listen,
+    /// Exit with an error.
+    Exit,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum OverloadPolicy {
+    /// Wait until there is space in the queue, so that the socket buffer fills up instead.
+    #[default]
+    Block,
+    /// Drop the metric that does not fit into the queue anymore.
+    DropNewest,
+    /// Drop the oldest metric in the queue to make space.
+    DropOldest,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum OversizedMetricPolicy {
+    /// Drop the metric.
+    #[default]
+    Drop,
+    /// Remove tags from the end until the metric fits, or drop it if it does not fit without tags.
+    TruncateTags,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            receiver_threads: 1,
+            recv_batch_size: 1,
+            tcp_listen: None,
+            tls: None,
+            quic: None,
+            max_connections: 1000,
+            http_listen: None,
+            otlp_grpc_listen: None,
+            admin_listen: None,
+            recv_buffer_size: None,
+            accept_batches: false,
+            self_metrics: None,
+            worker_threads: 0,
+            worker_queue_size: 1000,
+            overload_policy: OverloadPolicy::Block,
+            interface: None,
+            ipv6_only: None,
+            max_metric_length: None,
+            oversized_metric_policy: OversizedMetricPolicy::Drop,
+            packet_rate_limit: None,
+            client_tag: None,
+            compliance: None,
+            startup: None,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct SelfMetricsConfig {
+    pub prefix: String,
+    /// Emit self metrics every `interval` seconds.
+    pub interval: u64,
+}
+
+impl Default for SelfMetricsConfig {
+    fn default() -> Self {
+        SelfMetricsConfig {
+            prefix: "statsdproxy".to_string(),
+            interval: 10,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+#[non_exhaustive]
+pub struct UpstreamConfig {
+    /// The local address to send metrics from, e.g. `10.0.0.5:0` for a specific interface or
+    /// `0.0.0.0:8200` for a fixed source port. Defaults to an ephemeral port on all interfaces.
+    pub bind: Option<String>,
+    /// The format of the datagrams sent upstream.
+    pub format: UpstreamFormat,
+    /// With a TCP upstream, the number of metrics buffered while reconnecting. Once the buffer is
+    /// full, the oldest metrics are dropped.
+    pub max_buffered_lines: usize,
+    /// The maximum size of a datagram sent to a UDP upstream. Metrics that do not fit into a
+    /// datagram on their own are handled according to `oversized_metric_policy`.
+    pub max_datagram_size: usize,
+    /// Send datagrams as large as the MTU of the route to a UDP upstream allows instead, falling
+    /// back to `max_datagram_size` if it cannot be determined.
+    pub discover_mtu: bool,
+    pub oversized_metric_policy: OversizedDatagramPolicy,
+    /// Tag names to keep longest when truncating tags of oversized metrics, most important
+    /// first. Tags not listed here are removed first.
+    pub tag_priority: Vec<String>,
+    /// Resolve the upstream's hostname again every this many seconds, and send to the new address
+    /// if it changed. Defaults to resolving it once at startup.
+    pub resolve_interval: Option<u64>,
+    /// Spool metrics that could not be sent to disk, and send them once sending succeeds again.
+    pub spool: Option<SpoolConfig>,
+    /// How to connect with `--upstream tls:host:port`. Requires the `tls` feature.
+    pub tls: Option<TlsUpstreamConfig>,
+    /// How to connect with `--upstream quic:host:port`. Requires the `quic` feature.
+    pub quic: Option<QuicUpstreamConfig>,
+    /// Stop sending to a UDP upstream for a while once most sends to it fail.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// On Linux, send up to this many full datagrams to a UDP upstream with a single `sendmmsg`
+    /// syscall. Values above 1 enable batched sends, and are ignored on other platforms.
+    pub send_batch_size: usize,
+    /// Send at most this many datagrams or bytes per second to a UDP upstream.
+    pub rate_limit: Option<UpstreamRateLimitConfig>,
+    /// Merge metrics passed through and flushed by middlewares into paced batches.
+    pub scheduler: Option<OutputSchedulerConfig>,
+    /// Send to a secondary upstream while the upstream is failing.
+    pub failover: Option<FailoverConfig>,
+    /// Send metrics to an upstream chosen by the value of a tag.
+    pub routing: Option<RoutingConfig>,
+    /// Also append every metric leaving the middlewares to a file.
+    pub file_output: Option<FileOutputConfig>,
+    /// How to print metrics with `--upstream stdout://` or `stderr://`.
+    pub print: PrintConfig,
+    /// How to send metrics with `--upstream remote-write:<url>`.
+    pub remote_write: RemoteWriteConfig,
+    /// How to send metrics with `--upstream otlp:<url>`.
+    pub otlp: OtlpExportConfig,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            bind: None,
+            format: UpstreamFormat::Text,
+            max_buffered_lines: 10000,
+            max_datagram_size: 512,
+            discover_mtu: false,
+            oversized_metric_policy: OversizedDatagramPolicy::Send,
+            tag_priority: Vec::new(),
+            resolve_interval: None,
+            spool: None,
+            tls: None,
+            quic: None,
+            circuit_breaker: None,
+            send_batch_size: 1,
+            rate_limit: None,
+            scheduler: None,
+            failover: None,
+            routing: None,
+            file_output: None,
+            print: PrintConfig::default(),
+            remote_write: RemoteWriteConfig::default(),
+            otlp: OtlpExportConfig::default(),
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum OversizedDatagramPolicy {
+    /// Send the metric in a datagram of its own anyway, which may be fragmented or dropped on the
+    /// way.
+    #[default]
+    Send,
+    /// Drop the metric.
+    Drop,
+    /// Remove tags by priority until the metric fits, or drop it if it does not fit without tags.
+    TruncateTags,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct FailoverConfig {
+    /// The address of the secondary upstream, in the same form as `--upstream`.
+    pub secondary: String,
+    /// A TCP address to connect to every `health_check_interval` seconds. The primary upstream is
+    /// considered failing while connecting fails or takes longer than `health_check_timeout`
+    /// seconds.
+    pub health_check: Option<String>,
+    pub health_check_interval: u64,
+    pub health_check_timeout: u64,
+    /// Send to the secondary upstream for at least this many seconds after the primary failed.
+    pub failback_after: u64,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        FailoverConfig {
+            secondary: String::new(),
+            health_check: None,
+            health_check_interval: 5,
+            health_check_timeout: 1,
+            failback_after: 30,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct RoutingConfig {
+    /// The name of the tag to route by, e.g. `team`.
+    pub tag: String,
+    /// Upstreams by tag value, in the same form as `--upstream`. Metrics without the tag or with
+    /// another value go to `--upstream`.
+    pub routes: BTreeMap<String, String>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum UpstreamFormat {
+    /// Newline-separated statsd lines.
+    #[default]
+    Text,
+    /// The binary batch format of `crate::batch`, only understood by other statsdproxy instances.
+    Batch,
+    /// Batches compressed with zstd, only understood by other statsdproxy instances. Requires the
+    /// `zstd` feature, without which `Upstream` sends them uncompressed.
+    CompressedBatch,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PacketRateLimitConfig {
+    pub packets_per_second: u64,
+    /// The number of packets that can be received at once after a quiet period. Defaults to
+    /// `packets_per_second`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub burst: Option<u64>,
+    /// Limit every source IP address separately, instead of all sources together.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub per_source: bool,
+}
+
+impl PacketRateLimitConfig {
+    pub fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.packets_per_second) as f64
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct ClientTagConfig {
+    /// The name of the tag.
+    pub tag: String,
+    /// Names to use as the tag value instead of the IP address, keyed by IP address.
+    pub names: BTreeMap<String, String>,
+}
+
+impl Default for ClientTagConfig {
+    fn default() -> Self {
+        ClientTagConfig {
+            tag: "client".to_string(),
+            names: BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct ComplianceConfig {
+    pub action: ComplianceAction,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum ComplianceAction {
+    /// Only report violations, and pass metrics on unchanged.
+    #[default]
+    Report,
+    /// Report violations, and fix those that can be fixed before passing metrics on.
+    Normalize,
+    /// Report violations, and drop metrics with any violation.
+    Drop,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsListenConfig {
+    pub listen: String,
+    /// Path to the PEM-encoded certificate chain.
+    pub cert_path: String,
+    /// Path to the PEM-encoded private key.
+    pub key_path: String,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsUpstreamConfig {
+    /// Path to the PEM-encoded certificates of the authorities the upstream's certificate must
+    /// be signed by.
+    pub ca_path: String,
+    /// Path to the PEM-encoded client certificate chain, for upstreams that require mutual TLS.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub cert_path: Option<String>,
+    /// Path to the PEM-encoded private key of the client certificate.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub key_path: Option<String>,
+    /// The name to verify the upstream's certificate against. Defaults to the upstream's host.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub server_name: Option<String>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuicUpstreamConfig {
+    /// Path to the PEM-encoded certificates of the authorities the upstream's certificate must
+    /// be signed by.
+    pub ca_path: String,
+    /// The name to verify the upstream's certificate against. Defaults to the upstream's host.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub server_name: Option<String>,
+    /// Classes of metrics by name prefix, checked in order. Metrics in no class are sent as
+    /// unreliable datagrams.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub classes: Vec<QuicClassConfig>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuicClassConfig {
+    /// Metric name prefixes that belong to this class.
+    pub prefixes: Vec<String>,
+    /// Send the metrics of this class over a stream, on which lost packets are retransmitted,
+    /// instead of as unreliable datagrams.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub reliable: bool,
+    /// The priority of the stream of a reliable class. Streams with a higher priority are sent
+    /// first while the connection is congested.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub priority: i32,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct ExemptionConfig {
+    /// Metric name prefixes that are exempt.
+    pub prefixes: Vec<String>,
+    /// Tags that make a metric exempt, either as `key` to match any value or as `key:value`.
+    pub tags: Vec<String>,
+}
+
+impl ExemptionConfig {
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty() && self.tags.is_empty()
+    }
 }
 
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "cli", serde(tag = "type", rename_all = "kebab-case"))]
+#[non_exhaustive]
 pub enum MiddlewareConfig {
     DenyTag(DenyTagConfig),
     AllowTag(AllowTagConfig),
+    DenyMetric(DenyMetricConfig),
+    AllowMetric(AllowMetricConfig),
+    DenyType(TypeFilterConfig),
+    AllowType(TypeFilterConfig),
     CardinalityLimit(CardinalityLimitConfig),
     AggregateMetrics(AggregateMetricsConfig),
     Sample(SampleConfig),
+    NormalizeSampleRate(NormalizeSampleRateConfig),
     AddTag(AddTagConfig),
+    RewriteName(RewriteNameConfig),
+    NameToTags(NameToTagsConfig),
+    ScrubTags(ScrubTagsConfig),
+    ValidateValues(ValidateValuesConfig),
+    RateLimit(RateLimitConfig),
+    DedupeGauges(DedupeGaugesConfig),
     TagCardinalityLimit(TagCardinalityLimitConfig),
+    Exec(ExecConfig),
+    Schedule(ScheduleConfig),
+    Route(RouteConfig),
+    Mirror(MirrorConfig),
+    Usage(UsageConfig),
+    ByteBudget(ByteBudgetConfig),
+    MaxTags(MaxTagsConfig),
+    DuplicateTags(DuplicateTagsConfig),
+    CleanTags(CleanTagsConfig),
+    DowngradeDogstatsd(DowngradeDogstatsdConfig),
+    Sanitize(SanitizeConfig),
+    Truncate(TruncateConfig),
+    AddTimestamp(AddTimestampConfig),
+    DeriveRate(DeriveRateConfig),
+    CumulativeCounters(CumulativeCountersConfig),
+    Catalog(CatalogConfig),
+}
+
+impl MiddlewareConfig {
+    /// Whether the middleware may drop metrics, and exempted metrics should therefore bypass it.
+    pub fn may_drop_metrics(&self) -> bool {
+        match self {
+            MiddlewareConfig::Sample(_)
+            | MiddlewareConfig::CardinalityLimit(_)
+            | MiddlewareConfig::Exec(_)
+            | MiddlewareConfig::ByteBudget(_)
+            | MiddlewareConfig::CumulativeCounters(_)
+            | MiddlewareConfig::DenyMetric(_)
+            | MiddlewareConfig::AllowMetric(_)
+            | MiddlewareConfig::DenyType(_)
+            | MiddlewareConfig::AllowType(_)
+            | MiddlewareConfig::ValidateValues(_)
+            | MiddlewareConfig::RateLimit(_)
+            | MiddlewareConfig::DedupeGauges(_) => true,
+            MiddlewareConfig::DenyTag(_)
+            | MiddlewareConfig::AllowTag(_)
+            | MiddlewareConfig::AggregateMetrics(_)
+            | MiddlewareConfig::AddTag(_)
+            | MiddlewareConfig::RewriteName(_)
+            | MiddlewareConfig::NameToTags(_)
+            | MiddlewareConfig::ScrubTags(_)
+            | MiddlewareConfig::TagCardinalityLimit(_)
+            | MiddlewareConfig::Usage(_)
+            | MiddlewareConfig::MaxTags(_)
+            | MiddlewareConfig::DuplicateTags(_)
+            | MiddlewareConfig::CleanTags(_)
+            | MiddlewareConfig::DowngradeDogstatsd(_)
+            | MiddlewareConfig::Sanitize(_)
+            | MiddlewareConfig::Truncate(_)
+            | MiddlewareConfig::NormalizeSampleRate(_)
+            | MiddlewareConfig::AddTimestamp(_)
+            | MiddlewareConfig::DeriveRate(_)
+            | MiddlewareConfig::Catalog(_) => false,
+            // Their nested middlewares are checked individually.
+            MiddlewareConfig::Schedule(_)
+            | MiddlewareConfig::Route(_)
+            | MiddlewareConfig::Mirror(_) => false,
+        }
+    }
 }
 
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct DenyTagConfig {
+    #[cfg_attr(feature = "cli", serde(default))]
     pub tags: Vec<String>,
+    /// Regular expressions matched against tag values. Tags with a matching value are removed,
+    /// regardless of their name.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub values: Vec<String>,
 }
 
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AllowTagConfig {
     pub tags: Vec<String>,
 }
 
+/// Metric names are matched by glob patterns in `names`, where `*` matches any characters and `?`
+/// any single character, e.g. `jvm.gc.*`, and by regular expressions in `patterns`.
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct DenyMetricConfig {
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub names: Vec<String>,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub patterns: Vec<String>,
+}
+
+/// Like `DenyMetricConfig`, for the metrics to keep.
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct AllowMetricConfig {
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub names: Vec<String>,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub patterns: Vec<String>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeFilterConfig {
+    /// Metric types as written in the metric, e.g. `h` for histograms.
+    pub types: Vec<String>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct DedupeGaugesConfig {
+    /// Pass on a repeated gauge once this many seconds passed since the last one of its series
+    /// was passed on, so that the upstream still sees static gauges regularly.
+    pub interval: u64,
+    /// Gauges of series beyond this many are always passed on.
+    pub max_series: usize,
+}
+
+impl Default for DedupeGaugesConfig {
+    fn default() -> Self {
+        DedupeGaugesConfig {
+            interval: 60,
+            max_series: 100000,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    /// Tried in order, the first rule matching a metric name applies.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub rules: Vec<RateLimitRuleConfig>,
+    /// The rate of metrics matching no rule, or none to not limit them.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub default_per_second: Option<f64>,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub per: RateLimitKey,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitRuleConfig {
+    /// Glob patterns and regular expressions matching metric names, as in `DenyMetricConfig`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub names: Vec<String>,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub patterns: Vec<String>,
+    /// The number of metrics per second that may pass.
+    pub per_second: f64,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum RateLimitKey {
+    /// Limit every metric name, regardless of tags.
+    #[default]
+    Name,
+    /// Limit every series, i.e. name, type and tags.
+    Series,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidateValuesConfig {
+    /// The range of values by metric type, e.g. `c`. Values of other types are only checked to be
+    /// finite numbers.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub ranges: BTreeMap<String, ValueRangeConfig>,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub out_of_range: OutOfRangePolicy,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ValueRangeConfig {
+    /// Defaults to no minimum.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub min: Option<f64>,
+    /// Defaults to no maximum.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub max: Option<f64>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum OutOfRangePolicy {
+    /// Drop metrics with any value out of range.
+    #[default]
+    Drop,
+    /// Replace values out of range with the closest value in range.
+    Clamp,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameToTagsConfig {
+    /// Templates of dot-separated names, where `{key}` turns the segment into the value of the
+    /// tag `key`, `*` matches any segment and other segments have to match literally.
+    pub templates: Vec<String>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScrubTagsConfig {
+    /// Tags whose values are always scrubbed, e.g. `user_id`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub tags: Vec<String>,
+    /// Kinds of personal data scrubbed from the values of any tag.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub patterns: Vec<PiiPattern>,
+    /// Regular expressions matched against the values of any tag, like `patterns`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub values: Vec<String>,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub action: ScrubAction,
+    /// The secret key of the HMAC-SHA256 hash of values with `ScrubAction::Hash`. Without a
+    /// secret key, hashes of identifiers could be reversed by hashing all possible identifiers.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub key: Option<String>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum PiiPattern {
+    Uuid,
+    Email,
+    /// IPv4 addresses anywhere in the value, or a value that is an IPv6 address.
+    Ip,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum ScrubAction {
+    /// Replace values with `redacted`.
+    #[default]
+    Redact,
+    /// Replace values with a hash, so that series of different values stay apart.
+    Hash,
+}
+
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct LimitConfig {
     pub window: u16, // in seconds
     pub limit: u64,
+    /// Warn once the number of timeseries reaches this percentage of `limit`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub warn_percent: Option<u8>,
 }
 
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct CardinalityLimitConfig {
     pub limits: Vec<LimitConfig>,
+    /// Whether to share the limits with all other shared cardinality limits with the same
+    /// `limits`, e.g. in other worker or receiver threads.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub shared: bool,
+    /// Instead of dropping metrics of timeseries beyond the limits, replace their tags with
+    /// `overflow:true`, so that totals per metric name stay correct.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub overflow_series: bool,
 }
 
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct TagLimitConfig {
     pub tag: String,
     pub limit: u64,
+    /// Warn once the number of values reaches this percentage of `limit`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub warn_percent: Option<u8>,
 }
 
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct TagCardinalityLimitConfig {
     pub limits: Vec<TagLimitConfig>,
+    /// Replace values beyond the limit with this value, e.g. `__other__`, instead of removing
+    /// the tag, so that dashboards grouping by the tag still see the metrics.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub overflow_value: Option<String>,
 }
 
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AddTagConfig {
     pub tags: Vec<String>,
 }
 
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RewriteNameConfig {
+    /// Tried in order, the first rule matching a metric name applies.
+    pub rules: Vec<RewriteRuleConfig>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RewriteRuleConfig {
+    /// A regular expression matched against the metric name.
+    pub pattern: String,
+    /// Replaces the first match of `pattern`, with capture groups referred to as `$1` or `$name`.
+    pub replacement: String,
+    /// Tags to add to rewritten metrics, e.g. `user_id:$1`, with capture groups as in
+    /// `replacement`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub tags: Vec<String>,
+}
+
 #[cfg(feature = "cli")]
 fn default_true() -> bool {
     true
 }
 
+#[cfg(feature = "cli")]
+fn default_max_set_size() -> usize {
+    10000
+}
+
 #[cfg(feature = "cli")]
 fn default_flush_interval() -> u64 {
     1
@@ -89,48 +943,1836 @@ fn default_flush_offset() -> i64 {
 }
 
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
 pub struct AggregateMetricsConfig {
     #[cfg_attr(feature = "cli", serde(default = "default_true"))]
     pub aggregate_counters: bool,
     #[cfg_attr(feature = "cli", serde(default = "default_true"))]
     pub aggregate_gauges: bool,
+    /// Pack the values of timers, histograms and distributions into a single line per flush,
+    /// using the multi-value syntax of dogstatsd (`name:1:2:3|ms`).
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub aggregate_timers: bool,
+    /// Count the distinct values of sets, and flush the count as a gauge of the same name.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub aggregate_sets: bool,
+    /// Values of a set beyond this many distinct ones per flush are not counted.
+    #[cfg_attr(feature = "cli", serde(default = "default_max_set_size"))]
+    pub max_set_size: usize,
     #[cfg_attr(feature = "cli", serde(default = "default_flush_interval"))]
     pub flush_interval: u64,
     #[cfg_attr(feature = "cli", serde(default = "default_flush_offset"))]
     pub flush_offset: i64,
+    /// Flush the buckets of a flush interval early once it holds this many, instead of adding
+    /// another.
     #[cfg_attr(feature = "cli", serde(default))]
     pub max_map_size: Option<usize>,
+    /// Different flush intervals for metrics with certain name prefixes. The first matching
+    /// override applies.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub overrides: Vec<FlushIntervalOverrideConfig>,
+    /// Emit a marker once for counters and gauges that stop arriving.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub staleness: Option<StalenessConfig>,
+    /// With `aggregate_timers`, flush a summary of the values of timers, histograms and
+    /// distributions instead of the values themselves.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub timer_summary: Option<TimerSummaryConfig>,
 }
 
 #[cfg_attr(feature = "cli", derive(Deserialize))]
-#[derive(Debug, PartialEq)]
-pub struct SampleConfig {
-    pub sample_rate: f64,
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct TimerSummaryConfig {
+    /// The percentiles to flush, e.g. 99.9.
+    pub percentiles: Vec<f64>,
 }
 
-#[cfg(test)]
-#[cfg(feature = "cli")]
-mod tests {
-    use super::*;
+impl Default for TimerSummaryConfig {
+    fn default() -> Self {
+        TimerSummaryConfig {
+            percentiles: vec![50.0, 90.0, 99.0],
+        }
+    }
+}
 
-    #[test]
-    fn config() {
-        let config = Config::new("example.yaml").unwrap();
-        insta::assert_debug_snapshot!(config, @r###"
-        Config {
-            middlewares: [
-                DenyTag(
-                    DenyTagConfig {
-                        tags: [
-                            "a",
-                            "b",
-                            "c",
-                        ],
-                    },
-                ),
-                AllowTag(
-                    AllowTagConfig {
+impl Default for AggregateMetricsConfig {
+    fn default() -> Self {
+        AggregateMetricsConfig {
+            aggregate_counters: true,
+            aggregate_gauges: true,
+            aggregate_timers: false,
+            aggregate_sets: false,
+            max_set_size: 10000,
+            flush_interval: 1,
+            flush_offset: 0,
+            max_map_size: None,
+            overrides: Vec::new(),
+            staleness: None,
+            timer_summary: None,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum StalenessMarker {
+    /// The series itself with a value of zero and `tags` added.
+    #[default]
+    Zero,
+    /// A counter named `metric`, tagged with the name and tags of the series.
+    Metric,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct StalenessConfig {
+    /// A series is stale once it was missing from this many flushes in a row.
+    pub after_flushes: u32,
+    pub marker: StalenessMarker,
+    /// Tags added to the zero of `StalenessMarker::Zero`.
+    pub tags: Vec<String>,
+    /// The name of the counter of `StalenessMarker::Metric`.
+    pub metric: String,
+    /// The maximum number of series to track per flush interval. Further series are never marked
+    /// stale.
+    pub max_series: usize,
+}
+
+impl Default for StalenessConfig {
+    fn default() -> Self {
+        StalenessConfig {
+            after_flushes: 1,
+            marker: StalenessMarker::Zero,
+            tags: vec!["stale:true".to_string()],
+            metric: "stale_series".to_string(),
+            max_series: 10000,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlushIntervalOverrideConfig {
+    pub prefix: String,
+    pub flush_interval: u64,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleConfig {
+    /// The sample rate for metrics matching none of the `rules`.
+    #[cfg_attr(feature = "cli", serde(default = "default_sample_rate"))]
+    pub sample_rate: f64,
+    /// Metrics are sampled with the rate of the first rule matching them.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub rules: Vec<SampleRuleConfig>,
+}
+
+#[cfg(feature = "cli")]
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+/// A rule matches metrics matching both its names and types, and needs at least one of them.
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleRuleConfig {
+    /// Glob patterns matched against the metric name, like in `DenyMetricConfig`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub names: Vec<String>,
+    /// Regular expressions matched against the metric name.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub patterns: Vec<String>,
+    /// Metric types as written in the metric, e.g. `ms`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub types: Vec<String>,
+    pub sample_rate: f64,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct NormalizeSampleRateConfig {}
+
+#[cfg(feature = "cli")]
+fn default_exec_buffer_size() -> usize {
+    1000
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecConfig {
+    /// The program to run, followed by its arguments.
+    pub command: Vec<String>,
+    /// The number of lines that can be queued up for the child process before `submit` blocks.
+    #[cfg_attr(feature = "cli", serde(default = "default_exec_buffer_size"))]
+    pub buffer_size: usize,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(rename_all = "lowercase"))]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeWindowConfig {
+    /// The days on which the window applies. Empty means every day.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub days: Vec<Weekday>,
+    /// Start of the window as `HH:MM` in UTC.
+    pub start: String,
+    /// End of the window as `HH:MM` in UTC. May be before `start` for windows spanning midnight.
+    pub end: String,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduleConfig {
+    pub windows: Vec<TimeWindowConfig>,
+    /// Apply the middlewares only outside of the time windows instead.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub invert: bool,
+    pub middlewares: Vec<MiddlewareConfig>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct CanaryConfig {
+    /// The percentage of timeseries initially sent through the reloaded middlewares.
+    pub percentage: f64,
+    /// The number of seconds over which the percentage ramps up to 100. Zero disables ramping.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub ramp_duration: u64,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteConfig {
+    /// Metrics go through the middlewares of the first route matching them.
+    pub routes: Vec<RouteRuleConfig>,
+    /// The middlewares for metrics matching no route. Empty passes them straight through.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub default: Vec<MiddlewareConfig>,
+}
+
+/// A route matches metrics matching all of its configured criteria, and needs at least one.
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteRuleConfig {
+    /// Glob patterns matched against the metric name, like in `DenyMetricConfig`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub names: Vec<String>,
+    /// Regular expressions matched against the metric name.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub patterns: Vec<String>,
+    /// Metric types as written in the metric, e.g. `ms`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub types: Vec<String>,
+    /// Tags as `key` to match any value, or `key:value`. Any of them has to be present.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub tags: Vec<String>,
+    pub middlewares: Vec<MiddlewareConfig>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirrorConfig {
+    pub branches: Vec<MirrorBranchConfig>,
+}
+
+/// A copy of every metric goes through `middlewares` to `upstream`.
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirrorBranchConfig {
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub middlewares: Vec<MiddlewareConfig>,
+    /// An upstream like the `--upstream` argument, e.g. `tcp:host:port`. It uses the other
+    /// `upstream` settings, but neither routing, failover nor spooling.
+    pub upstream: String,
+}
+
+#[cfg(feature = "cli")]
+fn default_usage_metric_prefix() -> String {
+    "statsdproxy.usage".to_string()
+}
+
+#[cfg(feature = "cli")]
+fn default_usage_flush_interval() -> u64 {
+    60
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsageConfig {
+    /// The tag key whose values usage is accounted by, e.g. `team`.
+    pub tag: String,
+    #[cfg_attr(feature = "cli", serde(default = "default_usage_metric_prefix"))]
+    pub metric_prefix: String,
+    /// Emit usage metrics every `flush_interval` seconds.
+    #[cfg_attr(feature = "cli", serde(default = "default_usage_flush_interval"))]
+    pub flush_interval: u64,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct BudgetConfig {
+    /// Only apply the budget to metrics whose name starts with this prefix.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub prefix: Option<String>,
+    /// Apply a separate budget to each value of this tag, e.g. per tenant. Metrics without the
+    /// tag are not subject to the budget.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub tag: Option<String>,
+    pub bytes_per_second: u64,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ByteBudgetConfig {
+    pub budgets: Vec<BudgetConfig>,
+    /// Middlewares to send traffic over budget through. If not set, it is dropped.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub over_budget: Option<Vec<MiddlewareConfig>>,
+}
+
+#[cfg(feature = "cli")]
+fn default_relay_flush_interval() -> u64 {
+    10
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct RelayConfig {
+    /// Flush aggregated metrics every `flush_interval` seconds.
+    #[cfg_attr(feature = "cli", serde(default = "default_relay_flush_interval"))]
+    pub flush_interval: u64,
+    #[cfg_attr(feature = "cli", serde(default = "default_flush_offset"))]
+    pub flush_offset: i64,
+    /// Spool metrics that could not be sent to disk, and send them once sending succeeds again.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub spool: Option<SpoolConfig>,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            flush_interval: 10,
+            flush_offset: 0,
+            spool: None,
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+fn default_spool_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpoolConfig {
+    pub path: String,
+    /// Metrics that would grow the spool file beyond this size are dropped.
+    #[cfg_attr(feature = "cli", serde(default = "default_spool_max_bytes"))]
+    pub max_bytes: u64,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct CircuitBreakerConfig {
+    /// Open the circuit once this share of the sends in a window failed, between 0 and 1.
+    pub error_rate: f64,
+    /// The number of sends in a window before the circuit may open.
+    pub min_sends: u64,
+    /// The length of a window in seconds.
+    pub window: u64,
+    /// Reject sends for this many seconds once the circuit opened, then try a single send.
+    pub open_for: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            error_rate: 0.5,
+            min_sends: 20,
+            window: 10,
+            open_for: 5,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct UpstreamRateLimitConfig {
+    /// Send at most this many datagrams per second. Defaults to no limit.
+    pub packets_per_second: Option<u64>,
+    /// Send at most this many bytes per second. Defaults to no limit.
+    pub bytes_per_second: Option<u64>,
+    pub policy: RateLimitPolicy,
+    /// With `RateLimitPolicy::Queue`, the number of bytes held back, after which the oldest
+    /// datagrams are dropped.
+    pub max_queued_bytes: usize,
+}
+
+impl Default for UpstreamRateLimitConfig {
+    fn default() -> Self {
+        UpstreamRateLimitConfig {
+            packets_per_second: None,
+            bytes_per_second: None,
+            policy: RateLimitPolicy::Queue,
+            max_queued_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// What happens to datagrams over the rate limit of the upstream.
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum RateLimitPolicy {
+    /// Hold them back and send them once the rate allows.
+    #[default]
+    Queue,
+    /// Drop them.
+    Shed,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct OutputSchedulerConfig {
+    /// Send at most this many metrics per second, spread over all worker threads. Defaults to no
+    /// pacing.
+    pub max_metrics_per_second: Option<u64>,
+    /// Forward metrics to the upstream in batches of this many.
+    pub batch_size: usize,
+    /// Forward a batch that is not full yet after this many milliseconds.
+    pub max_delay_ms: u64,
+    /// The number of metrics held back by pacing, after which the oldest are dropped.
+    pub max_queued: usize,
+}
+
+impl Default for OutputSchedulerConfig {
+    fn default() -> Self {
+        OutputSchedulerConfig {
+            max_metrics_per_second: None,
+            batch_size: 100,
+            max_delay_ms: 100,
+            max_queued: 100000,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct PrintConfig {
+    /// Printed before every metric.
+    pub prefix: String,
+    /// Print at most this many metrics per second, and only count the rest in the log.
+    pub max_lines_per_second: Option<u64>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct RemoteWriteConfig {
+    /// Send the current value of every series every this many seconds.
+    pub flush_interval: u64,
+    /// Give up on a request after this many seconds.
+    pub timeout: u64,
+    /// Stop sending a series once no metric of it was received for this many seconds.
+    pub series_ttl: u64,
+    /// The maximum number of series to send. Metrics of further series are dropped.
+    pub max_series: usize,
+}
+
+impl Default for RemoteWriteConfig {
+    fn default() -> Self {
+        RemoteWriteConfig {
+            flush_interval: 10,
+            timeout: 5,
+            series_ttl: 300,
+            max_series: 100000,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct OtlpExportConfig {
+    /// Send the metrics received since the previous request every this many seconds.
+    pub flush_interval: u64,
+    /// Give up on a request after this many seconds.
+    pub timeout: u64,
+    /// Attributes of the resource all metrics belong to, in the form of tags, e.g.
+    /// `service.name:statsdproxy`.
+    pub resource_attributes: Vec<String>,
+    /// The maximum number of series per flush. Metrics of further series are dropped.
+    pub max_series: usize,
+}
+
+impl Default for OtlpExportConfig {
+    fn default() -> Self {
+        OtlpExportConfig {
+            flush_interval: 10,
+            timeout: 5,
+            resource_attributes: Vec::new(),
+            max_series: 100000,
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+fn default_file_output_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+#[cfg(feature = "cli")]
+fn default_file_output_max_files() -> usize {
+    5
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileOutputConfig {
+    pub path: String,
+    /// Rotate the file once it would grow beyond this size.
+    #[cfg_attr(feature = "cli", serde(default = "default_file_output_max_bytes"))]
+    pub max_bytes: u64,
+    /// The number of rotated files to keep. Zero discards the file when rotating.
+    #[cfg_attr(feature = "cli", serde(default = "default_file_output_max_files"))]
+    pub max_files: usize,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaxTagsConfig {
+    /// The maximum number of tags per metric.
+    pub max_tags: usize,
+    /// Tag names to drop last, the last one first.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub keep: Vec<String>,
+    /// Tag names to drop first, in this order.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub drop: Vec<String>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "lowercase"))]
+pub enum DuplicateTagPolicy {
+    /// Keep the first value.
+    #[default]
+    First,
+    /// Keep the last value.
+    Last,
+    /// Drop the tag entirely if its values conflict.
+    Drop,
+    /// Join all distinct values with the separator.
+    Concat,
+}
+
+#[cfg(feature = "cli")]
+fn default_duplicate_tags_separator() -> String {
+    "_".to_string()
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateTagsConfig {
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub policy: DuplicateTagPolicy,
+    /// Separates the values joined by `DuplicateTagPolicy::Concat`.
+    #[cfg_attr(feature = "cli", serde(default = "default_duplicate_tags_separator"))]
+    pub separator: String,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct DowngradeDogstatsdConfig {
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub tags: TagDowngrade,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum TagDowngrade {
+    /// Append the tags to the name as `.key_value` segments, sorted.
+    #[default]
+    Append,
+    /// Drop the tags.
+    Drop,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct CleanTagsConfig {
+    /// Also strip whitespace around tags, e.g. `#a:1, b:2` becomes `#a:1,b:2`.
+    #[cfg_attr(feature = "cli", serde(default = "default_true"))]
+    pub trim_whitespace: bool,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct SanitizeConfig {
+    pub name: SanitizeRuleConfig,
+    pub tag_keys: SanitizeRuleConfig,
+}
+
+impl Default for SanitizeConfig {
+    fn default() -> Self {
+        SanitizeConfig {
+            name: SanitizeRuleConfig {
+                allowed: "_.".to_string(),
+                max_length: Some(200),
+            },
+            tag_keys: SanitizeRuleConfig {
+                allowed: "_.-/".to_string(),
+                max_length: Some(200),
+            },
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SanitizeRuleConfig {
+    /// Characters allowed in addition to ASCII letters and digits.
+    pub allowed: String,
+    /// The maximum length in bytes, or none for no limit.
+    pub max_length: Option<usize>,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct TruncateConfig {
+    /// The maximum lengths in bytes, or none for no limit.
+    pub max_name_length: Option<usize>,
+    pub max_tag_key_length: Option<usize>,
+    pub max_tag_value_length: Option<usize>,
+    /// End truncated names, keys and values in `_` and 8 hex digits of a hash of the original, so
+    /// that they stay distinct.
+    pub hash_suffix: bool,
+}
+
+impl Default for TruncateConfig {
+    fn default() -> Self {
+        TruncateConfig {
+            max_name_length: Some(200),
+            max_tag_key_length: Some(200),
+            max_tag_value_length: Some(200),
+            hash_suffix: false,
+        }
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct AddTimestampConfig {
+    /// The metric types to add timestamps to.
+    pub types: Vec<String>,
+}
+
+impl Default for AddTimestampConfig {
+    fn default() -> Self {
+        AddTimestampConfig {
+            // The types DogStatsD accepts timestamps for.
+            types: vec!["c".to_string(), "g".to_string()],
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+fn default_derive_rate_max_series() -> usize {
+    10000
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeriveRateConfig {
+    pub rules: Vec<DeriveRateRule>,
+    /// The maximum number of series, i.e. combinations of name and tags, to remember the last
+    /// value of. Values of further series are not derived from.
+    #[cfg_attr(feature = "cli", serde(default = "default_derive_rate_max_series"))]
+    pub max_series: usize,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeriveRateRule {
+    /// The name of the gauge to derive from.
+    pub metric: String,
+    /// The name of the derived metric. Defaults to `<metric>.rate`.
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub name: Option<String>,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub mode: DeriveRateMode,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub metric_type: DerivedMetricType,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum DeriveRateMode {
+    /// The change per second since the previous value.
+    #[default]
+    PerSecond,
+    /// The change since the previous value.
+    Delta,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum DerivedMetricType {
+    #[default]
+    Gauge,
+    Counter,
+}
+
+#[cfg(feature = "cli")]
+fn default_cumulative_counters_max_series() -> usize {
+    10000
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct CumulativeCountersConfig {
+    /// The first rule whose prefix matches a counter's name applies.
+    pub rules: Vec<CumulativeCounterRule>,
+    /// The maximum number of series, i.e. combinations of name and tags, to track. Counters of
+    /// further series are passed on unchanged.
+    #[cfg_attr(
+        feature = "cli",
+        serde(default = "default_cumulative_counters_max_series")
+    )]
+    pub max_series: usize,
+}
+
+#[cfg(feature = "cli")]
+fn default_cumulative_counters_min_samples() -> usize {
+    5
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct CumulativeCounterRule {
+    pub prefix: String,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub mode: CumulativeCounterMode,
+    /// With `CumulativeCounterMode::Detect`, the number of consecutive non-decreasing values of
+    /// at least `min_value` after which a series is treated as cumulative.
+    #[cfg_attr(
+        feature = "cli",
+        serde(default = "default_cumulative_counters_min_samples")
+    )]
+    pub min_samples: usize,
+    #[cfg_attr(feature = "cli", serde(default))]
+    pub min_value: f64,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum CumulativeCounterMode {
+    /// Treat a series as cumulative once its values look like it.
+    #[default]
+    Detect,
+    /// Treat every series as cumulative.
+    Convert,
+}
+
+#[cfg_attr(feature = "cli", derive(Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", serde(default))]
+pub struct CatalogConfig {
+    /// Forget kinds of metrics not seen for this many hours.
+    pub retention: u64,
+    /// The maximum number of kinds of metrics, i.e. combinations of name, type and tag names, to
+    /// record. Further kinds are not recorded until others expire.
+    pub max_entries: usize,
+}
+
+impl Default for CatalogConfig {
+    fn default() -> Self {
+        CatalogConfig {
+            retention: 24,
+            max_entries: 100000,
+        }
+    }
+}
+
+/// Checks of config values that their types cannot express. They run when the config is loaded
+/// or reloaded, so that invalid values are rejected up front rather than misbehaving at runtime.
+pub trait Validate {
+    /// A description of every invalid value, empty if there is none.
+    fn validate(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Prefix every error with `prefix`, e.g. the position and type of a middleware.
+fn prefixed(prefix: &str, errors: Vec<String>) -> Vec<String> {
+    errors
+        .into_iter()
+        .map(|error| format!("{}: {}", prefix, error))
+        .collect()
+}
+
+/// An error if `values` is empty.
+fn require_non_empty<T>(name: &str, values: &[T]) -> Option<String> {
+    values
+        .is_empty()
+        .then(|| format!("{} must not be empty", name))
+}
+
+/// Validate a list of middlewares, naming each by its position in `name` and its type.
+fn validate_middlewares(name: &str, middlewares: &[MiddlewareConfig]) -> Vec<String> {
+    middlewares
+        .iter()
+        .enumerate()
+        .flat_map(|(i, middleware)| {
+            prefixed(
+                &format!("{}[{}] ({})", name, i, middleware.name()),
+                middleware.validate(),
+            )
+        })
+        .collect()
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = prefixed("upstream", self.upstream.validate());
+        if let Some(relay) = &self.relay {
+            errors.extend(prefixed("relay", relay.validate()));
+        }
+        if let Some(cluster) = &self.cluster {
+            errors.extend(prefixed("cluster", cluster.validate()));
+        }
+        errors.extend(prefixed("events", self.events.validate()));
+        if let Some(compare) = &self.compare {
+            errors.extend(prefixed("compare", compare.validate()));
+            if self.observe_only {
+                errors.push("compare cannot be used together with observe_only".to_string());
+            }
+        }
+        if let Some(canary) = &self.canary {
+            errors.extend(prefixed("canary", canary.validate()));
+        }
+        errors.extend(validate_middlewares("middlewares", &self.middlewares));
+        errors
+    }
+}
+
+impl Validate for EventsConfig {
+    fn validate(&self) -> Vec<String> {
+        let has_upstream = self.upstream.as_ref().is_some_and(|x| !x.is_empty());
+        match (self.action, has_upstream) {
+            (EventAction::Upstream, false) => {
+                vec!["upstream is required with action upstream".to_string()]
+            }
+            (EventAction::Pass | EventAction::Drop, true) => {
+                vec!["upstream is only used with action upstream".to_string()]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Validate for ClusterConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if !self.peers.contains(&self.local_peer) {
+            errors.push("local_peer must be one of peers".to_string());
+        }
+        if self.max_datagram_size == 0 {
+            errors.push("max_datagram_size must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for CompareConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.interval == 0 {
+            errors.push("interval must be at least 1".to_string());
+        }
+        if self.max_series == 0 {
+            errors.push("max_series must be at least 1".to_string());
+        }
+        errors.extend(validate_middlewares("middlewares", &self.middlewares));
+        errors
+    }
+}
+
+impl Validate for UpstreamConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.max_datagram_size == 0 {
+            errors.push("max_datagram_size must be at least 1".to_string());
+        }
+        if let Some(tls) = &self.tls {
+            errors.extend(prefixed("tls", tls.validate()));
+        }
+        if let Some(quic) = &self.quic {
+            errors.extend(prefixed("quic", quic.validate()));
+        }
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            errors.extend(prefixed("circuit_breaker", circuit_breaker.validate()));
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            errors.extend(prefixed("rate_limit", rate_limit.validate()));
+        }
+        if let Some(scheduler) = &self.scheduler {
+            errors.extend(prefixed("scheduler", scheduler.validate()));
+        }
+        if let Some(failover) = &self.failover {
+            errors.extend(prefixed("failover", failover.validate()));
+        }
+        if let Some(routing) = &self.routing {
+            errors.extend(prefixed("routing", routing.validate()));
+        }
+        if let Some(file_output) = &self.file_output {
+            errors.extend(prefixed("file_output", file_output.validate()));
+        }
+        errors.extend(prefixed("remote_write", self.remote_write.validate()));
+        errors.extend(prefixed("otlp", self.otlp.validate()));
+        errors
+    }
+}
+
+impl Validate for StalenessConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.after_flushes == 0 {
+            errors.push("after_flushes must be at least 1".to_string());
+        }
+        if self.marker == StalenessMarker::Metric && self.metric.is_empty() {
+            errors.push("metric is required with marker metric".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for OtlpExportConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.flush_interval == 0 {
+            errors.push("flush_interval must be at least 1".to_string());
+        }
+        if self.timeout == 0 {
+            errors.push("timeout must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for RemoteWriteConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.flush_interval == 0 {
+            errors.push("flush_interval must be at least 1".to_string());
+        }
+        if self.timeout == 0 {
+            errors.push("timeout must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for FileOutputConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.path.is_empty() {
+            errors.push("path is required".to_string());
+        }
+        if self.max_bytes == 0 {
+            errors.push("max_bytes must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for TlsUpstreamConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.ca_path.is_empty() {
+            errors.push("ca_path is required".to_string());
+        }
+        if self.cert_path.is_some() != self.key_path.is_some() {
+            errors.push("cert_path and key_path must be given together".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for QuicUpstreamConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.ca_path.is_empty() {
+            errors.push("ca_path is required".to_string());
+        }
+        for (i, class) in self.classes.iter().enumerate() {
+            errors.extend(require_non_empty(
+                &format!("classes[{}].prefixes", i),
+                &class.prefixes,
+            ));
+        }
+        errors
+    }
+}
+
+impl Validate for CircuitBreakerConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if !(self.error_rate > 0.0 && self.error_rate <= 1.0) {
+            errors.push("error_rate must be greater than 0 and at most 1".to_string());
+        }
+        if self.min_sends == 0 {
+            errors.push("min_sends must be at least 1".to_string());
+        }
+        if self.window == 0 {
+            errors.push("window must be at least 1".to_string());
+        }
+        if self.open_for == 0 {
+            errors.push("open_for must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for UpstreamRateLimitConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.packets_per_second.is_none() && self.bytes_per_second.is_none() {
+            errors.push("packets_per_second or bytes_per_second is required".to_string());
+        }
+        if self.packets_per_second == Some(0) {
+            errors.push("packets_per_second must be at least 1".to_string());
+        }
+        if self.bytes_per_second == Some(0) {
+            errors.push("bytes_per_second must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for OutputSchedulerConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.max_metrics_per_second == Some(0) {
+            errors.push("max_metrics_per_second must be at least 1".to_string());
+        }
+        if self.batch_size == 0 {
+            errors.push("batch_size must be at least 1".to_string());
+        }
+        if self.max_queued == 0 {
+            errors.push("max_queued must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for FailoverConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.secondary.is_empty() {
+            errors.push("secondary is required".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for RoutingConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.tag.trim_end_matches(':').is_empty() {
+            errors.push("tag is required".to_string());
+        }
+        if self.routes.values().any(String::is_empty) {
+            errors.push("routes must not contain empty upstreams".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for RelayConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.flush_interval == 0 {
+            errors.push("flush_interval must be at least 1 second".to_string());
+        }
+        errors
+    }
+}
+
+impl MiddlewareConfig {
+    /// The type of the middleware as written in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MiddlewareConfig::DenyTag(_) => "deny-tag",
+            MiddlewareConfig::AllowTag(_) => "allow-tag",
+            MiddlewareConfig::DenyMetric(_) => "deny-metric",
+            MiddlewareConfig::AllowMetric(_) => "allow-metric",
+            MiddlewareConfig::DenyType(_) => "deny-type",
+            MiddlewareConfig::AllowType(_) => "allow-type",
+            MiddlewareConfig::CardinalityLimit(_) => "cardinality-limit",
+            MiddlewareConfig::AggregateMetrics(_) => "aggregate-metrics",
+            MiddlewareConfig::Sample(_) => "sample",
+            MiddlewareConfig::NormalizeSampleRate(_) => "normalize-sample-rate",
+            MiddlewareConfig::AddTag(_) => "add-tag",
+            MiddlewareConfig::RewriteName(_) => "rewrite-name",
+            MiddlewareConfig::NameToTags(_) => "name-to-tags",
+            MiddlewareConfig::ScrubTags(_) => "scrub-tags",
+            MiddlewareConfig::ValidateValues(_) => "validate-values",
+            MiddlewareConfig::RateLimit(_) => "rate-limit",
+            MiddlewareConfig::DedupeGauges(_) => "dedupe-gauges",
+            MiddlewareConfig::TagCardinalityLimit(_) => "tag-cardinality-limit",
+            MiddlewareConfig::Exec(_) => "exec",
+            MiddlewareConfig::Schedule(_) => "schedule",
+            MiddlewareConfig::Route(_) => "route",
+            MiddlewareConfig::Mirror(_) => "mirror",
+            MiddlewareConfig::Usage(_) => "usage",
+            MiddlewareConfig::ByteBudget(_) => "byte-budget",
+            MiddlewareConfig::MaxTags(_) => "max-tags",
+            MiddlewareConfig::DuplicateTags(_) => "duplicate-tags",
+            MiddlewareConfig::CleanTags(_) => "clean-tags",
+            MiddlewareConfig::DowngradeDogstatsd(_) => "downgrade-dogstatsd",
+            MiddlewareConfig::Sanitize(_) => "sanitize",
+            MiddlewareConfig::Truncate(_) => "truncate",
+            MiddlewareConfig::AddTimestamp(_) => "add-timestamp",
+            MiddlewareConfig::DeriveRate(_) => "derive-rate",
+            MiddlewareConfig::CumulativeCounters(_) => "cumulative-counters",
+            MiddlewareConfig::Catalog(_) => "catalog",
+        }
+    }
+}
+
+impl Validate for MiddlewareConfig {
+    fn validate(&self) -> Vec<String> {
+        match self {
+            MiddlewareConfig::DenyTag(config) => config.validate(),
+            MiddlewareConfig::AllowTag(config) => config.validate(),
+            MiddlewareConfig::DenyMetric(config) => {
+                validate_metric_names(&config.names, &config.patterns)
+            }
+            MiddlewareConfig::AllowMetric(config) => {
+                validate_metric_names(&config.names, &config.patterns)
+            }
+            MiddlewareConfig::DenyType(config) => config.validate(),
+            MiddlewareConfig::AllowType(config) => config.validate(),
+            MiddlewareConfig::CardinalityLimit(config) => config.validate(),
+            MiddlewareConfig::AggregateMetrics(config) => config.validate(),
+            MiddlewareConfig::Sample(config) => config.validate(),
+            MiddlewareConfig::NormalizeSampleRate(config) => config.validate(),
+            MiddlewareConfig::AddTag(config) => config.validate(),
+            MiddlewareConfig::RewriteName(config) => config.validate(),
+            MiddlewareConfig::NameToTags(config) => config.validate(),
+            MiddlewareConfig::ScrubTags(config) => config.validate(),
+            MiddlewareConfig::ValidateValues(config) => config.validate(),
+            MiddlewareConfig::RateLimit(config) => config.validate(),
+            MiddlewareConfig::DedupeGauges(config) => config.validate(),
+            MiddlewareConfig::TagCardinalityLimit(config) => config.validate(),
+            MiddlewareConfig::Exec(config) => config.validate(),
+            MiddlewareConfig::Schedule(config) => config.validate(),
+            MiddlewareConfig::Route(config) => config.validate(),
+            MiddlewareConfig::Mirror(config) => config.validate(),
+            MiddlewareConfig::Usage(config) => config.validate(),
+            MiddlewareConfig::ByteBudget(config) => config.validate(),
+            MiddlewareConfig::MaxTags(config) => config.validate(),
+            MiddlewareConfig::DuplicateTags(config) => config.validate(),
+            MiddlewareConfig::CleanTags(config) => config.validate(),
+            MiddlewareConfig::DowngradeDogstatsd(config) => config.validate(),
+            MiddlewareConfig::Sanitize(config) => config.validate(),
+            MiddlewareConfig::Truncate(config) => config.validate(),
+            MiddlewareConfig::AddTimestamp(config) => config.validate(),
+            MiddlewareConfig::DeriveRate(config) => config.validate(),
+            MiddlewareConfig::CumulativeCounters(config) => config.validate(),
+            MiddlewareConfig::Catalog(config) => config.validate(),
+        }
+    }
+}
+
+impl Validate for DenyTagConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.tags.is_empty() && self.values.is_empty() {
+            errors.push("tags and values must not both be empty".to_string());
+        }
+        for value in &self.values {
+            if let Err(e) = regex::bytes::Regex::new(value) {
+                errors.push(format!("invalid regular expression in values: {}", e));
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for AllowTagConfig {}
+
+fn validate_metric_names(names: &[String], patterns: &[String]) -> Vec<String> {
+    let mut errors = Vec::new();
+    if names.is_empty() && patterns.is_empty() {
+        errors.push("names and patterns must not both be empty".to_string());
+    }
+    for pattern in patterns {
+        if let Err(e) = regex::bytes::Regex::new(pattern) {
+            errors.push(format!("invalid regular expression in patterns: {}", e));
+        }
+    }
+    errors
+}
+
+impl Validate for CardinalityLimitConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("limits", &self.limits)
+            .into_iter()
+            .collect();
+        for (i, limit) in self.limits.iter().enumerate() {
+            errors.extend(prefixed(&format!("limits[{}]", i), limit.validate()));
+        }
+        errors
+    }
+}
+
+impl Validate for LimitConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        // Usage is counted in granules of at least a second.
+        if self.window == 0 {
+            errors.push("window must be at least 1 second".to_string());
+        }
+        if usize::try_from(self.limit).is_err() {
+            errors.push(format!("limit must be at most {}", usize::MAX));
+        }
+        errors.extend(validate_warn_percent(self.warn_percent));
+        errors
+    }
+}
+
+/// An error if a `warn_percent` is not between 1 and 100.
+fn validate_warn_percent(warn_percent: Option<u8>) -> Option<String> {
+    warn_percent
+        .filter(|x| !(1..=100).contains(x))
+        .map(|x| format!("warn_percent must be between 1 and 100, got {}", x))
+}
+
+impl Validate for AggregateMetricsConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.flush_interval == 0 {
+            errors.push("flush_interval must be at least 1 second".to_string());
+        }
+        for (i, flush_override) in self.overrides.iter().enumerate() {
+            if flush_override.flush_interval == 0 {
+                errors.push(format!(
+                    "overrides[{}]: flush_interval must be at least 1 second",
+                    i
+                ));
+            }
+        }
+        if let Some(staleness) = &self.staleness {
+            errors.extend(prefixed("staleness", staleness.validate()));
+        }
+        if self.aggregate_sets && self.max_set_size == 0 {
+            errors.push("max_set_size must be positive".to_string());
+        }
+        if self.max_map_size == Some(0) {
+            errors.push("max_map_size must be positive".to_string());
+        }
+        if let Some(summary) = &self.timer_summary {
+            if !self.aggregate_timers {
+                errors.push("timer_summary requires aggregate_timers".to_string());
+            }
+            for percentile in &summary.percentiles {
+                if !(*percentile > 0.0 && *percentile <= 100.0) {
+                    errors.push(format!(
+                        "timer_summary: percentile {} must be above 0 and at most 100",
+                        percentile
+                    ));
+                }
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for NormalizeSampleRateConfig {
+    fn validate(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl Validate for NameToTagsConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("templates", &self.templates)
+            .into_iter()
+            .collect();
+        for template in &self.templates {
+            let keys: Vec<_> = template
+                .split('.')
+                .filter_map(|x| x.strip_prefix('{').and_then(|x| x.strip_suffix('}')))
+                .collect();
+            if keys.is_empty() {
+                errors.push(format!("template {:?} has no {{key}} segment", template));
+            }
+            if keys.len() == template.split('.').count() {
+                errors.push(format!(
+                    "template {:?} must keep at least one segment in the name",
+                    template
+                ));
+            }
+            if keys
+                .iter()
+                .any(|key| key.is_empty() || key.contains([':', ',', '|', '#']))
+            {
+                errors.push(format!(
+                    "template {:?} has an invalid tag key, keys must not be empty or contain \
+                     ':', ',', '|' or '#'",
+                    template
+                ));
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for DowngradeDogstatsdConfig {
+    fn validate(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl Validate for SampleConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = validate_sample_rate(self.sample_rate).into_iter().collect();
+        for (i, rule) in self.rules.iter().enumerate() {
+            errors.extend(prefixed(&format!("rules[{}]", i), rule.validate()));
+        }
+        errors
+    }
+}
+
+impl Validate for SampleRuleConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = validate_sample_rate(self.sample_rate).into_iter().collect();
+        if self.names.is_empty() && self.patterns.is_empty() && self.types.is_empty() {
+            errors.push("names, patterns and types must not all be empty".to_string());
+        }
+        for pattern in &self.patterns {
+            if let Err(e) = regex::bytes::Regex::new(pattern) {
+                errors.push(format!("invalid regular expression in patterns: {}", e));
+            }
+        }
+        errors.extend(validate_metric_types(&self.types));
+        errors
+    }
+}
+
+fn validate_sample_rate(sample_rate: f64) -> Option<String> {
+    (!(0.0..=1.0).contains(&sample_rate))
+        .then(|| format!("sample_rate must be between 0 and 1, got {}", sample_rate))
+}
+
+impl Validate for AddTagConfig {
+    fn validate(&self) -> Vec<String> {
+        require_non_empty("tags", &self.tags).into_iter().collect()
+    }
+}
+
+impl Validate for TypeFilterConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("types", &self.types)
+            .into_iter()
+            .collect();
+        errors.extend(validate_metric_types(&self.types));
+        errors
+    }
+}
+
+fn validate_metric_types(types: &[String]) -> Vec<String> {
+    types
+        .iter()
+        .filter(|ty| !["c", "g", "ms", "h", "d", "s"].contains(&ty.as_str()))
+        .map(|ty| {
+            format!(
+                "unknown type {:?}, expected one of c, g, ms, h, d and s",
+                ty
+            )
+        })
+        .collect()
+}
+
+impl Validate for DedupeGaugesConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.interval == 0 {
+            errors.push("interval must be at least 1 second".to_string());
+        }
+        if self.max_series == 0 {
+            errors.push("max_series must be positive".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for RateLimitConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.rules.is_empty() && self.default_per_second.is_none() {
+            errors.push("rules and default_per_second must not both be empty".to_string());
+        }
+        if self
+            .default_per_second
+            .is_some_and(|x| !x.is_finite() || x <= 0.0)
+        {
+            errors.push("default_per_second must be positive".to_string());
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            let mut rule_errors = validate_metric_names(&rule.names, &rule.patterns);
+            if !rule.per_second.is_finite() || rule.per_second <= 0.0 {
+                rule_errors.push("per_second must be positive".to_string());
+            }
+            errors.extend(prefixed(&format!("rules[{}]", i), rule_errors));
+        }
+        errors
+    }
+}
+
+impl Validate for ValidateValuesConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (ty, range) in &self.ranges {
+            if !["c", "g", "ms", "h", "d"].contains(&ty.as_str()) {
+                errors.push(format!(
+                    "ranges: unknown type {:?}, expected one of c, g, ms, h and d",
+                    ty
+                ));
+            }
+            if let (Some(min), Some(max)) = (range.min, range.max) {
+                if min > max {
+                    errors.push(format!("ranges: min of {} must not exceed max", ty));
+                }
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for ScrubTagsConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.tags.is_empty() && self.patterns.is_empty() && self.values.is_empty() {
+            errors.push("tags, patterns and values must not all be empty".to_string());
+        }
+        for value in &self.values {
+            if let Err(e) = regex::bytes::Regex::new(value) {
+                errors.push(format!("invalid regular expression in values: {}", e));
+            }
+        }
+        if self.action == ScrubAction::Hash && self.key.as_ref().is_none_or(|x| x.is_empty()) {
+            errors.push("key is required with action hash".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for RewriteNameConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("rules", &self.rules)
+            .into_iter()
+            .collect();
+        for (i, rule) in self.rules.iter().enumerate() {
+            errors.extend(prefixed(&format!("rules[{}]", i), rule.validate()));
+        }
+        errors
+    }
+}
+
+impl Validate for RewriteRuleConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Err(e) = regex::bytes::Regex::new(&self.pattern) {
+            errors.push(format!("invalid regular expression in pattern: {}", e));
+        }
+        if self.replacement.contains(':') || self.replacement.contains('|') {
+            errors.push("replacement must not contain ':' or '|'".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for TagCardinalityLimitConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("limits", &self.limits)
+            .into_iter()
+            .collect();
+        for (i, limit) in self.limits.iter().enumerate() {
+            if limit.tag.is_empty() {
+                errors.push(format!("limits[{}]: tag must not be empty", i));
+            }
+            if let Some(error) = validate_warn_percent(limit.warn_percent) {
+                errors.push(format!("limits[{}]: {}", i, error));
+            }
+        }
+        if let Some(overflow_value) = &self.overflow_value {
+            if overflow_value.is_empty() || overflow_value.contains([',', '|']) {
+                errors.push("overflow_value must not be empty or contain ',' or '|'".to_string());
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for ExecConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("command", &self.command)
+            .into_iter()
+            .collect();
+        if self.buffer_size == 0 {
+            errors.push("buffer_size must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for ScheduleConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("windows", &self.windows)
+            .into_iter()
+            .collect();
+        for (i, window) in self.windows.iter().enumerate() {
+            for time in [&window.start, &window.end] {
+                if let Err(e) = crate::middleware::schedule::parse_time_of_day(time) {
+                    errors.push(format!("windows[{}]: {}", i, e));
+                }
+            }
+        }
+        errors.extend(validate_middlewares("middlewares", &self.middlewares));
+        errors
+    }
+}
+
+impl Validate for CanaryConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if !(0.0..=100.0).contains(&self.percentage) {
+            errors.push(format!(
+                "percentage must be between 0 and 100, got {}",
+                self.percentage
+            ));
+        }
+        errors
+    }
+}
+
+impl Validate for RouteConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("routes", &self.routes)
+            .into_iter()
+            .collect();
+        for (i, route) in self.routes.iter().enumerate() {
+            errors.extend(prefixed(&format!("routes[{}]", i), route.validate()));
+        }
+        errors.extend(validate_middlewares("default", &self.default));
+        errors
+    }
+}
+
+impl Validate for RouteRuleConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.names.is_empty()
+            && self.patterns.is_empty()
+            && self.types.is_empty()
+            && self.tags.is_empty()
+        {
+            errors.push("names, patterns, types and tags must not all be empty".to_string());
+        }
+        for pattern in &self.patterns {
+            if let Err(e) = regex::bytes::Regex::new(pattern) {
+                errors.push(format!("invalid regular expression in patterns: {}", e));
+            }
+        }
+        errors.extend(validate_metric_types(&self.types));
+        errors.extend(validate_middlewares("middlewares", &self.middlewares));
+        errors
+    }
+}
+
+impl Validate for MirrorConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("branches", &self.branches)
+            .into_iter()
+            .collect();
+        for (i, branch) in self.branches.iter().enumerate() {
+            let name = format!("branches[{}]", i);
+            if branch.upstream.is_empty() {
+                errors.push(format!("{}: upstream must not be empty", name));
+            }
+            errors.extend(validate_middlewares(
+                &format!("{}.middlewares", name),
+                &branch.middlewares,
+            ));
+        }
+        errors
+    }
+}
+
+impl Validate for UsageConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.tag.is_empty() {
+            errors.push("tag must not be empty".to_string());
+        }
+        if self.flush_interval == 0 {
+            errors.push("flush_interval must be at least 1 second".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for ByteBudgetConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("budgets", &self.budgets)
+            .into_iter()
+            .collect();
+        if let Some(over_budget) = &self.over_budget {
+            errors.extend(validate_middlewares("over_budget", over_budget));
+        }
+        errors
+    }
+}
+
+impl Validate for MaxTagsConfig {}
+
+impl Validate for DuplicateTagsConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.policy == DuplicateTagPolicy::Concat && self.separator.is_empty() {
+            errors.push("separator must not be empty with policy concat".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for CleanTagsConfig {}
+
+impl Validate for SanitizeConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (name, rule) in [("name", &self.name), ("tag_keys", &self.tag_keys)] {
+            if rule.max_length == Some(0) {
+                errors.push(format!("{}: max_length must be positive", name));
+            }
+            if rule.allowed.contains([':', '|', ',', '#', '@']) {
+                errors.push(format!(
+                    "{}: allowed must not contain ':', '|', ',', '#' or '@'",
+                    name
+                ));
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for TruncateConfig {
+    fn validate(&self) -> Vec<String> {
+        // The hash suffix is `_` and 8 hex digits.
+        let min_length = if self.hash_suffix { 10 } else { 1 };
+        [
+            ("max_name_length", self.max_name_length),
+            ("max_tag_key_length", self.max_tag_key_length),
+            ("max_tag_value_length", self.max_tag_value_length),
+        ]
+        .into_iter()
+        .filter(|(_, length)| length.is_some_and(|x| x < min_length))
+        .map(|(name, _)| match self.hash_suffix {
+            true => format!("{} must be at least {} with hash_suffix", name, min_length),
+            false => format!("{} must be positive", name),
+        })
+        .collect()
+    }
+}
+
+impl Validate for AddTimestampConfig {
+    fn validate(&self) -> Vec<String> {
+        require_non_empty("types", &self.types)
+            .into_iter()
+            .collect()
+    }
+}
+
+impl Validate for DeriveRateConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("rules", &self.rules)
+            .into_iter()
+            .collect();
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.metric.is_empty() {
+                errors.push(format!("rules[{}]: metric must not be empty", i));
+            }
+        }
+        if self.max_series == 0 {
+            errors.push("max_series must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for CumulativeCountersConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<_> = require_non_empty("rules", &self.rules)
+            .into_iter()
+            .collect();
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.mode == CumulativeCounterMode::Detect && rule.min_samples < 2 {
+                errors.push(format!(
+                    "rules[{}]: min_samples must be at least 2 with mode detect",
+                    i
+                ));
+            }
+        }
+        if self.max_series == 0 {
+            errors.push("max_series must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+impl Validate for CatalogConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.retention == 0 {
+            errors.push("retention must be at least 1 hour".to_string());
+        }
+        if self.max_entries == 0 {
+            errors.push("max_entries must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "cli")]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config() {
+        let config = Config::new("example.yaml").unwrap();
+        insta::assert_debug_snapshot!(config, @r###"
+        Config {
+            server: ServerConfig {
+                receiver_threads: 1,
+                recv_batch_size: 1,
+                tcp_listen: None,
+                tls: None,
+                quic: None,
+                max_connections: 1000,
+                http_listen: None,
+                otlp_grpc_listen: None,
+                admin_listen: None,
+                recv_buffer_size: None,
+                accept_batches: false,
+                self_metrics: None,
+                worker_threads: 0,
+                worker_queue_size: 1000,
+                overload_policy: Block,
+                interface: None,
+                ipv6_only: None,
+                max_metric_length: None,
+                oversized_metric_policy: Drop,
+                packet_rate_limit: None,
+                client_tag: None,
+                compliance: None,
+                startup: None,
+            },
+            exemptions: ExemptionConfig {
+                prefixes: [],
+                tags: [],
+            },
+            events: EventsConfig {
+                action: Pass,
+                upstream: None,
+            },
+            relay: None,
+            cluster: None,
+            upstream: UpstreamConfig {
+                bind: None,
+                format: Text,
+                max_buffered_lines: 10000,
+                max_datagram_size: 512,
+                discover_mtu: false,
+                oversized_metric_policy: Send,
+                tag_priority: [],
+                resolve_interval: None,
+                spool: None,
+                tls: None,
+                quic: None,
+                circuit_breaker: None,
+                send_batch_size: 1,
+                rate_limit: None,
+                scheduler: None,
+                failover: None,
+                routing: None,
+                file_output: None,
+                print: PrintConfig {
+                    prefix: "",
+                    max_lines_per_second: None,
+                },
+                remote_write: RemoteWriteConfig {
+                    flush_interval: 10,
+                    timeout: 5,
+                    series_ttl: 300,
+                    max_series: 100000,
+                },
+                otlp: OtlpExportConfig {
+                    flush_interval: 10,
+                    timeout: 5,
+                    resource_attributes: [],
+                    max_series: 100000,
+                },
+            },
+            observe_only: false,
+            compare: None,
+            canary: None,
+            middlewares: [
+                DenyTag(
+                    DenyTagConfig {
+                        tags: [
+                            "a",
+                            "b",
+                            "c",
+                        ],
+                        values: [],
+                    },
+                ),
+                AllowTag(
+                    AllowTagConfig {
                         tags: [
                             "x",
                             "y",
@@ -138,27 +2780,81 @@ mod tests {
                         ],
                     },
                 ),
+                DenyMetric(
+                    DenyMetricConfig {
+                        names: [
+                            "jvm.gc.*",
+                        ],
+                        patterns: [],
+                    },
+                ),
                 CardinalityLimit(
                     CardinalityLimitConfig {
                         limits: [
                             LimitConfig {
                                 window: 3600,
                                 limit: 3,
+                                warn_percent: None,
                             },
                         ],
+                        shared: false,
+                        overflow_series: false,
                     },
                 ),
                 AggregateMetrics(
                     AggregateMetricsConfig {
                         aggregate_counters: true,
                         aggregate_gauges: true,
+                        aggregate_timers: false,
+                        aggregate_sets: false,
+                        max_set_size: 10000,
                         flush_interval: 1,
                         flush_offset: 0,
                         max_map_size: None,
+                        overrides: [],
+                        staleness: None,
+                        timer_summary: None,
                     },
                 ),
             ],
         }
         "###);
     }
+
+    #[test]
+    fn validate() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+            canary:
+              percentage: 150
+            middlewares:
+              - type: sample
+                sample_rate: 1.5
+              - type: cardinality-limit
+                limits:
+                  - window: 0
+                    limit: 10
+              - type: schedule
+                windows:
+                  - start: "22:00"
+                    end: "02:00"
+                middlewares:
+                  - type: deny-tag
+                    tags: []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate(),
+            [
+                "canary: percentage must be between 0 and 100, got 150",
+                "middlewares[0] (sample): sample_rate must be between 0 and 1, got 1.5",
+                "middlewares[1] (cardinality-limit): limits[0]: window must be at least 1 second",
+                "middlewares[2] (schedule): middlewares[0] (deny-tag): tags and values must not \
+                 both be empty",
+            ]
+        );
+        assert!(config.check().is_err());
+        assert!(Config::default().check().is_ok());
+    }
 }
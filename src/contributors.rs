@@ -0,0 +1,228 @@
+//! Attribution of new timeseries to the metric names they belong to, to tell which metrics are
+//! responsible when a limit is approached or trips.
+//!
+//! Limits that reject metrics record a sample of them with `rejected`, counted per limit, metric
+//! name and tag key in the current window of the limit. The counts are available from the admin
+//! endpoint, to tell right away which metrics, and so which deploy, made a limit trip.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::types::Metric;
+
+// Names beyond this many are counted under `OTHER_NAME`, so that a flood of distinct names cannot
+// grow the counts without bounds.
+const MAX_NAMES: usize = 1000;
+const OTHER_NAME: &[u8] = b"other";
+
+// Only every this many rejected metrics are recorded, to keep the cost low while a limit rejects
+// a flood of them.
+const SAMPLE_EVERY: u64 = 10;
+// The number of metric names and tag keys listed per limit.
+const TOP: usize = 10;
+
+/// The window in which rejections are attributed for limits without a window of their own.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+/// The number of new timeseries per metric name, reported in a log at most every `interval`.
+#[derive(Clone, Debug)]
+pub struct Contributors {
+    counts: HashMap<Vec<u8>, u64>,
+    interval: Duration,
+    last_reported_at: Option<Instant>,
+}
+
+impl Contributors {
+    pub fn new(interval: Duration) -> Self {
+        Contributors {
+            counts: HashMap::new(),
+            interval,
+            last_reported_at: None,
+        }
+    }
+
+    /// Count a new timeseries of the metric named `name`.
+    pub fn record(&mut self, name: &[u8]) {
+        if let Some(count) = self.counts.get_mut(name) {
+            *count += 1;
+        } else if self.counts.len() < MAX_NAMES {
+            self.counts.insert(name.to_vec(), 1);
+        } else {
+            *self.counts.entry(OTHER_NAME.to_vec()).or_default() += 1;
+        }
+    }
+
+    /// The `n` names with the most new timeseries, most first.
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut top: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(name, count)| (String::from_utf8_lossy(name).into_owned(), *count))
+            .collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    /// If the last report was at least `interval` ago, the top 5 names formatted for a log, e.g.
+    /// `users.online (12), servers.online (3)`, and start counting anew.
+    pub fn report(&mut self, now: Instant) -> Option<String> {
+        if self
+            .last_reported_at
+            .is_some_and(|at| now.saturating_duration_since(at) < self.interval)
+        {
+            return None;
+        }
+        self.last_reported_at = Some(now);
+        let report = self
+            .top(5)
+            .iter()
+            .map(|(name, count)| format!("{} ({})", name, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.counts.clear();
+        Some(report)
+    }
+}
+
+/// The sampled rejections of a limit in its current window.
+struct Rejections {
+    window: Duration,
+    started_at: Instant,
+    sampled: u64,
+    names: Contributors,
+    tag_keys: Contributors,
+}
+
+thread_local! {
+    // The number of rejected metrics on this thread, for sampling without contention.
+    static REJECTED: Cell<u64> = const { Cell::new(0) };
+}
+// Keyed by a description of the limit, like `cardinality-limit window=3600`.
+static REJECTIONS: Mutex<BTreeMap<String, Rejections>> = Mutex::new(BTreeMap::new());
+
+/// Record that `limit`, whose counts start over every `window`, rejected the metric `raw`. Only a
+/// sample of the calls is recorded.
+pub fn rejected(limit: impl fmt::Display, window: Duration, raw: &[u8]) {
+    let rejected = REJECTED.get();
+    REJECTED.set(rejected + 1);
+    if !rejected.is_multiple_of(SAMPLE_EVERY) {
+        return;
+    }
+    let now = Instant::now();
+    let mut rejections = REJECTIONS.lock().unwrap();
+    let rejections = rejections
+        .entry(limit.to_string())
+        .or_insert_with(|| Rejections {
+            window,
+            started_at: now,
+            sampled: 0,
+            names: Contributors::new(window),
+            tag_keys: Contributors::new(window),
+        });
+    if now.saturating_duration_since(rejections.started_at) >= rejections.window {
+        rejections.started_at = now;
+        rejections.sampled = 0;
+        rejections.names.counts.clear();
+        rejections.tag_keys.counts.clear();
+    }
+    rejections.sampled += 1;
+    let metric = Metric::new(raw.to_vec());
+    rejections.names.record(metric.name().unwrap_or_default());
+    for tag in metric.tags_iter() {
+        rejections.tag_keys.record(tag.name());
+    }
+}
+
+/// The sampled rejections of a limit, see `report`.
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    pub limit: String,
+    /// Seconds since the window of the counts started.
+    pub age: u64,
+    pub sampled: u64,
+    pub names: Vec<(String, u64)>,
+    pub tag_keys: Vec<(String, u64)>,
+}
+
+/// The metric names and tag keys with the most sampled rejections per limit, in the last window
+/// in which the limit rejected metrics.
+pub fn report() -> Vec<Report> {
+    let now = Instant::now();
+    REJECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(limit, rejections)| Report {
+            limit: limit.clone(),
+            age: now
+                .saturating_duration_since(rejections.started_at)
+                .as_secs(),
+            sampled: rejections.sampled,
+            names: rejections.names.top(TOP),
+            tag_keys: rejections.tag_keys.top(TOP),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_names() {
+        let mut contributors = Contributors::new(Duration::from_secs(60));
+        for name in ["a", "b", "b", "c", "c", "c"] {
+            contributors.record(name.as_bytes());
+        }
+        assert_eq!(
+            contributors.top(2),
+            [("c".to_owned(), 3), ("b".to_owned(), 2)]
+        );
+
+        let start = Instant::now();
+        assert_eq!(
+            contributors.report(start).as_deref(),
+            Some("c (3), b (2), a (1)")
+        );
+        contributors.record(b"d");
+        assert_eq!(contributors.report(start + Duration::from_secs(59)), None);
+        assert_eq!(
+            contributors
+                .report(start + Duration::from_secs(60))
+                .as_deref(),
+            Some("d (1)")
+        );
+    }
+
+    #[test]
+    fn rejections() {
+        for i in 0..SAMPLE_EVERY * 30 {
+            let raw = match i % 3 {
+                0 => "users.online:1|c|#user_id:1",
+                _ => "users.clicks:1|c|#user_id:1,env:prod",
+            };
+            rejected("contributors-test", Duration::from_secs(60), raw.as_bytes());
+        }
+        let report = report()
+            .into_iter()
+            .find(|report| report.limit == "contributors-test")
+            .unwrap();
+        assert_eq!(
+            report,
+            Report {
+                limit: "contributors-test".to_owned(),
+                age: 0,
+                sampled: 30,
+                names: vec![
+                    ("users.clicks".to_owned(), 20),
+                    ("users.online".to_owned(), 10)
+                ],
+                tag_keys: vec![("user_id".to_owned(), 30), ("env".to_owned(), 20)],
+            }
+        );
+    }
+}
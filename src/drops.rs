@@ -0,0 +1,142 @@
+//! Accounting of metrics discarded by statsdproxy. Every component that drops a metric records
+//! why, and the drops are counted per reason and metric name prefix. Counts are reported in the
+//! `dropped_metrics` self metric, and the totals since startup are available from the admin
+//! endpoint.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::self_metrics;
+
+// Drops for more distinct prefixes than this are counted under `OTHER_PREFIX`, so that a flood of
+// bogus metric names cannot grow the counts without bounds.
+const MAX_PREFIXES: usize = 1000;
+const OTHER_PREFIX: &str = "other";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DropReason {
+    /// Not chosen by `sample`.
+    Sampled,
+    /// Over a limit of `cardinality-limit`.
+    Cardinality,
+    /// Over a budget of `byte-budget`, without middlewares for traffic over budget.
+    OverBudget,
+    /// The queue of a worker thread or of the output scheduler was full.
+    Overload,
+    /// A UDP packet over the packet rate limit. To keep the overhead low while flooded, every
+    /// packet is counted once, with the prefix of its first metric.
+    RateLimited,
+    /// Could not be parsed, e.g. a line that is too long.
+    Malformed,
+    /// The child process of `exec` was not running.
+    ProcessUnavailable,
+    /// Could not be sent to a TCP upstream before its buffer was full or on shutdown, or not sent
+    /// to a UDP upstream while its circuit breaker was open.
+    UpstreamUnavailable,
+    /// Over the rate limit of the UDP upstream, see `upstream.rate_limit`.
+    UpstreamRateLimited,
+    /// Of a type the upstream cannot represent, e.g. a timer sent with Prometheus remote write.
+    Unsupported,
+    /// Matched by `deny-metric` or `deny-type`, or not matched by `allow-metric` or `allow-type`.
+    Denied,
+    /// A value that is not a finite number, or out of range with `validate-values`.
+    InvalidValue,
+    /// Over the rate limit of its metric name or series in `rate-limit`.
+    MetricRateLimited,
+    /// A DogStatsD event or service check, with `events.action` set to drop them.
+    Event,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::Sampled => "sampled",
+            DropReason::Cardinality => "cardinality",
+            DropReason::OverBudget => "over_budget",
+            DropReason::Overload => "overload",
+            DropReason::RateLimited => "rate_limited",
+            DropReason::Malformed => "malformed",
+            DropReason::ProcessUnavailable => "process_unavailable",
+            DropReason::UpstreamUnavailable => "upstream_unavailable",
+            DropReason::UpstreamRateLimited => "upstream_rate_limited",
+            DropReason::Unsupported => "unsupported",
+            DropReason::Denied => "denied",
+            DropReason::InvalidValue => "invalid_value",
+            DropReason::MetricRateLimited => "metric_rate_limited",
+            DropReason::Event => "event",
+        }
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Keyed by reason and prefix.
+static TOTALS: Mutex<BTreeMap<(DropReason, String), u64>> = Mutex::new(BTreeMap::new());
+
+/// The first component of the metric name, e.g. `users` for `users.online:1|c`.
+pub(crate) fn prefix(raw: &[u8]) -> String {
+    let end = raw
+        .iter()
+        .position(|x| matches!(x, b'.' | b':' | b'|' | b',' | b'#'))
+        .unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..end]).into_owned()
+}
+
+/// Record that the metric `raw` was dropped.
+pub fn record(reason: DropReason, raw: &[u8]) {
+    let mut prefix = prefix(raw);
+    {
+        let mut totals = TOTALS.lock().unwrap();
+        if totals.len() >= MAX_PREFIXES && !totals.contains_key(&(reason, prefix.clone())) {
+            prefix = OTHER_PREFIX.to_string();
+        }
+        *totals.entry((reason, prefix.clone())).or_insert(0) += 1;
+    }
+    self_metrics::incr(
+        "dropped_metrics",
+        &[("reason", reason.as_str()), ("prefix", &prefix)],
+        1,
+    );
+}
+
+/// The number of dropped metrics per reason and prefix since startup.
+pub fn totals() -> Vec<(DropReason, String, u64)> {
+    TOTALS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((reason, prefix), count)| (*reason, prefix.clone(), *count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic() {
+        assert_eq!(prefix(b"drops-test.users.online:1|c"), "drops-test");
+        assert_eq!(prefix(b"drops-test:1|c"), "drops-test");
+
+        record(DropReason::Sampled, b"drops-test.users.online:1|c");
+        record(DropReason::Sampled, b"drops-test.servers.online:1|c");
+        record(DropReason::Malformed, b"drops-test");
+
+        let totals: Vec<_> = totals()
+            .into_iter()
+            .filter(|(_, prefix, _)| prefix == "drops-test")
+            .collect();
+        assert_eq!(
+            totals,
+            [
+                (DropReason::Sampled, "drops-test".to_string(), 2),
+                (DropReason::Malformed, "drops-test".to_string(), 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Health of statsdproxy, for liveness and readiness probes. The server and upstream report their
+//! state here, and the admin endpoint exposes it.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+// A receive loop that has not checked in for this long is considered stuck.
+const MAX_HEARTBEAT_AGE: Duration = Duration::from_secs(30);
+// Metrics dropped because the pipeline could not keep up within this long make it not ready.
+const DROP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// The last heartbeat of every running receive loop.
+static HEARTBEATS: Mutex<Option<HashMap<ThreadId, Instant>>> = Mutex::new(None);
+static UPSTREAM_FAILING: AtomicBool = AtomicBool::new(false);
+static LAST_OVERLOAD_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Report that the receive loop on the current thread is alive. The first heartbeat marks the
+/// socket as bound and listening.
+pub fn heartbeat() {
+    HEARTBEATS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(thread::current().id(), Instant::now());
+}
+
+/// Report that the receive loop on the current thread has stopped.
+pub fn stopped() {
+    if let Some(heartbeats) = HEARTBEATS.lock().unwrap().as_mut() {
+        heartbeats.remove(&thread::current().id());
+    }
+}
+
+/// Report whether the last attempt to send to the upstream succeeded.
+pub fn upstream_result(ok: bool) {
+    UPSTREAM_FAILING.store(!ok, Ordering::Relaxed);
+}
+
+/// Report that metrics were dropped because the pipeline could not keep up.
+pub fn overloaded() {
+    *LAST_OVERLOAD_AT.lock().unwrap() = Some(Instant::now());
+}
+
+/// The result of a single health check.
+#[derive(Debug, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+}
+
+/// Whether the process is alive, i.e. no receive loop is stuck.
+pub fn liveness() -> Vec<Check> {
+    let heartbeats = HEARTBEATS.lock().unwrap();
+    let ok = heartbeats
+        .iter()
+        .flatten()
+        .all(|(_, at)| at.elapsed() < MAX_HEARTBEAT_AGE);
+    vec![Check {
+        name: "receive_loop",
+        ok,
+    }]
+}
+
+/// Whether the process is ready to receive metrics: it is listening, the upstream accepts
+/// datagrams, and no metrics were recently dropped because the pipeline could not keep up.
+pub fn readiness() -> Vec<Check> {
+    let listening = HEARTBEATS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|x| !x.is_empty());
+    let keeping_up = LAST_OVERLOAD_AT
+        .lock()
+        .unwrap()
+        .is_none_or(|at| at.elapsed() >= DROP_GRACE_PERIOD);
+
+    let mut checks = liveness();
+    checks.extend([
+        Check {
+            name: "listening",
+            ok: listening,
+        },
+        Check {
+            name: "upstream",
+            ok: !UPSTREAM_FAILING.load(Ordering::Relaxed),
+        },
+        Check {
+            name: "keeping_up",
+            ok: keeping_up,
+        },
+    ]);
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_ok(checks: &[Check], name: &str) -> bool {
+        checks.iter().find(|x| x.name == name).unwrap().ok
+    }
+
+    #[test]
+    fn basic() {
+        heartbeat();
+        assert!(is_ok(&liveness(), "receive_loop"));
+        assert!(is_ok(&readiness(), "listening"));
+
+        overloaded();
+        assert!(!is_ok(&readiness(), "keeping_up"));
+
+        stopped();
+    }
+}
@@ -0,0 +1,118 @@
+//! Sending metrics to upstreams that push them in HTTP requests, like Prometheus remote write.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use ureq::{Agent, AgentBuilder};
+
+use crate::health;
+use crate::self_metrics;
+
+/// Send a POST request, returning an error unless the response has a 2xx status.
+fn post(agent: &Agent, url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<(), Error> {
+    let mut request = agent.post(url);
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    match request.send_bytes(body) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(status, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            Err(anyhow!("{}: {}", status, body.trim()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Sends requests to an endpoint in a background thread, so that a slow endpoint does not hold up
+/// the middlewares. Failed requests are logged, and counted in the `upstream.send_errors` self
+/// metric.
+pub struct Sender {
+    agent: Agent,
+    url: String,
+    headers: Vec<(&'static str, &'static str)>,
+    // At most one request waits while another is sent.
+    requests: Mutex<SyncSender<Vec<u8>>>,
+    send_errors: Arc<AtomicU64>,
+}
+
+fn send(agent: &Agent, url: &str, headers: &[(&str, &str)], body: &[u8], send_errors: &AtomicU64) {
+    match post(agent, url, headers, body) {
+        Ok(()) => health::upstream_result(true),
+        Err(e) => {
+            log::error!("failed to send metrics to {}: {}", url, e);
+            health::upstream_result(false);
+            self_metrics::incr("upstream.send_errors", &[], 1);
+            send_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Sender {
+    pub fn new(
+        url: &str,
+        headers: Vec<(&'static str, &'static str)>,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let agent = AgentBuilder::new().timeout(timeout).build();
+        let scheme = agent.post(url).request_url()?.scheme().to_owned();
+        if scheme != "http" && scheme != "https" {
+            return Err(anyhow!(
+                "only http:// and https:// URLs are supported, got {:?}",
+                url
+            ));
+        }
+        let url = url.to_owned();
+        let send_errors = Arc::new(AtomicU64::new(0));
+        let (requests, receiver) = mpsc::sync_channel::<Vec<u8>>(1);
+        thread::Builder::new()
+            .name("http-upstream".to_owned())
+            .spawn({
+                let agent = agent.clone();
+                let url = url.clone();
+                let headers = headers.clone();
+                let send_errors = send_errors.clone();
+                move || {
+                    for body in receiver {
+                        send(&agent, &url, &headers, &body, &send_errors);
+                    }
+                }
+            })?;
+        Ok(Sender {
+            agent,
+            url,
+            headers,
+            requests: Mutex::new(requests),
+            send_errors,
+        })
+    }
+
+    /// Queue a request, returning false if it was not sent because the previous requests are
+    /// still being sent.
+    pub fn send(&self, body: Vec<u8>) -> bool {
+        !matches!(
+            self.requests.lock().unwrap().try_send(body),
+            Err(TrySendError::Full(_))
+        )
+    }
+
+    /// Send a request right away, e.g. on shutdown.
+    pub fn send_now(&self, body: &[u8]) {
+        send(
+            &self.agent,
+            &self.url,
+            &self.headers,
+            body,
+            &self.send_errors,
+        );
+    }
+
+    /// The number of requests that failed so far.
+    pub fn send_errors(&self) -> u64 {
+        self.send_errors.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,57 @@
+//! Sources of metrics for [`Server`](crate::middleware::server::Server).
+//!
+//! statsdproxy receives metrics over UDP by default, but the server's receive loop works with any
+//! [`Ingest`], so that library users can plug in their own source, like a message queue, and still
+//! get line splitting, client tags, polling of the middlewares, reloads and graceful shutdown.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::Error;
+
+mod reader;
+pub(crate) mod socket;
+mod udp;
+#[cfg(unix)]
+mod unix;
+
+pub use reader::{StdinIngest, TcpIngest};
+pub use udp::UdpIngest;
+#[cfg(unix)]
+pub use unix::UnixIngest;
+
+/// A source of newline-separated metrics.
+///
+/// The server calls `recv` in a loop. In between calls, it polls the middlewares, emits self
+/// metrics and checks whether it should reload or shut down, so `recv` should not block for much
+/// longer than a second.
+pub trait Ingest: Send {
+    /// Wait for the next payloads and pass each of them to `on_payload`, together with the IP
+    /// address of the client that sent it if known. Every payload holds one or more complete
+    /// metrics, separated by newlines. Returns false once no more payloads will arrive, which
+    /// shuts down the server.
+    fn recv(&mut self, on_payload: &mut dyn FnMut(Option<IpAddr>, &[u8])) -> Result<bool, Error>;
+
+    /// Called about once a second, e.g. to report on the health of the source.
+    fn housekeeping(&mut self) {}
+
+    /// Wait at most `timeout` for the next payloads in `recv` from now on, so that middlewares
+    /// are polled in time for their next deadline, see `crate::timers`. Sources that cannot limit
+    /// their wait ignore it.
+    fn set_timeout(&mut self, _timeout: Duration) {}
+}
+
+impl<I> Ingest for Box<I>
+where
+    I: Ingest + ?Sized,
+{
+    fn recv(&mut self, on_payload: &mut dyn FnMut(Option<IpAddr>, &[u8])) -> Result<bool, Error> {
+        self.as_mut().recv(on_payload)
+    }
+    fn housekeeping(&mut self) {
+        self.as_mut().housekeeping()
+    }
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.as_mut().set_timeout(timeout)
+    }
+}
@@ -0,0 +1,252 @@
+//! Sources that read byte streams, in which metrics can be split across reads, in background
+//! threads.
+
+use std::io::{ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Error;
+
+use crate::config::ServerConfig;
+use crate::ingest::socket::bind_tcp_listener;
+use crate::ingest::Ingest;
+use crate::line_buffer::LineBuffer;
+
+// The maximum length of a line, matching the maximum size of a UDP datagram.
+const MAX_LINE_LENGTH: usize = 65535;
+
+// How many payloads the reader threads may be ahead of the server, before they stop reading.
+const CHANNEL_SIZE: usize = 1024;
+
+// How long to wait for payloads, and between attempts to accept connections while there are none.
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+type Payload = (Option<IpAddr>, Vec<u8>);
+
+/// The receiving end of the reader threads.
+struct Payloads {
+    receiver: Receiver<Payload>,
+    // Set when the source is dropped, so that the reader threads stop.
+    stop: Arc<AtomicBool>,
+}
+
+impl Payloads {
+    fn recv(&mut self, on_payload: &mut dyn FnMut(Option<IpAddr>, &[u8])) -> Result<bool, Error> {
+        let (source, payload) = match self.receiver.recv_timeout(RECV_TIMEOUT) {
+            Ok(x) => x,
+            Err(RecvTimeoutError::Timeout) => return Ok(true),
+            Err(RecvTimeoutError::Disconnected) => return Ok(false),
+        };
+        on_payload(source, &payload);
+        // Pass on whatever else is ready, up to the size of the channel.
+        for _ in 0..CHANNEL_SIZE {
+            match self.receiver.try_recv() {
+                Ok((source, payload)) => on_payload(source, &payload),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for Payloads {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Read `reader` until it ends or `stop` is set, and send every chunk of complete lines to
+/// `sender`.
+fn read_lines<R>(
+    mut reader: R,
+    source: Option<IpAddr>,
+    sender: &SyncSender<Payload>,
+    stop: &AtomicBool,
+) -> Result<(), Error>
+where
+    R: Read,
+{
+    let mut buf = [0; 65535];
+    let mut lines = LineBuffer::new(MAX_LINE_LENGTH);
+    let send = |payload: Vec<u8>| {
+        // If the source was dropped, there is nobody left to receive anything.
+        payload.is_empty() || sender.send((source, payload)).is_ok()
+    };
+
+    while !stop.load(Ordering::Relaxed) {
+        let num_bytes = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => continue,
+                _ => return Err(Error::from(e)),
+            },
+        };
+        let mut payload = Vec::new();
+        lines.push(&buf[..num_bytes], |line| {
+            payload.extend(line);
+            payload.push(b'\n');
+        });
+        if !send(payload) {
+            return Ok(());
+        }
+    }
+
+    let mut payload = Vec::new();
+    lines.finish(|line| payload.extend(line));
+    send(payload);
+    Ok(())
+}
+
+/// Receives newline-separated metrics on standard input, e.g. piped from a file. The server shuts
+/// down once standard input is closed.
+pub struct StdinIngest {
+    payloads: Payloads,
+}
+
+impl StdinIngest {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_SIZE);
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader_stop = Arc::clone(&stop);
+        // Reading standard input cannot be interrupted, so this thread is not joined.
+        thread::spawn(move || {
+            if let Err(e) = read_lines(std::io::stdin().lock(), None, &sender, &reader_stop) {
+                log::error!("failed to read standard input: {}", e);
+            }
+        });
+        StdinIngest {
+            payloads: Payloads { receiver, stop },
+        }
+    }
+}
+
+impl Default for StdinIngest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ingest for StdinIngest {
+    fn recv(&mut self, on_payload: &mut dyn FnMut(Option<IpAddr>, &[u8])) -> Result<bool, Error> {
+        self.payloads.recv(on_payload)
+    }
+}
+
+/// Receives newline-separated metrics over TCP connections, each of which is read in its own
+/// thread.
+///
+/// Unlike the `tcp_listen` setting, which accepts TCP connections in addition to receiving UDP,
+/// this is for servers that only receive over TCP. Metrics that were read but not yet received
+/// by the server when it shuts down are lost.
+pub struct TcpIngest {
+    payloads: Payloads,
+    local_addr: SocketAddr,
+}
+
+impl TcpIngest {
+    /// Bind `listen`, applying the socket settings in `config`.
+    pub fn bind(listen: &str, config: &ServerConfig) -> Result<Self, Error> {
+        let listener = bind_tcp_listener(listen, config)?;
+        let local_addr = listener.local_addr()?;
+        // Accept without blocking, so that the thread notices when the source is dropped.
+        listener.set_nonblocking(true)?;
+
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_SIZE);
+        let stop = Arc::new(AtomicBool::new(false));
+        let accept_stop = Arc::clone(&stop);
+        thread::spawn(move || accept(listener, sender, accept_stop));
+        Ok(TcpIngest {
+            payloads: Payloads { receiver, stop },
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+fn accept(listener: TcpListener, sender: SyncSender<Payload>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        let (stream, addr) = match listener.accept() {
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let sender = sender.clone();
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, addr, &sender, &stop) {
+                log::warn!("failed to handle connection: {}", e);
+            }
+        });
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    sender: &SyncSender<Payload>,
+    stop: &AtomicBool,
+) -> Result<(), Error> {
+    // Accepted sockets may inherit non-blocking mode from the listener on some platforms.
+    stream.set_nonblocking(false)?;
+    // Wake up regularly to check whether the source was dropped.
+    stream.set_read_timeout(Some(RECV_TIMEOUT))?;
+    read_lines(stream, Some(addr.ip()), sender, stop)
+}
+
+impl Ingest for TcpIngest {
+    fn recv(&mut self, on_payload: &mut dyn FnMut(Option<IpAddr>, &[u8])) -> Result<bool, Error> {
+        self.payloads.recv(on_payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn tcp() {
+        let mut ingest = TcpIngest::bind("127.0.0.1:0", &ServerConfig::default()).unwrap();
+        let mut stream = TcpStream::connect(ingest.local_addr()).unwrap();
+        stream.write_all(b"users.online:1|c\nservers.").unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        stream.write_all(b"online:1|c").unwrap();
+        drop(stream);
+
+        let mut received = Vec::new();
+        for _ in 0..5 {
+            ingest
+                .recv(&mut |source, payload| {
+                    assert_eq!(source, Some("127.0.0.1".parse().unwrap()));
+                    received.push(payload.to_vec());
+                })
+                .unwrap();
+            if received.len() == 2 {
+                break;
+            }
+        }
+        assert_eq!(
+            received,
+            [&b"users.online:1|c\n"[..], b"servers.online:1|c"]
+        );
+    }
+}
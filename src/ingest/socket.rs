@@ -0,0 +1,124 @@
+//! Resolving listen addresses and binding sockets, shared by the UDP and TCP sources.
+
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, ToSocketAddrs};
+
+use anyhow::{anyhow, Error};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::ServerConfig;
+
+pub(crate) fn resolve_listen_address(listen: &str) -> Result<SocketAddr, Error> {
+    if let Some(addr) = parse_scoped_address(listen)? {
+        return Ok(addr);
+    }
+    listen
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("could not resolve listen address {}", listen))
+}
+
+/// Parse link-local IPv6 addresses with a zone, like `[fe80::1%eth0]:8125` or `[fe80::1%2]:8125`,
+/// which the standard library does not support.
+fn parse_scoped_address(listen: &str) -> Result<Option<SocketAddr>, Error> {
+    let Some((host, port)) = listen
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("]:"))
+    else {
+        return Ok(None);
+    };
+    let Some((ip, zone)) = host.split_once('%') else {
+        return Ok(None);
+    };
+    let ip: Ipv6Addr = ip
+        .parse()
+        .map_err(|_| anyhow!("invalid IPv6 address in {}", listen))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| anyhow!("invalid port in {}", listen))?;
+    let scope_id = match zone.parse() {
+        Ok(scope_id) => scope_id,
+        Err(_) => interface_index(zone)?,
+    };
+    Ok(Some(SocketAddrV6::new(ip, port, 0, scope_id).into()))
+}
+
+#[cfg(target_os = "linux")]
+fn interface_index(name: &str) -> Result<u32, Error> {
+    let c_name = std::ffi::CString::new(name)?;
+    // SAFETY: `c_name` is a valid, NUL-terminated string.
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(anyhow!("unknown network interface {}", name)),
+        index => Ok(index),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_index(name: &str) -> Result<u32, Error> {
+    Err(anyhow!(
+        "interface names are only supported on Linux, use the numeric zone index instead of {}",
+        name
+    ))
+}
+
+/// Apply the socket options shared by the UDP and TCP sockets.
+pub(crate) fn configure_socket(
+    socket: &Socket,
+    addr: SocketAddr,
+    config: &ServerConfig,
+) -> Result<(), Error> {
+    if let (Some(ipv6_only), SocketAddr::V6(_)) = (config.ipv6_only, addr) {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    if let Some(interface) = &config.interface {
+        #[cfg(target_os = "linux")]
+        socket
+            .bind_device(Some(interface.as_bytes()))
+            .map_err(|e| anyhow!("failed to bind to interface {}: {}", interface, e))?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(anyhow!(
+            "cannot bind to interface {}: only supported on Linux",
+            interface
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn bind_tcp_listener(listen: &str, config: &ServerConfig) -> Result<TcpListener, Error> {
+    let addr = resolve_listen_address(listen)?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    configure_socket(&socket, addr, config)?;
+    socket.set_reuse_address(true)?;
+
+    if config.receiver_threads > 1 {
+        // Like for UDP, the kernel distributes incoming connections between receiver threads.
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_addresses() {
+        assert_eq!(
+            resolve_listen_address("[fe80::1%2]:8125").unwrap(),
+            SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 8125, 0, 2))
+        );
+        assert_eq!(
+            resolve_listen_address("[::]:8125").unwrap(),
+            "[::]:8125".parse().unwrap()
+        );
+        assert_eq!(
+            resolve_listen_address("0.0.0.0:8125").unwrap(),
+            "0.0.0.0:8125".parse().unwrap()
+        );
+        assert!(resolve_listen_address("[fe80::1%2]:port").is_err());
+        assert!(resolve_listen_address("[fe80::1%no-such-interface]:8125").is_err());
+    }
+}
@@ -0,0 +1,389 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+#[cfg(any(target_os = "linux", not(unix)))]
+use anyhow::anyhow;
+use anyhow::Error;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::ServerConfig;
+use crate::contributors;
+use crate::drops::{self, DropReason};
+use crate::ingest::socket::{configure_socket, resolve_listen_address};
+use crate::ingest::Ingest;
+use crate::packet_limiter::PacketLimiter;
+#[cfg(target_os = "linux")]
+use crate::{health, self_metrics};
+
+// if sending this large udp dataframes happens to work randomly, we should not be the
+// one that breaks that setup.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Receives metrics over UDP, one or more metrics per datagram.
+pub struct UdpIngest {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+    // With `recv_batch_size` > 1, many datagrams are received per syscall instead.
+    #[cfg(target_os = "linux")]
+    ring: Option<recvmmsg::RecvRing>,
+    packet_limiter: Option<PacketLimiter>,
+    // The socket is freshly bound, so nothing has been dropped before.
+    #[cfg(target_os = "linux")]
+    kernel_drops: u64,
+}
+
+impl UdpIngest {
+    /// Bind `listen`, applying the socket settings in `config`.
+    pub fn bind(listen: &str, config: &ServerConfig) -> Result<Self, Error> {
+        let socket = bind_socket(listen, config)?;
+        // An acceptable balance between busyloop and responsiveness to signals.
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+        Ok(UdpIngest {
+            socket,
+            buf: vec![0; MAX_DATAGRAM_SIZE],
+            #[cfg(target_os = "linux")]
+            ring: (config.recv_batch_size > 1)
+                .then(|| recvmmsg::RecvRing::new(config.recv_batch_size, MAX_DATAGRAM_SIZE)),
+            packet_limiter: config
+                .packet_rate_limit
+                .clone()
+                .map(|x| PacketLimiter::new(x, Instant::now())),
+            #[cfg(target_os = "linux")]
+            kernel_drops: 0,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+}
+
+impl Ingest for UdpIngest {
+    fn recv(&mut self, on_payload: &mut dyn FnMut(Option<IpAddr>, &[u8])) -> Result<bool, Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(ring) = &mut self.ring {
+            let num_datagrams = match ring.recv(&self.socket) {
+                Err(err) if is_timeout(err.kind()) => return Ok(true),
+                Err(err) => return Err(Error::from(err)),
+                Ok(n) => n,
+            };
+            for i in 0..num_datagrams {
+                if ring.truncated(i) {
+                    // Only datagrams larger than the buffers are cut off, so the last metric in
+                    // them is incomplete.
+                    drops::record(DropReason::Malformed, ring.datagram(i));
+                    continue;
+                }
+                let source = ring.source_ip(i);
+                if allow_packet(&mut self.packet_limiter, source, ring.datagram(i)) {
+                    on_payload(source, ring.datagram(i));
+                }
+            }
+            return Ok(true);
+        }
+
+        let (num_bytes, addr) = match self.socket.recv_from(&mut self.buf) {
+            Err(err) if is_timeout(err.kind()) => return Ok(true),
+            Err(err) => return Err(Error::from(err)),
+            Ok(s) => s,
+        };
+        let datagram = &self.buf[..num_bytes];
+        if allow_packet(&mut self.packet_limiter, Some(addr.ip()), datagram) {
+            on_payload(Some(addr.ip()), datagram);
+        }
+        Ok(true)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        if let Err(e) = self.socket.set_read_timeout(Some(timeout)) {
+            log::debug!("failed to set the read timeout: {}", e);
+        }
+    }
+
+    fn housekeeping(&mut self) {
+        #[cfg(target_os = "linux")]
+        match kernel_drops(&self.socket) {
+            Ok(drops) => {
+                let new_drops = drops.saturating_sub(self.kernel_drops);
+                if new_drops > 0 {
+                    log::warn!(
+                        "the kernel dropped {} datagrams, consider increasing recv_buffer_size",
+                        new_drops
+                    );
+                    self_metrics::incr("server.kernel_drops", &[], new_drops);
+                    health::overloaded();
+                }
+                self.kernel_drops = drops;
+            }
+            Err(e) => log::debug!("failed to read kernel drop counter: {}", e),
+        }
+    }
+}
+
+// Different timeout errors might be raised depending on platform.
+fn is_timeout(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Whether a packet is within the packet rate limit. Packets over the limit are counted as
+/// dropped.
+fn allow_packet(
+    packet_limiter: &mut Option<PacketLimiter>,
+    source: Option<IpAddr>,
+    datagram: &[u8],
+) -> bool {
+    let Some(packet_limiter) = packet_limiter else {
+        return true;
+    };
+    if packet_limiter.allow(source, Instant::now()) {
+        return true;
+    }
+    drops::record(DropReason::RateLimited, datagram);
+    let first_line = datagram.split(|&x| x == b'\n').next().unwrap_or_default();
+    contributors::rejected(
+        "packet-rate-limit",
+        contributors::DEFAULT_WINDOW,
+        first_line,
+    );
+    false
+}
+
+/// Read the number of datagrams the kernel dropped for this socket, for example because its
+/// receive buffer was full.
+#[cfg(target_os = "linux")]
+fn kernel_drops(socket: &UdpSocket) -> Result<u64, Error> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::MetadataExt;
+
+    let inode = std::fs::metadata(format!("/proc/self/fd/{}", socket.as_raw_fd()))?.ino();
+    for path in ["/proc/net/udp", "/proc/net/udp6"] {
+        let Ok(table) = std::fs::read_to_string(path) else {
+            continue;
+        };
+        // The columns are: sl local_address rem_address st tx_queue:rx_queue tr:tm->when
+        // retrnsmt uid timeout inode ref pointer drops
+        for line in table.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(9).and_then(|x| x.parse().ok()) == Some(inode) {
+                return fields
+                    .get(12)
+                    .and_then(|x| x.parse().ok())
+                    .ok_or_else(|| anyhow!("failed to parse {}", path));
+            }
+        }
+    }
+    Err(anyhow!("socket not found in /proc/net/udp"))
+}
+
+fn bind_socket(listen: &str, config: &ServerConfig) -> Result<UdpSocket, Error> {
+    let addr = resolve_listen_address(listen)?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    configure_socket(&socket, addr, config)?;
+
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+        log::info!(
+            "requested a receive buffer of {} bytes, got {} bytes",
+            size,
+            socket.recv_buffer_size()?
+        );
+    }
+
+    if config.receiver_threads > 1 {
+        // Several receiver threads bind the same address, and the kernel load-balances incoming
+        // datagrams between them.
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(anyhow!(
+            "receiver_threads > 1 requires SO_REUSEPORT, which is unsupported"
+        ));
+    }
+
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(target_os = "linux")]
+mod recvmmsg {
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+    use std::os::fd::AsRawFd;
+    use std::{mem, ptr};
+
+    /// A set of reusable receive buffers, filled by a single `recvmmsg` call.
+    pub struct RecvRing {
+        buffers: Vec<Vec<u8>>,
+        // The iovecs point into `buffers`, and the headers point into `iovecs` and `addresses`.
+        // None of these vectors is resized after construction, so the pointers stay valid.
+        iovecs: Vec<libc::iovec>,
+        // The source addresses of the datagrams.
+        addresses: Vec<libc::sockaddr_storage>,
+        headers: Vec<libc::mmsghdr>,
+    }
+
+    // SAFETY: the raw pointers only point into heap allocations owned by the ring itself, which
+    // move along with it.
+    unsafe impl Send for RecvRing {}
+
+    impl RecvRing {
+        pub fn new(batch_size: usize, buffer_size: usize) -> Self {
+            let mut buffers = vec![vec![0u8; buffer_size]; batch_size];
+            let mut iovecs: Vec<libc::iovec> = buffers
+                .iter_mut()
+                .map(|buffer| libc::iovec {
+                    iov_base: buffer.as_mut_ptr().cast(),
+                    iov_len: buffer.len(),
+                })
+                .collect();
+            // SAFETY: sockaddr_storage is a plain C struct for which all zeroes is a valid value.
+            let mut addresses: Vec<libc::sockaddr_storage> =
+                vec![unsafe { mem::zeroed() }; batch_size];
+            let headers = iovecs
+                .iter_mut()
+                .zip(&mut addresses)
+                .map(|(iovec, address)| {
+                    // SAFETY: mmsghdr is a plain C struct for which all zeroes is a valid value.
+                    let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                    header.msg_hdr.msg_iov = iovec;
+                    header.msg_hdr.msg_iovlen = 1;
+                    header.msg_hdr.msg_name = (address as *mut libc::sockaddr_storage).cast();
+                    header
+                })
+                .collect();
+
+            RecvRing {
+                buffers,
+                iovecs,
+                addresses,
+                headers,
+            }
+        }
+
+        /// Receive up to `batch_size` datagrams, blocking until at least one datagram arrives or
+        /// the socket's read timeout expires. Returns the number of datagrams received.
+        pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+            debug_assert_eq!(self.iovecs.len(), self.headers.len());
+            for header in &mut self.headers {
+                // Overwritten by the kernel with the actual length of each address.
+                header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            }
+            // SAFETY: all headers point to iovecs, which point to buffers owned by `self`.
+            let received = unsafe {
+                libc::recvmmsg(
+                    socket.as_raw_fd(),
+                    self.headers.as_mut_ptr(),
+                    self.headers.len() as _,
+                    libc::MSG_WAITFORONE as _,
+                    ptr::null_mut(),
+                )
+            };
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(received as usize)
+        }
+
+        /// The contents of the `i`-th datagram received by the last call to `recv`.
+        pub fn datagram(&self, i: usize) -> &[u8] {
+            &self.buffers[i][..self.headers[i].msg_len as usize]
+        }
+
+        /// Whether the `i`-th datagram received by the last call to `recv` was larger than its
+        /// buffer, and so cut off.
+        pub fn truncated(&self, i: usize) -> bool {
+            self.headers[i].msg_hdr.msg_flags & libc::MSG_TRUNC != 0
+        }
+
+        /// The IP address that sent the `i`-th datagram received by the last call to `recv`.
+        pub fn source_ip(&self, i: usize) -> Option<IpAddr> {
+            let address: *const libc::sockaddr_storage = &self.addresses[i];
+            // SAFETY: the kernel wrote an address of the family given by `ss_family`, and
+            // sockaddr_storage is large enough and suitably aligned for all of them.
+            match self.addresses[i].ss_family as libc::c_int {
+                libc::AF_INET => {
+                    let address = unsafe { &*address.cast::<libc::sockaddr_in>() };
+                    Some(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)).into())
+                }
+                libc::AF_INET6 => {
+                    let address = unsafe { &*address.cast::<libc::sockaddr_in6>() };
+                    Some(Ipv6Addr::from(address.sin6_addr.s6_addr).into())
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn recv_batch(listen: &str, sender: &str) -> Vec<(Option<IpAddr>, Vec<u8>)> {
+        let config = ServerConfig {
+            recv_batch_size: 8,
+            ..Default::default()
+        };
+        let mut ingest = UdpIngest::bind(listen, &config).unwrap();
+        let addr = ingest.local_addr().unwrap();
+
+        let sender = UdpSocket::bind(sender).unwrap();
+        for datagram in [&b"users.online:1|c"[..], b"a:1|c\nb:2|c", b"c:3|g"] {
+            sender.send_to(datagram, addr).unwrap();
+        }
+
+        // All datagrams are queued on the loopback interface, so a single call receives them.
+        let mut payloads = vec![];
+        ingest
+            .recv(&mut |source, payload| payloads.push((source, payload.to_vec())))
+            .unwrap();
+        payloads
+    }
+
+    #[test]
+    fn recvmmsg_ipv4() {
+        let source = Some("127.0.0.1".parse().unwrap());
+        assert_eq!(
+            recv_batch("127.0.0.1:0", "127.0.0.1:0"),
+            [
+                (source, b"users.online:1|c".to_vec()),
+                (source, b"a:1|c\nb:2|c".to_vec()),
+                (source, b"c:3|g".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn recvmmsg_ipv6() {
+        let source = Some("::1".parse().unwrap());
+        assert_eq!(
+            recv_batch("[::1]:0", "[::1]:0"),
+            [
+                (source, b"users.online:1|c".to_vec()),
+                (source, b"a:1|c\nb:2|c".to_vec()),
+                (source, b"c:3|g".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn recvmmsg_truncated() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender
+            .send_to(b"a:1|c", socket.local_addr().unwrap())
+            .unwrap();
+        sender
+            .send_to(b"users.online:1|c", socket.local_addr().unwrap())
+            .unwrap();
+
+        let mut ring = recvmmsg::RecvRing::new(2, 8);
+        assert_eq!(ring.recv(&socket).unwrap(), 2);
+        assert!(!ring.truncated(0));
+        assert_eq!(ring.datagram(0), b"a:1|c");
+        assert!(ring.truncated(1));
+        assert_eq!(ring.datagram(1), b"users.on");
+    }
+}
@@ -0,0 +1,87 @@
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+
+use crate::ingest::Ingest;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Receives metrics over a Unix datagram socket, one or more metrics per datagram, like the
+/// Unix socket of DogStatsD.
+pub struct UnixIngest {
+    socket: UnixDatagram,
+    path: PathBuf,
+    buf: Vec<u8>,
+}
+
+impl UnixIngest {
+    /// Bind a socket at `path`. A socket left behind at `path` by a previous run is replaced.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(anyhow!("{} exists and is not a socket", path.display())),
+            Err(_) => {}
+        }
+        let socket = UnixDatagram::bind(path)
+            .map_err(|e| anyhow!("failed to bind {}: {}", path.display(), e))?;
+        // An acceptable balance between busyloop and responsiveness to signals.
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(UnixIngest {
+            socket,
+            path: path.to_owned(),
+            buf: vec![0; MAX_DATAGRAM_SIZE],
+        })
+    }
+}
+
+impl Drop for UnixIngest {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Ingest for UnixIngest {
+    fn recv(&mut self, on_payload: &mut dyn FnMut(Option<IpAddr>, &[u8])) -> Result<bool, Error> {
+        match self.socket.recv(&mut self.buf) {
+            Ok(num_bytes) => on_payload(None, &self.buf[..num_bytes]),
+            Err(err) => match err.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => {}
+                _ => return Err(Error::from(err)),
+            },
+        }
+        Ok(true)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        if let Err(e) = self.socket.set_read_timeout(Some(timeout)) {
+            log::debug!("failed to set the read timeout: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_datagrams() {
+        let path = std::env::temp_dir().join(format!("statsdproxy-{}.sock", std::process::id()));
+        // A socket left behind by a previous run is replaced.
+        drop(UnixDatagram::bind(&path).unwrap());
+        let mut ingest = UnixIngest::bind(&path).unwrap();
+
+        let client = UnixDatagram::unbound().unwrap();
+        client.send_to(b"users.online:1|c", &path).unwrap();
+        let mut received = Vec::new();
+        ingest
+            .recv(&mut |source, payload| received.push((source, payload.to_vec())))
+            .unwrap();
+        assert_eq!(received, [(None, b"users.online:1|c".to_vec())]);
+    }
+}
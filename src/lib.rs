@@ -1,7 +1,50 @@
+//! A proxy for statsd metrics, usable as a binary or as a library. Library users should import
+//! from `prelude`, which is the only part of the API kept stable between minor releases. The other
+//! public modules exist for the bundled binary and are hidden from the documentation.
+
+#[doc(hidden)]
+pub mod batch;
+mod bounded_queue;
 #[cfg(feature = "cadence")]
 pub mod cadence;
+mod circuit_breaker;
+#[cfg(feature = "cli")]
+mod client_tag;
+#[doc(hidden)]
+pub mod compliance;
+#[doc(hidden)]
 pub mod config;
+#[doc(hidden)]
+pub mod contributors;
+#[doc(hidden)]
+pub mod drops;
+#[doc(hidden)]
+pub mod health;
+#[cfg(feature = "http")]
+mod http_client;
+#[cfg(feature = "cli")]
+#[doc(hidden)]
+pub mod ingest;
+#[cfg(feature = "cli")]
+mod line_buffer;
+#[cfg(feature = "cli")]
+mod line_handler;
+#[doc(hidden)]
 pub mod middleware;
+#[cfg(feature = "cli")]
+mod packet_limiter;
+pub mod prelude;
+mod resolve;
+#[doc(hidden)]
+pub mod self_metrics;
+mod spool;
+#[doc(hidden)]
+pub mod startup;
 
+#[cfg(test)]
 mod testutils;
+mod timers;
+mod token_bucket;
+#[doc(hidden)]
 pub mod types;
+mod upstream_limiter;
@@ -0,0 +1,131 @@
+use crate::drops::{self, DropReason};
+use crate::self_metrics;
+
+/// Reassembles newline-separated lines from a stream of chunks, such as reads from a socket, in
+/// which lines can be split across chunks.
+///
+/// Lines longer than `max_line_length` are discarded up to the next newline, so that a client that
+/// never sends a newline cannot make us buffer indefinitely. They are counted in the
+/// `server.oversized_lines` self metric.
+pub struct LineBuffer {
+    pending: Vec<u8>,
+    max_line_length: usize,
+    // Whether the rest of an oversized line is being skipped.
+    discarding: bool,
+}
+
+impl LineBuffer {
+    pub fn new(max_line_length: usize) -> Self {
+        LineBuffer {
+            pending: Vec::new(),
+            max_line_length,
+            discarding: false,
+        }
+    }
+
+    /// Add a chunk, calling `on_line` with every line completed by it.
+    pub fn push<F>(&mut self, mut chunk: &[u8], mut on_line: F)
+    where
+        F: FnMut(&[u8]),
+    {
+        if self.discarding {
+            let Some(end) = chunk.iter().position(|&x| x == b'\n') else {
+                return;
+            };
+            chunk = &chunk[end + 1..];
+            self.discarding = false;
+        }
+
+        if let Some(end) = chunk.iter().rposition(|&x| x == b'\n') {
+            let complete = if self.pending.is_empty() {
+                &chunk[..end]
+            } else {
+                self.pending.extend(&chunk[..end]);
+                &self.pending[..]
+            };
+            for line in complete.split(|&x| x == b'\n') {
+                if line.len() > self.max_line_length {
+                    oversized(line);
+                } else if !line.is_empty() {
+                    on_line(line);
+                }
+            }
+            self.pending.clear();
+            chunk = &chunk[end + 1..];
+        }
+
+        if self.pending.len() + chunk.len() > self.max_line_length {
+            let mut start = std::mem::take(&mut self.pending);
+            start.extend(&chunk[..chunk.len().min(self.max_line_length)]);
+            oversized(&start);
+            self.discarding = true;
+        } else {
+            self.pending.extend(chunk);
+        }
+    }
+
+    /// Call `on_line` with the last line, which does not need to be terminated by a newline.
+    pub fn finish<F>(&mut self, mut on_line: F)
+    where
+        F: FnMut(&[u8]),
+    {
+        if !self.pending.is_empty() {
+            on_line(&self.pending);
+        }
+        self.pending.clear();
+        self.discarding = false;
+    }
+}
+
+fn oversized(line: &[u8]) {
+    log::warn!("discarding line longer than the maximum line length");
+    self_metrics::incr("server.oversized_lines", &[], 1);
+    drops::record(DropReason::Malformed, line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_all(buffer: &mut LineBuffer, chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        for chunk in chunks {
+            buffer.push(chunk, |line| lines.push(line.to_vec()));
+        }
+        buffer.finish(|line| lines.push(line.to_vec()));
+        lines
+    }
+
+    #[test]
+    fn split_lines() {
+        let mut buffer = LineBuffer::new(100);
+        assert_eq!(
+            push_all(
+                &mut buffer,
+                &[
+                    b"users.on",
+                    b"line:1|c\nservers",
+                    b".online:1|c\n\na:1",
+                    b"|c"
+                ]
+            ),
+            [&b"users.online:1|c"[..], b"servers.online:1|c", b"a:1|c"]
+        );
+    }
+
+    #[test]
+    fn oversized_lines() {
+        let mut buffer = LineBuffer::new(10);
+        assert_eq!(
+            push_all(
+                &mut buffer,
+                &[
+                    b"a:1|c\nusers.online:1|c\nb:1|c\nservers.",
+                    b"online",
+                    b":1|c\nc:1|c\n"
+                ]
+            ),
+            [&b"a:1|c"[..], b"b:1|c", b"c:1|c"]
+        );
+    }
+}
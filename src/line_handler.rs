@@ -0,0 +1,189 @@
+//! Turning received lines into metrics, the same way for every transport.
+
+use std::net::IpAddr;
+
+use anyhow::Error;
+
+use crate::client_tag::ClientTag;
+use crate::compliance;
+use crate::config::{ComplianceAction, ComplianceConfig, OversizedMetricPolicy, ServerConfig};
+use crate::drops::{self, DropReason};
+use crate::self_metrics;
+use crate::types::Metric;
+
+/// Applies the server settings for individual metrics, before they are passed to the middlewares.
+pub struct LineHandler {
+    client_tag: Option<ClientTag>,
+    max_metric_length: Option<usize>,
+    oversized_metric_policy: OversizedMetricPolicy,
+    compliance: Option<ComplianceConfig>,
+}
+
+/// The client that sent a payload, as needed for every metric in it.
+pub struct Source {
+    addr: Option<IpAddr>,
+    tag: Option<Vec<u8>>,
+}
+
+impl LineHandler {
+    pub fn new(config: &ServerConfig) -> Result<Self, Error> {
+        Ok(LineHandler {
+            client_tag: config.client_tag.as_ref().map(ClientTag::new).transpose()?,
+            max_metric_length: config.max_metric_length,
+            oversized_metric_policy: config.oversized_metric_policy,
+            compliance: config.compliance.clone(),
+        })
+    }
+
+    /// The client at `addr`, if known. Called once per payload, since building the client tag
+    /// allocates.
+    pub fn source(&self, addr: Option<IpAddr>) -> Source {
+        let tag = self
+            .client_tag
+            .as_ref()
+            .zip(addr)
+            .map(|(client_tag, addr)| client_tag.for_addr(addr));
+        Source { addr, tag }
+    }
+
+    /// Prepare a freshly received metric from `source`, adding the client tag to it. Returns false
+    /// if the metric must be dropped.
+    pub fn prepare(&self, metric: &mut Metric, source: &Source) -> bool {
+        if let Some(config) = &self.compliance {
+            if !self.check_compliance(config, metric, source) {
+                return false;
+            }
+        }
+        if let Some(tag) = &source.tag {
+            metric.append_tags(tag);
+        }
+        match self.max_metric_length {
+            Some(max_length) if metric.raw.len() > max_length => self.shorten(metric, max_length),
+            _ => true,
+        }
+    }
+
+    fn check_compliance(
+        &self,
+        config: &ComplianceConfig,
+        metric: &mut Metric,
+        source: &Source,
+    ) -> bool {
+        let violations = compliance::check(metric);
+        if violations.is_empty() {
+            return true;
+        }
+        compliance::record(source.addr, &violations, &metric.raw);
+        match config.action {
+            ComplianceAction::Report => true,
+            ComplianceAction::Normalize => {
+                if let Some(normalized) = compliance::normalize(metric) {
+                    *metric = Metric::new(normalized);
+                }
+                true
+            }
+            ComplianceAction::Drop => {
+                drops::record(DropReason::Malformed, &metric.raw);
+                false
+            }
+        }
+    }
+
+    fn shorten(&self, metric: &mut Metric, max_length: usize) -> bool {
+        if self.oversized_metric_policy == OversizedMetricPolicy::TruncateTags {
+            let mut tags: Vec<Vec<u8>> = metric.tags_iter().map(|x| x.raw.to_vec()).collect();
+            while metric.raw.len() > max_length && !tags.is_empty() {
+                tags.pop();
+                metric.set_tags(&tags.join(&b","[..]));
+            }
+            if metric.raw.len() <= max_length {
+                self_metrics::incr("server.oversized_metrics", &[("action", "truncated")], 1);
+                return true;
+            }
+        }
+        self_metrics::incr("server.oversized_metrics", &[("action", "dropped")], 1);
+        drops::record(DropReason::Malformed, &metric.raw);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prepare(policy: OversizedMetricPolicy, raw: &[u8]) -> Option<Metric> {
+        let config = ServerConfig {
+            max_metric_length: Some(30),
+            oversized_metric_policy: policy,
+            ..Default::default()
+        };
+        let mut metric = Metric::new(raw.to_vec());
+        let source = Source {
+            addr: None,
+            tag: Some(b"client:a".to_vec()),
+        };
+        LineHandler::new(&config)
+            .unwrap()
+            .prepare(&mut metric, &source)
+            .then_some(metric)
+    }
+
+    #[test]
+    fn oversized_metrics() {
+        assert_eq!(
+            prepare(OversizedMetricPolicy::Drop, b"users.online:1|c"),
+            Some(Metric::new(b"users.online:1|c|#client:a".to_vec()))
+        );
+        assert_eq!(
+            prepare(
+                OversizedMetricPolicy::Drop,
+                b"users.online:1|c|#country:china"
+            ),
+            None
+        );
+        assert_eq!(
+            prepare(
+                OversizedMetricPolicy::TruncateTags,
+                b"users.online:1|c|#a:1,country:china"
+            ),
+            Some(Metric::new(b"users.online:1|c|#a:1".to_vec()))
+        );
+        assert_eq!(
+            prepare(
+                OversizedMetricPolicy::TruncateTags,
+                b"users.online.in.all.countries.of.the.world:1|c"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn compliance() {
+        let prepare = |action, raw: &[u8]| {
+            let config = ServerConfig {
+                compliance: Some(ComplianceConfig { action }),
+                ..Default::default()
+            };
+            let handler = LineHandler::new(&config).unwrap();
+            let mut metric = Metric::new(raw.to_vec());
+            handler
+                .prepare(&mut metric, &handler.source(None))
+                .then_some(metric)
+        };
+
+        let raw = b"users online:1|c|#a b";
+        assert_eq!(
+            prepare(ComplianceAction::Report, raw),
+            Some(Metric::new(raw.to_vec()))
+        );
+        assert_eq!(
+            prepare(ComplianceAction::Normalize, raw),
+            Some(Metric::new(b"users_online:1|c|#a_b".to_vec()))
+        );
+        assert_eq!(prepare(ComplianceAction::Drop, raw), None);
+        assert_eq!(
+            prepare(ComplianceAction::Drop, b"users.online:1|c"),
+            Some(Metric::new(b"users.online:1|c".to_vec()))
+        );
+    }
+}
@@ -1,18 +1,57 @@
 #![cfg(feature = "cli")]
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
 use anyhow::Error;
+use arc_swap::ArcSwap;
 use clap::Parser;
 
 use statsdproxy::config;
-use statsdproxy::middleware::{self, server::Server, upstream::Upstream};
+use statsdproxy::ingest::{self, Ingest};
+use statsdproxy::middleware::{
+    self,
+    compare::{Compare, Emitted},
+    events::Events,
+    failover::{Failover, SendErrors},
+    file_output::FileOutput,
+    graphite::Graphite,
+    mirror::Mirror,
+    observe::{Collector, Observe},
+    peer_forward::PeerForward,
+    print::Print,
+    relay::RelayPipeline,
+    route::Route,
+    scheduler::OutputScheduler,
+    server::Server,
+    sharded::Sharded,
+    shared::Shared,
+    suggest::Suggest,
+    tcp_upstream::TcpUpstream,
+    upstream::Upstream,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
-    listen: String,
+    /// Addresses to receive metrics on over UDP, in 'host:port' format. Use 'tcp:host:port' to
+    /// receive over TCP instead, 'unix:path' for a Unix datagram socket, or '-' for standard
+    /// input. Can be given multiple times, or as a comma-separated list. TCP, TLS and HTTP
+    /// listeners from the configuration file are only started once, together with the first
+    /// address.
+    #[arg(short, long, required = true, value_delimiter = ',')]
+    listen: Vec<String>,
 
-    /// Specify an address to an upstream statsd server in 'host:port' format.
+    /// Specify an address to an upstream statsd server in 'host:port' format. Use 'tcp:host:port'
+    /// to send over TCP instead of UDP, 'tls:host:port' to send over TCP wrapped in TLS as
+    /// configured in upstream.tls, 'quic:host:port' to send to another statsdproxy over QUIC as
+    /// configured in upstream.quic, 'graphite:host:port' to send to Graphite's plaintext
+    /// protocol over TCP, 'remote-write:http://host:port/path' to send counters and gauges with
+    /// Prometheus remote write, 'otlp:http://host:port/v1/metrics' to send to an OpenTelemetry
+    /// collector, or 'stdout://' or 'stderr://' to print metrics instead. 'suggest://' profiles
+    /// the metrics and prints a draft configuration with suggested middlewares on shutdown.
     #[arg(short, long)]
     upstream: String,
 
@@ -22,30 +61,76 @@ struct Args {
     config_path: Option<String>,
 }
 
-fn main() -> Result<(), Error> {
-    env_logger::init();
-
-    let args = Args::parse();
-
-    if args.config_path.is_none() {
-        log::warn!("No config file specified. No middlewares will be used.");
-    }
+type BoxedMiddleware = Box<dyn middleware::Middleware + Send>;
 
-    let config = args
-        .config_path
-        .as_deref()
-        .map(config::Config::new)
-        .transpose()?
-        .unwrap_or_default();
+/// Build a chain of middlewares, with the first middleware in `middlewares` on top and `client`
+/// at the bottom.
+///
+/// Every middleware that may drop metrics is bypassed by metrics matching the exemptions in
+/// `root`, the whole config, which also provides the settings for upstreams of nested chains.
+fn build_middlewares(
+    middlewares: Vec<config::MiddlewareConfig>,
+    root: &config::Config,
+    mut client: BoxedMiddleware,
+) -> Result<BoxedMiddleware, Error> {
+    let exemptions = &root.exemptions;
+    for middleware_config in middlewares.into_iter().rev() {
+        let exempt_next = if !exemptions.is_empty() && middleware_config.may_drop_metrics() {
+            let next = Shared::new(client);
+            client = Box::new(next.clone());
+            Some(next)
+        } else {
+            None
+        };
 
-    let mut client: Box<dyn middleware::Middleware> = Box::new(Upstream::new(args.upstream)?);
-    for middleware_config in config.middlewares.into_iter().rev() {
         match middleware_config {
             config::MiddlewareConfig::AllowTag(config) => {
                 client = Box::new(middleware::allow_tag::AllowTag::new(config, client));
             }
             config::MiddlewareConfig::DenyTag(config) => {
-                client = Box::new(middleware::deny_tag::DenyTag::new(config, client));
+                client = Box::new(middleware::deny_tag::DenyTag::new(config, client)?);
+            }
+            config::MiddlewareConfig::DenyType(config) => {
+                client = Box::new(middleware::filter_type::FilterType::new(
+                    config, false, client,
+                ));
+            }
+            config::MiddlewareConfig::AllowType(config) => {
+                client = Box::new(middleware::filter_type::FilterType::new(
+                    config, true, client,
+                ));
+            }
+            config::MiddlewareConfig::DedupeGauges(config) => {
+                client = Box::new(middleware::dedupe_gauges::DedupeGauges::new(config, client));
+            }
+            config::MiddlewareConfig::RateLimit(config) => {
+                client = Box::new(middleware::rate_limit::RateLimit::new(config, client)?);
+            }
+            config::MiddlewareConfig::ValidateValues(config) => {
+                client = Box::new(middleware::validate_values::ValidateValues::new(
+                    config, client,
+                ));
+            }
+            config::MiddlewareConfig::Truncate(config) => {
+                client = Box::new(middleware::truncate::Truncate::new(config, client));
+            }
+            config::MiddlewareConfig::Sanitize(config) => {
+                client = Box::new(middleware::sanitize::Sanitize::new(config, client));
+            }
+            config::MiddlewareConfig::ScrubTags(config) => {
+                client = Box::new(middleware::scrub_tags::ScrubTags::new(config, client)?);
+            }
+            config::MiddlewareConfig::NameToTags(config) => {
+                client = Box::new(middleware::name_to_tags::NameToTags::new(config, client));
+            }
+            config::MiddlewareConfig::RewriteName(config) => {
+                client = Box::new(middleware::rewrite_name::RewriteName::new(config, client)?);
+            }
+            config::MiddlewareConfig::DenyMetric(config) => {
+                client = Box::new(middleware::deny_metric::DenyMetric::new(config, client)?);
+            }
+            config::MiddlewareConfig::AllowMetric(config) => {
+                client = Box::new(middleware::allow_metric::AllowMetric::new(config, client)?);
             }
             config::MiddlewareConfig::CardinalityLimit(config) => {
                 client = Box::new(middleware::cardinality_limit::CardinalityLimit::new(
@@ -64,15 +149,801 @@ fn main() -> Result<(), Error> {
                 ))
             }
             config::MiddlewareConfig::Sample(config) => {
-                client = Box::new(middleware::sample::Sample::new(config, client))
+                client = Box::new(middleware::sample::Sample::new(config, client)?)
+            }
+            config::MiddlewareConfig::NormalizeSampleRate(config) => {
+                client = Box::new(middleware::normalize_sample_rate::NormalizeSampleRate::new(
+                    config, client,
+                ))
+            }
+            config::MiddlewareConfig::Exec(config) => {
+                client = Box::new(middleware::exec::Exec::new(config, client))
+            }
+            config::MiddlewareConfig::Schedule(mut config) => {
+                let next = Shared::new(client);
+                let inner = build_middlewares(
+                    std::mem::take(&mut config.middlewares),
+                    root,
+                    Box::new(next.clone()),
+                )?;
+                client = Box::new(middleware::schedule::Schedule::new(config, inner, next)?)
+            }
+            config::MiddlewareConfig::Route(config) => {
+                let next = Shared::new(client);
+                let routes = config
+                    .routes
+                    .into_iter()
+                    .map(|mut route| {
+                        let middlewares = std::mem::take(&mut route.middlewares);
+                        let tail = Box::new(next.submit_only());
+                        let chain = build_middlewares(middlewares, root, tail)?;
+                        Ok((route, chain))
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                let default = build_middlewares(config.default, root, Box::new(next))?;
+                client = Box::new(middleware::route_rules::RouteRules::new(routes, default)?)
+            }
+            config::MiddlewareConfig::Mirror(config) => {
+                for branch in config.branches.into_iter().rev() {
+                    let upstream: BoxedMiddleware = build_upstream(root, &branch.upstream)?;
+                    let branch = build_middlewares(branch.middlewares, root, upstream)?;
+                    client = Box::new(Mirror::new(branch, client));
+                }
+            }
+            config::MiddlewareConfig::Usage(config) => {
+                client = Box::new(middleware::usage::UsageAccounting::new(config, client))
+            }
+            config::MiddlewareConfig::MaxTags(config) => {
+                client = Box::new(middleware::max_tags::MaxTags::new(config, client))
+            }
+            config::MiddlewareConfig::DuplicateTags(config) => {
+                client = Box::new(middleware::duplicate_tags::DuplicateTags::new(
+                    config, client,
+                ))
+            }
+            config::MiddlewareConfig::DowngradeDogstatsd(config) => {
+                client = Box::new(middleware::downgrade_dogstatsd::DowngradeDogstatsd::new(
+                    config, client,
+                ))
+            }
+            config::MiddlewareConfig::CleanTags(config) => {
+                client = Box::new(middleware::clean_tags::CleanTags::new(config, client))
+            }
+            config::MiddlewareConfig::AddTimestamp(config) => {
+                client = Box::new(middleware::add_timestamp::AddTimestamp::new(config, client))
+            }
+            config::MiddlewareConfig::DeriveRate(config) => {
+                client = Box::new(middleware::derive_rate::DeriveRate::new(config, client))
+            }
+            config::MiddlewareConfig::CumulativeCounters(config) => {
+                client = Box::new(middleware::cumulative_counters::CumulativeCounters::new(
+                    config, client,
+                ))
+            }
+            config::MiddlewareConfig::Catalog(config) => {
+                client = Box::new(middleware::catalog::Catalog::new(config, client))
+            }
+            config::MiddlewareConfig::ByteBudget(mut config) => {
+                let next = Shared::new(client);
+                let over_budget = config
+                    .over_budget
+                    .take()
+                    .map(|middlewares| build_middlewares(middlewares, root, Box::new(next.clone())))
+                    .transpose()?;
+                client = Box::new(middleware::byte_budget::ByteBudget::new(
+                    config,
+                    over_budget,
+                    next,
+                ))
+            }
+            // `MiddlewareConfig` is non-exhaustive outside of the library.
+            other => {
+                return Err(anyhow::anyhow!(
+                    "middleware {} is not supported by this binary",
+                    other.name()
+                ))
+            }
+        }
+
+        if let Some(next) = exempt_next {
+            client = Box::new(middleware::exempt::Exempt::new(
+                exemptions.clone(),
+                client,
+                next,
+            ));
+        }
+    }
+    Ok(client)
+}
+
+/// Build the middlewares like `build_middlewares`, but only observing what each of them would
+/// change, see `Observe`. All metrics reach `client` unchanged.
+fn build_observed_middlewares(
+    middlewares: Vec<config::MiddlewareConfig>,
+    root: &config::Config,
+    mut client: BoxedMiddleware,
+) -> Result<BoxedMiddleware, Error> {
+    for (position, middleware_config) in middlewares.into_iter().enumerate().rev() {
+        let name = middleware_config.name();
+        let collected = Shared::new(Collector::default());
+        let inner = build_middlewares(vec![middleware_config], root, Box::new(collected.clone()))?;
+        client = Box::new(Observe::new(position, name, inner, collected, client));
+    }
+    Ok(client)
+}
+
+/// Build the middlewares in `config` forwarding to `client`, and the candidate middlewares in
+/// `compare` next to them, see `Compare`.
+fn build_compared_middlewares(
+    config: &config::Config,
+    compare: &config::CompareConfig,
+    client: BoxedMiddleware,
+) -> Result<BoxedMiddleware, Error> {
+    let current_emitted = Shared::new(Emitted::new(compare.max_series));
+    let candidate_emitted = Shared::new(Emitted::new(compare.max_series));
+    let current = build_middlewares(
+        config.middlewares.clone(),
+        config,
+        Box::new(Mirror::new(current_emitted.clone(), client)),
+    )?;
+    let candidate = build_middlewares(
+        compare.middlewares.clone(),
+        config,
+        Box::new(candidate_emitted.clone()),
+    )?;
+    Ok(Box::new(Compare::new(
+        compare,
+        current,
+        current_emitted,
+        candidate,
+        candidate_emitted,
+    )))
+}
+
+/// Forward metrics owned by other instances in `cluster` to them before `client`, see
+/// `PeerForward`.
+fn build_peer_forward(
+    cluster: &config::ClusterConfig,
+    client: BoxedMiddleware,
+) -> Result<PeerForward<Upstream, BoxedMiddleware>, Error> {
+    let local = cluster
+        .peers
+        .iter()
+        .position(|peer| *peer == cluster.local_peer)
+        .ok_or_else(|| anyhow::anyhow!("cluster.local_peer must be one of cluster.peers"))?;
+    let peers = cluster
+        .peers
+        .iter()
+        .map(|peer| {
+            let upstream = Upstream::new(peer.as_str())?
+                .with_format(config::UpstreamFormat::Batch)
+                .with_max_datagram_size(
+                    cluster.max_datagram_size,
+                    config::OversizedDatagramPolicy::Send,
+                    &[],
+                )
+                .with_max_delay(Duration::from_millis(cluster.max_delay_ms));
+            Ok((peer.clone(), upstream))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(PeerForward::new(peers, local, client))
+}
+
+fn load_config(config_path: Option<&str>) -> Result<config::Config, Error> {
+    let mut config = config_path
+        .map(config::Config::new)
+        .transpose()?
+        .unwrap_or_default();
+
+    if config.relay.is_some() && config.server.self_metrics.is_none() {
+        // A relay should always report on itself, e.g. on metrics that failed to send.
+        config.server.self_metrics = Some(config::SelfMetricsConfig::default());
+    }
+    Ok(config)
+}
+
+fn build_tcp_upstream(config: &config::Config, upstream: &str) -> Result<TcpUpstream, Error> {
+    if config.upstream.bind.is_some() {
+        return Err(anyhow::anyhow!(
+            "upstream.bind is not supported with a TCP upstream"
+        ));
+    }
+    if config.upstream.format != config::UpstreamFormat::Text {
+        return Err(anyhow::anyhow!(
+            "a TCP upstream only supports the text format"
+        ));
+    }
+    if config.upstream.circuit_breaker.is_some() {
+        return Err(anyhow::anyhow!(
+            "upstream.circuit_breaker is not supported with a TCP upstream"
+        ));
+    }
+    if config.upstream.rate_limit.is_some() {
+        return Err(anyhow::anyhow!(
+            "upstream.rate_limit is not supported with a TCP upstream"
+        ));
+    }
+    if config.upstream.send_batch_size > 1 {
+        return Err(anyhow::anyhow!(
+            "upstream.send_batch_size is not supported with a TCP upstream"
+        ));
+    }
+    let mut tcp_upstream = TcpUpstream::new(upstream, config.upstream.max_buffered_lines)?;
+    if let Some(interval) = config.upstream.resolve_interval {
+        tcp_upstream = tcp_upstream.with_resolve_interval(upstream, Duration::from_secs(interval));
+    }
+    if let Some(spool) = &config.upstream.spool {
+        tcp_upstream = tcp_upstream.with_spool(spool);
+    }
+    Ok(tcp_upstream)
+}
+
+/// Build the upstream for `tls:host:port`.
+fn build_tls_upstream(config: &config::Config, upstream: &str) -> Result<TcpUpstream, Error> {
+    let Some(tls) = &config.upstream.tls else {
+        return Err(anyhow::anyhow!(
+            "upstream.tls is required to send to {} over TLS",
+            upstream
+        ));
+    };
+    #[cfg(feature = "tls")]
+    {
+        build_tcp_upstream(config, upstream)?.with_tls(tls, host(upstream))
+    }
+    #[cfg(not(feature = "tls"))]
+    {
+        let _ = tls;
+        Err(anyhow::anyhow!(
+            "cannot send to {} over TLS: statsdproxy was built without the tls feature",
+            upstream
+        ))
+    }
+}
+
+/// Build the upstream for `quic:host:port`.
+fn build_quic_upstream(
+    config: &config::Config,
+    upstream: &str,
+) -> Result<Box<dyn SendErrors + Send>, Error> {
+    let Some(quic) = &config.upstream.quic else {
+        return Err(anyhow::anyhow!(
+            "upstream.quic is required to send to {} over QUIC",
+            upstream
+        ));
+    };
+    let unsupported = [
+        ("upstream.bind", config.upstream.bind.is_some()),
+        (
+            "upstream.resolve_interval",
+            config.upstream.resolve_interval.is_some(),
+        ),
+        ("upstream.spool", config.upstream.spool.is_some()),
+        (
+            "upstream.circuit_breaker",
+            config.upstream.circuit_breaker.is_some(),
+        ),
+        ("upstream.rate_limit", config.upstream.rate_limit.is_some()),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(anyhow::anyhow!(
+            "{} is not supported with a QUIC upstream",
+            name
+        ));
+    }
+    if config.upstream.format != config::UpstreamFormat::Text {
+        return Err(anyhow::anyhow!(
+            "a QUIC upstream only supports the text format"
+        ));
+    }
+    #[cfg(feature = "quic")]
+    {
+        use statsdproxy::middleware::quic::QuicUpstream;
+        Ok(Box::new(QuicUpstream::new(
+            upstream,
+            host(upstream),
+            quic,
+            config.upstream.max_buffered_lines,
+        )?))
+    }
+    #[cfg(not(feature = "quic"))]
+    {
+        let _ = quic;
+        Err(anyhow::anyhow!(
+            "cannot send to {} over QUIC: statsdproxy was built without the quic feature",
+            upstream
+        ))
+    }
+}
+
+/// The host in `host:port`, without the brackets around IPv6 addresses.
+#[cfg(any(feature = "tls", feature = "quic"))]
+fn host(upstream: &str) -> &str {
+    upstream
+        .rsplit_once(':')
+        .map_or(upstream, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+}
+
+fn build_udp_upstream(config: &config::Config, upstream: &str) -> Result<Upstream, Error> {
+    #[cfg(not(feature = "zstd"))]
+    if config.upstream.format == config::UpstreamFormat::CompressedBatch {
+        return Err(anyhow::anyhow!(
+            "cannot send compressed batches: statsdproxy was built without the zstd feature"
+        ));
+    }
+    let mut udp_upstream = match &config.upstream.bind {
+        Some(bind) => Upstream::with_bind_address(upstream, bind.as_str())?,
+        None => Upstream::new(upstream)?,
+    };
+    if let Some(interval) = config.upstream.resolve_interval {
+        udp_upstream = udp_upstream.with_resolve_interval(upstream, Duration::from_secs(interval));
+    }
+    udp_upstream = udp_upstream
+        .with_format(config.upstream.format)
+        .with_max_datagram_size(
+            config.upstream.max_datagram_size,
+            config.upstream.oversized_metric_policy,
+            &config.upstream.tag_priority,
+        );
+    if config.upstream.discover_mtu {
+        udp_upstream = udp_upstream.with_mtu_discovery();
+    }
+    if let Some(circuit_breaker) = &config.upstream.circuit_breaker {
+        udp_upstream = udp_upstream.with_circuit_breaker(circuit_breaker);
+    }
+    if config.upstream.send_batch_size > 1 {
+        udp_upstream = udp_upstream.with_send_batch_size(config.upstream.send_batch_size);
+    }
+    if let Some(rate_limit) = &config.upstream.rate_limit {
+        udp_upstream = udp_upstream.with_rate_limit(rate_limit);
+    }
+    if let Some(spool) = &config.upstream.spool {
+        udp_upstream = udp_upstream.with_spool(spool);
+    }
+    Ok(udp_upstream)
+}
+
+/// Build the output for `stdout://`, `stderr://` or `suggest://`, if `upstream` is one of them.
+fn build_print(config: &config::Config, upstream: &str) -> Option<Box<dyn SendErrors + Send>> {
+    match upstream {
+        "stdout://" => Some(Box::new(Print::new(
+            &config.upstream.print,
+            std::io::stdout(),
+        ))),
+        "stderr://" => Some(Box::new(Print::new(
+            &config.upstream.print,
+            std::io::stderr(),
+        ))),
+        "suggest://" => Some(Box::new(Suggest::new(std::io::stdout()))),
+        _ => None,
+    }
+}
+
+/// Build the upstream for `remote-write:<url>`.
+fn build_remote_write(
+    config: &config::Config,
+    url: &str,
+) -> Result<Box<dyn SendErrors + Send>, Error> {
+    #[cfg(feature = "http")]
+    {
+        use statsdproxy::middleware::remote_write::RemoteWrite;
+        Ok(Box::new(RemoteWrite::new(
+            url,
+            &config.upstream.remote_write,
+        )?))
+    }
+    #[cfg(not(feature = "http"))]
+    {
+        let _ = config;
+        Err(anyhow::anyhow!(
+            "cannot send to {}: statsdproxy was built without the http feature",
+            url
+        ))
+    }
+}
+
+/// Build the upstream for `otlp:<url>`.
+fn build_otlp_export(
+    config: &config::Config,
+    url: &str,
+) -> Result<Box<dyn SendErrors + Send>, Error> {
+    #[cfg(feature = "otlp")]
+    {
+        use statsdproxy::middleware::otlp_export::OtlpExport;
+        Ok(Box::new(OtlpExport::new(url, &config.upstream.otlp)?))
+    }
+    #[cfg(not(feature = "otlp"))]
+    {
+        let _ = config;
+        Err(anyhow::anyhow!(
+            "cannot send to {}: statsdproxy was built without the otlp feature",
+            url
+        ))
+    }
+}
+
+/// Build the upstream for `upstream`, see `Args::upstream`.
+fn build_upstream(
+    config: &config::Config,
+    upstream: &str,
+) -> Result<Box<dyn SendErrors + Send>, Error> {
+    if let Some(print) = build_print(config, upstream) {
+        return Ok(print);
+    }
+    if let Some(url) = upstream.strip_prefix("remote-write:") {
+        return build_remote_write(config, url);
+    }
+    if let Some(url) = upstream.strip_prefix("otlp:") {
+        return build_otlp_export(config, url);
+    }
+    if let Some(upstream) = upstream.strip_prefix("graphite:") {
+        return Ok(Box::new(Graphite::new(build_tcp_upstream(
+            config, upstream,
+        )?)));
+    }
+    if let Some(upstream) = upstream.strip_prefix("tls:") {
+        return Ok(Box::new(build_tls_upstream(config, upstream)?));
+    }
+    if let Some(upstream) = upstream.strip_prefix("quic:") {
+        return build_quic_upstream(config, upstream);
+    }
+    Ok(match upstream.strip_prefix("tcp:") {
+        Some(upstream) => Box::new(build_tcp_upstream(config, upstream)?),
+        None => Box::new(build_udp_upstream(config, upstream)?),
+    })
+}
+
+/// Forward to `next` through the relay pipeline in `config`, if any.
+fn with_relay<M>(config: &config::Config, next: M) -> BoxedMiddleware
+where
+    M: middleware::Middleware + Send + 'static,
+{
+    match &config.relay {
+        Some(relay_config) => Box::new(RelayPipeline::with_next(relay_config, next)),
+        None => Box::new(next),
+    }
+}
+
+/// Build the middlewares in `config`, forwarding to `upstream`. With worker threads, every worker
+/// gets its own instance of the middlewares.
+fn build_client(config: &config::Config, upstream: &str) -> Result<BoxedMiddleware, Error> {
+    let spool = config
+        .relay
+        .as_ref()
+        .is_some_and(|relay| relay.spool.is_some());
+    if spool && config.upstream.spool.is_some() {
+        return Err(anyhow::anyhow!(
+            "relay.spool and upstream.spool cannot be used together"
+        ));
+    }
+    let build_chain = || -> Result<BoxedMiddleware, Error> {
+        let client: BoxedMiddleware = if let Some(routing) = &config.upstream.routing {
+            if spool || config.upstream.spool.is_some() {
+                return Err(anyhow::anyhow!(
+                    "spooling is not supported with upstream.routing"
+                ));
+            }
+            if config.upstream.failover.is_some() {
+                return Err(anyhow::anyhow!(
+                    "upstream.failover and upstream.routing cannot be used together"
+                ));
+            }
+            let routes = routing
+                .routes
+                .iter()
+                .map(|(value, route)| Ok((value.clone(), build_upstream(config, route)?)))
+                .collect::<Result<Vec<_>, Error>>()?;
+            let route = Route::new(&routing.tag, routes, build_upstream(config, upstream)?);
+            with_relay(config, route)
+        } else if let Some(failover_config) = &config.upstream.failover {
+            if spool || config.upstream.spool.is_some() {
+                return Err(anyhow::anyhow!(
+                    "spooling is not supported with upstream.failover"
+                ));
+            }
+            let failover = Failover::new(
+                failover_config,
+                build_upstream(config, upstream)?,
+                build_upstream(config, &failover_config.secondary)?,
+            )?;
+            with_relay(config, failover)
+        } else if let Some(print) = build_print(config, upstream) {
+            if spool {
+                return Err(anyhow::anyhow!(
+                    "relay.spool is not supported when printing metrics"
+                ));
+            }
+            with_relay(config, print)
+        } else if let Some(url) = upstream.strip_prefix("remote-write:") {
+            if spool || config.upstream.spool.is_some() {
+                return Err(anyhow::anyhow!(
+                    "spooling is not supported with remote write"
+                ));
+            }
+            with_relay(config, build_remote_write(config, url)?)
+        } else if let Some(url) = upstream.strip_prefix("otlp:") {
+            if spool || config.upstream.spool.is_some() {
+                return Err(anyhow::anyhow!("spooling is not supported with OTLP"));
+            }
+            with_relay(config, build_otlp_export(config, url)?)
+        } else if let Some(upstream) = upstream.strip_prefix("tcp:") {
+            if spool {
+                return Err(anyhow::anyhow!(
+                    "relay.spool is not supported with a TCP upstream, use upstream.spool"
+                ));
+            }
+            with_relay(config, build_tcp_upstream(config, upstream)?)
+        } else if let Some(upstream) = upstream.strip_prefix("tls:") {
+            if spool {
+                return Err(anyhow::anyhow!(
+                    "relay.spool is not supported with a TLS upstream, use upstream.spool"
+                ));
             }
+            with_relay(config, build_tls_upstream(config, upstream)?)
+        } else if let Some(upstream) = upstream.strip_prefix("quic:") {
+            if spool {
+                return Err(anyhow::anyhow!(
+                    "relay.spool is not supported with a QUIC upstream"
+                ));
+            }
+            with_relay(config, build_quic_upstream(config, upstream)?)
+        } else if let Some(upstream) = upstream.strip_prefix("graphite:") {
+            if spool {
+                return Err(anyhow::anyhow!(
+                    "relay.spool is not supported with Graphite, use upstream.spool"
+                ));
+            }
+            with_relay(config, Graphite::new(build_tcp_upstream(config, upstream)?))
+        } else {
+            // Only the UDP upstream supports spooling with relay.spool.
+            let upstream = build_udp_upstream(config, upstream)?;
+            match &config.relay {
+                Some(relay_config) => Box::new(RelayPipeline::from_config(relay_config, upstream)),
+                None => Box::new(upstream),
+            }
+        };
+        let client: BoxedMiddleware = match &config.upstream.scheduler {
+            Some(scheduler) => {
+                // Every worker paces its share of the metrics.
+                let workers = config.server.worker_threads.max(1) as u64;
+                let scheduler = config::OutputSchedulerConfig {
+                    max_metrics_per_second: scheduler
+                        .max_metrics_per_second
+                        .map(|rate| rate.div_ceil(workers)),
+                    ..scheduler.clone()
+                };
+                Box::new(OutputScheduler::new(&scheduler, client))
+            }
+            None => client,
+        };
+        let mut client: BoxedMiddleware = match &config.upstream.file_output {
+            // Written first, since the upstream may change metrics, e.g. truncate their tags.
+            Some(file_output) => Box::new(Mirror::new(FileOutput::new(file_output)?, client)),
+            None => client,
+        };
+        let events: Option<BoxedMiddleware> = match config.events.action {
+            config::EventAction::Pass => {
+                // The chain drives the upstream already, so the events only submit to it.
+                let next = Shared::new(client);
+                let events = next.submit_only();
+                client = Box::new(next);
+                Some(Box::new(events))
+            }
+            config::EventAction::Drop => None,
+            config::EventAction::Upstream => {
+                let upstream = config.events.upstream.as_deref().unwrap_or_default();
+                Some(build_upstream(config, upstream)?)
+            }
+        };
+        let client = if config.observe_only {
+            build_observed_middlewares(config.middlewares.clone(), config, client)?
+        } else if let Some(compare) = &config.compare {
+            build_compared_middlewares(config, compare, client)?
+        } else {
+            build_middlewares(config.middlewares.clone(), config, client)?
+        };
+        let client: BoxedMiddleware = match &config.cluster {
+            Some(cluster) => Box::new(build_peer_forward(cluster, client)?),
+            None => client,
+        };
+        Ok(Box::new(Events::new(client, events)))
+    };
+
+    if config.server.worker_threads == 0 {
+        return build_chain();
+    }
+    let chains = (0..config.server.worker_threads)
+        .map(|_| build_chain())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Box::new(Sharded::new(
+        chains,
+        config.server.worker_queue_size,
+        config.server.overload_policy,
+    )))
+}
+
+/// Bind the source of metrics given by `listen`, see `Args::listen`.
+fn bind_ingest(listen: &str, config: &config::ServerConfig) -> Result<Box<dyn Ingest>, Error> {
+    let udp = !(listen == "-" || listen.starts_with("tcp:") || listen.starts_with("unix:"));
+    if !udp && config.receiver_threads > 1 {
+        return Err(anyhow::anyhow!(
+            "cannot receive on {} with more than one receiver thread",
+            listen
+        ));
+    }
+
+    if listen == "-" {
+        return Ok(Box::new(ingest::StdinIngest::new()));
+    }
+    if let Some(listen) = listen.strip_prefix("tcp:") {
+        return Ok(Box::new(ingest::TcpIngest::bind(listen, config)?));
+    }
+    if let Some(path) = listen.strip_prefix("unix:") {
+        #[cfg(unix)]
+        return Ok(Box::new(ingest::UnixIngest::bind(path)?));
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "cannot listen on {}: Unix sockets are not supported on this platform",
+            path
+        ));
+    }
+    Ok(Box::new(ingest::UdpIngest::bind(listen, config)?))
+}
+
+/// Build a server receiving metrics on `listen`, which rebuilds its middlewares whenever a new
+/// config is stored in `config`.
+fn build_server(
+    listen: &str,
+    args: &Args,
+    config: &Arc<ArcSwap<config::Config>>,
+    server_config: config::ServerConfig,
+) -> Result<Server<BoxedMiddleware>, Error> {
+    let client = build_client(&config.load(), &args.upstream)?;
+    let upstream = args.upstream.clone();
+    let ingest = bind_ingest(listen, &server_config)?;
+    Ok(
+        Server::from_ingest(ingest, server_config, client)?.with_canary_snapshot(
+            Arc::clone(config),
+            move |config| build_client(config, &upstream),
+            |config| config.canary.clone(),
+        ),
+    )
+}
+
+/// Load the config again and store it in `config`, so that all servers rebuild their middlewares
+/// from it. If loading fails, the current config is kept. `initial_config` is the config the
+/// servers were started with.
+fn reload_config(
+    config_path: Option<&str>,
+    initial_config: &config::Config,
+    config: &ArcSwap<config::Config>,
+) {
+    log::info!("reloading {}", config_path.unwrap_or("(no config)"));
+    let new_config = match load_config(config_path) {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            log::error!("failed to reload, keeping the current middlewares: {}", e);
+            return;
         }
+    };
+    if new_config.server != initial_config.server {
+        log::warn!("changes to the server settings are only applied after a restart");
     }
+    config.store(Arc::new(new_config));
+}
 
-    let server = Server::new(args.listen.clone(), client)?;
-    log::info!("Listening on {}", args.listen);
+fn main() -> Result<(), Error> {
+    env_logger::init();
 
-    server.run()?;
+    let args = Args::parse();
+
+    if args.config_path.is_none() {
+        log::warn!("No config file specified. No middlewares will be used.");
+    }
+
+    let config = load_config(args.config_path.as_deref())?;
+    // Loaded once on SIGHUP and shared by all receiver threads, so that they all switch to the
+    // same config.
+    let config_snapshot = Arc::new(ArcSwap::from_pointee(config.clone()));
 
+    // Shared by all receiver threads, since it reports on all of them.
+    if let Some(admin_listen) = &config.server.admin_listen {
+        #[cfg(feature = "http")]
+        statsdproxy::middleware::admin::AdminListener::bind(admin_listen)?.spawn();
+        #[cfg(not(feature = "http"))]
+        return Err(anyhow::anyhow!(
+            "cannot listen on {}: statsdproxy was built without the http feature",
+            admin_listen
+        ));
+    }
+
+    if let Some(startup) = &config.server.startup {
+        let routes = config
+            .upstream
+            .routing
+            .iter()
+            .flat_map(|x| x.routes.values());
+        let upstreams: Vec<_> = std::iter::once(&args.upstream)
+            .chain(routes)
+            .filter_map(|upstream| {
+                ["tcp:", "tls:", "graphite:"]
+                    .iter()
+                    .find_map(|prefix| upstream.strip_prefix(prefix))
+            })
+            .collect();
+        statsdproxy::startup::wait_for(startup, &upstreams)?;
+    }
+
+    // Bind all sockets upfront so that configuration errors surface before any thread starts.
+    let mut servers = Vec::new();
+    for (i, listen) in args.listen.iter().enumerate() {
+        let mut listen_config = config.server.clone();
+        if i > 0 {
+            listen_config.tcp_listen = None;
+            listen_config.tls = None;
+            listen_config.quic = None;
+            listen_config.http_listen = None;
+        }
+        for _ in 0..config.server.receiver_threads.max(1) {
+            servers.push(build_server(
+                listen,
+                &args,
+                &config_snapshot,
+                listen_config.clone(),
+            )?);
+        }
+    }
+    log::info!(
+        "Listening on {} with {} receiver thread(s)",
+        args.listen.join(", "),
+        servers.len()
+    );
+
+    let (result_tx, result_rx) = mpsc::channel();
+    for server in servers {
+        let result_tx = result_tx.clone();
+        thread::spawn(move || result_tx.send(server.run()));
+    }
+    drop(result_tx);
+
+    let reload = Arc::new(AtomicBool::new(false));
+    #[cfg(not(windows))] // No SIGHUP on windows.
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload))?;
+    loop {
+        match result_rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(result) => result?,
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if reload.swap(false, Ordering::Relaxed) {
+            reload_config(args.config_path.as_deref(), &config, &config_snapshot);
+        }
+    }
+
+    for entry in middleware::observe::report() {
+        log::info!(
+            "observed middlewares[{}] ({}): would have {} {} metrics, e.g. {}",
+            entry.position,
+            entry.middleware,
+            entry.change,
+            entry.count,
+            entry.example
+        );
+    }
+    if config.compare.is_some() {
+        let report = middleware::compare::report();
+        log::info!(
+            "compared series: {} matched, {} missing, {} extra, {} different, {} untracked",
+            report.matched,
+            report.missing,
+            report.extra,
+            report.different,
+            report.untracked
+        );
+        for example in report.examples {
+            log::info!("compared series {}", example);
+        }
+    }
     Ok(())
 }
@@ -27,19 +27,7 @@ where
     }
 
     fn submit(&mut self, metric: &mut Metric) {
-        match metric.tags() {
-            Some(tags) => {
-                let mut tag_buffer: Vec<u8> = Vec::new();
-                tag_buffer.extend(tags);
-                tag_buffer.extend(",".as_bytes());
-                tag_buffer.extend(&self.tags);
-                metric.set_tags(&tag_buffer);
-            }
-            None => {
-                metric.set_tags(&self.tags);
-            }
-        }
-
+        metric.append_tags(&self.tags);
         self.next.submit(metric)
     }
 
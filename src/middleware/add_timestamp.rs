@@ -0,0 +1,136 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::AddTimestampConfig;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+use anyhow::Error;
+
+/// Appends the time of ingestion as a `|T<unix timestamp>` section to metrics without one, so that
+/// the final receiver attributes them to the interval they were received in, rather than the one
+/// they arrived in after being forwarded.
+pub struct AddTimestamp<M> {
+    types: Vec<Vec<u8>>,
+    // The timestamp section for the current second, rebuilt once per second.
+    section: Vec<u8>,
+    section_secs: u64,
+    next: M,
+}
+
+impl<M> AddTimestamp<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: AddTimestampConfig, next: M) -> Self {
+        Self {
+            types: config.types.into_iter().map(String::into_bytes).collect(),
+            section: Vec::new(),
+            section_secs: 0,
+            next,
+        }
+    }
+
+    fn needs_timestamp(&self, metric: &Metric) -> bool {
+        let Some(ty) = metric.ty() else {
+            return false;
+        };
+        if !self.types.iter().any(|x| x == ty) {
+            return false;
+        }
+        !metric
+            .raw
+            .split(|&x| x == b'|')
+            .skip(2)
+            .any(|section| section.starts_with(b"T"))
+    }
+
+    fn update_section(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if now != self.section_secs {
+            self.section_secs = now;
+            self.section = format!("|T{}", now).into_bytes();
+        }
+    }
+}
+
+impl<M> Middleware for AddTimestamp<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.needs_timestamp(metric) {
+            self.update_section();
+            // Appending keeps the position of the tags valid.
+            metric.raw.extend(&self.section);
+        }
+        self.next.submit(metric)
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = AddTimestampConfig {
+            types: vec!["c".to_string(), "g".to_string()],
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut middleware = AddTimestamp::new(config, next);
+
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        for raw in [
+            &b"users.online:1|c|#country:china"[..],
+            b"users.online:1|g",
+            b"users.online:1|c|T1692653389",
+            b"request.duration:1|ms",
+            b"not a metric",
+        ] {
+            middleware.submit(&mut Metric::new(raw.to_vec()));
+        }
+        let after = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let results = results.borrow();
+        let timestamp = |metric: &Metric| -> u64 {
+            let raw = std::str::from_utf8(&metric.raw).unwrap();
+            raw.rsplit_once("|T").unwrap().1.parse().unwrap()
+        };
+        assert!((before..=after).contains(&timestamp(&results[0])));
+        assert!(results[0]
+            .raw
+            .starts_with(b"users.online:1|c|#country:china|T"));
+        assert_eq!(results[0].tags(), Some(&b"country:china"[..]));
+        assert!(results[1].raw.starts_with(b"users.online:1|g|T"));
+        assert_eq!(
+            results[2..],
+            [
+                Metric::new(b"users.online:1|c|T1692653389".to_vec()),
+                Metric::new(b"request.duration:1|ms".to_vec()),
+                Metric::new(b"not a metric".to_vec()),
+            ]
+        );
+    }
+}
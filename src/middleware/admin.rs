@@ -0,0 +1,308 @@
+//! A small HTTP server for inspecting the state of statsdproxy while debugging.
+//!
+//! `GET /aggregation` lists the metrics currently held by `aggregate-metrics` and relay mode, one
+//! per line as they would be flushed right now, followed by the age of the bucket in seconds.
+//! `?prefix=` restricts the output to metric names starting with a prefix, and `?limit=` caps
+//! the number of lines (default 1000).
+//!
+//! `GET /healthz` and `GET /readyz` are liveness and readiness probes. They respond with 200 if all
+//! checks pass and 503 otherwise, listing every check and whether it passed.
+//!
+//! `GET /drops` lists the number of metrics dropped since startup, one line per reason and metric
+//! name prefix.
+//!
+//! `GET /compliance` lists the number of metrics violating the DogStatsD format since startup, one
+//! line per client address and violation, followed by the last such metric.
+//!
+//! `GET /catalog` lists every kind of metric seen by `catalog` middlewares, one line per name,
+//! type and set of tag names, followed by the number of such metrics and when one was seen last.
+//! `?format=json` returns the same as a JSON array instead.
+//!
+//! `GET /limits` lists which metrics made limits trip: for every cardinality, tag cardinality and
+//! packet rate limit that rejected metrics, the metric names and tag keys with the most rejected
+//! metrics in the limit's current window, one line each, followed by the number of sampled
+//! rejections. Only a sample of rejections is counted.
+//!
+//! `GET /observe` lists what the middlewares would have changed since startup with `observe_only`,
+//! one line per middleware and kind of change, followed by the number of metrics and the last
+//! such change.
+//!
+//! `GET /compare` lists how many series the candidate middlewares of `compare` emitted the same
+//! as the middlewares, missed, added or emitted differently since startup, followed by the last
+//! differences.
+//!
+//! `GET /stats` lists settings chosen at runtime, currently the maximum datagram size for each UDP
+//! upstream address.
+
+use std::fmt::Write;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, Error};
+use tiny_http::{Method, Request, Response};
+
+use crate::compliance;
+use crate::contributors;
+use crate::drops;
+use crate::health::{self, Check};
+use crate::middleware::{aggregate, catalog, compare, observe, upstream};
+
+const DEFAULT_LIMIT: usize = 1000;
+
+pub struct AdminListener {
+    server: tiny_http::Server,
+}
+
+impl AdminListener {
+    pub fn bind(listen: &str) -> Result<Self, Error> {
+        let server = tiny_http::Server::http(listen)
+            .map_err(|e| anyhow!("failed to listen on {}: {}", listen, e))?;
+        Ok(AdminListener { server })
+    }
+
+    /// Handle requests in a background thread for the lifetime of the process.
+    pub fn spawn(self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            for request in self.server.incoming_requests() {
+                handle_request(request);
+            }
+        })
+    }
+}
+
+fn handle_request(request: Request) {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let response = if *request.method() != Method::Get {
+        Response::from_string("").with_status_code(405)
+    } else {
+        match path {
+            "/aggregation" => match parse_query(query) {
+                Ok((prefix, limit)) => Response::from_string(render_aggregation(&prefix, limit)),
+                Err(e) => Response::from_string(e.to_string()).with_status_code(400),
+            },
+            "/drops" => Response::from_string(render_drops()),
+            "/compliance" => Response::from_string(render_compliance()),
+            "/catalog" => match query {
+                "" | "format=text" => Response::from_string(render_catalog()),
+                "format=json" => Response::from_string(render_catalog_json()).with_header(
+                    "Content-Type: application/json"
+                        .parse::<tiny_http::Header>()
+                        .unwrap(),
+                ),
+                _ => {
+                    Response::from_string(format!("unknown query: {}", query)).with_status_code(400)
+                }
+            },
+            "/limits" => Response::from_string(render_limits()),
+            "/observe" => Response::from_string(render_observe()),
+            "/compare" => Response::from_string(render_compare()),
+            "/stats" => Response::from_string(render_stats()),
+            "/healthz" => render_checks(health::liveness()),
+            "/readyz" => render_checks(health::readiness()),
+            _ => Response::from_string("").with_status_code(404),
+        }
+    };
+
+    if let Err(e) = request.respond(response) {
+        log::debug!("failed to respond to admin request: {}", e);
+    }
+}
+
+fn parse_query(query: &str) -> Result<(Vec<u8>, usize), Error> {
+    let mut prefix = Vec::new();
+    let mut limit = DEFAULT_LIMIT;
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "prefix" => prefix = percent_decode(value)?,
+            "limit" => {
+                limit = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid limit: {}", value))?
+            }
+            _ => return Err(anyhow!("unknown query parameter: {}", key)),
+        }
+    }
+    Ok((prefix, limit))
+}
+
+fn percent_decode(value: &str) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::new();
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next(), bytes.next()];
+                let hex = match hex {
+                    [Some(a), Some(b)] => std::str::from_utf8(&[a, b])
+                        .ok()
+                        .and_then(|x| u8::from_str_radix(x, 16).ok()),
+                    _ => None,
+                };
+                decoded.push(hex.ok_or_else(|| anyhow!("invalid percent-encoding: {}", value))?);
+            }
+            b'+' => decoded.push(b' '),
+            _ => decoded.push(byte),
+        }
+    }
+    Ok(decoded)
+}
+
+fn render_aggregation(prefix: &[u8], limit: usize) -> String {
+    let mut output = String::new();
+    for bucket in aggregate::snapshot(prefix, limit) {
+        writeln!(
+            output,
+            "{} {:.3}",
+            String::from_utf8_lossy(&bucket.metric.raw),
+            bucket.age.as_secs_f64()
+        )
+        .unwrap();
+    }
+    output
+}
+
+fn render_checks(checks: Vec<Check>) -> Response<std::io::Cursor<Vec<u8>>> {
+    let mut output = String::new();
+    for check in &checks {
+        let status = if check.ok { "ok" } else { "failing" };
+        writeln!(output, "{} {}", check.name, status).unwrap();
+    }
+    let status = if checks.iter().all(|x| x.ok) {
+        200
+    } else {
+        503
+    };
+    Response::from_string(output).with_status_code(status)
+}
+
+fn render_drops() -> String {
+    let mut output = String::new();
+    for (reason, prefix, count) in drops::totals() {
+        writeln!(output, "{} {} {}", reason, prefix, count).unwrap();
+    }
+    output
+}
+
+fn render_compliance() -> String {
+    let mut output = String::new();
+    for entry in compliance::report() {
+        writeln!(
+            output,
+            "{} {} {} {}",
+            entry.source,
+            entry.violation,
+            entry.count,
+            String::from_utf8_lossy(&entry.example)
+        )
+        .unwrap();
+    }
+    output
+}
+
+fn render_catalog() -> String {
+    let mut output = String::new();
+    for entry in catalog::snapshot() {
+        let tag_keys = if entry.tag_keys.is_empty() {
+            "-".to_string()
+        } else {
+            entry.tag_keys.join(",")
+        };
+        writeln!(
+            output,
+            "{} {} {} {} {}",
+            entry.name, entry.ty, tag_keys, entry.count, entry.last_seen
+        )
+        .unwrap();
+    }
+    output
+}
+
+fn render_catalog_json() -> String {
+    let entries: Vec<_> = catalog::snapshot()
+        .into_iter()
+        .map(|entry| {
+            serde_json::json!({
+                "name": entry.name,
+                "type": entry.ty,
+                "tag_keys": entry.tag_keys,
+                "count": entry.count,
+                "last_seen": entry.last_seen,
+            })
+        })
+        .collect();
+    serde_json::Value::from(entries).to_string()
+}
+
+fn render_limits() -> String {
+    let mut output = String::new();
+    for report in contributors::report() {
+        writeln!(
+            output,
+            "{} window_age={}s sampled={}",
+            report.limit, report.age, report.sampled
+        )
+        .unwrap();
+        for (kind, top) in [("name", &report.names), ("tag_key", &report.tag_keys)] {
+            for (value, count) in top {
+                writeln!(output, "  {} {} {}", kind, value, count).unwrap();
+            }
+        }
+    }
+    output
+}
+
+fn render_observe() -> String {
+    let mut output = String::new();
+    for entry in observe::report() {
+        writeln!(
+            output,
+            "middlewares[{}] {} {} {} {}",
+            entry.position, entry.middleware, entry.change, entry.count, entry.example
+        )
+        .unwrap();
+    }
+    output
+}
+
+fn render_compare() -> String {
+    let report = compare::report();
+    let mut output = String::new();
+    for (outcome, count) in [
+        ("matched", report.matched),
+        ("missing", report.missing),
+        ("extra", report.extra),
+        ("different", report.different),
+        ("untracked", report.untracked),
+    ] {
+        writeln!(output, "{} {}", outcome, count).unwrap();
+    }
+    for example in report.examples {
+        writeln!(output, "{}", example).unwrap();
+    }
+    output
+}
+
+fn render_stats() -> String {
+    let mut output = String::new();
+    for (addr, size) in upstream::datagram_sizes() {
+        writeln!(output, "upstream.max_datagram_size {} {}", addr, size).unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query() {
+        assert_eq!(parse_query("").unwrap(), (vec![], DEFAULT_LIMIT));
+        assert_eq!(
+            parse_query("prefix=users%2Eonline&limit=5").unwrap(),
+            (b"users.online".to_vec(), 5)
+        );
+        assert!(parse_query("limit=-1").is_err());
+        assert!(parse_query("prefix=%zz").is_err());
+        assert!(parse_query("foo=bar").is_err());
+    }
+}
@@ -1,38 +1,96 @@
-#[cfg(test)]
-use std::sync::Mutex;
-
+use std::sync::{Arc, Mutex, Weak};
 use std::{
-    collections::HashMap,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use std::{fmt, str};
 
-use crate::{config::AggregateMetricsConfig, middleware::Middleware, types::Metric};
+use anyhow::Error;
 
-#[derive(Hash, Eq, PartialEq)]
+use crate::{
+    config::{AggregateMetricsConfig, StalenessConfig, StalenessMarker, TimerSummaryConfig},
+    middleware::{scheduler, Middleware},
+    timers::Timer,
+    types::Metric,
+};
+
+/// Everything but the value of a metric, which identifies its bucket. For example,
+/// `users.online:1|c|@0.5|#country:china` is stored as name `users.online`, type `c`, extensions
+/// `[@0.5]` and tags `country:china`. Flushed metrics are built from these parts alone, with the
+/// tags last.
+#[derive(Clone, Hash, Eq, PartialEq)]
 struct BucketKey {
-    // contains the raw metric bytes with the value stripped out
-    // for example, `users.online:1|c|#country:china` would be stored as:
-    //
-    //   metric_bytes: users.online:|c|#country:china
-    //   insert_value_at: 13
-    metric_bytes: Vec<u8>,
-    insert_value_at: usize,
+    name: Vec<u8>,
+    ty: Vec<u8>,
+    // The other `|`-separated sections, like `@0.5` or `T1692653389`, in their original order.
+    extensions: Vec<Vec<u8>>,
+    tags: Option<Vec<u8>>,
+}
+
+impl BucketKey {
+    /// Split a line into its key and its raw value, or None if it is not of the form
+    /// `name:value|type[|section...]` with at most one tags section.
+    fn parse(raw: &[u8]) -> Option<(BucketKey, &[u8])> {
+        let mut sections = raw.split(|&x| x == b'|');
+        let name_and_value = sections.next()?;
+        let separator = name_and_value.iter().position(|&x| x == b':')?;
+        let (name, value) = (
+            &name_and_value[..separator],
+            &name_and_value[separator + 1..],
+        );
+        let ty = sections.next()?;
+        if name.is_empty() || value.is_empty() || ty.is_empty() || ty.starts_with(b"#") {
+            return None;
+        }
+
+        let mut extensions = Vec::new();
+        let mut tags = None;
+        for section in sections {
+            match section.strip_prefix(b"#") {
+                Some(_) if tags.is_some() => return None,
+                Some(section_tags) => tags = Some(section_tags.to_vec()),
+                None => extensions.push(section.to_vec()),
+            }
+        }
+        let key = BucketKey {
+            name: name.to_vec(),
+            ty: ty.to_vec(),
+            extensions,
+            tags,
+        };
+        Some((key, value))
+    }
 }
 
 impl fmt::Debug for BucketKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lossy = |x: &[u8]| String::from_utf8_lossy(x).into_owned();
         f.debug_struct("BucketKey")
-            .field("metric_bytes", &str::from_utf8(&self.metric_bytes))
-            .field("insert_value_at", &self.insert_value_at)
+            .field("name", &lossy(&self.name))
+            .field("ty", &lossy(&self.ty))
+            .field(
+                "extensions",
+                &self.extensions.iter().map(|x| lossy(x)).collect::<Vec<_>>(),
+            )
+            .field("tags", &self.tags.as_deref().map(lossy))
             .finish()
     }
 }
 
+// The maximum length of the packed values of a timer. Once exceeded, the timer is submitted
+// early so that lines stay well below the maximum datagram size.
+const MAX_PACKED_VALUES_LEN: usize = 4096;
+
 #[derive(Debug)]
 enum BucketValue {
     Counter(f64),
     Gauge(f64),
+    // The raw values of a timer, histogram or distribution, separated by `:`.
+    Values(Vec<u8>),
+    // The values of a timer, histogram or distribution, with `timer_summary`.
+    Samples(Vec<f64>),
+    // The distinct values of a set, flushed as their count.
+    Set(HashSet<Vec<u8>>),
 }
 
 impl BucketValue {
@@ -40,6 +98,12 @@ impl BucketValue {
         match (self, other) {
             (BucketValue::Gauge(a), BucketValue::Gauge(b)) => *a = *b,
             (BucketValue::Counter(a), BucketValue::Counter(b)) => *a += *b,
+            (BucketValue::Values(a), BucketValue::Values(b)) => {
+                a.push(b':');
+                a.extend(b);
+            }
+            (BucketValue::Samples(a), BucketValue::Samples(b)) => a.extend(b),
+            (BucketValue::Set(a), BucketValue::Set(b)) => a.extend(b.iter().cloned()),
             // this codepath should never happen because two different bucket values end up in
             // different hashmap keys
             _ => panic!("attempted to merge two unrelated bucket values together"),
@@ -47,10 +111,76 @@ impl BucketValue {
     }
 }
 
+struct Bucket {
+    value: BucketValue,
+    created_at: Instant,
+}
+
+/// The buckets of all metrics flushed at the same interval.
+struct IntervalBuckets {
+    flush_interval: u64,
+    metrics_map: HashMap<BucketKey, Bucket>,
+    // On the monotonic clock, so that changes to the system clock cannot stall or repeat flushes.
+    flush_timer: Timer,
+    // With `staleness`, the counters and gauges flushed before, and how many flushes in a row
+    // they have been missing from since.
+    seen: HashMap<BucketKey, u32>,
+}
+
+type SharedIntervals = Arc<Mutex<Vec<IntervalBuckets>>>;
+
+// The buckets of every `AggregateMetrics`, to inspect them for debugging.
+static INSTANCES: Mutex<Vec<Weak<Mutex<Vec<IntervalBuckets>>>>> = Mutex::new(Vec::new());
+
+/// A metric currently held by an `AggregateMetrics`.
+#[derive(Debug, PartialEq)]
+pub struct BucketSnapshot {
+    /// The metric as it would be flushed right now.
+    pub metric: Metric,
+    /// The time since the first metric was aggregated into this bucket.
+    pub age: Duration,
+}
+
+/// Return up to `limit` metrics held by any `AggregateMetrics`, with names starting with
+/// `prefix`.
+pub fn snapshot(prefix: &[u8], limit: usize) -> Vec<BucketSnapshot> {
+    let mut instances = INSTANCES.lock().unwrap();
+    instances.retain(|instance| instance.strong_count() > 0);
+
+    let now = Instant::now();
+    let mut snapshots = Vec::new();
+    for instance in instances.iter().filter_map(Weak::upgrade) {
+        let intervals = instance.lock().unwrap();
+        for (key, bucket) in intervals.iter().flat_map(|x| &x.metrics_map) {
+            if snapshots.len() >= limit {
+                return snapshots;
+            }
+            if key.name.starts_with(prefix) {
+                snapshots.push(BucketSnapshot {
+                    metric: bucket_metric(key, &bucket.value),
+                    age: now.saturating_duration_since(bucket.created_at),
+                });
+            }
+        }
+    }
+    snapshots
+}
+
+impl IntervalBuckets {
+    fn new(flush_interval: u64) -> Self {
+        IntervalBuckets {
+            flush_interval,
+            metrics_map: HashMap::new(),
+            flush_timer: Timer::new(),
+            seen: HashMap::new(),
+        }
+    }
+}
+
 pub struct AggregateMetrics<M> {
     config: AggregateMetricsConfig,
-    metrics_map: HashMap<BucketKey, BucketValue>,
-    last_flushed_at: u64,
+    // The buckets for the default flush interval, followed by those of every override.
+    intervals: SharedIntervals,
     next: M,
 }
 
@@ -59,20 +189,36 @@ where
     M: Middleware,
 {
     pub fn new(config: AggregateMetricsConfig, next: M) -> Self {
+        let mut intervals = vec![IntervalBuckets::new(config.flush_interval)];
+        for flush_override in &config.overrides {
+            intervals.push(IntervalBuckets::new(flush_override.flush_interval));
+        }
+        let intervals = Arc::new(Mutex::new(intervals));
+        INSTANCES.lock().unwrap().push(Arc::downgrade(&intervals));
         AggregateMetrics {
             config,
-            metrics_map: HashMap::new(),
+            intervals,
             next,
-            last_flushed_at: 0,
         }
     }
 
+    /// The index into `intervals` for a metric, based on the first matching override.
+    fn interval_index(&self, metric: &Metric) -> usize {
+        let Some(name) = metric.name() else {
+            return 0;
+        };
+        self.config
+            .overrides
+            .iter()
+            .position(|x| name.starts_with(x.prefix.as_bytes()))
+            .map_or(0, |i| i + 1)
+    }
+
     fn insert_metric(&mut self, metric: &Metric) -> Result<(), &'static str> {
-        let raw_value = metric
-            .value()
-            .and_then(|x| str::from_utf8(x).ok())
-            .ok_or("failed to parse metric value as utf8")?;
-        let value = match metric.ty().ok_or("failed to parse metric type")? {
+        let (key, raw_value) = BucketKey::parse(&metric.raw).ok_or("failed to parse metric")?;
+        let raw_value =
+            str::from_utf8(raw_value).map_err(|_| "failed to parse metric value as utf8")?;
+        let value = match key.ty.as_slice() {
             b"c" if self.config.aggregate_counters => BucketValue::Counter(
                 raw_value
                     .parse()
@@ -83,77 +229,304 @@ where
                     .parse()
                     .map_err(|_| "failed to parse gauge value")?,
             ),
+            b"ms" | b"h" | b"d" if self.config.aggregate_timers => {
+                match self.config.timer_summary {
+                    Some(_) => BucketValue::Samples(
+                        raw_value
+                            .split(':')
+                            .map(|x| x.parse().ok().filter(|x: &f64| x.is_finite()))
+                            .collect::<Option<_>>()
+                            .ok_or("failed to parse timer value")?,
+                    ),
+                    None => BucketValue::Values(raw_value.as_bytes().to_vec()),
+                }
+            }
+            b"s" if self.config.aggregate_sets => {
+                BucketValue::Set(HashSet::from([raw_value.as_bytes().to_vec()]))
+            }
             _ => return Err("unsupported metric type"),
         };
 
-        let value_start = raw_value.as_ptr() as usize - metric.raw.as_ptr() as usize;
-        let value_end = value_start + raw_value.len();
-        let mut metric_bucket_bytes = metric.raw[..value_start].to_vec();
-        metric_bucket_bytes.extend(&metric.raw[value_end..]);
+        let interval_index = self.interval_index(metric);
+        if let Some(max_map_size) = self.config.max_map_size {
+            let intervals = self.intervals.lock().unwrap();
+            let metrics_map = &intervals[interval_index].metrics_map;
+            if metrics_map.len() >= max_map_size && !metrics_map.contains_key(&key) {
+                drop(intervals);
+                // Not a scheduled flush, so series missing from it are not counted as stale.
+                self.flush_metrics(interval_index, false);
+            }
+        }
 
-        let key = BucketKey {
-            metric_bytes: metric_bucket_bytes,
-            insert_value_at: value_start,
-        };
+        let mut intervals = self.intervals.lock().unwrap();
+        let metrics_map = &mut intervals[interval_index].metrics_map;
+        if let (Some(BucketValue::Values(existing)), BucketValue::Values(new)) =
+            (metrics_map.get(&key).map(|x| &x.value), &value)
+        {
+            if existing.len() + new.len() >= MAX_PACKED_VALUES_LEN {
+                let existing = metrics_map.remove(&key).unwrap();
+                self.next.submit(&mut bucket_metric(&key, &existing.value));
+            }
+        }
 
-        self.metrics_map
-            .entry(key)
-            .and_modify(|other_value| other_value.merge(&value))
-            .or_insert(value);
+        match metrics_map.get_mut(&key) {
+            Some(Bucket {
+                value: BucketValue::Set(values),
+                ..
+            }) if values.len() >= self.config.max_set_size => {}
+            Some(bucket) => bucket.value.merge(&value),
+            None => {
+                metrics_map.insert(
+                    key,
+                    Bucket {
+                        value,
+                        created_at: Instant::now(),
+                    },
+                );
+            }
+        }
 
         Ok(())
     }
 
-    fn flush_metrics(&mut self) {
+    /// Flush the buckets of an interval, and with `mark_stale`, mark series that stopped arriving
+    /// as stale.
+    fn flush_metrics(&mut self, interval_index: usize, mark_stale: bool) {
         self.next.poll();
 
-        let mut values_iter = self.metrics_map.drain();
-
-        for (key, value) in &mut values_iter {
-            let value_bytes = match value {
-                BucketValue::Gauge(x) => x.to_string().into_bytes(),
-                BucketValue::Counter(x) => x.to_string().into_bytes(),
-            };
+        let mut intervals = self.intervals.lock().unwrap();
+        let interval = &mut intervals[interval_index];
+        let mut stale = Vec::new();
+        if let (Some(staleness), true) = (&self.config.staleness, mark_stale) {
+            for missed in interval.seen.values_mut() {
+                *missed += 1;
+            }
+            for (key, bucket) in &interval.metrics_map {
+                if let BucketValue::Counter(_) | BucketValue::Gauge(_) = bucket.value {
+                    if let Some(missed) = interval.seen.get_mut(key) {
+                        *missed = 0;
+                    } else if interval.seen.len() < staleness.max_series {
+                        interval.seen.insert(key.clone(), 0);
+                    }
+                }
+            }
+            interval.seen.retain(|key, missed| {
+                if *missed < staleness.after_flushes {
+                    return true;
+                }
+                stale.push(stale_metric(key, staleness));
+                false
+            });
+        }
 
-            let mut metric_bytes = key.metric_bytes[..key.insert_value_at].to_vec();
-            metric_bytes.extend(value_bytes);
-            metric_bytes.extend(&key.metric_bytes[key.insert_value_at..]);
+        scheduler::flushing(|| {
+            for (key, bucket) in interval.metrics_map.drain() {
+                match (bucket.value, &self.config.timer_summary) {
+                    (BucketValue::Samples(values), Some(summary)) => {
+                        for mut metric in summary_metrics(&key, values, summary) {
+                            self.next.submit(&mut metric);
+                        }
+                    }
+                    (value, _) => self.next.submit(&mut bucket_metric(&key, &value)),
+                }
+            }
+            for mut metric in stale {
+                self.next.submit(&mut metric);
+            }
+        });
+    }
+}
 
-            self.next.submit(&mut Metric::new(metric_bytes));
+/// The marker for a series that stopped arriving.
+fn stale_metric(key: &BucketKey, config: &StalenessConfig) -> Metric {
+    let mut metric = bucket_metric(key, &BucketValue::Counter(0.0));
+    match config.marker {
+        StalenessMarker::Zero => {
+            if !config.tags.is_empty() {
+                metric.append_tags(config.tags.join(",").as_bytes());
+            }
+            metric
+        }
+        StalenessMarker::Metric => {
+            let mut raw = format!("{}:1|c|#series:", config.metric).into_bytes();
+            raw.extend(metric.name().unwrap_or_default());
+            if let Some(tags) = metric.tags().filter(|tags| !tags.is_empty()) {
+                raw.push(b',');
+                raw.extend(tags);
+            }
+            Metric::new(raw)
         }
     }
 }
 
+/// The summary of the values of a timer, histogram or distribution: `<name>.count`, scaled up by
+/// the sample rate, as a counter, and `<name>.sum`, `.min`, `.max`, `.mean` and a `.p<percentile>`
+/// per configured percentile as gauges, e.g. `.p99_9` for the 99.9th percentile.
+fn summary_metrics(
+    key: &BucketKey,
+    mut values: Vec<f64>,
+    config: &TimerSummaryConfig,
+) -> Vec<Metric> {
+    let sample_rate = key
+        .extensions
+        .iter()
+        .find_map(|x| x.strip_prefix(b"@"))
+        .and_then(|x| str::from_utf8(x).ok()?.parse::<f64>().ok())
+        .filter(|x| *x > 0.0 && *x <= 1.0)
+        .unwrap_or(1.0);
+    values.sort_unstable_by(f64::total_cmp);
+    let count = values.len() as f64;
+    let sum: f64 = values.iter().sum();
+
+    let mut stats = vec![
+        ("sum".to_owned(), sum),
+        ("min".to_owned(), values[0]),
+        ("max".to_owned(), values[values.len() - 1]),
+        ("mean".to_owned(), sum / count),
+    ];
+    for percentile in &config.percentiles {
+        // The nearest rank.
+        let rank = (percentile / 100.0 * count).ceil() as usize;
+        let value = values[rank.clamp(1, values.len()) - 1];
+        stats.push((format!("p{}", percentile).replace('.', "_"), value));
+    }
+
+    let derived = |suffix: &str, ty: &[u8]| BucketKey {
+        name: [&key.name[..], b".", suffix.as_bytes()].concat(),
+        ty: ty.to_vec(),
+        extensions: key
+            .extensions
+            .iter()
+            .filter(|x| !x.starts_with(b"@"))
+            .cloned()
+            .collect(),
+        tags: key.tags.clone(),
+    };
+    let mut metrics = vec![bucket_metric(
+        &derived("count", b"c"),
+        &BucketValue::Counter(count / sample_rate),
+    )];
+    for (suffix, value) in stats {
+        metrics.push(bucket_metric(
+            &derived(&suffix, b"g"),
+            &BucketValue::Gauge(value),
+        ));
+    }
+    metrics
+}
+
+fn bucket_metric(key: &BucketKey, value: &BucketValue) -> Metric {
+    let ty: &[u8] = match value {
+        BucketValue::Set(_) => b"g",
+        _ => &key.ty,
+    };
+    let value_bytes = match value {
+        BucketValue::Gauge(x) => x.to_string().into_bytes(),
+        BucketValue::Counter(x) => x.to_string().into_bytes(),
+        BucketValue::Values(x) => x.clone(),
+        BucketValue::Samples(x) => x
+            .iter()
+            .map(f64::to_string)
+            .collect::<Vec<_>>()
+            .join(":")
+            .into_bytes(),
+        BucketValue::Set(x) => x.len().to_string().into_bytes(),
+    };
+
+    let mut raw = Vec::with_capacity(
+        key.name.len()
+            + value_bytes.len()
+            + ty.len()
+            + 2
+            + key.extensions.iter().map(|x| x.len() + 1).sum::<usize>()
+            + key.tags.as_ref().map_or(0, |x| x.len() + 2),
+    );
+    raw.extend(&key.name);
+    raw.push(b':');
+    raw.extend(value_bytes);
+    raw.push(b'|');
+    raw.extend(ty);
+    for extension in &key.extensions {
+        raw.push(b'|');
+        raw.extend(extension);
+    }
+    let mut tags_pos = None;
+    if let Some(tags) = &key.tags {
+        raw.extend(b"|#");
+        let start = raw.len();
+        raw.extend(tags);
+        tags_pos = Some((start, raw.len()));
+    }
+    Metric::from_raw_parts(raw, tags_pos)
+}
+
 #[cfg(test)]
 static CURRENT_TIME: Mutex<Option<u64>> = Mutex::new(None);
+// Follows `CURRENT_TIME` unless set, to simulate changes to the system clock.
+#[cfg(test)]
+static CURRENT_MONOTONIC_TIME: Mutex<Option<u64>> = Mutex::new(None);
+
+/// The current time on the monotonic clock, and on the wall clock since the UNIX epoch.
+fn now() -> (Instant, Duration) {
+    #[cfg(test)]
+    if let Some(wall) = *CURRENT_TIME.lock().unwrap() {
+        static START: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+        let monotonic = CURRENT_MONOTONIC_TIME.lock().unwrap().unwrap_or(wall);
+        return (
+            *START.get_or_init(Instant::now) + Duration::from_secs(monotonic),
+            Duration::from_secs(wall),
+        );
+    }
+
+    let wall = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (Instant::now(), wall)
+}
+
+/// The time until the wall clock reaches the next multiple of `flush_interval`, shifted by
+/// `flush_offset` seconds.
+fn time_until_flush(wall: Duration, flush_interval: u64, flush_offset: i64) -> Duration {
+    let flush_interval = i128::from(flush_interval.max(1)) * 1000;
+    let since_flush =
+        (wall.as_millis() as i128 - i128::from(flush_offset) * 1000).rem_euclid(flush_interval);
+    Duration::from_millis((flush_interval - since_flush) as u64)
+}
 
 impl<M> Middleware for AggregateMetrics<M>
 where
     M: Middleware,
 {
-    fn poll(&mut self) {
-        #[cfg(test)]
-        let overwrite_now = *CURRENT_TIME.lock().unwrap();
-        #[cfg(not(test))]
-        let overwrite_now = None;
-
-        #[allow(clippy::unnecessary_literal_unwrap)]
-        let now = overwrite_now.unwrap_or_else(|| {
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-        });
+    fn join(&mut self) -> Result<(), Error> {
+        // Flush whatever has been aggregated so far instead of losing it.
+        for interval_index in 0..self.config.overrides.len() + 1 {
+            self.flush_metrics(interval_index, false);
+        }
+        self.next.join()
+    }
 
-        let rounded_bucket =
-            i64::try_from((now / self.config.flush_interval) * self.config.flush_interval)
-                .expect("overflow when calculating with flush_interval");
-        let rounded_bucket = u64::try_from(rounded_bucket + self.config.flush_offset)
-            .expect("overflow when calculating with flush_interval");
+    fn poll(&mut self) {
+        // Flushes are scheduled on the monotonic clock. The wall clock is only used to align them
+        // with multiples of the flush interval, so a jump of the system clock delays or advances
+        // at most one flush.
+        let (monotonic, wall) = now();
 
-        if self.last_flushed_at + self.config.flush_interval <= rounded_bucket {
-            self.flush_metrics();
-            self.last_flushed_at = rounded_bucket;
+        for interval_index in 0..self.config.overrides.len() + 1 {
+            let (flush_interval, mut flush_timer) = {
+                let intervals = self.intervals.lock().unwrap();
+                let interval = &intervals[interval_index];
+                (interval.flush_interval, interval.flush_timer)
+            };
+            let is_due = flush_timer.is_due(monotonic);
+            if is_due {
+                self.flush_metrics(interval_index, true);
+            }
+            if is_due || !flush_timer.is_set() {
+                let time_until_flush =
+                    time_until_flush(wall, flush_interval, self.config.flush_offset);
+                flush_timer.set(monotonic + time_until_flush);
+                self.intervals.lock().unwrap()[interval_index].flush_timer = flush_timer;
+            }
         }
 
         self.next.poll()
@@ -176,16 +549,18 @@ mod tests {
 
     use super::*;
 
+    use crate::config::{FlushIntervalOverrideConfig, TimerSummaryConfig};
     use crate::testutils::FnStep;
 
+    // Held by every test that sets `CURRENT_TIME`, so that they don't interfere.
+    static TIME_LOCK: Mutex<()> = Mutex::new(());
+
     #[test]
     fn basic() {
+        let _guard = TIME_LOCK.lock().unwrap();
         let config = AggregateMetricsConfig {
-            aggregate_counters: true,
-            aggregate_gauges: true,
             flush_interval: 10,
-            flush_offset: 0,
-            max_map_size: None,
+            ..Default::default()
         };
         let results = RefCell::new(vec![]);
         let next = FnStep(|metric: &mut Metric| {
@@ -225,12 +600,10 @@ mod tests {
 
     #[test]
     fn gauges() {
+        let _guard = TIME_LOCK.lock().unwrap();
         let config = AggregateMetricsConfig {
-            aggregate_counters: true,
-            aggregate_gauges: true,
             flush_interval: 10,
-            flush_offset: 0,
-            max_map_size: None,
+            ..Default::default()
         };
         let results = RefCell::new(vec![]);
         let next = FnStep(|metric: &mut Metric| {
@@ -267,4 +640,564 @@ mod tests {
             )]
         );
     }
+
+    #[test]
+    fn max_map_size() {
+        let config = AggregateMetricsConfig {
+            flush_interval: 10,
+            max_map_size: Some(2),
+            ..Default::default()
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut aggregator = AggregateMetrics::new(config, next);
+
+        for raw in [&b"a:1|c"[..], b"b:1|c", b"a:1|c"] {
+            aggregator.submit(&mut Metric::new(raw.to_vec()));
+        }
+        assert_eq!(results.borrow().len(), 0);
+
+        // A third bucket does not fit, so the first two are flushed early.
+        aggregator.submit(&mut Metric::new(b"c:1|c".to_vec()));
+        let mut flushed = results.borrow().clone();
+        flushed.sort_by(|a, b| a.raw.cmp(&b.raw));
+        assert_eq!(
+            flushed,
+            [
+                Metric::new(b"a:2|c".to_vec()),
+                Metric::new(b"b:1|c".to_vec())
+            ]
+        );
+
+        aggregator.join().unwrap();
+        assert_eq!(results.borrow()[2], Metric::new(b"c:1|c".to_vec()));
+    }
+
+    #[test]
+    fn timers() {
+        let config = AggregateMetricsConfig {
+            aggregate_counters: true,
+            aggregate_gauges: true,
+            aggregate_timers: true,
+            aggregate_sets: false,
+            max_set_size: 10000,
+            flush_interval: 10,
+            flush_offset: 0,
+            max_map_size: None,
+            overrides: vec![],
+            staleness: None,
+            timer_summary: None,
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut aggregator = AggregateMetrics::new(config, next);
+
+        aggregator.submit(&mut Metric::new(
+            b"request.duration:12|ms|#route:home".to_vec(),
+        ));
+        aggregator.submit(&mut Metric::new(
+            b"request.duration:30.5|ms|#route:home".to_vec(),
+        ));
+        aggregator.submit(&mut Metric::new(b"request.size:100|d".to_vec()));
+        aggregator.submit(&mut Metric::new(b"response.size:1:2|h|@0.5".to_vec()));
+        aggregator.submit(&mut Metric::new(b"response.size:3|h|@0.5".to_vec()));
+
+        assert_eq!(results.borrow_mut().len(), 0);
+
+        // Joining flushes everything aggregated so far, regardless of the flush interval.
+        aggregator.join().unwrap();
+
+        let mut results = results.borrow_mut();
+        results.sort_by(|a, b| a.raw.cmp(&b.raw));
+        assert_eq!(
+            results.as_slice(),
+            &[
+                Metric::new(b"request.duration:12:30.5|ms|#route:home".to_vec()),
+                Metric::new(b"request.size:100|d".to_vec()),
+                Metric::new(b"response.size:1:2:3|h|@0.5".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn timer_summary() {
+        let config = AggregateMetricsConfig {
+            aggregate_counters: true,
+            aggregate_gauges: true,
+            aggregate_timers: true,
+            aggregate_sets: false,
+            max_set_size: 10000,
+            flush_interval: 10,
+            flush_offset: 0,
+            max_map_size: None,
+            overrides: vec![],
+            staleness: None,
+            timer_summary: Some(TimerSummaryConfig {
+                percentiles: vec![50.0, 99.9],
+            }),
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut aggregator = AggregateMetrics::new(config, next);
+
+        for raw in [
+            &b"request.duration:30:10|ms|@0.5|#route:home|T1692653389"[..],
+            b"request.duration:20|ms|@0.5|#route:home|T1692653389",
+            b"request.duration:12|ms|@0.5|#route:home|T1692653389",
+            b"request.duration:abc|ms",
+        ] {
+            aggregator.submit(&mut Metric::new(raw.to_vec()));
+        }
+        // Timers with invalid values are passed on right away.
+        assert_eq!(
+            results.borrow_mut().as_slice(),
+            &[Metric::new(b"request.duration:abc|ms".to_vec())]
+        );
+        results.borrow_mut().clear();
+
+        aggregator.join().unwrap();
+        let mut results = results.borrow_mut();
+        results.sort_by(|a, b| a.raw.cmp(&b.raw));
+        let expected = [
+            "request.duration.count:8|c|T1692653389|#route:home",
+            "request.duration.max:30|g|T1692653389|#route:home",
+            "request.duration.mean:18|g|T1692653389|#route:home",
+            "request.duration.min:10|g|T1692653389|#route:home",
+            "request.duration.p50:12|g|T1692653389|#route:home",
+            "request.duration.p99_9:30|g|T1692653389|#route:home",
+            "request.duration.sum:72|g|T1692653389|#route:home",
+        ];
+        assert_eq!(
+            results.as_slice(),
+            expected.map(|x| Metric::new(x.as_bytes().to_vec()))
+        );
+    }
+
+    #[test]
+    fn sets() {
+        let config = AggregateMetricsConfig {
+            aggregate_counters: true,
+            aggregate_gauges: true,
+            aggregate_timers: false,
+            aggregate_sets: true,
+            max_set_size: 3,
+            flush_interval: 10,
+            flush_offset: 0,
+            max_map_size: None,
+            overrides: vec![],
+            staleness: None,
+            timer_summary: None,
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut aggregator = AggregateMetrics::new(config, next);
+
+        for user in ["a", "b", "a", "c", "d", "b"] {
+            let raw = format!("users.unique:{user}|s|#country:china");
+            aggregator.submit(&mut Metric::new(raw.into_bytes()));
+        }
+        aggregator.submit(&mut Metric::new(b"pages.unique:home|s".to_vec()));
+        assert_eq!(results.borrow_mut().len(), 0);
+
+        aggregator.join().unwrap();
+        let mut results = results.borrow_mut();
+        results.sort_by(|a, b| a.raw.cmp(&b.raw));
+        // Values beyond `max_set_size` are not counted.
+        assert_eq!(
+            results.as_slice(),
+            &[
+                Metric::new(b"pages.unique:1|g".to_vec()),
+                Metric::new(b"users.unique:3|g|#country:china".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        let _guard = TIME_LOCK.lock().unwrap();
+        let config = AggregateMetricsConfig {
+            aggregate_counters: true,
+            aggregate_gauges: true,
+            aggregate_timers: false,
+            aggregate_sets: false,
+            max_set_size: 10000,
+            flush_interval: 10,
+            flush_offset: 0,
+            max_map_size: None,
+            overrides: vec![FlushIntervalOverrideConfig {
+                prefix: "checkout.".to_string(),
+                flush_interval: 1,
+            }],
+            staleness: None,
+            timer_summary: None,
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut aggregator = AggregateMetrics::new(config, next);
+
+        *CURRENT_TIME.lock().unwrap() = Some(0);
+        aggregator.poll();
+        for raw in [&b"checkout.started:1|c"[..], b"users.online:1|c"] {
+            aggregator.submit(&mut Metric::new(raw.to_vec()));
+            aggregator.submit(&mut Metric::new(raw.to_vec()));
+        }
+
+        *CURRENT_TIME.lock().unwrap() = Some(1);
+        aggregator.poll();
+        assert_eq!(
+            results.borrow_mut().as_slice(),
+            &[Metric::new(b"checkout.started:2|c".to_vec())]
+        );
+
+        *CURRENT_TIME.lock().unwrap() = Some(11);
+        aggregator.poll();
+        assert_eq!(
+            results.borrow_mut().as_slice(),
+            &[
+                Metric::new(b"checkout.started:2|c".to_vec()),
+                Metric::new(b"users.online:2|c".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshots() {
+        let config = AggregateMetricsConfig {
+            aggregate_counters: true,
+            aggregate_gauges: true,
+            aggregate_timers: false,
+            aggregate_sets: false,
+            max_set_size: 10000,
+            flush_interval: 10,
+            flush_offset: 0,
+            max_map_size: None,
+            overrides: vec![],
+            staleness: None,
+            timer_summary: None,
+        };
+        let mut aggregator = AggregateMetrics::new(config, FnStep(|_: &mut Metric| {}));
+
+        // Other tests aggregate concurrently, so only look at metrics unique to this test.
+        for raw in [
+            &b"snapshots.users.online:1|c|#country:china"[..],
+            b"snapshots.users.online:1|c|#country:china",
+            b"snapshots.servers.online:1|c",
+        ] {
+            aggregator.submit(&mut Metric::new(raw.to_vec()));
+        }
+
+        let buckets = snapshot(b"snapshots.users.", 10);
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(
+            buckets[0].metric,
+            Metric::new(b"snapshots.users.online:2|c|#country:china".to_vec())
+        );
+        assert_eq!(snapshot(b"snapshots.", 1).len(), 1);
+
+        drop(aggregator);
+        assert!(snapshot(b"snapshots.", 10).is_empty());
+    }
+
+    #[test]
+    fn clock_jumps() {
+        let _guard = TIME_LOCK.lock().unwrap();
+        let config = AggregateMetricsConfig {
+            aggregate_counters: true,
+            aggregate_gauges: true,
+            aggregate_timers: false,
+            aggregate_sets: false,
+            max_set_size: 10000,
+            flush_interval: 10,
+            flush_offset: 0,
+            max_map_size: None,
+            overrides: vec![],
+            staleness: None,
+            timer_summary: None,
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut aggregator = AggregateMetrics::new(config, next);
+
+        let mut poll_at = |wall: u64, monotonic: u64| {
+            *CURRENT_TIME.lock().unwrap() = Some(wall);
+            *CURRENT_MONOTONIC_TIME.lock().unwrap() = Some(monotonic);
+            aggregator.poll();
+            aggregator.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+            results.borrow_mut().drain(..).count()
+        };
+
+        assert_eq!(poll_at(1000, 0), 0);
+        // The clock jumps back, which must not stall flushing until it catches up again.
+        assert_eq!(poll_at(5, 5), 0);
+        assert_eq!(poll_at(11, 11), 1);
+        // The next flush is aligned with the new wall clock.
+        assert_eq!(poll_at(15, 15), 0);
+        assert_eq!(poll_at(20, 20), 1);
+        // The clock jumps forward, which must not flush early.
+        assert_eq!(poll_at(3621, 21), 0);
+        assert_eq!(poll_at(3629, 29), 0);
+        assert_eq!(poll_at(3630, 30), 1);
+        assert_eq!(poll_at(3639, 39), 0);
+        assert_eq!(poll_at(3640, 40), 1);
+
+        *CURRENT_MONOTONIC_TIME.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn staleness() {
+        let run = |staleness: StalenessConfig| {
+            let config = AggregateMetricsConfig {
+                aggregate_counters: true,
+                aggregate_gauges: true,
+                aggregate_timers: true,
+                aggregate_sets: false,
+                max_set_size: 10000,
+                flush_interval: 10,
+                flush_offset: 0,
+                max_map_size: None,
+                overrides: vec![],
+                staleness: Some(staleness),
+                timer_summary: None,
+            };
+            let results = RefCell::new(vec![]);
+            let next = FnStep(|metric: &mut Metric| {
+                results
+                    .borrow_mut()
+                    .push(String::from_utf8(metric.raw.clone()).unwrap());
+            });
+            let mut aggregator = AggregateMetrics::new(config, next);
+            let mut flush = |metrics: &[&str]| {
+                for raw in metrics {
+                    aggregator.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+                }
+                aggregator.flush_metrics(0, true);
+                let mut flushed: Vec<_> = results.borrow_mut().drain(..).collect();
+                flushed.sort();
+                flushed
+            };
+            let mut flushes = vec![
+                flush(&["a:1|c|#env:prod", "b:2|g", "c:3|ms"]),
+                flush(&["a:1|c|#env:prod"]),
+                flush(&[]),
+                flush(&["b:3|g"]),
+            ];
+            // Not marked on shutdown, although `b` is missing from the last flush.
+            aggregator.join().unwrap();
+            flushes.push(results.borrow_mut().drain(..).collect());
+            flushes
+        };
+
+        let after_two = run(StalenessConfig {
+            after_flushes: 2,
+            ..Default::default()
+        });
+        assert_eq!(
+            after_two,
+            [
+                vec!["a:1|c|#env:prod", "b:2|g", "c:3|ms"],
+                vec!["a:1|c|#env:prod"],
+                vec!["b:0|g|#stale:true"],
+                vec!["a:0|c|#env:prod,stale:true", "b:3|g"],
+                vec![],
+            ]
+        );
+
+        let metric = run(StalenessConfig {
+            marker: StalenessMarker::Metric,
+            ..Default::default()
+        });
+        assert_eq!(metric[1], ["a:1|c|#env:prod", "stale_series:1|c|#series:b"]);
+        assert_eq!(metric[2], ["stale_series:1|c|#series:a,env:prod"]);
+    }
+
+    #[test]
+    fn flush_offset() {
+        assert_eq!(
+            time_until_flush(Duration::from_secs(0), 10, 0),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            time_until_flush(Duration::from_secs(3), 10, 0),
+            Duration::from_secs(7)
+        );
+        assert_eq!(
+            time_until_flush(Duration::from_secs(3), 10, 5),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            time_until_flush(Duration::from_secs(3), 10, -5),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            time_until_flush(Duration::from_millis(3500), 10, 0),
+            Duration::from_millis(6500)
+        );
+    }
+
+    #[test]
+    fn sections() {
+        let config = AggregateMetricsConfig {
+            aggregate_counters: true,
+            aggregate_gauges: true,
+            aggregate_timers: true,
+            aggregate_sets: false,
+            max_set_size: 10000,
+            flush_interval: 10,
+            flush_offset: 0,
+            max_map_size: None,
+            overrides: vec![],
+            staleness: None,
+            timer_summary: None,
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut aggregator = AggregateMetrics::new(config, next);
+        for raw in [
+            "users.online:1|c|#country:china|T1692653389",
+            "users.online:2|c|#country:china|T1692653389",
+            "request.duration:1:2|ms",
+            "request.duration:3|ms",
+            // Several tags sections are passed through as they are.
+            "users.online:1|c|#a|#b",
+            "users.online:|c",
+            ":1|c",
+            "users.online:1|#c",
+        ] {
+            aggregator.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        aggregator.join().unwrap();
+
+        let mut results: Vec<_> = results
+            .borrow()
+            .iter()
+            .map(|x| String::from_utf8(x.raw.clone()).unwrap())
+            .collect();
+        results.sort();
+        assert_eq!(
+            results,
+            [
+                ":1|c",
+                "request.duration:1:2:3|ms",
+                "users.online:1|#c",
+                "users.online:1|c|#a|#b",
+                // Tags are moved to the end.
+                "users.online:3|c|T1692653389|#country:china",
+                "users.online:|c",
+            ]
+        );
+    }
+
+    /// Aggregate random lines, and check that every flushed metric parses into the key and value
+    /// it was aggregated from, and that every other line is passed through unchanged.
+    #[test]
+    fn flushed_metrics_parse() {
+        use rand::rngs::SmallRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = SmallRng::seed_from_u64(1790);
+        let random_bytes = |rng: &mut SmallRng, alphabet: &[u8], max_len: usize| {
+            let len = rng.gen_range(0..=max_len);
+            (0..len)
+                .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                .collect::<Vec<u8>>()
+        };
+        let types: [&[u8]; 8] = [b"c", b"g", b"ms", b"h", b"d", b"s", b"", b"#c"];
+
+        for _ in 0..500 {
+            let config = AggregateMetricsConfig {
+                aggregate_counters: true,
+                aggregate_gauges: true,
+                aggregate_timers: true,
+                aggregate_sets: false,
+                max_set_size: 10000,
+                flush_interval: 10,
+                flush_offset: 0,
+                max_map_size: None,
+                overrides: vec![],
+                staleness: None,
+                timer_summary: None,
+            };
+            let results = RefCell::new(vec![]);
+            let next = FnStep(|metric: &mut Metric| {
+                results.borrow_mut().push(metric.clone());
+            });
+            let mut aggregator = AggregateMetrics::new(config, next);
+
+            // What the aggregator should output, as keys with their values, and passed through
+            // lines.
+            let mut buckets: HashMap<BucketKey, BucketValue> = HashMap::new();
+            let mut passed_through = Vec::new();
+            for _ in 0..20 {
+                // Mostly valid lines, followed by arbitrary sections.
+                let mut raw = random_bytes(&mut rng, b"ab.#@,", 3);
+                raw.push(b':');
+                raw.extend(random_bytes(&mut rng, b"12.-:e", 3));
+                raw.push(b'|');
+                raw.extend(types[rng.gen_range(0..types.len())]);
+                raw.extend(random_bytes(&mut rng, b"ab1:|#,@T", 8));
+                let value = BucketKey::parse(&raw).and_then(|(key, value)| {
+                    let value = str::from_utf8(value).unwrap();
+                    let value = match key.ty.as_slice() {
+                        b"c" => BucketValue::Counter(value.parse().ok()?),
+                        b"g" => BucketValue::Gauge(value.parse().ok()?),
+                        b"ms" | b"h" | b"d" => BucketValue::Values(value.as_bytes().to_vec()),
+                        _ => return None,
+                    };
+                    Some((key, value))
+                });
+                match value {
+                    Some((key, value)) => match buckets.get_mut(&key) {
+                        Some(bucket) => bucket.merge(&value),
+                        None => {
+                            buckets.insert(key, value);
+                        }
+                    },
+                    None => passed_through.push(raw.clone()),
+                }
+                aggregator.submit(&mut Metric::new(raw));
+            }
+            aggregator.join().unwrap();
+
+            for metric in results.into_inner() {
+                assert_eq!(Metric::new(metric.raw.clone()), metric);
+                if let Some(i) = passed_through.iter().position(|x| *x == metric.raw) {
+                    passed_through.swap_remove(i);
+                    continue;
+                }
+                let line = String::from_utf8_lossy(&metric.raw).into_owned();
+                let (key, value) = BucketKey::parse(&metric.raw).expect(&line);
+                let value = str::from_utf8(value).unwrap();
+                match buckets.remove(&key).expect(&line) {
+                    BucketValue::Counter(x) | BucketValue::Gauge(x) => {
+                        assert_eq!(
+                            value.parse::<f64>().unwrap().to_bits(),
+                            x.to_bits(),
+                            "{}",
+                            line
+                        )
+                    }
+                    BucketValue::Values(x) => assert_eq!(value.as_bytes(), x, "{}", line),
+                    BucketValue::Samples(_) | BucketValue::Set(_) => {
+                        unreachable!("timer_summary and aggregate_sets are not set")
+                    }
+                }
+            }
+            assert!(passed_through.is_empty(), "{:?}", passed_through);
+            assert!(buckets.is_empty(), "{:?}", buckets.keys());
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use anyhow::Error;
+use regex::bytes::RegexSet;
+
+use crate::config::AllowMetricConfig;
+use crate::drops::{self, DropReason};
+use crate::middleware::deny_metric::name_matcher;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Drops metrics whose name matches none of the configured patterns.
+pub struct AllowMetric<M> {
+    matcher: RegexSet,
+    next: M,
+}
+
+impl<M> AllowMetric<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: AllowMetricConfig, next: M) -> Result<Self, Error> {
+        Ok(AllowMetric {
+            matcher: name_matcher(&config.names, &config.patterns)?,
+            next,
+        })
+    }
+}
+
+impl<M> Middleware for AllowMetric<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        match metric.name() {
+            Some(name) if self.matcher.is_match(name) => self.next.submit(metric),
+            _ => drops::record(DropReason::Denied, &metric.raw),
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn allow() {
+        let config = AllowMetricConfig {
+            names: vec!["checkout.*".to_owned()],
+            patterns: vec!["^http\\.(requests|errors)$".to_owned()],
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut allow = AllowMetric::new(config, next).unwrap();
+
+        for raw in [
+            "checkout.started:1|c",
+            "checkout:1|c",
+            "http.requests:1|c|#route:home",
+            "http.requests.size:100|d",
+            "jvm.gc.pause:12|ms",
+        ] {
+            allow.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        assert_eq!(
+            *results.borrow(),
+            [
+                Metric::new(b"checkout.started:1|c".to_vec()),
+                Metric::new(b"http.requests:1|c|#route:home".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use anyhow::Error;
+
+use crate::config::{BudgetConfig, ByteBudgetConfig};
+use crate::drops::{self, DropReason};
+use crate::middleware::Middleware;
+use crate::token_bucket::TokenBucket;
+use crate::types::Metric;
+
+// Once a budget has this many buckets, forget about those that have been quiet long enough for
+// their bucket to be full again.
+const PRUNE_BUCKETS_AT: usize = 10000;
+
+struct Budget {
+    prefix: Option<Vec<u8>>,
+    tag: Option<Vec<u8>>,
+    bytes_per_second: f64,
+    // For prefix budgets there is a single bucket under the empty key, for tag budgets there is
+    // one bucket per tag value.
+    buckets: HashMap<Vec<u8>, TokenBucket>,
+    prune_buckets_at: usize,
+}
+
+impl From<BudgetConfig> for Budget {
+    fn from(config: BudgetConfig) -> Self {
+        Budget {
+            prefix: config.prefix.map(String::into_bytes),
+            tag: config.tag.map(String::into_bytes),
+            bytes_per_second: config.bytes_per_second as f64,
+            buckets: HashMap::new(),
+            prune_buckets_at: PRUNE_BUCKETS_AT,
+        }
+    }
+}
+
+impl Budget {
+    /// The key of the bucket this metric is accounted to, or `None` if the budget does not apply.
+    fn bucket_key(&self, metric: &Metric) -> Option<Vec<u8>> {
+        if let Some(prefix) = &self.prefix {
+            if !metric.name()?.starts_with(prefix) {
+                return None;
+            }
+        }
+
+        match &self.tag {
+            Some(tag) => metric
+                .tags_iter()
+                .find(|t| t.name() == tag.as_slice())
+                .map(|t| t.value().unwrap_or_default().to_vec()),
+            None => Some(Vec::new()),
+        }
+    }
+
+    /// Forget about full buckets once there are too many, so that tag values seen once do not
+    /// stay around forever.
+    fn prune(&mut self, now: Instant) {
+        if self.buckets.len() >= self.prune_buckets_at {
+            self.buckets.retain(|_, bucket| !bucket.is_full(now));
+            self.prune_buckets_at = (self.buckets.len() * 2).max(PRUNE_BUCKETS_AT);
+        }
+    }
+}
+
+/// Limits the number of bytes per second of metrics per name prefix or per tag value. Traffic
+/// over budget is dropped, or sent through a separate middleware chain (for example sampling or
+/// aggregation) if one is configured.
+pub struct ByteBudget<M, N> {
+    budgets: Vec<Budget>,
+    over_budget: Option<M>,
+    next: N,
+}
+
+impl<M, N> ByteBudget<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    /// `over_budget` is expected to end in (a handle to) `next`.
+    pub fn new(config: ByteBudgetConfig, over_budget: Option<M>, next: N) -> Self {
+        ByteBudget {
+            budgets: config.budgets.into_iter().map(Budget::from).collect(),
+            over_budget,
+            next,
+        }
+    }
+
+    fn is_over_budget(&mut self, metric: &Metric, now: Instant) -> bool {
+        // The first budget that applies to the metric is enforced.
+        for budget in &mut self.budgets {
+            if let Some(key) = budget.bucket_key(metric) {
+                let rate = budget.bytes_per_second;
+                let bucket = budget
+                    .buckets
+                    .entry(key)
+                    .or_insert_with(|| TokenBucket::new(rate, rate, now));
+                return !bucket.try_take(metric.raw.len() as f64, now);
+            }
+        }
+        false
+    }
+}
+
+impl<M, N> Middleware for ByteBudget<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        match &mut self.over_budget {
+            // The nested chain joins `next` as well.
+            Some(over_budget) => over_budget.join(),
+            None => self.next.join(),
+        }
+    }
+
+    fn poll(&mut self) {
+        let now = Instant::now();
+        for budget in &mut self.budgets {
+            budget.prune(now);
+        }
+        match &mut self.over_budget {
+            // The nested chain polls `next` as well.
+            Some(over_budget) => over_budget.poll(),
+            None => self.next.poll(),
+        }
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if !self.is_over_budget(metric, Instant::now()) {
+            self.next.submit(metric);
+        } else if let Some(over_budget) = &mut self.over_budget {
+            over_budget.submit(metric);
+        } else {
+            log::debug!("byte_budget: Dropping metric {:?}", metric.name());
+            drops::record(DropReason::OverBudget, &metric.raw);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = ByteBudgetConfig {
+            budgets: vec![
+                BudgetConfig {
+                    prefix: Some("api.".to_string()),
+                    tag: None,
+                    bytes_per_second: 30,
+                },
+                BudgetConfig {
+                    prefix: None,
+                    tag: Some("tenant".to_string()),
+                    bytes_per_second: 60,
+                },
+            ],
+            over_budget: None,
+        };
+
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut budget = ByteBudget::new(config, None::<FnStep<fn(&mut Metric)>>, next);
+        let now = Instant::now();
+
+        // 20 bytes each
+        let api = Metric::new(b"api.requests:1|c|#a".to_vec());
+        assert!(!budget.is_over_budget(&api, now));
+        assert!(budget.is_over_budget(&api, now));
+        assert!(!budget.is_over_budget(&api, now + Duration::from_secs(1)));
+
+        // 26 bytes each, separate budgets per tenant
+        let tenant_a = Metric::new(b"db.queries:1|c|#tenant:a".to_vec());
+        let tenant_b = Metric::new(b"db.queries:1|c|#tenant:b".to_vec());
+        assert!(!budget.is_over_budget(&tenant_a, now));
+        assert!(!budget.is_over_budget(&tenant_a, now));
+        assert!(budget.is_over_budget(&tenant_a, now));
+        assert!(!budget.is_over_budget(&tenant_b, now));
+
+        // Metrics without a budget are never limited.
+        budget.submit(&mut Metric::new(b"other:1|c".to_vec()));
+        assert_eq!(results.borrow().len(), 1);
+    }
+
+    #[test]
+    fn prune() {
+        let config = ByteBudgetConfig {
+            budgets: vec![BudgetConfig {
+                prefix: None,
+                tag: Some("tenant".to_string()),
+                bytes_per_second: 60,
+            }],
+            over_budget: None,
+        };
+        let next = FnStep(|_: &mut Metric| {});
+        let mut budget = ByteBudget::new(config, None::<FnStep<fn(&mut Metric)>>, next);
+        let now = Instant::now();
+
+        for i in 0..PRUNE_BUCKETS_AT {
+            let metric = Metric::new(format!("db.queries:1|c|#tenant:{i}").into_bytes());
+            assert!(!budget.is_over_budget(&metric, now));
+        }
+        assert_eq!(budget.budgets[0].buckets.len(), PRUNE_BUCKETS_AT);
+
+        // Once refilled, the buckets are forgotten.
+        budget.budgets[0].prune(now + Duration::from_secs(1));
+        assert!(budget.budgets[0].buckets.is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use crc32fast::Hasher;
+
+use crate::config::CanaryConfig;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Sends a percentage of timeseries through a candidate middleware chain, and all other
+/// timeseries through the baseline. The server uses this on reload, with the chain built from the
+/// new config as the candidate and the previous chain as the baseline. The percentage ramps up
+/// linearly to 100% over the configured duration, which allows rolling out risky changes
+/// gradually.
+///
+/// Timeseries are assigned by hashing their name and tags, so a timeseries is consistently
+/// handled by the same chain, and once moved to the candidate it stays there.
+///
+/// Both chains end in their own upstream, so both are polled and joined.
+pub struct Canary<M, N> {
+    start_percentage: f64,
+    ramp_duration: Duration,
+    started_at: Instant,
+    candidate: M,
+    baseline: N,
+}
+
+impl<M, N> Canary<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    /// The ramp starts at `started_at`, e.g. when the new config was loaded, so that servers
+    /// rebuilding their chains at different times still ramp up together.
+    pub fn new(config: &CanaryConfig, started_at: Instant, candidate: M, baseline: N) -> Self {
+        Canary {
+            start_percentage: config.percentage.clamp(0.0, 100.0),
+            ramp_duration: Duration::from_secs(config.ramp_duration),
+            started_at,
+            candidate,
+            baseline,
+        }
+    }
+
+    /// Whether all timeseries go through the candidate, so that the baseline can be joined.
+    pub fn is_done(&self) -> bool {
+        self.percentage(self.started_at.elapsed()) >= 100.0
+    }
+
+    /// The candidate and the baseline.
+    pub fn into_parts(self) -> (M, N) {
+        (self.candidate, self.baseline)
+    }
+
+    fn percentage(&self, elapsed: Duration) -> f64 {
+        if elapsed >= self.ramp_duration {
+            return if self.ramp_duration.is_zero() {
+                self.start_percentage
+            } else {
+                100.0
+            };
+        }
+        let progress = elapsed.as_secs_f64() / self.ramp_duration.as_secs_f64();
+        self.start_percentage + (100.0 - self.start_percentage) * progress
+    }
+
+    fn is_candidate(&self, metric: &Metric) -> bool {
+        let mut hasher = Hasher::new();
+        if let Some(name) = metric.name() {
+            hasher.update(name);
+        }
+        if let Some(tags) = metric.tags() {
+            hasher.update(tags);
+        }
+        let bucket = f64::from(hasher.finalize() % 10000) / 100.0;
+        bucket < self.percentage(self.started_at.elapsed())
+    }
+}
+
+impl<M, N> Middleware for Canary<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        self.candidate.join()?;
+        self.baseline.join()
+    }
+
+    fn poll(&mut self) {
+        self.candidate.poll();
+        self.baseline.poll();
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.is_candidate(metric) {
+            self.candidate.submit(metric)
+        } else {
+            self.baseline.submit(metric)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = CanaryConfig {
+            percentage: 50.0,
+            ramp_duration: 0,
+        };
+
+        let results = RefCell::new(vec![]);
+        let candidate = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push((true, metric.clone()));
+        });
+        let baseline = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push((false, metric.clone()));
+        });
+        let mut canary = Canary::new(&config, Instant::now(), candidate, baseline);
+
+        for i in 0..100 {
+            canary.submit(&mut Metric::new(
+                format!("users.online:1|c|#id:{i}").into_bytes(),
+            ));
+            canary.submit(&mut Metric::new(
+                format!("users.online:2|c|#id:{i}").into_bytes(),
+            ));
+        }
+
+        let results = results.borrow();
+        let candidates = results.iter().filter(|(candidate, _)| *candidate).count();
+        assert!((60..140).contains(&candidates), "{candidates}");
+        // The same timeseries always ends up in the same chain.
+        for pair in results.chunks(2) {
+            assert_eq!(pair[0].0, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn ramp() {
+        let config = CanaryConfig {
+            percentage: 10.0,
+            ramp_duration: 100,
+        };
+        let canary = Canary::new(
+            &config,
+            Instant::now(),
+            FnStep(|_: &mut Metric| {}),
+            FnStep(|_: &mut Metric| {}),
+        );
+
+        assert_eq!(canary.percentage(Duration::ZERO), 10.0);
+        assert_eq!(canary.percentage(Duration::from_secs(50)), 55.0);
+        assert_eq!(canary.percentage(Duration::from_secs(100)), 100.0);
+        assert_eq!(canary.percentage(Duration::from_secs(1000)), 100.0);
+        assert!(!canary.is_done());
+
+        // The ramp started before this chain was built, e.g. by another server. Skipped while
+        // the monotonic clock is younger than the ramp, e.g. right after booting.
+        if let Some(started_at) = Instant::now().checked_sub(Duration::from_secs(100)) {
+            let canary = Canary::new(
+                &config,
+                started_at,
+                FnStep(|_: &mut Metric| {}),
+                FnStep(|_: &mut Metric| {}),
+            );
+            assert!(canary.is_done());
+        }
+    }
+}
@@ -1,21 +1,56 @@
 use crate::config::{CardinalityLimitConfig, LimitConfig};
+use crate::contributors::{self, Contributors};
+use crate::drops::{self, DropReason};
 use crate::middleware::Middleware;
+use crate::self_metrics;
 use crate::types::Metric;
 use anyhow::Error;
 use crc32fast::Hasher;
 use std::collections::{BTreeMap, BTreeSet};
 use std::convert::From;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 // Vaguely modelled after https://github.com/getsentry/sentry-redis-tools/blob/main/sentry_redis_tools/cardinality_limiter.py
 // but without redis
 
+// The number of independently locked parts of a shared limiter.
+const STRIPES: usize = 16;
+
+// How often new timeseries over a warning threshold are logged.
+const WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The quotas of a shared limiter.
+struct SharedQuotas {
+    /// The timeseries seen by the quotas, striped by hash so that threads checking different
+    /// timeseries rarely contend.
+    stripes: Vec<Mutex<Vec<Quota>>>,
+    /// The number of timeseries per granule of every quota in all stripes together, so that the
+    /// limits apply to their total. Only locked to check and update the counts.
+    counts: Mutex<Counts>,
+}
+
+struct Counts {
+    /// The latest time that counts were updated at, so that they never see the time go back.
+    now: u64,
+    /// The number of distinct hashes per granule, for each quota.
+    granules: Vec<BTreeMap<u64, usize>>,
+}
+
+// Shared limiters, keyed by their limits. Several chains can exist at once, e.g. with worker
+// threads or while reloading.
+static SHARED: Mutex<Vec<(Vec<LimitConfig>, Weak<SharedQuotas>)>> = Mutex::new(Vec::new());
+
 struct Quota {
     /// The time window for which the limit applies. "We accept only 3 distinct metrics per hour"
     /// means the limit is 3, and our window is 3600.
     window: u64,
     /// The number of distinct hashes we want to accept per the timewindow `window`.
     limit: usize,
+    /// The number of distinct hashes from which on new hashes are accepted with a warning.
+    warn_at: Option<usize>,
+    /// The percentage of `limit` that `warn_at` corresponds to.
+    warn_percent: u8,
     /// The timewindow `window` is always relative to the current timestamp, i.e. it "slides", so
     /// that for example an hourly window does not reset the entire limit every hour. This would
     /// create unpleasant "step" effects where at the beginning of each hour, there is a burst of
@@ -74,6 +109,19 @@ impl Quota {
         }
     }
 
+    /// Whether accepting `hash` crosses the warning threshold, or did so before.
+    fn is_over_warning(&self, now: u64, hash: u32) -> bool {
+        let Some(warn_at) = self.warn_at else {
+            return false;
+        };
+        match self.usage.get(&(now - self.window)) {
+            Some(oldest_granule) => {
+                oldest_granule.len() + 1 >= warn_at && !oldest_granule.contains(&hash)
+            }
+            None => warn_at <= 1,
+        }
+    }
+
     fn insert_metric(&mut self, now: u64, hash: u32) {
         let mut current_granule = now - self.window;
 
@@ -82,6 +130,18 @@ impl Quota {
             current_granule += self.granularity;
         }
     }
+
+    /// The granules that `insert_metric` would newly add `hash` to.
+    fn new_granules(&self, now: u64, hash: u32) -> Vec<u64> {
+        (now - self.window..now)
+            .step_by(self.granularity as usize)
+            .filter(|granule| {
+                self.usage
+                    .get(granule)
+                    .is_none_or(|hashes| !hashes.contains(&hash))
+            })
+            .collect()
+    }
 }
 
 impl From<LimitConfig> for Quota {
@@ -97,20 +157,173 @@ impl From<LimitConfig> for Quota {
             _ => 3600,
         };
 
+        let limit: usize = config
+            .limit
+            .try_into()
+            .expect("quota limit does not fit into native integer (usize)");
         Quota {
             window: config.window.into(),
-            limit: config
-                .limit
-                .try_into()
-                .expect("quota limit does not fit into native integer (usize)"),
+            limit,
+            warn_at: config
+                .warn_percent
+                .map(|percent| limit.saturating_mul(usize::from(percent)).div_ceil(100)),
+            warn_percent: config.warn_percent.unwrap_or(100),
             granularity,
             usage: BTreeMap::new(),
         }
     }
 }
 
+enum Quotas {
+    Local(Vec<Quota>),
+    Shared(Arc<SharedQuotas>),
+}
+
+/// Get the shared limiter for `limits`, or create it.
+fn shared_quotas(limits: Vec<LimitConfig>) -> Arc<SharedQuotas> {
+    let mut shared = SHARED.lock().unwrap();
+    shared.retain(|(_, quotas)| quotas.strong_count() > 0);
+    if let Some(quotas) = shared
+        .iter()
+        .find(|(x, _)| *x == limits)
+        .and_then(|(_, quotas)| quotas.upgrade())
+    {
+        return quotas;
+    }
+    let quotas = Arc::new(SharedQuotas {
+        stripes: (0..STRIPES)
+            .map(|_| Mutex::new(limits.iter().cloned().map(Quota::from).collect()))
+            .collect(),
+        counts: Mutex::new(Counts {
+            now: 0,
+            granules: vec![BTreeMap::new(); limits.len()],
+        }),
+    });
+    shared.push((limits, Arc::downgrade(&quotas)));
+    quotas
+}
+
+/// Whether a metric fits into quotas.
+#[derive(Debug, PartialEq)]
+enum Admission {
+    Accepted,
+    /// Accepted as a new timeseries over the warning threshold of the quota with this window and
+    /// `warn_percent`.
+    Warned {
+        window: u64,
+        warn_percent: u8,
+    },
+    /// Rejected by the quota with this window.
+    Rejected {
+        window: u64,
+    },
+}
+
+/// Check whether a metric fits into all `quotas`, and count it if it does.
+fn admit(quotas: &mut [Quota], now: u64, hash: u32) -> Admission {
+    for quota in quotas.iter_mut() {
+        quota.remove_old_keys(now);
+
+        if !quota.does_metric_fit(now, hash) {
+            return Admission::Rejected {
+                window: quota.window,
+            };
+        }
+    }
+
+    let mut admission = Admission::Accepted;
+    for quota in quotas {
+        if admission == Admission::Accepted && quota.is_over_warning(now, hash) {
+            admission = Admission::Warned {
+                window: quota.window,
+                warn_percent: quota.warn_percent,
+            };
+        }
+        quota.insert_metric(now, hash);
+    }
+    admission
+}
+
+/// Like `admit`, but for a shared limiter: check the hash against the quotas of its stripe, and
+/// its count against the total of all stripes.
+fn admit_shared(shared: &SharedQuotas, mut now: u64, hash: u32) -> Admission {
+    let mut quotas = shared.stripes[hash as usize % shared.stripes.len()]
+        .lock()
+        .unwrap();
+    loop {
+        let new_granules: Vec<_> = quotas
+            .iter_mut()
+            .map(|quota| {
+                quota.remove_old_keys(now);
+                quota.new_granules(now, hash)
+            })
+            .collect();
+
+        let mut counts = shared.counts.lock().unwrap();
+        if counts.now > now {
+            // Another stripe already counted a later time, whose oldest granules differ.
+            now = counts.now;
+            continue;
+        }
+        counts.now = now;
+
+        let mut admission = Admission::Accepted;
+        for ((quota, new_granules), counts) in
+            quotas.iter().zip(&new_granules).zip(&mut counts.granules)
+        {
+            let window_start = now - quota.window;
+            while let Some(entry) = counts.first_entry() {
+                if *entry.key() >= window_start {
+                    break;
+                }
+                entry.remove_entry();
+            }
+
+            // Timeseries already in the oldest granule always fit.
+            if !new_granules.contains(&window_start) {
+                continue;
+            }
+            let count = counts.get(&window_start).copied().unwrap_or_default();
+            if count >= quota.limit {
+                return Admission::Rejected {
+                    window: quota.window,
+                };
+            }
+            if admission == Admission::Accepted
+                && quota.warn_at.is_some_and(|warn_at| count + 1 >= warn_at)
+            {
+                admission = Admission::Warned {
+                    window: quota.window,
+                    warn_percent: quota.warn_percent,
+                };
+            }
+        }
+        for (new_granules, counts) in new_granules.iter().zip(&mut counts.granules) {
+            for granule in new_granules {
+                *counts.entry(*granule).or_default() += 1;
+            }
+        }
+        drop(counts);
+
+        for quota in quotas.iter_mut() {
+            quota.insert_metric(now, hash);
+        }
+        return admission;
+    }
+}
+
+/// Drops metrics of timeseries beyond the limits. Limits with `warn_percent` warn about new
+/// timeseries beyond that share of the limit ahead of dropping them: they are counted in the
+/// `cardinality_limit.warnings` self metric, and logged with the metric names with the most new
+/// timeseries at most every minute.
+///
+/// With `overflow_series`, metrics beyond the limits are sent on as the catch-all timeseries of
+/// their name tagged `overflow:true` instead, which is not limited, and counted in the
+/// `cardinality_limit.overflow` self metric.
 pub struct CardinalityLimit<M> {
-    quotas: Vec<Quota>,
+    quotas: Quotas,
+    overflow_series: bool,
+    contributors: Contributors,
     next: M,
 }
 
@@ -119,8 +332,17 @@ where
     M: Middleware,
 {
     pub fn new(config: CardinalityLimitConfig, next: M) -> Self {
-        let quotas = config.limits.into_iter().map(Quota::from).collect();
-        Self { quotas, next }
+        let quotas = if config.shared {
+            Quotas::Shared(shared_quotas(config.limits))
+        } else {
+            Quotas::Local(config.limits.into_iter().map(Quota::from).collect())
+        };
+        Self {
+            quotas,
+            overflow_series: config.overflow_series,
+            contributors: Contributors::new(WARN_INTERVAL),
+            next,
+        }
     }
 
     fn hash_metric(&self, metric: &Metric) -> u32 {
@@ -150,20 +372,47 @@ where
             .unwrap()
             .as_secs();
 
-        for quota in &mut self.quotas {
-            quota.remove_old_keys(now);
-
-            if !quota.does_metric_fit(now, metric_hash) {
-                log::debug!("Dropping metric {:?}", metric.name());
-                return;
+        let admission = match &mut self.quotas {
+            Quotas::Local(quotas) => admit(quotas, now, metric_hash),
+            Quotas::Shared(quotas) => admit_shared(quotas, now, metric_hash),
+        };
+        match admission {
+            Admission::Accepted => {}
+            Admission::Warned {
+                window,
+                warn_percent,
+            } => {
+                let window = window.to_string();
+                self_metrics::incr("cardinality_limit.warnings", &[("window", &window)], 1);
+                self.contributors.record(metric.name().unwrap_or_default());
+                if let Some(top) = self.contributors.report(Instant::now()) {
+                    log::warn!(
+                        "cardinality limit with a window of {}s is over {}% full, new timeseries by metric name: {}",
+                        window,
+                        warn_percent,
+                        top
+                    );
+                }
+            }
+            Admission::Rejected { window } => {
+                contributors::rejected(
+                    format_args!("cardinality-limit window={}", window),
+                    Duration::from_secs(window),
+                    &metric.raw,
+                );
+                if self.overflow_series {
+                    let window = window.to_string();
+                    self_metrics::incr("cardinality_limit.overflow", &[("window", &window)], 1);
+                    metric.set_tags(b"overflow:true");
+                } else {
+                    log::debug!("Dropping metric {:?}", metric.name());
+                    drops::record(DropReason::Cardinality, &metric.raw);
+                    return;
+                }
             }
         }
 
         self.next.submit(metric);
-
-        for quota in &mut self.quotas {
-            quota.insert_metric(now, metric_hash);
-        }
     }
 
     fn join(&mut self) -> Result<(), Error> {
@@ -173,7 +422,9 @@ where
 
 #[cfg(test)]
 mod tests {
-    use std::cell::RefCell;
+    use std::cell::{Cell, RefCell};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
 
     use super::*;
     use crate::testutils::FnStep;
@@ -184,7 +435,10 @@ mod tests {
             limits: vec![LimitConfig {
                 limit: 2,
                 window: 3600,
+                warn_percent: None,
             }],
+            shared: false,
+            overflow_series: false,
         };
 
         let results = RefCell::new(vec![]);
@@ -215,4 +469,196 @@ mod tests {
         ));
         assert_eq!(results.borrow_mut().len(), 3);
     }
+
+    #[test]
+    fn warning() {
+        let mut quotas = vec![
+            Quota::from(LimitConfig {
+                limit: 100,
+                window: 60,
+                warn_percent: None,
+            }),
+            Quota::from(LimitConfig {
+                limit: 5,
+                window: 3600,
+                warn_percent: Some(50),
+            }),
+        ];
+        let admissions: Vec<_> = [1, 2, 2, 3, 4, 3, 5, 6]
+            .into_iter()
+            .map(|hash| admit(&mut quotas, 10000, hash))
+            .collect();
+        assert_eq!(
+            admissions,
+            [
+                Admission::Accepted,
+                Admission::Accepted,
+                // Known timeseries never warn.
+                Admission::Accepted,
+                // The third of five crosses 50%.
+                Admission::Warned {
+                    window: 3600,
+                    warn_percent: 50,
+                },
+                Admission::Warned {
+                    window: 3600,
+                    warn_percent: 50,
+                },
+                Admission::Accepted,
+                Admission::Warned {
+                    window: 3600,
+                    warn_percent: 50,
+                },
+                Admission::Rejected { window: 3600 },
+            ]
+        );
+    }
+
+    #[test]
+    fn shared() {
+        let config = CardinalityLimitConfig {
+            limits: vec![LimitConfig {
+                // Unlike in other tests, so that the limiter is not shared with them. With a window
+                // of at most 5 minutes, timeseries accepted in one second still count in the next.
+                limit: STRIPES as u64 * 2,
+                window: 298,
+                warn_percent: None,
+            }],
+            shared: true,
+            overflow_series: false,
+        };
+
+        let results = RefCell::new(vec![]);
+        let next = || {
+            FnStep(|metric: &mut Metric| {
+                results.borrow_mut().push(metric.clone());
+            })
+        };
+        let mut first = CardinalityLimit::new(config.clone(), next());
+        let mut second = CardinalityLimit::new(config, next());
+
+        let mut metrics = (0..1000)
+            .map(|i| Metric::new(format!("users.online:1|c|#id:{}", i).into_bytes()))
+            .collect::<Vec<_>>();
+        for (i, metric) in metrics.iter_mut().enumerate() {
+            if i % 2 == 0 {
+                first.submit(metric);
+            } else {
+                second.submit(metric);
+            }
+        }
+        // Both instances together accept exactly the limit.
+        assert_eq!(results.borrow().len(), STRIPES * 2);
+
+        // Accepted timeseries keep passing through either instance.
+        let accepted = results.borrow()[0].clone();
+        second.submit(&mut accepted.clone());
+        first.submit(&mut accepted.clone());
+        assert_eq!(results.borrow().len(), STRIPES * 2 + 2);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let config = CardinalityLimitConfig {
+            limits: vec![LimitConfig {
+                // Unlike in other tests, so that the limiter is not shared with them. With a window
+                // of at most 5 minutes, timeseries accepted in one second still count in the next,
+                // in case the threads take longer than a second.
+                limit: STRIPES as u64 * 3,
+                window: 299,
+                warn_percent: None,
+            }],
+            shared: true,
+            overflow_series: false,
+        };
+
+        let accepted = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for thread in 0..8 {
+                let mut limiter = CardinalityLimit::new(
+                    config.clone(),
+                    FnStep(|_: &mut Metric| {
+                        accepted.fetch_add(1, Ordering::Relaxed);
+                    }),
+                );
+                scope.spawn(move || {
+                    for i in 0..1000 {
+                        let raw = format!("users.online:1|c|#id:{}-{}", thread, i);
+                        limiter.submit(&mut Metric::new(raw.into_bytes()));
+                    }
+                });
+            }
+        });
+        // All threads together accept exactly the limit.
+        assert_eq!(accepted.into_inner(), STRIPES * 3);
+    }
+
+    #[test]
+    fn shared_uneven() {
+        // Neither limit is a multiple of the number of stripes, and the first is less than it.
+        for (limit, window) in [(5, 297), (STRIPES as u64 * 2 + 5, 296)] {
+            let config = CardinalityLimitConfig {
+                limits: vec![LimitConfig {
+                    limit,
+                    window,
+                    warn_percent: None,
+                }],
+                shared: true,
+                overflow_series: false,
+            };
+
+            let accepted = Cell::new(0);
+            let next = || FnStep(|_: &mut Metric| accepted.set(accepted.get() + 1));
+            let mut first = CardinalityLimit::new(config.clone(), next());
+            let mut second = CardinalityLimit::new(config, next());
+            for i in 0..1000 {
+                let mut metric = Metric::new(format!("users.online:1|c|#id:{}", i).into_bytes());
+                if i % 2 == 0 {
+                    first.submit(&mut metric);
+                } else {
+                    second.submit(&mut metric);
+                }
+            }
+            assert_eq!(accepted.get(), limit, "limit {limit}");
+        }
+    }
+
+    #[test]
+    fn overflow_series() {
+        let config = CardinalityLimitConfig {
+            limits: vec![LimitConfig {
+                limit: 1,
+                window: 3600,
+                warn_percent: None,
+            }],
+            shared: false,
+            overflow_series: true,
+        };
+
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut limiter = CardinalityLimit::new(config, next);
+
+        for raw in [
+            "users.online:1|c|#country:china",
+            "users.online:2|c|#country:japan",
+            "users.online:3|c|@0.5|#country:india|T1692653389",
+            "users.online:4|c",
+        ] {
+            limiter.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+
+        assert_eq!(
+            results.into_inner(),
+            [
+                "users.online:1|c|#country:china",
+                "users.online:2|c|#overflow:true",
+                "users.online:3|c|@0.5|#overflow:true|T1692653389",
+                "users.online:4|c|#overflow:true",
+            ]
+            .map(|raw| Metric::new(raw.as_bytes().to_vec()))
+        );
+    }
 }
@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::CatalogConfig;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+use anyhow::Error;
+
+// How often entries older than the retention are removed.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Entry {
+    count: u64,
+    // Unix timestamp in seconds.
+    last_seen: u64,
+}
+
+// Keyed by `<name>|<type>|<sorted tag keys>`, which cannot be ambiguous since names and types do
+// not contain `|`.
+type Entries = Mutex<HashMap<Vec<u8>, Entry>>;
+
+// The entries of every `Catalog`, to list them on the admin endpoint.
+static INSTANCES: Mutex<Vec<Weak<Entries>>> = Mutex::new(Vec::new());
+
+/// A kind of metric seen by any `Catalog`.
+#[derive(Debug, PartialEq)]
+pub struct CatalogEntry {
+    pub name: String,
+    pub ty: String,
+    /// The distinct tag names, sorted.
+    pub tag_keys: Vec<String>,
+    /// The number of such metrics seen since the kind was first recorded.
+    pub count: u64,
+    /// When a metric was seen last, as a Unix timestamp in seconds.
+    pub last_seen: u64,
+}
+
+/// Every kind of metric seen by any `Catalog`, sorted by name, type and tag names.
+pub fn snapshot() -> Vec<CatalogEntry> {
+    let mut instances = INSTANCES.lock().unwrap();
+    instances.retain(|instance| instance.strong_count() > 0);
+
+    // Several chains, e.g. in worker threads, may have seen the same kind of metric.
+    let mut merged = BTreeMap::<Vec<u8>, (u64, u64)>::new();
+    for instance in instances.iter().filter_map(Weak::upgrade) {
+        for (key, entry) in instance.lock().unwrap().iter() {
+            let merged = merged.entry(key.clone()).or_default();
+            merged.0 += entry.count;
+            merged.1 = merged.1.max(entry.last_seen);
+        }
+    }
+    merged
+        .into_iter()
+        .map(|(key, (count, last_seen))| {
+            let mut parts = key.splitn(3, |&x| x == b'|');
+            let mut next =
+                || String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+            let (name, ty, tag_keys) = (next(), next(), next());
+            CatalogEntry {
+                name,
+                ty,
+                tag_keys: tag_keys
+                    .split(',')
+                    .filter(|x| !x.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                count,
+                last_seen,
+            }
+        })
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Records the name, type and tag names of every metric passing through, so that the admin
+/// endpoint can list what kinds of metrics flow through the proxy. Metrics are passed on
+/// unchanged.
+pub struct Catalog<M> {
+    retention: u64,
+    max_entries: usize,
+    entries: Arc<Entries>,
+    // Reused to build the key of every metric.
+    key: Vec<u8>,
+    last_expired_at: Instant,
+    warned_full: bool,
+    next: M,
+}
+
+impl<M> Catalog<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: CatalogConfig, next: M) -> Self {
+        let entries = Arc::new(Mutex::new(HashMap::new()));
+        INSTANCES.lock().unwrap().push(Arc::downgrade(&entries));
+        Self {
+            retention: config.retention * 3600,
+            max_entries: config.max_entries,
+            entries,
+            key: Vec::new(),
+            last_expired_at: Instant::now(),
+            warned_full: false,
+            next,
+        }
+    }
+
+    fn record(&mut self, metric: &Metric, now: u64) {
+        let (Some(name), Some(ty)) = (metric.name(), metric.ty()) else {
+            return;
+        };
+        let mut tag_keys: Vec<_> = metric
+            .tags()
+            .unwrap_or_default()
+            .split(|&x| x == b',')
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| tag.split(|&x| x == b':').next().unwrap_or(tag))
+            .collect();
+        tag_keys.sort_unstable();
+        tag_keys.dedup();
+
+        self.key.clear();
+        self.key.extend(name);
+        self.key.push(b'|');
+        self.key.extend(ty);
+        self.key.push(b'|');
+        for (i, tag_key) in tag_keys.iter().enumerate() {
+            if i > 0 {
+                self.key.push(b',');
+            }
+            self.key.extend(*tag_key);
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&self.key[..]) {
+            entry.count += 1;
+            entry.last_seen = now;
+        } else if entries.len() < self.max_entries {
+            entries.insert(
+                self.key.clone(),
+                Entry {
+                    count: 1,
+                    last_seen: now,
+                },
+            );
+        } else if !self.warned_full {
+            log::warn!(
+                "metrics catalog is full with {} entries, not recording new kinds of metrics",
+                entries.len()
+            );
+            self.warned_full = true;
+        }
+    }
+
+    fn expire(&mut self, now: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.last_seen + self.retention >= now);
+        if entries.len() < self.max_entries {
+            self.warned_full = false;
+        }
+    }
+}
+
+impl<M> Middleware for Catalog<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        if self.last_expired_at.elapsed() >= EXPIRE_INTERVAL {
+            self.last_expired_at = Instant::now();
+            self.expire(unix_now());
+        }
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        self.record(metric, unix_now());
+        self.next.submit(metric)
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = CatalogConfig {
+            retention: 1,
+            max_entries: 3,
+        };
+        let mut catalog = Catalog::new(config, FnStep(|_: &mut Metric| {}));
+        // Only this test's metrics, since other catalogs may exist at the same time.
+        let entries = |prefix: &str| -> Vec<CatalogEntry> {
+            snapshot()
+                .into_iter()
+                .filter(|entry| entry.name.starts_with(prefix))
+                .collect()
+        };
+
+        for raw in [
+            &b"catalog.a:1|c|#b:1,a:1"[..],
+            b"catalog.a:1|c|#a:2,b:2,a:3",
+            b"catalog.a:1|g",
+            b"catalog.b:1|ms|#c",
+            // Full.
+            b"catalog.c:1|c",
+            b"not a metric",
+        ] {
+            catalog.record(&Metric::new(raw.to_vec()), 1000);
+        }
+        catalog.record(&Metric::new(b"catalog.a:1|g".to_vec()), 2000);
+        assert_eq!(
+            entries("catalog."),
+            [
+                CatalogEntry {
+                    name: "catalog.a".to_string(),
+                    ty: "c".to_string(),
+                    tag_keys: vec!["a".to_string(), "b".to_string()],
+                    count: 2,
+                    last_seen: 1000,
+                },
+                CatalogEntry {
+                    name: "catalog.a".to_string(),
+                    ty: "g".to_string(),
+                    tag_keys: vec![],
+                    count: 2,
+                    last_seen: 2000,
+                },
+                CatalogEntry {
+                    name: "catalog.b".to_string(),
+                    ty: "ms".to_string(),
+                    tag_keys: vec!["c".to_string()],
+                    count: 1,
+                    last_seen: 1000,
+                },
+            ]
+        );
+
+        catalog.expire(1000 + 3600 + 1);
+        assert_eq!(entries("catalog.").len(), 1);
+        drop(catalog);
+        assert_eq!(entries("catalog."), []);
+    }
+}
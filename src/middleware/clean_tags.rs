@@ -0,0 +1,105 @@
+use crate::config::CleanTagsConfig;
+use crate::middleware::Middleware;
+use crate::types::{Metric, MetricTag};
+use anyhow::Error;
+
+pub struct CleanTags<M> {
+    config: CleanTagsConfig,
+    next: M,
+}
+
+impl<M> CleanTags<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: CleanTagsConfig, next: M) -> Self {
+        Self { config, next }
+    }
+}
+
+impl<M> Middleware for CleanTags<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let mut tags_to_keep = Vec::new();
+        let mut rewrite_tags = false;
+
+        for tag in metric.tags_iter() {
+            let raw = if self.config.trim_whitespace {
+                tag.raw.trim_ascii()
+            } else {
+                tag.raw
+            };
+            if raw.is_empty() {
+                rewrite_tags = true;
+            } else {
+                rewrite_tags |= raw.len() != tag.raw.len();
+                tags_to_keep.push(MetricTag::new(raw));
+            }
+        }
+
+        // A `|#` without any tags at all.
+        if metric.tags() == Some(b"") {
+            rewrite_tags = true;
+        }
+
+        if rewrite_tags {
+            let mut rewritten_metric = metric.clone();
+            rewritten_metric.set_tags_from_iter(tags_to_keep.into_iter());
+            self.next.submit(&mut rewritten_metric)
+        } else {
+            self.next.submit(metric)
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = CleanTagsConfig {
+            trim_whitespace: true,
+        };
+
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut clean_tags = CleanTags::new(config, next);
+
+        for raw in [
+            &b"servers.online:1|c|#a:1,,flag,"[..],
+            b"servers.online:1|c|#,a:1, flag ",
+            b"servers.online:1|c|#a:1,flag",
+            b"servers.online:1|c|#,,",
+            b"servers.online:1|c|#",
+        ] {
+            clean_tags.submit(&mut Metric::new(raw.to_vec()));
+        }
+
+        assert_eq!(
+            results.borrow().as_slice(),
+            &[
+                Metric::new(b"servers.online:1|c|#a:1,flag".to_vec()),
+                Metric::new(b"servers.online:1|c|#a:1,flag".to_vec()),
+                Metric::new(b"servers.online:1|c|#a:1,flag".to_vec()),
+                Metric::new(b"servers.online:1|c".to_vec()),
+                Metric::new(b"servers.online:1|c".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,345 @@
+//! Shadow comparison, to validate a rewrite of the middlewares on live traffic: a candidate chain
+//! of middlewares processes a copy of every metric, and what it emits is compared per series with
+//! what the current middlewares emit. Only the output of the current middlewares is forwarded.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+
+use crate::config::CompareConfig;
+use crate::middleware::shared::Shared;
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::Metric;
+
+// The number of differences kept as examples in the report.
+const MAX_EXAMPLES: usize = 20;
+
+/// The result of comparing a series.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// Both chains emitted the same number of metrics with the same sum of values.
+    Matched,
+    /// Only the current middlewares emitted the series.
+    Missing,
+    /// Only the candidate middlewares emitted the series.
+    Extra,
+    /// Both chains emitted the series, but a different number of metrics or values.
+    Different,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Matched => "matched",
+            Outcome::Missing => "missing",
+            Outcome::Extra => "extra",
+            Outcome::Different => "different",
+        }
+    }
+}
+
+/// The series compared since startup.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompareReport {
+    pub matched: u64,
+    pub missing: u64,
+    pub extra: u64,
+    pub different: u64,
+    /// Series not compared because more than `max_series` were emitted in an interval.
+    pub untracked: u64,
+    /// The last differences, oldest first, e.g.
+    /// `missing users.online|c|#env:prod: 2 metrics, sum 3 -> none`.
+    pub examples: VecDeque<String>,
+}
+
+static REPORT: Mutex<CompareReport> = Mutex::new(CompareReport {
+    matched: 0,
+    missing: 0,
+    extra: 0,
+    different: 0,
+    untracked: 0,
+    examples: VecDeque::new(),
+});
+
+/// The series compared since startup by all chains.
+pub fn report() -> CompareReport {
+    REPORT.lock().unwrap().clone()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct SeriesStats {
+    count: u64,
+    // The sum of all values, except for sets.
+    sum: f64,
+}
+
+impl SeriesStats {
+    fn describe(stats: Option<&SeriesStats>) -> String {
+        match stats {
+            Some(stats) => format!("{} metrics, sum {}", stats.count, stats.sum),
+            None => "none".to_owned(),
+        }
+    }
+}
+
+/// The name, type and sorted tags of a metric, e.g. `users.online|c|#env:prod,user_id:1`.
+fn series_key(metric: &Metric) -> Vec<u8> {
+    let mut key = metric.name().unwrap_or_default().to_vec();
+    key.push(b'|');
+    key.extend_from_slice(metric.ty().unwrap_or_default());
+    let mut tags: Vec<_> = metric.tags_iter().map(|tag| tag.raw).collect();
+    if !tags.is_empty() {
+        tags.sort_unstable();
+        key.extend_from_slice(b"|#");
+        key.extend_from_slice(&tags.join(&b","[..]));
+    }
+    key
+}
+
+/// The bottom of a compared chain, counting the metrics it emits per series until the next
+/// comparison.
+pub struct Emitted {
+    series: HashMap<Vec<u8>, SeriesStats>,
+    max_series: usize,
+    untracked: HashSet<Vec<u8>>,
+}
+
+impl Emitted {
+    pub fn new(max_series: usize) -> Self {
+        Emitted {
+            series: HashMap::new(),
+            max_series,
+            untracked: HashSet::new(),
+        }
+    }
+}
+
+impl Middleware for Emitted {
+    fn submit(&mut self, metric: &mut Metric) {
+        let key = series_key(metric);
+        if !self.series.contains_key(&key) && self.series.len() >= self.max_series {
+            self.untracked.insert(key);
+            return;
+        }
+        let stats = self.series.entry(key).or_default();
+        stats.count += 1;
+        if metric.ty() != Some(b"s") {
+            let values = metric
+                .name_and_value()
+                .unwrap_or_default()
+                .split(|&x| x == b':');
+            stats.sum += values
+                .skip(1)
+                .filter_map(|value| std::str::from_utf8(value).ok()?.parse::<f64>().ok())
+                .sum::<f64>();
+        }
+    }
+}
+
+/// Submits every metric to `current`, a chain forwarding to the upstream, and a copy to
+/// `candidate`. Both chains end in an `Emitted`, and what they emitted is compared every
+/// `interval` seconds, added to the report and counted in the `compare.series` self metric.
+pub struct Compare<M, C> {
+    current: M,
+    candidate: C,
+    current_emitted: Shared<Emitted>,
+    candidate_emitted: Shared<Emitted>,
+    interval: Duration,
+    compared_at: Instant,
+}
+
+impl<M, C> Compare<M, C>
+where
+    M: Middleware,
+    C: Middleware,
+{
+    pub fn new(
+        config: &CompareConfig,
+        current: M,
+        current_emitted: Shared<Emitted>,
+        candidate: C,
+        candidate_emitted: Shared<Emitted>,
+    ) -> Self {
+        Compare {
+            current,
+            candidate,
+            current_emitted,
+            candidate_emitted,
+            interval: Duration::from_secs(config.interval),
+            compared_at: Instant::now(),
+        }
+    }
+
+    fn compare(&mut self) {
+        let (current, current_untracked) = {
+            let mut emitted = self.current_emitted.lock();
+            let untracked = std::mem::take(&mut emitted.untracked);
+            (std::mem::take(&mut emitted.series), untracked)
+        };
+        let (candidate, candidate_untracked) = {
+            let mut emitted = self.candidate_emitted.lock();
+            let untracked = std::mem::take(&mut emitted.untracked);
+            (std::mem::take(&mut emitted.series), untracked)
+        };
+
+        let mut outcomes: HashMap<Outcome, u64> = HashMap::new();
+        let mut examples = Vec::new();
+        let keys: HashSet<_> = current.keys().chain(candidate.keys()).collect();
+        for key in keys {
+            if current_untracked.contains(key) || candidate_untracked.contains(key) {
+                continue;
+            }
+            let (a, b) = (current.get(key), candidate.get(key));
+            let outcome = match (a, b) {
+                (Some(a), Some(b)) if a == b => Outcome::Matched,
+                (Some(_), Some(_)) => Outcome::Different,
+                (Some(_), None) => Outcome::Missing,
+                (None, _) => Outcome::Extra,
+            };
+            *outcomes.entry(outcome).or_default() += 1;
+            if outcome != Outcome::Matched {
+                examples.push(format!(
+                    "{} {}: {} -> {}",
+                    outcome.as_str(),
+                    String::from_utf8_lossy(key),
+                    SeriesStats::describe(a),
+                    SeriesStats::describe(b)
+                ));
+            }
+        }
+        let untracked = current_untracked.union(&candidate_untracked).count() as u64;
+
+        let mut report = REPORT.lock().unwrap();
+        for (outcome, count) in outcomes {
+            *match outcome {
+                Outcome::Matched => &mut report.matched,
+                Outcome::Missing => &mut report.missing,
+                Outcome::Extra => &mut report.extra,
+                Outcome::Different => &mut report.different,
+            } += count;
+            self_metrics::incr("compare.series", &[("result", outcome.as_str())], count);
+        }
+        report.untracked += untracked;
+        if untracked > 0 {
+            self_metrics::incr("compare.series", &[("result", "untracked")], untracked);
+        }
+        for example in examples {
+            if report.examples.len() >= MAX_EXAMPLES {
+                report.examples.pop_front();
+            }
+            report.examples.push_back(example);
+        }
+    }
+}
+
+impl<M, C> Middleware for Compare<M, C>
+where
+    M: Middleware,
+    C: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        self.candidate.join()?;
+        self.current.join()?;
+        self.compare();
+        Ok(())
+    }
+
+    fn poll(&mut self) {
+        self.candidate.poll();
+        self.current.poll();
+        if self.compared_at.elapsed() >= self.interval {
+            self.compare();
+            self.compared_at = Instant::now();
+        }
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        self.candidate.submit(&mut metric.clone());
+        self.current.submit(metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::config::{AddTagConfig, DenyTagConfig};
+    use crate::middleware::add_tag::AddTag;
+    use crate::middleware::deny_tag::DenyTag;
+    use crate::middleware::mirror::Mirror;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn compare() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let current_emitted = Shared::new(Emitted::new(100));
+        let candidate_emitted = Shared::new(Emitted::new(100));
+        let current = AddTag::new(
+            AddTagConfig {
+                tags: vec!["env:prod".to_owned()],
+            },
+            Mirror::new(current_emitted.clone(), next),
+        );
+        let candidate = DenyTag::new(
+            DenyTagConfig {
+                tags: vec!["user_id".to_owned()],
+                values: vec![],
+            },
+            AddTag::new(
+                AddTagConfig {
+                    tags: vec!["env:prod".to_owned()],
+                },
+                candidate_emitted.clone(),
+            ),
+        )
+        .unwrap();
+        let config = CompareConfig {
+            middlewares: vec![],
+            interval: 60,
+            max_series: 100,
+        };
+        let mut compare = Compare::new(
+            &config,
+            current,
+            current_emitted,
+            candidate,
+            candidate_emitted,
+        );
+
+        let before = report();
+        for raw in [
+            "requests:1|c|#compare:a",
+            "requests:2|c|#user_id:1,compare:b",
+        ] {
+            compare.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        compare.join().unwrap();
+
+        // Only the output of the current middlewares is forwarded.
+        assert_eq!(
+            *results.borrow(),
+            [
+                Metric::new(b"requests:1|c|#compare:a,env:prod".to_vec()),
+                Metric::new(b"requests:2|c|#user_id:1,compare:b,env:prod".to_vec()),
+            ]
+        );
+        let after = report();
+        assert!(after.matched > before.matched);
+        assert!(after.missing > before.missing);
+        assert!(after.extra > before.extra);
+        assert!(after.examples.contains(
+            &"missing requests|c|#compare:b,env:prod,user_id:1: 1 metrics, sum 2 -> none"
+                .to_owned()
+        ));
+        assert!(after.examples.contains(
+            &"extra requests|c|#compare:b,env:prod: none -> 1 metrics, sum 2".to_owned()
+        ));
+    }
+}
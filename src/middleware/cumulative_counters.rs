@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use crate::config::{CumulativeCounterMode, CumulativeCounterRule, CumulativeCountersConfig};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+use anyhow::Error;
+
+/// What is known about a counter series.
+struct Series {
+    last: f64,
+    // With `CumulativeCounterMode::Detect`, the number of consecutive large, non-decreasing values.
+    rising: usize,
+    cumulative: bool,
+}
+
+enum Action {
+    /// Pass the metric on unchanged.
+    Pass,
+    /// Pass the metric on with this value instead.
+    Replace(f64),
+    /// Do not pass the metric on.
+    Skip,
+}
+
+/// Converts counters that clients mistakenly send as cumulative totals into the increments statsd
+/// expects, e.g. `requests:1000|c`, `requests:1005|c` into `requests:5|c`.
+///
+/// A value smaller than the previous one of the same series is taken as a reset of the client's
+/// total to zero, e.g. after a restart, so the increment is the new value itself.
+pub struct CumulativeCounters<M> {
+    rules: Vec<CumulativeCounterRule>,
+    max_series: usize,
+    // Keyed by name and tags.
+    series: HashMap<Vec<u8>, Series>,
+    next: M,
+}
+
+impl<M> CumulativeCounters<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: CumulativeCountersConfig, next: M) -> Self {
+        Self {
+            rules: config.rules,
+            max_series: config.max_series,
+            series: HashMap::new(),
+            next,
+        }
+    }
+
+    fn convert(&mut self, metric: &Metric) -> Action {
+        if metric.ty() != Some(b"c") {
+            return Action::Pass;
+        }
+        let Some(name) = metric.name() else {
+            return Action::Pass;
+        };
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| name.starts_with(rule.prefix.as_bytes()))
+        else {
+            return Action::Pass;
+        };
+        let Some(value) = metric
+            .value()
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| x.parse::<f64>().ok())
+            .filter(|x| *x >= 0.0)
+        else {
+            return Action::Pass;
+        };
+
+        let mut key = name.to_vec();
+        if let Some(tags) = metric.tags() {
+            key.push(b'|');
+            key.extend(tags);
+        }
+        let large = value >= rule.min_value;
+        let Some(series) = self.series.get_mut(&key) else {
+            if self.series.len() >= self.max_series {
+                return Action::Pass;
+            }
+            let cumulative = rule.mode == CumulativeCounterMode::Convert;
+            self.series.insert(
+                key,
+                Series {
+                    last: value,
+                    rising: usize::from(large),
+                    cumulative,
+                },
+            );
+            // The first value of a cumulative series is only the baseline for the next one.
+            return if cumulative {
+                Action::Skip
+            } else {
+                Action::Pass
+            };
+        };
+
+        let increment = if value >= series.last {
+            value - series.last
+        } else {
+            value
+        };
+        if !series.cumulative {
+            series.rising = if large && value >= series.last {
+                series.rising + 1
+            } else {
+                usize::from(large)
+            };
+            series.last = value;
+            if series.rising < rule.min_samples {
+                return Action::Pass;
+            }
+            log::info!(
+                "treating counter {} as cumulative",
+                String::from_utf8_lossy(name)
+            );
+            series.cumulative = true;
+        }
+        series.last = value;
+        Action::Replace(increment)
+    }
+}
+
+impl<M> Middleware for CumulativeCounters<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        match self.convert(metric) {
+            Action::Pass => self.next.submit(metric),
+            Action::Replace(increment) => {
+                let name_len = metric.name().map_or(0, |name| name.len());
+                let value_end = metric
+                    .raw
+                    .iter()
+                    .position(|&x| x == b'|')
+                    .unwrap_or(metric.raw.len());
+                let mut raw = metric.raw[..name_len + 1].to_vec();
+                raw.extend(increment.to_string().as_bytes());
+                raw.extend(&metric.raw[value_end..]);
+                self.next.submit(&mut Metric::new(raw))
+            }
+            Action::Skip => {}
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    fn run(rule: CumulativeCounterRule, metrics: &[&str]) -> Vec<String> {
+        let config = CumulativeCountersConfig {
+            rules: vec![rule],
+            max_series: 10,
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results
+                .borrow_mut()
+                .push(String::from_utf8(metric.raw.clone()).unwrap());
+        });
+        let mut middleware = CumulativeCounters::new(config, next);
+        for raw in metrics {
+            middleware.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        drop(middleware);
+        results.into_inner()
+    }
+
+    #[test]
+    fn convert() {
+        let rule = CumulativeCounterRule {
+            prefix: "app.".to_string(),
+            mode: CumulativeCounterMode::Convert,
+            min_samples: 5,
+            min_value: 0.0,
+        };
+        assert_eq!(
+            run(
+                rule,
+                &[
+                    "app.requests:100|c|#host:a",
+                    "app.requests:50|c|#host:b",
+                    "app.requests:110|c|#host:a",
+                    "app.requests:115|c|#host:a|T1692653389",
+                    // Reset.
+                    "app.requests:3|c|#host:a",
+                    "app.requests:60|c|#host:b",
+                    "app.latency:100|g",
+                    "other.requests:100|c",
+                ]
+            ),
+            [
+                "app.requests:10|c|#host:a",
+                "app.requests:5|c|#host:a|T1692653389",
+                "app.requests:3|c|#host:a",
+                "app.requests:10|c|#host:b",
+                "app.latency:100|g",
+                "other.requests:100|c",
+            ]
+        );
+    }
+
+    #[test]
+    fn detect() {
+        let rule = CumulativeCounterRule {
+            prefix: "".to_string(),
+            mode: CumulativeCounterMode::Detect,
+            min_samples: 3,
+            min_value: 1000.0,
+        };
+        assert_eq!(
+            run(
+                rule,
+                &[
+                    // Small increments are never cumulative.
+                    "a:1|c", "a:2|c", "a:3|c", "a:4|c",
+                    // Large values that keep rising are.
+                    "b:1000|c", "b:1200|c", "b:1000|c", "b:1100|c", "b:1150|c", "b:1160|c",
+                    "b:10|c",
+                ]
+            ),
+            [
+                "a:1|c", "a:2|c", "a:3|c", "a:4|c", "b:1000|c", "b:1200|c", "b:1000|c", "b:1100|c",
+                "b:50|c", "b:10|c", "b:10|c",
+            ]
+        );
+    }
+}
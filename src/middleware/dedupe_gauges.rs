@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+
+use crate::config::DedupeGaugesConfig;
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::Metric;
+
+struct Sent {
+    value: Vec<u8>,
+    at: Instant,
+}
+
+/// Suppresses gauges with the same value as the previous one of the same series, unless that one
+/// was passed on `interval` or longer ago, since many agents re-send static gauges every second.
+/// Relative gauges like `+1` and all other types are always passed on. Suppressed gauges are
+/// counted in the `dedupe_gauges.suppressed` self metric.
+pub struct DedupeGauges<M> {
+    interval: Duration,
+    max_series: usize,
+    // By the metric without its value, e.g. `disk.free|g|#host:a`.
+    sent: HashMap<Vec<u8>, Sent>,
+    suppressed: u64,
+    next: M,
+}
+
+impl<M> DedupeGauges<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: DedupeGaugesConfig, next: M) -> Self {
+        DedupeGauges {
+            interval: Duration::from_secs(config.interval),
+            max_series: config.max_series,
+            sent: HashMap::new(),
+            suppressed: 0,
+            next,
+        }
+    }
+
+    /// Whether the metric repeats the previous gauge of its series at `now`.
+    fn is_duplicate(&mut self, metric: &Metric, now: Instant) -> bool {
+        let (Some(b"g"), Some(name_and_value), Some(value)) =
+            (metric.ty(), metric.name_and_value(), metric.value())
+        else {
+            return false;
+        };
+        let name = &name_and_value[..name_and_value.len() - value.len() - 1];
+        let key = [name, &metric.raw[name_and_value.len()..]].concat();
+        if matches!(value.first(), Some(b'+' | b'-')) {
+            // The value after a relative change is unknown.
+            self.sent.remove(&key);
+            return false;
+        }
+
+        if let Some(sent) = self.sent.get_mut(&key) {
+            if sent.value == value && now.saturating_duration_since(sent.at) < self.interval {
+                return true;
+            }
+            *sent = Sent {
+                value: value.to_vec(),
+                at: now,
+            };
+            return false;
+        }
+        if self.sent.len() >= self.max_series {
+            let interval = self.interval;
+            self.sent
+                .retain(|_, sent| now.saturating_duration_since(sent.at) < interval);
+        }
+        if self.sent.len() < self.max_series {
+            let value = value.to_vec();
+            self.sent.insert(key, Sent { value, at: now });
+        }
+        false
+    }
+}
+
+impl<M> Middleware for DedupeGauges<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        if self.suppressed > 0 {
+            self_metrics::incr("dedupe_gauges.suppressed", &[], self.suppressed);
+            self.suppressed = 0;
+        }
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.is_duplicate(metric, Instant::now()) {
+            self.suppressed += 1;
+        } else {
+            self.next.submit(metric)
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn dedupe() {
+        let config = DedupeGaugesConfig {
+            interval: 60,
+            max_series: 100,
+        };
+        let mut dedupe = DedupeGauges::new(config, FnStep(|_: &mut Metric| {}));
+        let now = Instant::now();
+        let mut is_duplicate = |raw: &str, secs| {
+            let metric = Metric::new(raw.as_bytes().to_vec());
+            dedupe.is_duplicate(&metric, now + Duration::from_secs(secs))
+        };
+
+        assert!(!is_duplicate("disk.free:10|g|#host:a", 0));
+        assert!(is_duplicate("disk.free:10|g|#host:a", 1));
+        // Other series, values and types are passed on.
+        assert!(!is_duplicate("disk.free:10|g|#host:b", 1));
+        assert!(!is_duplicate("disk.free:11|g|#host:a", 2));
+        assert!(is_duplicate("disk.free:11|g|#host:a", 3));
+        assert!(!is_duplicate("requests:1|c", 3));
+        assert!(!is_duplicate("requests:1|c", 3));
+        // Repeated once `interval` passed since the last gauge that was passed on.
+        assert!(is_duplicate("disk.free:11|g|#host:a", 61));
+        assert!(!is_duplicate("disk.free:11|g|#host:a", 62));
+        // Relative gauges are passed on, and the next gauge too.
+        assert!(!is_duplicate("disk.free:+1|g|#host:a", 63));
+        assert!(!is_duplicate("disk.free:+1|g|#host:a", 63));
+        assert!(!is_duplicate("disk.free:11|g|#host:a", 64));
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::Error;
+use regex::bytes::RegexSet;
+
+use crate::config::DenyMetricConfig;
+use crate::drops::{self, DropReason};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// A regular expression matching exactly the names matched by `glob`, where `*` matches any
+/// characters, including none, and `?` any single character.
+fn glob_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// The set matching metric names by any of the glob patterns in `names` or regular expressions
+/// in `patterns`.
+pub(crate) fn name_matcher(names: &[String], patterns: &[String]) -> Result<RegexSet, Error> {
+    let regexes = names
+        .iter()
+        .map(|x| glob_regex(x))
+        .chain(patterns.iter().cloned());
+    Ok(RegexSet::new(regexes)?)
+}
+
+/// Drops metrics whose name matches any of the configured patterns, e.g. known-noisy metric
+/// families.
+pub struct DenyMetric<M> {
+    matcher: RegexSet,
+    next: M,
+}
+
+impl<M> DenyMetric<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: DenyMetricConfig, next: M) -> Result<Self, Error> {
+        Ok(DenyMetric {
+            matcher: name_matcher(&config.names, &config.patterns)?,
+            next,
+        })
+    }
+}
+
+impl<M> Middleware for DenyMetric<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        match metric.name() {
+            Some(name) if self.matcher.is_match(name) => {
+                drops::record(DropReason::Denied, &metric.raw);
+            }
+            _ => self.next.submit(metric),
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn deny() {
+        let config = DenyMetricConfig {
+            names: vec!["jvm.gc.*".to_owned(), "db.pool?.size".to_owned()],
+            patterns: vec!["^debug\\.".to_owned()],
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut deny = DenyMetric::new(config, next).unwrap();
+
+        for raw in [
+            "jvm.gc.pause:12|ms",
+            "jvm.gc:1|c",
+            "jvm_gc_pause:12|ms",
+            "db.pool1.size:3|g",
+            "db.pool12.size:3|g",
+            "debug.requests:1|c",
+            "app.debug.requests:1|c",
+        ] {
+            deny.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        assert_eq!(
+            *results.borrow(),
+            [
+                Metric::new(b"jvm.gc:1|c".to_vec()),
+                Metric::new(b"jvm_gc_pause:12|ms".to_vec()),
+                Metric::new(b"db.pool12.size:3|g".to_vec()),
+                Metric::new(b"app.debug.requests:1|c".to_vec()),
+            ]
+        );
+    }
+}
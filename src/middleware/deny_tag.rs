@@ -1,11 +1,13 @@
 use crate::config::DenyTagConfig;
 use crate::middleware::Middleware;
-use crate::types::Metric;
+use crate::types::{Metric, MetricTag};
 use anyhow::Error;
+use regex::bytes::RegexSet;
 use std::collections::HashSet;
 
 pub struct DenyTag<M> {
     tags: HashSet<Vec<u8>>,
+    values: RegexSet,
     next: M,
 }
 
@@ -13,11 +15,17 @@ impl<M> DenyTag<M>
 where
     M: Middleware,
 {
-    pub fn new(config: DenyTagConfig, next: M) -> Self {
+    pub fn new(config: DenyTagConfig, next: M) -> Result<Self, Error> {
         let tags: HashSet<Vec<u8>> =
             HashSet::from_iter(config.tags.iter().cloned().map(|tag| tag.into_bytes()));
+        let values = RegexSet::new(&config.values)?;
 
-        Self { tags, next }
+        Ok(Self { tags, values, next })
+    }
+
+    fn is_denied(&self, tag: &MetricTag<'_>) -> bool {
+        self.tags.contains(tag.name())
+            || tag.value().is_some_and(|value| self.values.is_match(value))
     }
 }
 
@@ -34,7 +42,7 @@ where
         let mut rewrite_tags = false;
 
         for tag in metric.tags_iter() {
-            if self.tags.contains(tag.name()) {
+            if self.is_denied(&tag) {
                 log::debug!("deny_tag: Dropping tag {:?}", tag.name());
                 rewrite_tags = true;
             } else {
@@ -67,13 +75,14 @@ mod tests {
     fn basic() {
         let config = DenyTagConfig {
             tags: vec!["nope".to_string()],
+            values: vec![],
         };
 
         let results = RefCell::new(vec![]);
         let next = FnStep(|metric: &mut Metric| {
             results.borrow_mut().push(metric.clone());
         });
-        let mut tag_denier = DenyTag::new(config, next);
+        let mut tag_denier = DenyTag::new(config, next).unwrap();
 
         tag_denier.submit(&mut Metric::new(
             b"servers.online:1|c|#country:china,nope:foo".to_vec(),
@@ -91,4 +100,38 @@ mod tests {
             Metric::new(b"servers.online:1|c|#country:china,extra_stuff,,".to_vec())
         );
     }
+
+    #[test]
+    fn values() {
+        let config = DenyTagConfig {
+            tags: vec![],
+            values: vec![
+                "^[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}$".to_string(),
+                "@".to_string(),
+            ],
+        };
+
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut tag_denier = DenyTag::new(config, next).unwrap();
+
+        tag_denier.submit(&mut Metric::new(
+            b"servers.online:1|c|#country:china,request:0b5e4f38-6b6f-4c55-9a47-7c3ec1d2b1a0,user:jane@example.com,flag".to_vec(),
+        ));
+        assert_eq!(
+            results.borrow()[0],
+            Metric::new(b"servers.online:1|c|#country:china,flag".to_vec())
+        );
+    }
+
+    #[test]
+    fn invalid_regex() {
+        let config = DenyTagConfig {
+            tags: vec![],
+            values: vec!["(".to_string()],
+        };
+        assert!(DenyTag::new(config, FnStep(|_: &mut Metric| {})).is_err());
+    }
 }
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::config::{DeriveRateConfig, DeriveRateMode, DerivedMetricType};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+use anyhow::Error;
+
+struct Rule {
+    metric: Vec<u8>,
+    name: Vec<u8>,
+    mode: DeriveRateMode,
+    ty: &'static [u8],
+}
+
+/// The last value of a gauge series.
+struct Sample {
+    value: f64,
+    seen_at: Instant,
+}
+
+/// Emits a derived metric for configured gauges, with the change or the per-second rate of change
+/// since the previous value of the same series, e.g. `network.bytes.rate` from a cumulative
+/// `network.bytes` gauge. The gauges themselves are passed on unchanged.
+///
+/// A value smaller than the previous one is taken as a reset of the source to zero, e.g. after a
+/// restart, so the change is the new value itself rather than a negative one.
+pub struct DeriveRate<M> {
+    rules: Vec<Rule>,
+    max_series: usize,
+    // Keyed by name and tags.
+    series: HashMap<Vec<u8>, Sample>,
+    next: M,
+}
+
+impl<M> DeriveRate<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: DeriveRateConfig, next: M) -> Self {
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| Rule {
+                name: rule
+                    .name
+                    .unwrap_or_else(|| format!("{}.rate", rule.metric))
+                    .into_bytes(),
+                metric: rule.metric.into_bytes(),
+                mode: rule.mode,
+                ty: match rule.metric_type {
+                    DerivedMetricType::Gauge => b"g",
+                    DerivedMetricType::Counter => b"c",
+                },
+            })
+            .collect();
+        Self {
+            rules,
+            max_series: config.max_series,
+            series: HashMap::new(),
+            next,
+        }
+    }
+
+    /// The metric derived from `metric` received at `now`, if any.
+    fn derive(&mut self, metric: &Metric, now: Instant) -> Option<Metric> {
+        if metric.ty() != Some(b"g") {
+            return None;
+        }
+        let name = metric.name()?;
+        let rule = self.rules.iter().find(|rule| rule.metric == name)?;
+        let raw_value = metric.value()?;
+        // Relative updates like `+5` cannot be followed without knowing the absolute value.
+        if matches!(raw_value.first(), Some(b'+' | b'-')) {
+            return None;
+        }
+        let value: f64 = std::str::from_utf8(raw_value).ok()?.parse().ok()?;
+
+        let mut key = name.to_vec();
+        if let Some(tags) = metric.tags() {
+            key.push(b'|');
+            key.extend(tags);
+        }
+        let Some(previous) = self.series.get_mut(&key) else {
+            if self.series.len() < self.max_series {
+                self.series.insert(
+                    key,
+                    Sample {
+                        value,
+                        seen_at: now,
+                    },
+                );
+            }
+            return None;
+        };
+
+        let elapsed = now.saturating_duration_since(previous.seen_at);
+        if rule.mode == DeriveRateMode::PerSecond && elapsed.is_zero() {
+            // Keep the previous value, so that the change counts towards the next rate.
+            return None;
+        }
+        let delta = if value >= previous.value {
+            value - previous.value
+        } else {
+            value
+        };
+        *previous = Sample {
+            value,
+            seen_at: now,
+        };
+        let derived = match rule.mode {
+            DeriveRateMode::Delta => delta,
+            DeriveRateMode::PerSecond => delta / elapsed.as_secs_f64(),
+        };
+
+        let mut raw = rule.name.clone();
+        raw.push(b':');
+        raw.extend(derived.to_string().as_bytes());
+        raw.push(b'|');
+        raw.extend(rule.ty);
+        let mut derived = Metric::new(raw);
+        if let Some(tags) = metric.tags() {
+            derived.set_tags(tags);
+        }
+        Some(derived)
+    }
+}
+
+impl<M> Middleware for DeriveRate<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let derived = self.derive(metric, Instant::now());
+        self.next.submit(metric);
+        if let Some(mut derived) = derived {
+            self.next.submit(&mut derived);
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::DeriveRateRule;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = DeriveRateConfig {
+            rules: vec![
+                DeriveRateRule {
+                    metric: "network.bytes".to_string(),
+                    name: None,
+                    mode: DeriveRateMode::PerSecond,
+                    metric_type: DerivedMetricType::Gauge,
+                },
+                DeriveRateRule {
+                    metric: "requests.total".to_string(),
+                    name: Some("requests".to_string()),
+                    mode: DeriveRateMode::Delta,
+                    metric_type: DerivedMetricType::Counter,
+                },
+            ],
+            max_series: 10,
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut middleware = DeriveRate::new(config, next);
+
+        let start = Instant::now();
+        let mut derive = |raw: &[u8], secs: u64| {
+            middleware
+                .derive(
+                    &Metric::new(raw.to_vec()),
+                    start + Duration::from_secs(secs),
+                )
+                .map(|metric| String::from_utf8(metric.raw).unwrap())
+        };
+        assert_eq!(derive(b"network.bytes:100|g|#host:a", 0), None);
+        assert_eq!(derive(b"network.bytes:100|g|#host:b", 0), None);
+        assert_eq!(
+            derive(b"network.bytes:150|g|#host:a", 10),
+            Some("network.bytes.rate:5|g|#host:a".to_string())
+        );
+        // Reset.
+        assert_eq!(
+            derive(b"network.bytes:20|g|#host:a", 20),
+            Some("network.bytes.rate:2|g|#host:a".to_string())
+        );
+        assert_eq!(
+            derive(b"network.bytes:125|g|#host:b", 10),
+            Some("network.bytes.rate:2.5|g|#host:b".to_string())
+        );
+        assert_eq!(derive(b"requests.total:7|g", 0), None);
+        assert_eq!(
+            derive(b"requests.total:10|g", 0),
+            Some("requests:3|c".to_string())
+        );
+        assert_eq!(derive(b"requests.total:+3|g", 1), None);
+        assert_eq!(derive(b"requests.total:3|c", 1), None);
+        assert_eq!(derive(b"other:3|g", 1), None);
+
+        // Derived metrics follow the gauges they are derived from.
+        middleware.submit(&mut Metric::new(b"requests.total:12|g".to_vec()));
+        assert_eq!(
+            *results.borrow(),
+            [
+                Metric::new(b"requests.total:12|g".to_vec()),
+                Metric::new(b"requests:2|c".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::Error;
+
+use crate::config::{DowngradeDogstatsdConfig, TagDowngrade};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Converts DogStatsD metrics into plain statsd for servers that don't understand the DogStatsD
+/// extensions, like the original Etsy statsd. Tags are appended to the name or dropped, and all
+/// other extensions after the type except for the sample rate are removed, e.g. with
+/// `TagDowngrade::Append` `requests:1|c|@0.5|#route:/home,env:prod|T1692653389` becomes
+/// `requests.env_prod.route__home:1|c|@0.5`. Lines without a type are passed on unchanged.
+pub struct DowngradeDogstatsd<M> {
+    tags: TagDowngrade,
+    next: M,
+}
+
+impl<M> DowngradeDogstatsd<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: DowngradeDogstatsdConfig, next: M) -> Self {
+        DowngradeDogstatsd {
+            tags: config.tags,
+            next,
+        }
+    }
+
+    fn downgrade(&self, metric: &Metric) -> Option<Metric> {
+        let name = metric.name()?;
+        let name_and_value = metric.name_and_value()?;
+        let ty = metric.ty()?;
+
+        let mut raw = name.to_vec();
+        if self.tags == TagDowngrade::Append {
+            // Sorted, so that every series gets a single name regardless of the order of its tags.
+            let mut tags: Vec<_> = metric
+                .tags_iter()
+                .filter(|tag| !tag.raw.is_empty())
+                .collect();
+            tags.sort_by_key(|tag| tag.raw);
+            for tag in tags {
+                raw.push(b'.');
+                append_segment(&mut raw, tag.name());
+                if let Some(value) = tag.value() {
+                    raw.push(b'_');
+                    append_segment(&mut raw, value);
+                }
+            }
+        }
+        raw.extend(&name_and_value[name.len()..]);
+        raw.push(b'|');
+        raw.extend(ty);
+        for section in metric.raw.split(|&x| x == b'|').skip(2) {
+            if section.starts_with(b"@") {
+                raw.push(b'|');
+                raw.extend(section);
+            }
+        }
+        Some(Metric::new(raw))
+    }
+}
+
+/// Append `segment` to the name in `raw`, replacing characters that would start another segment
+/// or that plain statsd servers don't allow in names.
+fn append_segment(raw: &mut Vec<u8>, segment: &[u8]) {
+    raw.extend(segment.iter().map(|&x| {
+        if x.is_ascii_alphanumeric() || x == b'-' || x == b'_' {
+            x
+        } else {
+            b'_'
+        }
+    }));
+}
+
+impl<M> Middleware for DowngradeDogstatsd<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if let Some(downgraded) = self.downgrade(metric) {
+            *metric = downgraded;
+        }
+        self.next.submit(metric)
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    fn downgrade(tags: TagDowngrade, raw: &str) -> String {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut downgrade = DowngradeDogstatsd::new(DowngradeDogstatsdConfig { tags }, next);
+        downgrade.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        let [metric] = <[Metric; 1]>::try_from(results.into_inner()).unwrap();
+        String::from_utf8(metric.raw).unwrap()
+    }
+
+    #[test]
+    fn append() {
+        let append = |raw| downgrade(TagDowngrade::Append, raw);
+        assert_eq!(
+            append("requests:1|c|@0.5|#route:/home,env:prod|T1692653389"),
+            "requests.env_prod.route__home:1|c|@0.5"
+        );
+        assert_eq!(
+            append("requests:1|c|#env:prod,,debug"),
+            "requests.debug.env_prod:1|c"
+        );
+        assert_eq!(append("requests:1|c"), "requests:1|c");
+        assert_eq!(append("invalid"), "invalid");
+    }
+
+    #[test]
+    fn drop() {
+        assert_eq!(
+            downgrade(TagDowngrade::Drop, "requests:1|c|#env:prod|c:abc"),
+            "requests:1|c"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use crate::config::{DuplicateTagPolicy, DuplicateTagsConfig};
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::{Metric, MetricTag};
+use anyhow::Error;
+
+pub struct DuplicateTags<M> {
+    config: DuplicateTagsConfig,
+    next: M,
+}
+
+impl<M> DuplicateTags<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: DuplicateTagsConfig, next: M) -> Self {
+        Self { config, next }
+    }
+
+    /// Resolve all occurrences of a tag key into at most one tag.
+    fn resolve(&self, name: &[u8], occurrences: &[&MetricTag<'_>]) -> Option<Vec<u8>> {
+        let mut distinct: Vec<&MetricTag<'_>> = Vec::new();
+        for tag in occurrences {
+            if !distinct.iter().any(|x| x.raw == tag.raw) {
+                distinct.push(tag);
+            }
+        }
+        if distinct.len() == 1 {
+            return Some(distinct[0].raw.to_vec());
+        }
+
+        match self.config.policy {
+            DuplicateTagPolicy::First => Some(distinct[0].raw.to_vec()),
+            DuplicateTagPolicy::Last => Some(occurrences[occurrences.len() - 1].raw.to_vec()),
+            DuplicateTagPolicy::Drop => None,
+            DuplicateTagPolicy::Concat => {
+                let mut tag = name.to_vec();
+                for (i, value) in distinct.iter().filter_map(|x| x.value()).enumerate() {
+                    if i == 0 {
+                        tag.push(b':');
+                    } else {
+                        tag.extend(self.config.separator.as_bytes());
+                    }
+                    tag.extend(value);
+                }
+                Some(tag)
+            }
+        }
+    }
+}
+
+impl<M> Middleware for DuplicateTags<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let tags: Vec<MetricTag<'_>> = metric.tags_iter().collect();
+        let is_duplicate = |i: usize| {
+            !tags[i].raw.is_empty() && tags[..i].iter().any(|x| x.name() == tags[i].name())
+        };
+        if !(0..tags.len()).any(is_duplicate) {
+            return self.next.submit(metric);
+        }
+
+        let mut tag_buffer = Vec::new();
+        let mut first = true;
+        for (i, tag) in tags.iter().enumerate() {
+            let resolved = if tag.raw.is_empty() {
+                Some(Vec::new())
+            } else if is_duplicate(i) {
+                // Already resolved with the first occurrence.
+                continue;
+            } else {
+                let occurrences: Vec<_> = tags[i..]
+                    .iter()
+                    .filter(|x| x.name() == tag.name())
+                    .collect();
+                if occurrences.len() > 1 {
+                    log::debug!("duplicate_tags: Resolving tag {:?}", tag.name());
+                    self_metrics::incr(
+                        "duplicate_tags",
+                        &[("key", &String::from_utf8_lossy(tag.name()))],
+                        occurrences.len() as u64 - 1,
+                    );
+                }
+                self.resolve(tag.name(), &occurrences)
+            };
+
+            if let Some(resolved) = resolved {
+                if !first {
+                    tag_buffer.push(b',');
+                }
+                first = false;
+                tag_buffer.extend(resolved);
+            }
+        }
+
+        let mut rewritten_metric = metric.clone();
+        rewritten_metric.set_tags(&tag_buffer);
+        self.next.submit(&mut rewritten_metric)
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    fn resolve(policy: DuplicateTagPolicy, raw: &[u8]) -> Metric {
+        let config = DuplicateTagsConfig {
+            policy,
+            separator: "_".to_string(),
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut duplicate_tags = DuplicateTags::new(config, next);
+        duplicate_tags.submit(&mut Metric::new(raw.to_vec()));
+        let mut results = results.into_inner();
+        results.pop().unwrap()
+    }
+
+    #[test]
+    fn policies() {
+        let raw = b"servers.online:1|c|#env:prod,country:china,env:staging,env:prod,env:dev";
+        assert_eq!(
+            resolve(DuplicateTagPolicy::First, raw),
+            Metric::new(b"servers.online:1|c|#env:prod,country:china".to_vec())
+        );
+        assert_eq!(
+            resolve(DuplicateTagPolicy::Last, raw),
+            Metric::new(b"servers.online:1|c|#env:dev,country:china".to_vec())
+        );
+        assert_eq!(
+            resolve(DuplicateTagPolicy::Drop, raw),
+            Metric::new(b"servers.online:1|c|#country:china".to_vec())
+        );
+        assert_eq!(
+            resolve(DuplicateTagPolicy::Concat, raw),
+            Metric::new(b"servers.online:1|c|#env:prod_staging_dev,country:china".to_vec())
+        );
+    }
+
+    #[test]
+    fn identical_duplicates() {
+        assert_eq!(
+            resolve(
+                DuplicateTagPolicy::Drop,
+                b"servers.online:1|c|#env:prod,env:prod,flag,flag"
+            ),
+            Metric::new(b"servers.online:1|c|#env:prod,flag".to_vec())
+        );
+        assert_eq!(
+            resolve(DuplicateTagPolicy::Drop, b"servers.online:1|c|#env:prod"),
+            Metric::new(b"servers.online:1|c|#env:prod".to_vec())
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::Error;
+
+use crate::drops::{self, DropReason};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Keeps DogStatsD events and service checks away from `inner`, whose middlewares would mangle
+/// them since they don't follow the metric grammar. They are sent to `events` instead, usually a
+/// submit-only handle to the upstream at the end of `inner`, which `inner` drives already, or a
+/// dedicated upstream, or dropped without one.
+pub struct Events<M, N> {
+    inner: M,
+    events: Option<N>,
+}
+
+impl<M, N> Events<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    pub fn new(inner: M, events: Option<N>) -> Self {
+        Events { inner, events }
+    }
+}
+
+impl<M, N> Middleware for Events<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        self.inner.join()?;
+        match &mut self.events {
+            Some(events) => events.join(),
+            None => Ok(()),
+        }
+    }
+
+    fn poll(&mut self) {
+        self.inner.poll();
+        if let Some(events) = &mut self.events {
+            events.poll();
+        }
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if !metric.is_event() && !metric.is_service_check() {
+            return self.inner.submit(metric);
+        }
+        match &mut self.events {
+            Some(events) => events.submit(metric),
+            None => drops::record(DropReason::Event, &metric.raw),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::middleware::shared::Shared;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn events() {
+        let results = RefCell::new(vec![]);
+        let next = |name: &'static str| {
+            let results = &results;
+            FnStep(move |metric: &mut Metric| {
+                let raw = String::from_utf8(metric.raw.clone()).unwrap();
+                results.borrow_mut().push((name, raw));
+            })
+        };
+        let mut events = Events::new(next("inner"), Some(next("events")));
+
+        for raw in [
+            "users.online:1|c|#a:b",
+            "_e{5,4}:title|text|#a:b",
+            "_sc|redis.can_connect|0|#a:b",
+        ] {
+            events.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+
+        assert_eq!(
+            results.into_inner(),
+            [
+                ("inner", "users.online:1|c|#a:b"),
+                ("events", "_e{5,4}:title|text|#a:b"),
+                ("events", "_sc|redis.can_connect|0|#a:b"),
+            ]
+            .map(|(name, raw)| (name, raw.to_string()))
+        );
+    }
+
+    #[test]
+    fn drives_upstream_once() {
+        #[derive(Default)]
+        struct Polls(usize);
+
+        impl Middleware for Polls {
+            fn poll(&mut self) {
+                self.0 += 1;
+            }
+
+            fn submit(&mut self, _metric: &mut Metric) {}
+        }
+
+        let upstream = Shared::new(Polls::default());
+        let mut events = Events::new(upstream.clone(), Some(upstream.submit_only()));
+        events.poll();
+        events.join().unwrap();
+        assert_eq!(upstream.lock().0, 1);
+    }
+}
@@ -0,0 +1,241 @@
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+
+use crate::config::ExecConfig;
+use crate::drops::{self, DropReason};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+// How long to wait between attempts to (re)spawn a child that exited or failed to start.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A running child process, with one thread feeding its stdin and one thread reading its stdout.
+///
+/// Lines are framed by newlines in both directions. The child may emit any number of lines per
+/// input line, including none at all to drop a metric.
+struct ChildProcess {
+    child: Child,
+    input: Option<SyncSender<Vec<u8>>>,
+    output: Receiver<Vec<u8>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl ChildProcess {
+    fn spawn(config: &ExecConfig) -> Result<Self, Error> {
+        let (program, args) = config
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow!("exec: command must not be empty"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        // The input channel is bounded, so that a slow child applies backpressure to `submit`.
+        // The output channel is unbounded so that the reader thread never blocks on us while we
+        // are blocked on the child, which would deadlock.
+        let (input_tx, input_rx) = mpsc::sync_channel::<Vec<u8>>(config.buffer_size);
+        let (output_tx, output_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut stdin = BufWriter::new(stdin);
+            while let Ok(line) = input_rx.recv() {
+                let mut result = stdin.write_all(&line).and_then(|_| stdin.write_all(b"\n"));
+                // Write out everything that is queued up, and flush once we caught up.
+                while let (Ok(()), Ok(line)) = (&result, input_rx.try_recv()) {
+                    result = stdin.write_all(&line).and_then(|_| stdin.write_all(b"\n"));
+                }
+                if let Err(e) = result.and_then(|_| stdin.flush()) {
+                    log::error!("exec: failed to write to child process: {}", e);
+                    break;
+                }
+            }
+        });
+
+        let reader = thread::spawn(move || {
+            for line in BufReader::new(stdout).split(b'\n') {
+                match line {
+                    Ok(line) if line.is_empty() => {}
+                    Ok(line) => {
+                        if output_tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("exec: failed to read from child process: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(ChildProcess {
+            child,
+            input: Some(input_tx),
+            output: output_rx,
+            reader: Some(reader),
+        })
+    }
+
+    fn has_exited(&mut self) -> bool {
+        !matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Close the child's stdin and wait for it to write out all remaining lines.
+    fn close(&mut self) {
+        self.input = None;
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        self.input = None;
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct Exec<M> {
+    config: ExecConfig,
+    child: Option<ChildProcess>,
+    last_spawned_at: Option<Instant>,
+    next: M,
+}
+
+impl<M> Exec<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: ExecConfig, next: M) -> Self {
+        let mut exec = Exec {
+            config,
+            child: None,
+            last_spawned_at: None,
+            next,
+        };
+        exec.ensure_child();
+        exec
+    }
+
+    /// Spawn the child if it is not running, restarting it if it crashed.
+    fn ensure_child(&mut self) {
+        if let Some(child) = &mut self.child {
+            if !child.has_exited() {
+                return;
+            }
+            log::error!("exec: child process exited, restarting");
+            self.drain_output();
+            self.child = None;
+        }
+
+        if self
+            .last_spawned_at
+            .is_some_and(|at| at.elapsed() < RESTART_BACKOFF)
+        {
+            return;
+        }
+
+        self.last_spawned_at = Some(Instant::now());
+        match ChildProcess::spawn(&self.config) {
+            Ok(child) => self.child = Some(child),
+            Err(e) => log::error!("exec: failed to spawn {:?}: {}", self.config.command, e),
+        }
+    }
+
+    fn drain_output(&mut self) {
+        let Some(child) = &self.child else {
+            return;
+        };
+        while let Ok(line) = child.output.try_recv() {
+            self.next.submit(&mut Metric::new(line));
+        }
+    }
+}
+
+impl<M> Middleware for Exec<M>
+where
+    M: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        if let Some(child) = &mut self.child {
+            child.close();
+        }
+        self.drain_output();
+        self.child = None;
+        self.next.join()
+    }
+
+    fn poll(&mut self) {
+        self.ensure_child();
+        self.drain_output();
+        self.next.poll();
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        self.drain_output();
+
+        let Some(input) = self.child.as_ref().and_then(|child| child.input.as_ref()) else {
+            log::debug!("exec: child process is not running, dropping metric");
+            drops::record(DropReason::ProcessUnavailable, &metric.raw);
+            return;
+        };
+
+        if input.send(metric.raw.clone()).is_err() {
+            log::debug!("exec: child process is not running, dropping metric");
+            drops::record(DropReason::ProcessUnavailable, &metric.raw);
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(unix)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = ExecConfig {
+            command: vec![
+                "sed".to_string(),
+                "-e".to_string(),
+                "s/users/people/".to_string(),
+            ],
+            buffer_size: 10,
+        };
+
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut exec = Exec::new(config, next);
+
+        exec.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+        exec.submit(&mut Metric::new(b"servers.online:1|c".to_vec()));
+        exec.join().unwrap();
+
+        assert_eq!(
+            results.borrow().as_slice(),
+            &[
+                Metric::new(b"people.online:1|c".to_vec()),
+                Metric::new(b"servers.online:1|c".to_vec())
+            ]
+        );
+    }
+}
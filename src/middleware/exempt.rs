@@ -0,0 +1,119 @@
+use anyhow::Error;
+
+use crate::config::ExemptionConfig;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Sends exempted metrics (SLO or billing metrics, for example) straight to `next`, bypassing a
+/// middleware chain that may drop metrics, and all other metrics through that chain.
+///
+/// The bypassed chain is expected to end in (a handle to) `next`.
+pub struct Exempt<M, N> {
+    prefixes: Vec<Vec<u8>>,
+    tags: Vec<Vec<u8>>,
+    inner: M,
+    next: N,
+}
+
+impl<M, N> Exempt<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    pub fn new(config: ExemptionConfig, inner: M, next: N) -> Self {
+        Exempt {
+            prefixes: config
+                .prefixes
+                .into_iter()
+                .map(String::into_bytes)
+                .collect(),
+            tags: config.tags.into_iter().map(String::into_bytes).collect(),
+            inner,
+            next,
+        }
+    }
+
+    fn is_exempt(&self, metric: &Metric) -> bool {
+        if let Some(name) = metric.name() {
+            if self.prefixes.iter().any(|prefix| name.starts_with(prefix)) {
+                return true;
+            }
+        }
+
+        metric.tags_iter().any(|tag| {
+            self.tags.iter().any(|exempt_tag| {
+                // `key:value` matches exactly, `key` matches any value.
+                if exempt_tag.contains(&b':') {
+                    tag.raw == exempt_tag.as_slice()
+                } else {
+                    tag.name() == exempt_tag.as_slice()
+                }
+            })
+        })
+    }
+}
+
+impl<M, N> Middleware for Exempt<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        // The bypassed chain joins `next` as well.
+        self.inner.join()
+    }
+
+    fn poll(&mut self) {
+        self.inner.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.is_exempt(metric) {
+            self.next.submit(metric)
+        } else {
+            self.inner.submit(metric)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = ExemptionConfig {
+            prefixes: vec!["slo.".to_string()],
+            tags: vec!["billing".to_string(), "team:sre".to_string()],
+        };
+
+        let results = RefCell::new(vec![]);
+        let inner = FnStep(|_: &mut Metric| {});
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut exempt = Exempt::new(config, inner, next);
+
+        for raw in [
+            "slo.requests:1|c",
+            "api.requests:1|c|#billing:yes",
+            "api.requests:1|c|#team:sre",
+            "api.requests:1|c|#team:web",
+            "api.slo.requests:1|c",
+        ] {
+            exempt.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+
+        assert_eq!(
+            results.borrow().as_slice(),
+            &[
+                Metric::new(b"slo.requests:1|c".to_vec()),
+                Metric::new(b"api.requests:1|c|#billing:yes".to_vec()),
+                Metric::new(b"api.requests:1|c|#team:sre".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+
+use crate::config::FailoverConfig;
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::Metric;
+
+/// A middleware sending metrics to an upstream, which counts its failures to send so that
+/// `Failover` can notice them.
+pub trait SendErrors: Middleware {
+    /// The number of failed attempts to send since creation.
+    fn send_errors(&self) -> u64;
+}
+
+impl<M> SendErrors for Box<M>
+where
+    M: SendErrors + ?Sized,
+{
+    fn send_errors(&self) -> u64 {
+        self.as_ref().send_errors()
+    }
+}
+
+/// The result of periodically connecting to an address over TCP in a background thread.
+struct HealthCheck {
+    healthy: AtomicBool,
+}
+
+// Health checks by address, interval and timeout, shared by all failovers checking the same
+// address. Several chains can exist at once, e.g. with worker threads or while reloading. The
+// thread of a check stops once no failover uses it anymore.
+type HealthChecks = Vec<((SocketAddr, Duration, Duration), Weak<HealthCheck>)>;
+static HEALTH_CHECKS: Mutex<HealthChecks> = Mutex::new(Vec::new());
+
+fn health_check(addr: SocketAddr, interval: Duration, timeout: Duration) -> Arc<HealthCheck> {
+    let key = (addr, interval, timeout);
+    let mut checks = HEALTH_CHECKS.lock().unwrap();
+    checks.retain(|(_, check)| check.strong_count() > 0);
+    if let Some(check) = checks
+        .iter()
+        .find(|(x, _)| *x == key)
+        .and_then(|(_, check)| check.upgrade())
+    {
+        return check;
+    }
+
+    // Healthy until proven otherwise, so that metrics go to the primary right away.
+    let check = Arc::new(HealthCheck {
+        healthy: AtomicBool::new(true),
+    });
+    let weak = Arc::downgrade(&check);
+    checks.push((key, weak.clone()));
+    thread::spawn(move || loop {
+        let healthy = TcpStream::connect_timeout(&addr, timeout).is_ok();
+        let Some(check) = weak.upgrade() else {
+            break;
+        };
+        if check.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            log::info!(
+                "health check of {} {}",
+                addr,
+                if healthy { "passed" } else { "failed" }
+            );
+        }
+        drop(check);
+        thread::sleep(interval);
+    });
+    check
+}
+
+/// Sends metrics to `primary`, and to `secondary` while the primary is failing. The primary is
+/// failing if sending to it fails, or if its health check fails. Once the health check passes
+/// again, or without a health check after `failback_after`, metrics go to the primary again.
+pub struct Failover<P, S> {
+    primary: P,
+    secondary: S,
+    health_check: Option<Arc<HealthCheck>>,
+    failback_after: Duration,
+    // The send errors of the primary seen so far.
+    primary_errors: u64,
+    // When the primary failed last, while metrics go to the secondary.
+    failed_at: Option<Instant>,
+}
+
+impl<P, S> Failover<P, S>
+where
+    P: SendErrors,
+    S: Middleware,
+{
+    pub fn new(config: &FailoverConfig, primary: P, secondary: S) -> Result<Self, Error> {
+        let health_check = config
+            .health_check
+            .as_deref()
+            .map(|addr| {
+                let addr = addr
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow!("could not resolve health check address {}", addr))?;
+                Ok::<_, Error>(health_check(
+                    addr,
+                    Duration::from_secs(config.health_check_interval),
+                    Duration::from_secs(config.health_check_timeout),
+                ))
+            })
+            .transpose()?;
+        Ok(Failover {
+            primary_errors: primary.send_errors(),
+            primary,
+            secondary,
+            health_check,
+            failback_after: Duration::from_secs(config.failback_after),
+            failed_at: None,
+        })
+    }
+
+    fn primary_healthy(&self) -> bool {
+        self.health_check
+            .as_ref()
+            .is_none_or(|check| check.healthy.load(Ordering::Relaxed))
+    }
+
+    /// Fail over or back depending on the state of the primary.
+    fn update(&mut self) {
+        let errors = self.primary.send_errors();
+        let send_failed = errors != self.primary_errors;
+        self.primary_errors = errors;
+
+        match self.failed_at {
+            None if send_failed || !self.primary_healthy() => {
+                log::warn!("primary upstream is failing, sending to the secondary upstream");
+                self_metrics::incr("upstream.failovers", &[], 1);
+                self.failed_at = Some(Instant::now());
+            }
+            // Metrics still buffered in the primary may fail to send after failing over.
+            Some(_) if send_failed => self.failed_at = Some(Instant::now()),
+            Some(failed_at)
+                if failed_at.elapsed() >= self.failback_after && self.primary_healthy() =>
+            {
+                log::info!("sending to the primary upstream again");
+                self_metrics::incr("upstream.failbacks", &[], 1);
+                self.failed_at = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<P, S> Middleware for Failover<P, S>
+where
+    P: SendErrors,
+    S: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        let primary = self.primary.join();
+        self.secondary.join()?;
+        primary
+    }
+
+    fn poll(&mut self) {
+        // Both are polled, so that metrics buffered before failing over or back are flushed.
+        self.primary.poll();
+        self.secondary.poll();
+        self.update();
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.failed_at.is_some() {
+            self.secondary.submit(metric)
+        } else {
+            self.primary.submit(metric)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::net::TcpListener;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    struct Primary {
+        errors: Rc<Cell<u64>>,
+        received: Rc<RefCell<Vec<Metric>>>,
+    }
+
+    impl Middleware for Primary {
+        fn submit(&mut self, metric: &mut Metric) {
+            self.received.borrow_mut().push(metric.clone());
+        }
+    }
+
+    impl SendErrors for Primary {
+        fn send_errors(&self) -> u64 {
+            self.errors.get()
+        }
+    }
+
+    fn config() -> FailoverConfig {
+        FailoverConfig {
+            secondary: "127.0.0.1:8125".to_string(),
+            health_check: None,
+            health_check_interval: 1,
+            health_check_timeout: 1,
+            failback_after: 0,
+        }
+    }
+
+    #[test]
+    fn send_errors() {
+        let errors = Rc::new(Cell::new(0));
+        let primary_received = Rc::new(RefCell::new(vec![]));
+        let primary = Primary {
+            errors: Rc::clone(&errors),
+            received: Rc::clone(&primary_received),
+        };
+        let secondary_received = RefCell::new(vec![]);
+        let secondary = FnStep(|metric: &mut Metric| {
+            secondary_received.borrow_mut().push(metric.clone());
+        });
+        let mut failover = Failover::new(
+            &FailoverConfig {
+                failback_after: 3600,
+                ..config()
+            },
+            primary,
+            secondary,
+        )
+        .unwrap();
+
+        failover.submit(&mut Metric::new(b"a:1|c".to_vec()));
+        errors.set(1);
+        failover.poll();
+        failover.submit(&mut Metric::new(b"b:1|c".to_vec()));
+        failover.failback_after = Duration::ZERO;
+        failover.poll();
+        failover.submit(&mut Metric::new(b"c:1|c".to_vec()));
+
+        assert_eq!(
+            *primary_received.borrow(),
+            [
+                Metric::new(b"a:1|c".to_vec()),
+                Metric::new(b"c:1|c".to_vec()),
+            ]
+        );
+        assert_eq!(
+            *secondary_received.borrow(),
+            [Metric::new(b"b:1|c".to_vec())]
+        );
+    }
+
+    #[test]
+    fn health_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let primary = Primary {
+            errors: Rc::new(Cell::new(0)),
+            received: Rc::new(RefCell::new(vec![])),
+        };
+        let mut failover = Failover::new(
+            &FailoverConfig {
+                health_check: Some(addr.to_string()),
+                ..config()
+            },
+            primary,
+            FnStep(|_: &mut Metric| {}),
+        )
+        .unwrap();
+
+        // Nothing listens on the address anymore, so the first check fails.
+        let started_at = Instant::now();
+        while failover.failed_at.is_none() {
+            assert!(started_at.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+            failover.poll();
+        }
+
+        // And fails back once something does.
+        let _listener = TcpListener::bind(addr).unwrap();
+        while failover.failed_at.is_some() {
+            assert!(started_at.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+            failover.poll();
+        }
+    }
+}
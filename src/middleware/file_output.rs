@@ -0,0 +1,195 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+
+use crate::config::FileOutputConfig;
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::Metric;
+
+// Write buffered lines to the file once this many bytes have accumulated, or after
+// `FLUSH_INTERVAL`.
+const FLUSH_BYTES: usize = 64 * 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An output file, rotated once it grows beyond `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(config: &FileOutputConfig) -> io::Result<Self> {
+        let path = PathBuf::from(&config.path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(RotatingFile {
+            size: file.metadata()?.len(),
+            path,
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+            file,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    /// Move the file to `<path>.1`, shifting older files up to `<path>.<max_files>`, and start a
+    /// new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                match fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, lines: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + lines.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(lines)?;
+        self.size += lines.len() as u64;
+        Ok(())
+    }
+}
+
+// Files by path, shared by all outputs writing to the same path, so that chains in several worker
+// threads do not rotate the file under each other.
+type Files = Vec<(PathBuf, Weak<Mutex<RotatingFile>>)>;
+static FILES: Mutex<Files> = Mutex::new(Vec::new());
+
+fn shared_file(config: &FileOutputConfig) -> io::Result<Arc<Mutex<RotatingFile>>> {
+    let path = PathBuf::from(&config.path);
+    let mut files = FILES.lock().unwrap();
+    files.retain(|(_, file)| file.strong_count() > 0);
+    if let Some(file) = files
+        .iter()
+        .find(|(x, _)| *x == path)
+        .and_then(|(_, file)| file.upgrade())
+    {
+        return Ok(file);
+    }
+
+    let file = Arc::new(Mutex::new(RotatingFile::open(config)?));
+    files.push((path, Arc::downgrade(&file)));
+    Ok(file)
+}
+
+/// Appends every metric as a raw statsd line to a file, e.g. to see what the middlewares emit, or
+/// to archive traffic for replaying it later. The file is rotated once it grows beyond
+/// `max_bytes`, keeping `max_files` rotated files as `<path>.1`, `<path>.2` and so on.
+pub struct FileOutput {
+    file: Arc<Mutex<RotatingFile>>,
+    // Lines not yet written to the file.
+    buffer: Vec<u8>,
+    last_flush_at: Instant,
+}
+
+impl FileOutput {
+    pub fn new(config: &FileOutputConfig) -> Result<Self, Error> {
+        Ok(FileOutput {
+            file: shared_file(config)?,
+            buffer: Vec::new(),
+            last_flush_at: Instant::now(),
+        })
+    }
+
+    fn flush(&mut self) {
+        self.last_flush_at = Instant::now();
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write(&self.buffer) {
+            log::error!("failed to write to {}: {}", file.path.display(), e);
+            self_metrics::incr("upstream.file_output_errors", &[], 1);
+        }
+        self.buffer.clear();
+    }
+}
+
+impl Drop for FileOutput {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl Middleware for FileOutput {
+    fn submit(&mut self, metric: &mut Metric) {
+        self.buffer.extend(&metric.raw);
+        self.buffer.push(b'\n');
+        if self.buffer.len() >= FLUSH_BYTES {
+            self.flush();
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.last_flush_at.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.flush();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate() {
+        let dir =
+            std::env::temp_dir().join(format!("statsdproxy-file-output-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics");
+        let config = FileOutputConfig {
+            path: path.to_str().unwrap().to_owned(),
+            max_bytes: 12,
+            max_files: 2,
+        };
+
+        // Both outputs write to the same file.
+        let mut output = FileOutput::new(&config).unwrap();
+        let mut output2 = FileOutput::new(&config).unwrap();
+        for raw in ["a:1|c", "b:1|c", "c:1|c", "d:1|c"] {
+            output.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+            output.join().unwrap();
+        }
+        output2.submit(&mut Metric::new(b"e:1|c".to_vec()));
+        output2.join().unwrap();
+
+        let read = |path: PathBuf| String::from_utf8(fs::read(path).unwrap()).unwrap();
+        assert_eq!(read(path.clone()), "e:1|c\n");
+        assert_eq!(read(dir.join("metrics.1")), "c:1|c\nd:1|c\n");
+        assert_eq!(read(dir.join("metrics.2")), "a:1|c\nb:1|c\n");
+        assert!(!dir.join("metrics.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::Error;
+
+use crate::config::TypeFilterConfig;
+use crate::drops::{self, DropReason};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Drops metrics by type, e.g. all histograms: with `allow` unset the metrics of the configured
+/// types, and with `allow` set all other metrics, including those without a type.
+pub struct FilterType<M> {
+    types: Vec<Vec<u8>>,
+    allow: bool,
+    next: M,
+}
+
+impl<M> FilterType<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: TypeFilterConfig, allow: bool, next: M) -> Self {
+        FilterType {
+            types: config.types.into_iter().map(String::into_bytes).collect(),
+            allow,
+            next,
+        }
+    }
+}
+
+impl<M> Middleware for FilterType<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let listed = metric
+            .ty()
+            .is_some_and(|ty| self.types.iter().any(|x| x == ty));
+        if listed == self.allow {
+            self.next.submit(metric)
+        } else {
+            drops::record(DropReason::Denied, &metric.raw);
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn filter() {
+        let metrics = [
+            "requests:1|c",
+            "response.size:1:2|h|#route:home",
+            "request.duration:12|ms",
+            "users.online:3|g",
+            "invalid",
+        ];
+        for (allow, expected) in [
+            (false, &["requests:1|c", "users.online:3|g", "invalid"][..]),
+            (
+                true,
+                &["response.size:1:2|h|#route:home", "request.duration:12|ms"][..],
+            ),
+        ] {
+            let config = TypeFilterConfig {
+                types: vec!["h".to_owned(), "ms".to_owned()],
+            };
+            let results = RefCell::new(vec![]);
+            let next = FnStep(|metric: &mut Metric| {
+                results
+                    .borrow_mut()
+                    .push(String::from_utf8(metric.raw.clone()).unwrap());
+            });
+            let mut filter = FilterType::new(config, allow, next);
+            for raw in metrics {
+                filter.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+            }
+            drop(filter);
+            assert_eq!(results.into_inner(), expected);
+        }
+    }
+}
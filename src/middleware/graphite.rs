@@ -0,0 +1,198 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+
+use crate::drops::{self, DropReason};
+use crate::middleware::failover::SendErrors;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Translates metrics into the Graphite plaintext protocol, `name;tag=value value timestamp`, for
+/// an upstream like a `TcpUpstream` connected to carbon or go-carbon.
+///
+/// Counters are scaled by their sample rate, and timers, histograms and distributions with several
+/// values become one line per value. The timestamp is taken from the `|T` section if there is one,
+/// and the current time otherwise. Sets and relative gauges like `+3` have no equivalent in
+/// Graphite and are dropped, as are tags without a value.
+pub struct Graphite<M> {
+    next: M,
+}
+
+impl<M> Graphite<M>
+where
+    M: Middleware,
+{
+    pub fn new(next: M) -> Self {
+        Graphite { next }
+    }
+}
+
+/// Replace characters that Graphite does not allow in a name, tag name or tag value.
+fn sanitize(out: &mut Vec<u8>, raw: &[u8], invalid: &[u8]) {
+    out.extend(raw.iter().map(|&x| {
+        if x.is_ascii_whitespace() || invalid.contains(&x) {
+            b'_'
+        } else {
+            x
+        }
+    }));
+}
+
+/// The metric as Graphite lines separated by newlines, or why it cannot be translated.
+fn translate(metric: &Metric, now: u64) -> Result<Vec<u8>, DropReason> {
+    let (Some(name), Some(ty), Some(name_and_value)) =
+        (metric.name(), metric.ty(), metric.name_and_value())
+    else {
+        return Err(DropReason::Malformed);
+    };
+    if name.is_empty() {
+        return Err(DropReason::Malformed);
+    }
+    let raw_values = name_and_value.get(name.len() + 1..).unwrap_or_default();
+    match ty {
+        b"c" | b"ms" | b"h" | b"d" => {}
+        b"g" if !matches!(raw_values.first(), Some(b'+' | b'-')) => {}
+        _ => return Err(DropReason::Unsupported),
+    }
+    let values = raw_values
+        .split(|&x| x == b':')
+        .map(|x| std::str::from_utf8(x).ok()?.parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()
+        .filter(|values| !values.is_empty())
+        .ok_or(DropReason::Malformed)?;
+    let timestamp = metric
+        .raw
+        .split(|&x| x == b'|')
+        .skip(2)
+        .find_map(|section| section.strip_prefix(b"T"))
+        .and_then(|x| std::str::from_utf8(x).ok()?.parse::<u64>().ok())
+        .unwrap_or(now);
+    let weight = match ty {
+        b"c" => 1.0 / metric.sample_rate().unwrap_or(1.0),
+        _ => 1.0,
+    };
+
+    let mut path = Vec::new();
+    sanitize(&mut path, name, b";");
+    for tag in metric.tags_iter() {
+        let Some(value) = tag.value().filter(|x| !x.is_empty()) else {
+            continue;
+        };
+        if tag.name().is_empty() {
+            continue;
+        }
+        path.push(b';');
+        sanitize(&mut path, tag.name(), b";!^=");
+        path.push(b'=');
+        // Values must not start with a tilde either.
+        let value = match value.strip_prefix(b"~") {
+            Some(rest) => {
+                path.push(b'_');
+                rest
+            }
+            None => value,
+        };
+        sanitize(&mut path, value, b";");
+    }
+
+    let mut lines = Vec::new();
+    for value in values {
+        if !lines.is_empty() {
+            lines.push(b'\n');
+        }
+        lines.extend(&path);
+        lines.extend(format!(" {} {}", value * weight, timestamp).bytes());
+    }
+    Ok(lines)
+}
+
+impl<M> SendErrors for Graphite<M>
+where
+    M: SendErrors,
+{
+    fn send_errors(&self) -> u64 {
+        self.next.send_errors()
+    }
+}
+
+impl<M> Middleware for Graphite<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        match translate(metric, now) {
+            Ok(lines) => self.next.submit(&mut Metric::new(lines)),
+            Err(reason) => drops::record(reason, &metric.raw),
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::FnStep;
+    use std::cell::RefCell;
+
+    #[test]
+    fn translate_lines() {
+        let translated = |raw: &str| {
+            translate(&Metric::new(raw.as_bytes().to_vec()), 1700000000)
+                .map(|x| String::from_utf8(x).unwrap())
+        };
+        assert_eq!(
+            translated("users.online:1|c|#env:prod,flag,host:a;b"),
+            Ok("users.online;env=prod;host=a_b 1 1700000000".to_owned())
+        );
+        assert_eq!(
+            translated("users.online:2|c|@0.5|T1600000000"),
+            Ok("users.online 4 1600000000".to_owned())
+        );
+        assert_eq!(
+            translated("request duration:1.5:3|ms|#a=b:~c"),
+            Ok(
+                "request_duration;a_b=_c 1.5 1700000000\nrequest_duration;a_b=_c 3 1700000000"
+                    .to_owned()
+            )
+        );
+        // Histograms and distributions are lists of samples like timers.
+        assert_eq!(
+            translated("request.size:512:256|h"),
+            Ok("request.size 512 1700000000\nrequest.size 256 1700000000".to_owned())
+        );
+        assert_eq!(
+            translated("queue.wait:3|d|@0.5"),
+            Ok("queue.wait 3 1700000000".to_owned())
+        );
+        assert_eq!(translated("temperature:-2|g"), Err(DropReason::Unsupported));
+        assert_eq!(translated("users:a|s"), Err(DropReason::Unsupported));
+        assert_eq!(translated("users.online:x|c"), Err(DropReason::Malformed));
+        assert_eq!(translated("users.online"), Err(DropReason::Malformed));
+    }
+
+    #[test]
+    fn graphite() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut graphite = Graphite::new(next);
+        graphite.submit(&mut Metric::new(b"users.online:1|g|T1600000000".to_vec()));
+        graphite.submit(&mut Metric::new(b"users:a|s".to_vec()));
+        assert_eq!(
+            *results.borrow(),
+            [Metric::new(b"users.online 1 1600000000".to_vec())]
+        );
+    }
+}
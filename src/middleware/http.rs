@@ -0,0 +1,356 @@
+//! Ingest of newline-separated metrics in the body of HTTP POST requests, for clients that cannot
+//! send UDP.
+//!
+//! With the `otlp` feature, `POST /v1/metrics` instead accepts OTLP/HTTP export requests in the
+//! JSON or protobuf encoding.
+
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use tiny_http::{Method, Request, Response};
+
+use crate::line_handler::LineHandler;
+use crate::middleware::shared::Shared;
+use crate::middleware::stream::Lines;
+use crate::middleware::Middleware;
+use crate::timers::Deadlines;
+
+// Request bodies larger than this are rejected.
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+pub struct HttpListener {
+    server: tiny_http::Server,
+}
+
+impl HttpListener {
+    pub fn bind(listen: &str) -> Result<Self, Error> {
+        let server = tiny_http::Server::http(listen)
+            .map_err(|e| anyhow!("failed to listen on {}: {}", listen, e))?;
+        Ok(HttpListener { server })
+    }
+
+    /// Handle requests in a background thread until `stop` is set. The middlewares schedule their
+    /// deadlines in `deadlines`.
+    pub fn spawn<M>(
+        self,
+        middleware: Shared<M>,
+        deadlines: Deadlines,
+        line_handler: Arc<LineHandler>,
+        stop: Arc<AtomicBool>,
+    ) -> JoinHandle<()>
+    where
+        M: Middleware + Send + 'static,
+    {
+        thread::spawn(move || {
+            let _entered = deadlines.enter();
+            while !stop.load(Ordering::Relaxed) {
+                match self.server.recv_timeout(Duration::from_secs(1)) {
+                    Ok(Some(request)) => {
+                        let source = line_handler.source(request.remote_addr().map(|x| x.ip()));
+                        let lines = Lines {
+                            handler: &line_handler,
+                            source: &source,
+                        };
+                        handle_request(request, &middleware, lines)
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::warn!("failed to receive HTTP request: {}", e),
+                }
+            }
+        })
+    }
+}
+
+fn handle_request<M>(mut request: Request, middleware: &Shared<M>, lines: Lines<'_>)
+where
+    M: Middleware,
+{
+    let status = if *request.method() != Method::Post {
+        405
+    } else if request.body_length().unwrap_or(0) > MAX_BODY_SIZE {
+        413
+    } else {
+        let mut body = Vec::new();
+        match request
+            .as_reader()
+            .take(MAX_BODY_SIZE as u64 + 1)
+            .read_to_end(&mut body)
+        {
+            Ok(_) if body.len() > MAX_BODY_SIZE => 413,
+            #[cfg(feature = "otlp")]
+            Ok(_) if request.url().split('?').next() == Some("/v1/metrics") => {
+                return handle_otlp_request(request, &body, middleware, lines);
+            }
+            Ok(_) => {
+                lines.submit_all(middleware, &body);
+                204
+            }
+            Err(e) => {
+                log::warn!("failed to read HTTP request body: {}", e);
+                400
+            }
+        }
+    };
+
+    if let Err(e) = request.respond(Response::empty(status)) {
+        log::debug!("failed to respond to HTTP request: {}", e);
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn handle_otlp_request<M>(request: Request, body: &[u8], middleware: &Shared<M>, lines: Lines<'_>)
+where
+    M: Middleware,
+{
+    use crate::middleware::otlp::ExportMetricsServiceRequest;
+
+    let content_type = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Content-Type"))
+        .map(|header| header.value.as_str())
+        .unwrap_or_default();
+    let is_json = content_type.starts_with("application/json");
+    let response = if !is_json && !content_type.starts_with("application/x-protobuf") {
+        log::debug!("rejected OTLP request with content type {:?}", content_type);
+        Response::from_string(
+            "unsupported content type, use application/json or application/x-protobuf",
+        )
+        .with_status_code(415)
+    } else {
+        let export = if is_json {
+            ExportMetricsServiceRequest::from_json(body).map_err(|e| e.to_string())
+        } else {
+            ExportMetricsServiceRequest::from_protobuf(body).map_err(|e| e.to_string())
+        };
+        match export {
+            Ok(export) => {
+                let mut middleware = middleware.lock();
+                for line in export.to_lines() {
+                    lines.submit(&mut *middleware, &line);
+                }
+                // An empty ExportMetricsServiceResponse, in the encoding of the request.
+                let (response_type, response_body) = if is_json {
+                    ("application/json", &b"{}"[..])
+                } else {
+                    ("application/x-protobuf", &b""[..])
+                };
+                let header = tiny_http::Header::from_bytes("Content-Type", response_type)
+                    .expect("valid header");
+                Response::from_data(response_body)
+                    .with_header(header)
+                    .with_status_code(200)
+            }
+            Err(e) => Response::from_string(e).with_status_code(400),
+        }
+    };
+
+    if let Err(e) = request.respond(response) {
+        log::debug!("failed to respond to HTTP request: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::testutils::FnStep;
+    use crate::types::Metric;
+
+    struct TestListener {
+        addr: SocketAddr,
+        results: Arc<Mutex<Vec<Metric>>>,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl TestListener {
+        fn spawn() -> Self {
+            let results = Arc::new(Mutex::new(vec![]));
+            let middleware = Shared::new(FnStep({
+                let results = Arc::clone(&results);
+                move |metric: &mut Metric| results.lock().unwrap().push(metric.clone())
+            }));
+            let line_handler = Arc::new(LineHandler::new(&ServerConfig::default()).unwrap());
+            let stop = Arc::new(AtomicBool::new(false));
+            let listener = HttpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.server.server_addr().to_ip().unwrap();
+            let thread = listener.spawn(
+                middleware,
+                Deadlines::new(),
+                line_handler,
+                Arc::clone(&stop),
+            );
+            TestListener {
+                addr,
+                results,
+                stop,
+                thread: Some(thread),
+            }
+        }
+
+        /// Send a request and return the status code, the headers and the body of the response.
+        fn request(&self, head: &str, body: &[u8]) -> (u16, String, Vec<u8>) {
+            let mut stream = TcpStream::connect(self.addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            write!(stream, "{}\r\nConnection: close\r\n\r\n", head).unwrap();
+            stream.write_all(body).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+
+            let split = response
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+                .unwrap();
+            let headers = String::from_utf8(response[..split].to_vec()).unwrap();
+            let status = headers.split(' ').nth(1).unwrap().parse().unwrap();
+            (status, headers, response[split + 4..].to_vec())
+        }
+
+        fn results(&self) -> Vec<Metric> {
+            self.results.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for TestListener {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+
+    #[test]
+    fn post_lines() {
+        let listener = TestListener::spawn();
+        let body = b"users.online:1|c\nrequests:2|c|#route:api\n";
+        let (status, _, _) = listener.request(
+            &format!("POST / HTTP/1.1\r\nContent-Length: {}", body.len()),
+            body,
+        );
+        assert_eq!(status, 204);
+        assert_eq!(
+            listener.results(),
+            [
+                Metric::new(b"users.online:1|c".to_vec()),
+                Metric::new(b"requests:2|c|#route:api".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let listener = TestListener::spawn();
+        let (status, _, _) = listener.request("GET / HTTP/1.1", b"");
+        assert_eq!(status, 405);
+        assert!(listener.results().is_empty());
+    }
+
+    #[test]
+    fn body_too_large() {
+        let listener = TestListener::spawn();
+        // The listener rejects the request by its Content-Length, without reading the body.
+        let (status, _, _) = listener.request(
+            &format!("POST / HTTP/1.1\r\nContent-Length: {}", MAX_BODY_SIZE + 1),
+            b"users.online:1|c\n",
+        );
+        assert_eq!(status, 413);
+        assert!(listener.results().is_empty());
+    }
+
+    #[cfg(feature = "otlp")]
+    const OTLP_JSON: &str = r#"{"resourceMetrics": [{"scopeMetrics": [{"metrics": [
+      {"name": "requests", "sum": {
+        "aggregationTemporality": 1, "isMonotonic": true, "dataPoints": [{"asInt": "3"}]
+      }}
+    ]}]}]}"#;
+
+    #[cfg(feature = "otlp")]
+    fn otlp_request(content_type: &str, body: &[u8]) -> String {
+        format!(
+            "POST /v1/metrics?source=test HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}",
+            content_type,
+            body.len()
+        )
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn otlp_json() {
+        let listener = TestListener::spawn();
+        let body = OTLP_JSON.as_bytes();
+        let (status, headers, response) =
+            listener.request(&otlp_request("application/json", body), body);
+        assert_eq!(status, 200);
+        assert!(headers.contains("Content-Type: application/json"));
+        assert_eq!(response, b"{}");
+        assert_eq!(listener.results(), [Metric::new(b"requests:3|c".to_vec())]);
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn otlp_protobuf() {
+        use opentelemetry_proto::tonic::collector::metrics::v1 as collector;
+        use opentelemetry_proto::tonic::metrics::v1 as proto;
+        use prost::Message;
+
+        let request = collector::ExportMetricsServiceRequest {
+            resource_metrics: vec![proto::ResourceMetrics {
+                scope_metrics: vec![proto::ScopeMetrics {
+                    metrics: vec![proto::Metric {
+                        name: "requests".to_owned(),
+                        data: Some(proto::metric::Data::Sum(proto::Sum {
+                            data_points: vec![proto::NumberDataPoint {
+                                value: Some(proto::number_data_point::Value::AsInt(3)),
+                                ..Default::default()
+                            }],
+                            aggregation_temporality: 1,
+                            is_monotonic: true,
+                        })),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        let listener = TestListener::spawn();
+        let body = request.encode_to_vec();
+        let (status, headers, response) =
+            listener.request(&otlp_request("application/x-protobuf", &body), &body);
+        assert_eq!(status, 200);
+        assert!(headers.contains("Content-Type: application/x-protobuf"));
+        assert!(response.is_empty());
+        assert_eq!(listener.results(), [Metric::new(b"requests:3|c".to_vec())]);
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn otlp_unsupported_content_type() {
+        let listener = TestListener::spawn();
+        let body = OTLP_JSON.as_bytes();
+        let (status, _, _) = listener.request(&otlp_request("text/plain", body), body);
+        assert_eq!(status, 415);
+        assert!(listener.results().is_empty());
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn otlp_invalid_body() {
+        let listener = TestListener::spawn();
+        for content_type in ["application/json", "application/x-protobuf"] {
+            let (status, _, _) = listener.request(&otlp_request(content_type, b"\xff"), b"\xff");
+            assert_eq!(status, 400);
+        }
+        assert!(listener.results().is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use crate::config::MaxTagsConfig;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+use anyhow::Error;
+
+pub struct MaxTags<M> {
+    max_tags: usize,
+    keep: Vec<Vec<u8>>,
+    drop: Vec<Vec<u8>>,
+    next: M,
+}
+
+impl<M> MaxTags<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: MaxTagsConfig, next: M) -> Self {
+        Self {
+            max_tags: config.max_tags,
+            keep: config.keep.into_iter().map(String::into_bytes).collect(),
+            drop: config.drop.into_iter().map(String::into_bytes).collect(),
+            next,
+        }
+    }
+
+    /// The order in which a tag is dropped, lower values first.
+    fn drop_order(&self, name: &[u8], position: usize) -> (u8, usize) {
+        if let Some(i) = self.drop.iter().position(|x| x == name) {
+            (0, i)
+        } else if let Some(i) = self.keep.iter().position(|x| x == name) {
+            (2, usize::MAX - i)
+        } else {
+            (1, usize::MAX - position)
+        }
+    }
+}
+
+impl<M> Middleware for MaxTags<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let tags: Vec<_> = metric
+            .tags_iter()
+            .filter(|tag| !tag.raw.is_empty())
+            .collect();
+        if tags.len() <= self.max_tags {
+            return self.next.submit(metric);
+        }
+
+        let mut order: Vec<usize> = (0..tags.len()).collect();
+        order.sort_by_key(|&i| self.drop_order(tags[i].name(), i));
+        let mut dropped = vec![false; tags.len()];
+        for &i in &order[..tags.len() - self.max_tags] {
+            log::debug!("max_tags: Dropping tag {:?}", tags[i].name());
+            dropped[i] = true;
+        }
+
+        let mut rewritten_metric = metric.clone();
+        rewritten_metric.set_tags_from_iter(
+            tags.into_iter()
+                .zip(dropped)
+                .filter(|(_, dropped)| !dropped)
+                .map(|(tag, _)| tag),
+        );
+        self.next.submit(&mut rewritten_metric)
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = MaxTagsConfig {
+            max_tags: 3,
+            keep: vec!["env".to_string(), "service".to_string()],
+            drop: vec!["pod".to_string(), "host".to_string()],
+        };
+
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut max_tags = MaxTags::new(config, next);
+
+        max_tags.submit(&mut Metric::new(
+            b"servers.online:1|c|#env:prod,pod:a,service:api".to_vec(),
+        ));
+        // Explicitly listed tags go first, then the last other tags.
+        max_tags.submit(&mut Metric::new(
+            b"servers.online:1|c|#host:x,env:prod,a:1,pod:a,service:api,b:2".to_vec(),
+        ));
+        max_tags.submit(&mut Metric::new(
+            b"servers.online:1|c|#host:x,env:prod,a:1,pod:a,service:api,b:2,c:3".to_vec(),
+        ));
+        max_tags.max_tags = 1;
+        // Tags to keep are dropped last.
+        max_tags.submit(&mut Metric::new(
+            b"servers.online:1|c|#service:api,b:2,env:prod,other".to_vec(),
+        ));
+
+        assert_eq!(
+            results.borrow().as_slice(),
+            &[
+                Metric::new(b"servers.online:1|c|#env:prod,pod:a,service:api".to_vec()),
+                Metric::new(b"servers.online:1|c|#env:prod,a:1,service:api".to_vec()),
+                Metric::new(b"servers.online:1|c|#env:prod,a:1,service:api".to_vec()),
+                Metric::new(b"servers.online:1|c|#env:prod".to_vec()),
+            ]
+        );
+    }
+}
@@ -3,6 +3,8 @@ use anyhow::Error;
 use crate::middleware::Middleware;
 use crate::types::Metric;
 
+/// Sends every metric to both `next` and `next2`. `next` gets a copy of the metric, so that its
+/// changes to the metric don't leak into `next2`.
 pub struct Mirror<M, M2> {
     next: M,
     next2: M2,
@@ -31,8 +33,38 @@ where
     }
 
     fn submit(&mut self, metric: &mut Metric) {
-        self.next.submit(metric);
-        // XXX: if next modifies the metric, it will be noticeable in next2
+        self.next.submit(&mut metric.clone());
         self.next2.submit(metric);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn copies() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            metric.append_tags(b"branch:1");
+            results.borrow_mut().push(metric.clone());
+        });
+        let next2 = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut mirror = Mirror::new(next, next2);
+
+        mirror.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+
+        assert_eq!(
+            results.into_inner(),
+            [
+                Metric::new(b"users.online:1|c|#branch:1".to_vec()),
+                Metric::new(b"users.online:1|c".to_vec()),
+            ]
+        );
+    }
+}
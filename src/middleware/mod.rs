@@ -3,19 +3,81 @@ use anyhow::Error;
 use crate::types::Metric;
 
 pub mod add_tag;
+pub mod add_timestamp;
 pub mod aggregate;
+pub mod allow_metric;
 pub mod allow_tag;
+pub mod byte_budget;
+pub mod canary;
 pub mod cardinality_limit;
+pub mod catalog;
+pub mod clean_tags;
+pub mod compare;
+pub mod cumulative_counters;
+pub mod dedupe_gauges;
+pub mod deny_metric;
 pub mod deny_tag;
+pub mod derive_rate;
+pub mod downgrade_dogstatsd;
+pub mod duplicate_tags;
+pub mod events;
+pub mod exec;
+pub mod exempt;
+pub mod failover;
+pub mod file_output;
+pub mod filter_type;
+pub mod graphite;
+pub mod max_tags;
 pub mod mirror;
+pub mod name_to_tags;
+pub mod normalize_sample_rate;
+pub mod observe;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+#[cfg(feature = "otlp")]
+pub mod otlp_export;
+#[cfg(feature = "otlp")]
+pub mod otlp_grpc;
+pub mod peer_forward;
+pub mod print;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod rate_limit;
+pub mod relay;
+#[cfg(feature = "http")]
+pub mod remote_write;
+pub mod rewrite_name;
+pub mod route;
+pub mod route_rules;
 pub mod sample;
+pub mod sanitize;
+pub mod schedule;
+pub mod scheduler;
+#[cfg(feature = "cli")]
+pub mod scrub_tags;
+pub mod sharded;
+pub mod shared;
+pub mod suggest;
 pub mod tag_cardinality_limit;
+pub mod tcp_upstream;
+pub mod truncate;
 pub mod upstream;
+pub mod usage;
+pub mod validate_values;
 
+#[cfg(feature = "http")]
+pub mod admin;
+#[cfg(feature = "http")]
+pub mod http;
 #[cfg(feature = "cli")]
 pub mod server;
+#[cfg(feature = "cli")]
+pub mod stream;
 
-impl Middleware for Box<dyn Middleware> {
+impl<M> Middleware for Box<M>
+where
+    M: Middleware + ?Sized,
+{
     fn join(&mut self) -> Result<(), Error> {
         self.as_mut().join()
     }
@@ -25,6 +87,9 @@ impl Middleware for Box<dyn Middleware> {
     fn submit(&mut self, metric: &mut Metric) {
         self.as_mut().submit(metric)
     }
+    fn submit_batch(&mut self, metrics: &mut [Metric]) {
+        self.as_mut().submit_batch(metrics)
+    }
 }
 
 pub trait Middleware {
@@ -33,4 +98,11 @@ pub trait Middleware {
     }
     fn poll(&mut self) {}
     fn submit(&mut self, metric: &mut Metric);
+    /// Submit several metrics received at once. Middlewares that can process metrics more
+    /// efficiently in bulk may override this, by default each metric is submitted individually.
+    fn submit_batch(&mut self, metrics: &mut [Metric]) {
+        for metric in metrics {
+            self.submit(metric);
+        }
+    }
 }
@@ -0,0 +1,155 @@
+use anyhow::Error;
+
+use crate::config::NameToTagsConfig;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+enum Segment {
+    Literal(Vec<u8>),
+    /// `*`, any segment, which is kept in the name.
+    Any,
+    /// `{key}`, any segment, which becomes the value of the tag `key`.
+    Tag(Vec<u8>),
+}
+
+/// Parse a template like `api.{endpoint}.*.count`.
+fn parse_template(template: &str) -> Vec<Segment> {
+    template
+        .split('.')
+        .map(
+            |segment| match segment.strip_prefix('{').and_then(|x| x.strip_suffix('}')) {
+                Some(key) => Segment::Tag(key.as_bytes().to_vec()),
+                None if segment == "*" => Segment::Any,
+                None => Segment::Literal(segment.as_bytes().to_vec()),
+            },
+        )
+        .collect()
+}
+
+/// Turns positional segments of Graphite-style names into tags, e.g. with the template
+/// `api.{endpoint}.{status}.count`, `api.users.200.count:1|c` becomes
+/// `api.count:1|c|#endpoint:users,status:200`, so that tag-based middlewares apply to metrics
+/// of legacy emitters. Names are matched by the first template with as many segments and the same
+/// literal segments. Metrics matching no template are passed on unchanged.
+pub struct NameToTags<M> {
+    templates: Vec<Vec<Segment>>,
+    next: M,
+}
+
+impl<M> NameToTags<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: NameToTagsConfig, next: M) -> Self {
+        NameToTags {
+            templates: config.templates.iter().map(|x| parse_template(x)).collect(),
+            next,
+        }
+    }
+
+    /// The new name and the tags of `name`, by the first matching template.
+    fn promote(&self, name: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let segments: Vec<&[u8]> = name.split(|&x| x == b'.').collect();
+        let template = self.templates.iter().find(|template| {
+            template.len() == segments.len()
+                && template.iter().zip(&segments).all(|(x, segment)| match x {
+                    Segment::Literal(literal) => literal == segment,
+                    Segment::Any | Segment::Tag(_) => true,
+                })
+        })?;
+
+        let mut new_name = Vec::new();
+        let mut tags = Vec::new();
+        for (x, segment) in template.iter().zip(segments) {
+            match x {
+                Segment::Literal(_) | Segment::Any => {
+                    if !new_name.is_empty() {
+                        new_name.push(b'.');
+                    }
+                    new_name.extend(segment);
+                }
+                Segment::Tag(key) => {
+                    if !tags.is_empty() {
+                        tags.push(b',');
+                    }
+                    tags.extend(key);
+                    tags.push(b':');
+                    tags.extend(segment);
+                }
+            }
+        }
+        Some((new_name, tags))
+    }
+}
+
+impl<M> Middleware for NameToTags<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let promoted = metric.name().and_then(|name| {
+            let (new_name, tags) = self.promote(name)?;
+            Some((name.len(), new_name, tags))
+        });
+        if let Some((name_len, new_name, tags)) = promoted {
+            let mut raw = new_name;
+            raw.extend(&metric.raw[name_len..]);
+            *metric = Metric::new(raw);
+            metric.append_tags(&tags);
+        }
+        self.next.submit(metric)
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn name_to_tags() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let config = NameToTagsConfig {
+            templates: vec![
+                "api.{endpoint}.{status}.count".to_string(),
+                "servers.{host}.*.*".to_string(),
+            ],
+        };
+        let mut name_to_tags = NameToTags::new(config, next);
+
+        for raw in [
+            "api.users.200.count:1|c",
+            "api.users.200.count:1|c|@0.5|#env:prod",
+            "api.users.count:1|c",
+            "servers.web-1.cpu.idle:93|g",
+            "invalid",
+        ] {
+            name_to_tags.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+
+        assert_eq!(
+            results.into_inner(),
+            [
+                "api.count:1|c|#endpoint:users,status:200",
+                "api.count:1|c|@0.5|#env:prod,endpoint:users,status:200",
+                "api.users.count:1|c",
+                "servers.cpu.idle:93|g|#host:web-1",
+                "invalid",
+            ]
+            .map(|raw| Metric::new(raw.as_bytes().to_vec()))
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::Error;
+
+use crate::config::NormalizeSampleRateConfig;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Scales the values of sampled counters up by their sample rate and removes the sample rate, e.g.
+/// `requests:1|c|@0.5` becomes `requests:2|c`, so that `aggregate-metrics` and servers ignoring
+/// sample rates count them correctly. Counters with values that aren't numbers, and all other
+/// types, are passed on unchanged.
+pub struct NormalizeSampleRate<M> {
+    next: M,
+}
+
+impl<M> NormalizeSampleRate<M>
+where
+    M: Middleware,
+{
+    pub fn new(_config: NormalizeSampleRateConfig, next: M) -> Self {
+        NormalizeSampleRate { next }
+    }
+}
+
+/// The counter with its values scaled up by `rate` and without its sample rate.
+fn normalize(metric: &Metric, rate: f64) -> Option<Metric> {
+    let name_and_value = metric.name_and_value()?;
+    let name = metric.name()?;
+    // Counters may hold several values, e.g. `requests:1:2|c`.
+    let values = name_and_value.get(name.len() + 1..)?;
+    let mut raw = name.to_vec();
+    for value in values.split(|&x| x == b':') {
+        let value: f64 = std::str::from_utf8(value).ok()?.parse().ok()?;
+        raw.push(b':');
+        raw.extend((value / rate).to_string().into_bytes());
+    }
+    for section in metric.raw.split(|&x| x == b'|').skip(1) {
+        if !section.starts_with(b"@") {
+            raw.push(b'|');
+            raw.extend(section);
+        }
+    }
+    Some(Metric::new(raw))
+}
+
+impl<M> Middleware for NormalizeSampleRate<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if metric.ty() == Some(b"c") {
+            if let Some(rate) = metric.sample_rate() {
+                if let Some(normalized) = normalize(metric, rate) {
+                    *metric = normalized;
+                }
+            }
+        }
+        self.next.submit(metric)
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn normalize() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut normalize = NormalizeSampleRate::new(NormalizeSampleRateConfig {}, next);
+
+        for raw in [
+            "requests:1|c|@0.5",
+            "requests:1:3|c|@0.25|#a:b",
+            "requests:1|c|#a:b|@0.1",
+            "requests:1|c",
+            "requests:abc|c|@0.5",
+            "request.duration:12|ms|@0.5",
+        ] {
+            normalize.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+
+        assert_eq!(
+            results.into_inner(),
+            [
+                "requests:2|c",
+                "requests:4:12|c|#a:b",
+                "requests:10|c|#a:b",
+                "requests:1|c",
+                "requests:abc|c|@0.5",
+                "request.duration:12|ms|@0.5",
+            ]
+            .map(|raw| Metric::new(raw.as_bytes().to_vec()))
+        );
+    }
+}
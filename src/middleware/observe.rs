@@ -0,0 +1,261 @@
+//! Observe mode, to roll out middlewares without affecting traffic: every middleware processes a
+//! copy of the metrics, and what it would have changed is counted and reported, while the
+//! original metrics are forwarded unchanged.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use anyhow::Error;
+
+use crate::middleware::shared::Shared;
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::Metric;
+
+// Examples are cut off after this many bytes.
+const MAX_EXAMPLE_LENGTH: usize = 300;
+
+/// What a middleware would have done to a metric.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    Dropped,
+    Modified,
+    /// Emitted a metric of its own, e.g. a flushed aggregate.
+    Added,
+}
+
+impl Change {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Change::Dropped => "dropped",
+            Change::Modified => "modified",
+            Change::Added => "added",
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportEntry {
+    /// The position of the middleware in `middlewares`.
+    pub position: usize,
+    pub middleware: String,
+    pub change: Change,
+    pub count: u64,
+    /// The last metric with this change, e.g. `users.online:1|c|#user_id:1 -> users.online:1|c`
+    /// for a modification.
+    pub example: String,
+}
+
+// The count and last example, keyed by position, middleware and change.
+type Report = BTreeMap<(usize, String, Change), (u64, String)>;
+static REPORT: Mutex<Report> = Mutex::new(BTreeMap::new());
+
+/// Everything the observed middlewares would have changed since startup, ordered by their
+/// position.
+pub fn report() -> Vec<ReportEntry> {
+    REPORT
+        .lock()
+        .unwrap()
+        .iter()
+        .map(
+            |((position, middleware, change), (count, example))| ReportEntry {
+                position: *position,
+                middleware: middleware.clone(),
+                change: *change,
+                count: *count,
+                example: example.clone(),
+            },
+        )
+        .collect()
+}
+
+fn example(raw: &[u8]) -> String {
+    String::from_utf8_lossy(&raw[..raw.len().min(MAX_EXAMPLE_LENGTH)]).into_owned()
+}
+
+/// The bottom of an observed middleware, collecting what it emits.
+#[derive(Default)]
+pub struct Collector(Vec<Metric>);
+
+impl Middleware for Collector {
+    fn submit(&mut self, metric: &mut Metric) {
+        self.0.push(metric.clone());
+    }
+}
+
+/// Runs `inner`, a middleware forwarding into `collected`, on a copy of every metric, and forwards
+/// the original metric to `next`. Changes are counted locally and added to the report and the
+/// `observe.changes` self metric on every poll.
+pub struct Observe<M, N> {
+    position: usize,
+    name: String,
+    inner: M,
+    collected: Shared<Collector>,
+    next: N,
+    changes: BTreeMap<Change, (u64, String)>,
+}
+
+impl<M, N> Observe<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    pub fn new(
+        position: usize,
+        name: &str,
+        inner: M,
+        collected: Shared<Collector>,
+        next: N,
+    ) -> Self {
+        Observe {
+            position,
+            name: name.to_owned(),
+            inner,
+            collected,
+            next,
+            changes: BTreeMap::new(),
+        }
+    }
+
+    fn count(&mut self, change: Change, example: String) {
+        let entry = self.changes.entry(change).or_default();
+        entry.0 += 1;
+        entry.1 = example;
+    }
+
+    /// Count everything `inner` emitted since the last call as added.
+    fn count_added(&mut self) {
+        let collected = std::mem::take(&mut self.collected.lock().0);
+        for metric in collected {
+            self.count(Change::Added, example(&metric.raw));
+        }
+    }
+
+    fn report(&mut self) {
+        self.count_added();
+        if self.changes.is_empty() {
+            return;
+        }
+        let position = self.position.to_string();
+        let mut report = REPORT.lock().unwrap();
+        for (change, (count, example)) in std::mem::take(&mut self.changes) {
+            let entry = report
+                .entry((self.position, self.name.clone(), change))
+                .or_default();
+            entry.0 += count;
+            entry.1 = example;
+            self_metrics::incr(
+                "observe.changes",
+                &[
+                    ("middleware", &self.name),
+                    ("position", &position),
+                    ("change", change.as_str()),
+                ],
+                count,
+            );
+        }
+    }
+}
+
+impl<M, N> Middleware for Observe<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    fn poll(&mut self) {
+        self.inner.poll();
+        self.report();
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        self.inner.submit(&mut metric.clone());
+        let mut collected = std::mem::take(&mut self.collected.lock().0);
+        match collected.iter().position(|x| x.raw == metric.raw) {
+            Some(unchanged) => {
+                collected.remove(unchanged);
+            }
+            None if collected.is_empty() => self.count(Change::Dropped, example(&metric.raw)),
+            None => {
+                let modified = collected.remove(0);
+                let example = format!("{} -> {}", example(&metric.raw), example(&modified.raw));
+                self.count(Change::Modified, example);
+            }
+        }
+        for added in collected {
+            self.count(Change::Added, example(&added.raw));
+        }
+        self.next.submit(metric)
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.inner.join()?;
+        self.report();
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::config::DenyTagConfig;
+    use crate::middleware::deny_tag::DenyTag;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn observe() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let collected = Shared::new(Collector::default());
+        let deny_tag = DenyTag::new(
+            DenyTagConfig {
+                tags: vec!["user_id".to_owned()],
+                values: vec![],
+            },
+            collected.clone(),
+        )
+        .unwrap();
+        let mut observe = Observe::new(7, "observe-test", deny_tag, collected, next);
+
+        let metrics = [
+            "users.online:1|c|#user_id:1,env:prod",
+            "users.online:1|c|#env:prod",
+            "users.online:1|c|#user_id:2",
+        ];
+        for raw in metrics {
+            observe.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        observe.poll();
+
+        // All metrics are forwarded unchanged.
+        assert_eq!(
+            *results.borrow(),
+            metrics.map(|raw| Metric::new(raw.as_bytes().to_vec()))
+        );
+        let report: Vec<_> = report()
+            .into_iter()
+            .filter(|entry| entry.middleware == "observe-test")
+            .collect();
+        assert_eq!(
+            report,
+            [ReportEntry {
+                position: 7,
+                middleware: "observe-test".to_owned(),
+                change: Change::Modified,
+                count: 2,
+                example: "users.online:1|c|#user_id:2 -> users.online:1|c".to_owned(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,491 @@
+//! Translation of OTLP metric export requests into dogstatsd lines, so that OpenTelemetry SDKs can
+//! send metrics through the same middlewares. Requests are accepted over OTLP/HTTP, in the JSON or
+//! protobuf encoding, and over OTLP/gRPC.
+//!
+//! Gauges and non-monotonic sums become gauges, monotonic delta sums become counters, and
+//! histograms are split into `<name>.count` and `<name>.sum`. Cumulative values become gauges, as
+//! statsd counters are always deltas. Resource and data point attributes become tags.
+
+use opentelemetry_proto::tonic::collector::metrics::v1 as collector;
+use opentelemetry_proto::tonic::common::v1 as common;
+use opentelemetry_proto::tonic::metrics::v1 as proto;
+use prost::Message;
+use serde::Deserialize;
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportMetricsServiceRequest {
+    resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct ResourceMetrics {
+    resource: Resource,
+    scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct ScopeMetrics {
+    metrics: Vec<OtlpMetric>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct OtlpMetric {
+    name: String,
+    gauge: Option<NumberPoints>,
+    sum: Option<Sum>,
+    histogram: Option<Histogram>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct NumberPoints {
+    data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct Sum {
+    data_points: Vec<NumberDataPoint>,
+    aggregation_temporality: u8,
+    is_monotonic: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct Histogram {
+    data_points: Vec<HistogramDataPoint>,
+    aggregation_temporality: u8,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct NumberDataPoint {
+    attributes: Vec<KeyValue>,
+    as_double: Option<f64>,
+    // 64-bit integers are encoded as strings in OTLP/JSON.
+    as_int: Option<Number>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct HistogramDataPoint {
+    attributes: Vec<KeyValue>,
+    count: Option<Number>,
+    sum: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    String(String),
+}
+
+impl Number {
+    /// The number as it goes into a dogstatsd line. Integers are kept as they are rather than
+    /// converted to floats, which cannot represent all of them above 2^53.
+    fn format(&self) -> Option<String> {
+        match self {
+            Number::Int(x) => Some(x.to_string()),
+            Number::Uint(x) => Some(x.to_string()),
+            Number::Float(x) => Some(x.to_string()),
+            Number::String(x) => {
+                if let Ok(x) = x.parse::<i64>() {
+                    Some(x.to_string())
+                } else if let Ok(x) = x.parse::<u64>() {
+                    Some(x.to_string())
+                } else {
+                    x.parse::<f64>().ok().map(|x| x.to_string())
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct AnyValue {
+    string_value: Option<String>,
+    bool_value: Option<bool>,
+    int_value: Option<Number>,
+    double_value: Option<f64>,
+}
+
+impl AnyValue {
+    fn to_string(&self) -> Option<String> {
+        if let Some(x) = &self.string_value {
+            Some(x.clone())
+        } else if let Some(x) = self.bool_value {
+            Some(x.to_string())
+        } else if let Some(x) = &self.int_value {
+            x.format()
+        } else {
+            self.double_value.map(|x| x.to_string())
+        }
+    }
+}
+
+// AGGREGATION_TEMPORALITY_DELTA in the OTLP protocol definition.
+const TEMPORALITY_DELTA: u8 = 1;
+
+/// Replace bytes that have a meaning in the dogstatsd format.
+fn sanitize(value: &str, extra: &[char]) -> String {
+    value
+        .replace(['|', ',', '#', '\n'], "_")
+        .replace(extra, "_")
+}
+
+fn format_line(name: &str, value: &str, ty: &str, tags: &[String]) -> Vec<u8> {
+    let mut line = format!("{}:{}|{}", sanitize(name, &[':', '@']), value, ty);
+    if !tags.is_empty() {
+        line.push_str("|#");
+        line.push_str(&tags.join(","));
+    }
+    line.into_bytes()
+}
+
+fn attributes_to_tags(resource: &[KeyValue], attributes: &[KeyValue]) -> Vec<String> {
+    resource
+        .iter()
+        .chain(attributes)
+        .filter_map(|kv| {
+            let value = kv.value.to_string()?;
+            Some(format!(
+                "{}:{}",
+                sanitize(&kv.key, &[':']),
+                sanitize(&value, &[])
+            ))
+        })
+        .collect()
+}
+
+impl ExportMetricsServiceRequest {
+    pub fn from_json(body: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(body)
+    }
+
+    pub fn from_protobuf(body: &[u8]) -> Result<Self, prost::DecodeError> {
+        collector::ExportMetricsServiceRequest::decode(body).map(Self::from)
+    }
+
+    /// Convert all data points in the request to dogstatsd lines.
+    pub fn to_lines(&self) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        for resource_metrics in &self.resource_metrics {
+            let resource = &resource_metrics.resource.attributes;
+            for metric in resource_metrics
+                .scope_metrics
+                .iter()
+                .flat_map(|scope| &scope.metrics)
+            {
+                if let Some(gauge) = &metric.gauge {
+                    for point in &gauge.data_points {
+                        if let Some(value) = point.value() {
+                            let tags = attributes_to_tags(resource, &point.attributes);
+                            lines.push(format_line(&metric.name, &value, "g", &tags));
+                        }
+                    }
+                }
+
+                if let Some(sum) = &metric.sum {
+                    let ty = if sum.is_monotonic && sum.aggregation_temporality == TEMPORALITY_DELTA
+                    {
+                        "c"
+                    } else {
+                        "g"
+                    };
+                    for point in &sum.data_points {
+                        if let Some(value) = point.value() {
+                            let tags = attributes_to_tags(resource, &point.attributes);
+                            lines.push(format_line(&metric.name, &value, ty, &tags));
+                        }
+                    }
+                }
+
+                if let Some(histogram) = &metric.histogram {
+                    let ty = if histogram.aggregation_temporality == TEMPORALITY_DELTA {
+                        "c"
+                    } else {
+                        "g"
+                    };
+                    for point in &histogram.data_points {
+                        let tags = attributes_to_tags(resource, &point.attributes);
+                        if let Some(count) = point.count.as_ref().and_then(Number::format) {
+                            let name = format!("{}.count", metric.name);
+                            lines.push(format_line(&name, &count, ty, &tags));
+                        }
+                        if let Some(sum) = point.sum {
+                            let name = format!("{}.sum", metric.name);
+                            lines.push(format_line(&name, &sum.to_string(), ty, &tags));
+                        }
+                    }
+                }
+            }
+        }
+        lines
+    }
+}
+
+impl From<collector::ExportMetricsServiceRequest> for ExportMetricsServiceRequest {
+    fn from(request: collector::ExportMetricsServiceRequest) -> Self {
+        ExportMetricsServiceRequest {
+            resource_metrics: request
+                .resource_metrics
+                .into_iter()
+                .map(|resource_metrics| ResourceMetrics {
+                    resource: Resource {
+                        attributes: resource_metrics
+                            .resource
+                            .map(|resource| key_values(resource.attributes))
+                            .unwrap_or_default(),
+                    },
+                    scope_metrics: resource_metrics
+                        .scope_metrics
+                        .into_iter()
+                        .map(|scope| ScopeMetrics {
+                            metrics: scope.metrics.into_iter().map(OtlpMetric::from).collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<proto::Metric> for OtlpMetric {
+    fn from(metric: proto::Metric) -> Self {
+        let mut result = OtlpMetric {
+            name: metric.name,
+            ..Default::default()
+        };
+        match metric.data {
+            Some(proto::metric::Data::Gauge(gauge)) => {
+                result.gauge = Some(NumberPoints {
+                    data_points: number_data_points(gauge.data_points),
+                });
+            }
+            Some(proto::metric::Data::Sum(sum)) => {
+                result.sum = Some(Sum {
+                    data_points: number_data_points(sum.data_points),
+                    aggregation_temporality: temporality(sum.aggregation_temporality),
+                    is_monotonic: sum.is_monotonic,
+                });
+            }
+            Some(proto::metric::Data::Histogram(histogram)) => {
+                result.histogram = Some(Histogram {
+                    data_points: histogram
+                        .data_points
+                        .into_iter()
+                        .map(|point| HistogramDataPoint {
+                            attributes: key_values(point.attributes),
+                            count: Some(Number::Uint(point.count)),
+                            sum: point.sum,
+                        })
+                        .collect(),
+                    aggregation_temporality: temporality(histogram.aggregation_temporality),
+                });
+            }
+            // Like in the JSON encoding, other kinds of metrics are skipped.
+            _ => {}
+        }
+        result
+    }
+}
+
+fn temporality(value: i32) -> u8 {
+    u8::try_from(value).unwrap_or_default()
+}
+
+fn number_data_points(points: Vec<proto::NumberDataPoint>) -> Vec<NumberDataPoint> {
+    points
+        .into_iter()
+        .map(|point| {
+            let (as_double, as_int) = match point.value {
+                Some(proto::number_data_point::Value::AsDouble(x)) => (Some(x), None),
+                Some(proto::number_data_point::Value::AsInt(x)) => (None, Some(Number::Int(x))),
+                None => (None, None),
+            };
+            NumberDataPoint {
+                attributes: key_values(point.attributes),
+                as_double,
+                as_int,
+            }
+        })
+        .collect()
+}
+
+fn key_values(attributes: Vec<common::KeyValue>) -> Vec<KeyValue> {
+    attributes
+        .into_iter()
+        .map(|kv| {
+            let mut value = AnyValue::default();
+            match kv.value.and_then(|x| x.value) {
+                Some(common::any_value::Value::StringValue(x)) => value.string_value = Some(x),
+                Some(common::any_value::Value::BoolValue(x)) => value.bool_value = Some(x),
+                Some(common::any_value::Value::IntValue(x)) => {
+                    value.int_value = Some(Number::Int(x))
+                }
+                Some(common::any_value::Value::DoubleValue(x)) => value.double_value = Some(x),
+                // Arrays, key-value lists and bytes have no tag representation.
+                _ => {}
+            }
+            KeyValue { key: kv.key, value }
+        })
+        .collect()
+}
+
+impl NumberDataPoint {
+    fn value(&self) -> Option<String> {
+        match self.as_double {
+            Some(x) => Some(x.to_string()),
+            None => self.as_int.as_ref().and_then(Number::format),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic() {
+        let request = ExportMetricsServiceRequest::from_json(
+            br#"{
+              "resourceMetrics": [{
+                "resource": {"attributes": [{"key": "service.name", "value": {"stringValue": "api"}}]},
+                "scopeMetrics": [{"metrics": [
+                  {"name": "requests", "sum": {
+                    "aggregationTemporality": 1, "isMonotonic": true,
+                    "dataPoints": [{"asInt": "3", "attributes": [{"key": "status", "value": {"intValue": "200"}}]}]
+                  }},
+                  {"name": "memory", "gauge": {"dataPoints": [{"asDouble": 1.5}]}},
+                  {"name": "bytes", "gauge": {"dataPoints": [{"asInt": "9007199254740993"}]}},
+                  {"name": "latency", "histogram": {
+                    "aggregationTemporality": 1,
+                    "dataPoints": [{"count": "2", "sum": 0.25}]
+                  }}
+                ]}]
+              }]
+            }"#,
+        )
+        .unwrap();
+
+        let lines: Vec<String> = request
+            .to_lines()
+            .into_iter()
+            .map(|line| String::from_utf8(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "requests:3|c|#service.name:api,status:200",
+                "memory:1.5|g|#service.name:api",
+                "bytes:9007199254740993|g|#service.name:api",
+                "latency.count:2|c|#service.name:api",
+                "latency.sum:0.25|c|#service.name:api",
+            ]
+        );
+    }
+
+    #[test]
+    fn protobuf() {
+        let attribute = |key: &str, value| common::KeyValue {
+            key: key.to_owned(),
+            value: Some(common::AnyValue { value: Some(value) }),
+        };
+        let number = |value| proto::NumberDataPoint {
+            value: Some(value),
+            ..Default::default()
+        };
+        let request = collector::ExportMetricsServiceRequest {
+            resource_metrics: vec![proto::ResourceMetrics {
+                resource: Some(opentelemetry_proto::tonic::resource::v1::Resource {
+                    attributes: vec![attribute(
+                        "service.name",
+                        common::any_value::Value::StringValue("api".to_owned()),
+                    )],
+                    ..Default::default()
+                }),
+                scope_metrics: vec![proto::ScopeMetrics {
+                    metrics: vec![
+                        proto::Metric {
+                            name: "requests".to_owned(),
+                            data: Some(proto::metric::Data::Sum(proto::Sum {
+                                data_points: vec![proto::NumberDataPoint {
+                                    attributes: vec![attribute(
+                                        "status",
+                                        common::any_value::Value::IntValue(200),
+                                    )],
+                                    ..number(proto::number_data_point::Value::AsInt(3))
+                                }],
+                                aggregation_temporality: 1,
+                                is_monotonic: true,
+                            })),
+                            ..Default::default()
+                        },
+                        proto::Metric {
+                            name: "bytes".to_owned(),
+                            data: Some(proto::metric::Data::Gauge(proto::Gauge {
+                                data_points: vec![number(proto::number_data_point::Value::AsInt(
+                                    9007199254740993,
+                                ))],
+                            })),
+                            ..Default::default()
+                        },
+                        proto::Metric {
+                            name: "latency".to_owned(),
+                            data: Some(proto::metric::Data::Histogram(proto::Histogram {
+                                data_points: vec![proto::HistogramDataPoint {
+                                    count: 2,
+                                    sum: Some(0.25),
+                                    ..Default::default()
+                                }],
+                                aggregation_temporality: 2,
+                            })),
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+
+        let lines: Vec<String> =
+            ExportMetricsServiceRequest::from_protobuf(&request.encode_to_vec())
+                .unwrap()
+                .to_lines()
+                .into_iter()
+                .map(|line| String::from_utf8(line).unwrap())
+                .collect();
+        assert_eq!(
+            lines,
+            [
+                "requests:3|c|#service.name:api,status:200",
+                "bytes:9007199254740993|g|#service.name:api",
+                "latency.count:2|g|#service.name:api",
+                "latency.sum:0.25|g|#service.name:api",
+            ]
+        );
+        assert!(ExportMetricsServiceRequest::from_protobuf(b"\xff").is_err());
+    }
+}
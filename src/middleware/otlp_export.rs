@@ -0,0 +1,462 @@
+//! Translation of dogstatsd metrics into OTLP export requests (in the OTLP/HTTP JSON encoding),
+//! sent to an OpenTelemetry collector.
+//!
+//! Counters become monotonic delta sums, unless a negative value was received, gauges become
+//! gauges, and timers, histograms and distributions become delta histograms with a single bucket,
+//! carrying their count, sum, minimum and maximum. Tags become attributes.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+use serde_json::{json, Value};
+
+use crate::config::OtlpExportConfig;
+use crate::drops::{self, DropReason};
+use crate::http_client::Sender;
+use crate::middleware::failover::SendErrors;
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::Metric;
+
+const HEADERS: [(&str, &str); 1] = [("Content-Type", "application/json")];
+// Collectors limit the size of requests, by default to a few MiB.
+const MAX_POINTS_PER_REQUEST: usize = 5000;
+
+// The kind of a series, ordered so that the series of one metric are grouped by kind.
+const SUM: u8 = 0;
+const GAUGE: u8 = 1;
+const HISTOGRAM: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+enum Point {
+    Sum(f64),
+    Gauge(f64),
+    Histogram {
+        count: f64,
+        sum: f64,
+        min: f64,
+        max: f64,
+    },
+}
+
+// The name, kind and attributes of a series.
+type SeriesKey = (String, u8, Vec<(String, String)>);
+// Keyed by series, so that the points of one metric are adjacent.
+type Points = BTreeMap<SeriesKey, Point>;
+
+struct State {
+    points: Points,
+    // Wall clock times in nanoseconds since the UNIX epoch.
+    start_time: u128,
+    last_flush_at: Instant,
+    warned_full: bool,
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn key_values<'a, I>(attributes: I) -> Vec<Value>
+where
+    I: IntoIterator<Item = &'a (String, String)>,
+{
+    attributes
+        .into_iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+/// Split tags like `name:value` into attributes, keeping the first of duplicate names.
+fn attributes<'a, I>(tags: I) -> Vec<(String, String)>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut attributes: Vec<(String, String)> = Vec::new();
+    for tag in tags {
+        let mut parts = tag.splitn(2, |&x| x == b':');
+        let name = String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+        let value = String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+        if !name.is_empty() && !attributes.iter().any(|(x, _)| *x == name) {
+            attributes.push((name, value));
+        }
+    }
+    attributes
+}
+
+/// Encode an `ExportMetricsServiceRequest` with the points of one flush.
+fn encode_request(
+    resource_attributes: &[(String, String)],
+    points: &[(&SeriesKey, &Point)],
+    start_time: u128,
+    time: u128,
+) -> Vec<u8> {
+    let mut metrics = Vec::new();
+    let mut i = 0;
+    while i < points.len() {
+        let (name, kind, _) = points[i].0;
+        let end = i + points[i..]
+            .iter()
+            .take_while(|((x, y, _), _)| x == name && y == kind)
+            .count();
+        let data_points: Vec<Value> = points[i..end]
+            .iter()
+            .map(|((_, _, attributes), point)| {
+                let mut data_point = json!({
+                    "attributes": key_values(attributes),
+                    "startTimeUnixNano": start_time.to_string(),
+                    "timeUnixNano": time.to_string(),
+                });
+                match point {
+                    Point::Sum(value) | Point::Gauge(value) => {
+                        data_point["asDouble"] = json!(value);
+                    }
+                    Point::Histogram {
+                        count,
+                        sum,
+                        min,
+                        max,
+                    } => {
+                        let count = (count.round() as u64).to_string();
+                        data_point["count"] = json!(count);
+                        data_point["sum"] = json!(sum);
+                        data_point["min"] = json!(min);
+                        data_point["max"] = json!(max);
+                        data_point["bucketCounts"] = json!([count]);
+                        data_point["explicitBounds"] = json!([]);
+                    }
+                }
+                data_point
+            })
+            .collect();
+        let metric = match *kind {
+            SUM => {
+                let monotonic = points[i..end]
+                    .iter()
+                    .all(|(_, point)| matches!(point, Point::Sum(x) if *x >= 0.0));
+                json!({"name": name, "sum": {
+                    "aggregationTemporality": 1,
+                    "isMonotonic": monotonic,
+                    "dataPoints": data_points,
+                }})
+            }
+            GAUGE => json!({"name": name, "gauge": {"dataPoints": data_points}}),
+            _ => json!({"name": name, "histogram": {
+                "aggregationTemporality": 1,
+                "dataPoints": data_points,
+            }}),
+        };
+        metrics.push(metric);
+        i = end;
+    }
+
+    json!({"resourceMetrics": [{
+        "resource": {"attributes": key_values(resource_attributes)},
+        "scopeMetrics": [{
+            "scope": {"name": "statsdproxy", "version": env!("CARGO_PKG_VERSION")},
+            "metrics": metrics,
+        }],
+    }]})
+    .to_string()
+    .into_bytes()
+}
+
+/// The metrics sent to one collector, shared by all chains sending to it, so that every series is
+/// sent once per flush even with worker threads.
+struct Exporter {
+    url: String,
+    config: OtlpExportConfig,
+    resource_attributes: Vec<(String, String)>,
+    state: Mutex<State>,
+    sender: Sender,
+}
+
+static EXPORTERS: Mutex<Vec<Weak<Exporter>>> = Mutex::new(Vec::new());
+
+impl Exporter {
+    fn shared(url: &str, config: &OtlpExportConfig) -> Result<Arc<Self>, Error> {
+        let mut exporters = EXPORTERS.lock().unwrap();
+        exporters.retain(|exporter| exporter.strong_count() > 0);
+        if let Some(exporter) = exporters
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|exporter| exporter.url == url)
+        {
+            return Ok(exporter);
+        }
+
+        let exporter = Arc::new(Exporter {
+            url: url.to_owned(),
+            config: config.clone(),
+            resource_attributes: attributes(
+                config.resource_attributes.iter().map(|x| x.as_bytes()),
+            ),
+            state: Mutex::new(State {
+                points: BTreeMap::new(),
+                start_time: unix_nanos(),
+                last_flush_at: Instant::now(),
+                warned_full: false,
+            }),
+            sender: Sender::new(url, HEADERS.to_vec(), Duration::from_secs(config.timeout))?,
+        });
+        exporters.push(Arc::downgrade(&exporter));
+        Ok(exporter)
+    }
+
+    fn record(&self, metric: &Metric) {
+        let (Some(name), Some(ty), Some(name_and_value)) =
+            (metric.name(), metric.ty(), metric.name_and_value())
+        else {
+            drops::record(DropReason::Malformed, &metric.raw);
+            return;
+        };
+        let kind = match ty {
+            b"c" => SUM,
+            b"g" => GAUGE,
+            b"ms" | b"h" | b"d" => HISTOGRAM,
+            _ => {
+                drops::record(DropReason::Unsupported, &metric.raw);
+                return;
+            }
+        };
+        // Timers may carry several values, like `name:1:2:3|ms`.
+        let raw_values = name_and_value.get(name.len() + 1..).unwrap_or_default();
+        let Some(values) = raw_values
+            .split(|&x| x == b':')
+            .map(|x| std::str::from_utf8(x).ok()?.parse::<f64>().ok())
+            .collect::<Option<Vec<f64>>>()
+            .filter(|values| !values.is_empty())
+        else {
+            drops::record(DropReason::Malformed, &metric.raw);
+            return;
+        };
+        let weight = 1.0 / metric.sample_rate().unwrap_or(1.0);
+
+        let key = (
+            String::from_utf8_lossy(name).into_owned(),
+            kind,
+            attributes(metric.tags_iter().map(|tag| tag.raw)),
+        );
+        let mut state = self.state.lock().unwrap();
+        if !state.points.contains_key(&key) && state.points.len() >= self.config.max_series {
+            if !state.warned_full {
+                log::warn!(
+                    "OTLP export has {} series, dropping metrics of new series until the next flush",
+                    state.points.len()
+                );
+                state.warned_full = true;
+            }
+            drops::record(DropReason::Cardinality, &metric.raw);
+            return;
+        }
+        let point = state.points.entry(key).or_insert(match kind {
+            SUM => Point::Sum(0.0),
+            GAUGE => Point::Gauge(0.0),
+            _ => Point::Histogram {
+                count: 0.0,
+                sum: 0.0,
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+            },
+        });
+        match point {
+            Point::Sum(sum) => *sum += values[0] * weight,
+            Point::Gauge(gauge) if matches!(raw_values.first(), Some(b'+' | b'-')) => {
+                *gauge += values[0]
+            }
+            Point::Gauge(gauge) => *gauge = values[0],
+            Point::Histogram {
+                count,
+                sum,
+                min,
+                max,
+            } => {
+                for value in values {
+                    *count += weight;
+                    *sum += value * weight;
+                    *min = min.min(value);
+                    *max = max.max(value);
+                }
+            }
+        }
+    }
+
+    /// The requests to send the points recorded since the previous flush.
+    fn flush(&self) -> Vec<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.last_flush_at = Instant::now();
+        state.warned_full = false;
+        let time = unix_nanos();
+        let start_time = std::mem::replace(&mut state.start_time, time);
+        let points = std::mem::take(&mut state.points);
+        drop(state);
+
+        let points: Vec<_> = points.iter().collect();
+        points
+            .chunks(MAX_POINTS_PER_REQUEST)
+            .map(|chunk| encode_request(&self.resource_attributes, chunk, start_time, time))
+            .collect()
+    }
+}
+
+/// Sends metrics to an OpenTelemetry collector over OTLP/HTTP, in the JSON encoding.
+///
+/// Metrics are aggregated for `flush_interval` and then sent in one request: counters are added
+/// up, the last value of a gauge is kept, and the values of timers are summarized. Sets are
+/// dropped, since OTLP has no equivalent.
+pub struct OtlpExport {
+    exporter: Arc<Exporter>,
+}
+
+impl OtlpExport {
+    pub fn new(url: &str, config: &OtlpExportConfig) -> Result<Self, Error> {
+        Ok(OtlpExport {
+            exporter: Exporter::shared(url, config)?,
+        })
+    }
+}
+
+impl SendErrors for OtlpExport {
+    fn send_errors(&self) -> u64 {
+        self.exporter.sender.send_errors()
+    }
+}
+
+impl Middleware for OtlpExport {
+    fn submit(&mut self, metric: &mut Metric) {
+        self.exporter.record(metric);
+    }
+
+    fn poll(&mut self) {
+        let exporter = &self.exporter;
+        let interval = Duration::from_secs(exporter.config.flush_interval);
+        if exporter.state.lock().unwrap().last_flush_at.elapsed() < interval {
+            return;
+        }
+        for body in exporter.flush() {
+            if !exporter.sender.send(body) {
+                log::warn!("OTLP collector is too slow, dropping a request");
+                self_metrics::incr("upstream.otlp_dropped_requests", &[], 1);
+            }
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        for body in self.exporter.flush() {
+            self.exporter.sender.send_now(&body);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export() {
+        let config = OtlpExportConfig {
+            resource_attributes: vec!["service.name:proxy".to_string()],
+            ..Default::default()
+        };
+        // Nothing listens on the discard port, and nothing is sent in this test.
+        let export = OtlpExport::new("http://127.0.0.1:9/v1/metrics", &config).unwrap();
+        let exporter = &export.exporter;
+        for raw in [
+            &b"requests:1|c|#env:prod"[..],
+            b"requests:2|c|@0.5|#env:prod",
+            b"requests:1|c|#env:dev,env:prod",
+            b"memory:10|g",
+            b"memory:-3|g",
+            b"latency:10:30|ms|#flag",
+            b"latency:20|ms|@0.5|#flag",
+            b"users:a|s",
+        ] {
+            exporter.record(&Metric::new(raw.to_vec()));
+        }
+
+        let requests = exporter.flush();
+        assert_eq!(requests.len(), 1);
+        let request: Value = serde_json::from_slice(&requests[0]).unwrap();
+        let resource_metrics = &request["resourceMetrics"][0];
+        assert_eq!(
+            resource_metrics["resource"]["attributes"],
+            json!([{"key": "service.name", "value": {"stringValue": "proxy"}}])
+        );
+        let metrics = &resource_metrics["scopeMetrics"][0]["metrics"];
+        let data_point = |metric: &Value, kind: &str, i: usize| {
+            let mut data_point = metric[kind]["dataPoints"][i].clone();
+            let data_point = data_point.as_object_mut().unwrap();
+            assert!(data_point.remove("startTimeUnixNano").is_some());
+            assert!(data_point.remove("timeUnixNano").is_some());
+            Value::Object(data_point.clone())
+        };
+
+        assert_eq!(metrics[0]["name"], "latency");
+        assert_eq!(
+            data_point(&metrics[0], "histogram", 0),
+            json!({
+                "attributes": [{"key": "flag", "value": {"stringValue": ""}}],
+                "count": "4",
+                "sum": 80.0,
+                "min": 10.0,
+                "max": 30.0,
+                "bucketCounts": ["4"],
+                "explicitBounds": [],
+            })
+        );
+        assert_eq!(metrics[1]["name"], "memory");
+        assert_eq!(
+            data_point(&metrics[1], "gauge", 0),
+            json!({"attributes": [], "asDouble": 7.0})
+        );
+        assert_eq!(metrics[2]["name"], "requests");
+        assert_eq!(metrics[2]["sum"]["isMonotonic"], true);
+        assert_eq!(
+            data_point(&metrics[2], "sum", 0),
+            json!({"attributes": [{"key": "env", "value": {"stringValue": "dev"}}], "asDouble": 1.0})
+        );
+        assert_eq!(
+            data_point(&metrics[2], "sum", 1),
+            json!({"attributes": [{"key": "env", "value": {"stringValue": "prod"}}], "asDouble": 5.0})
+        );
+        assert_eq!(metrics.as_array().unwrap().len(), 3);
+
+        // Points are only sent once.
+        assert!(exporter.flush().is_empty());
+    }
+
+    #[test]
+    fn histogram_types() {
+        let config = OtlpExportConfig::default();
+        // Nothing listens on the discard port, and nothing is sent in this test.
+        let export = OtlpExport::new("http://127.0.0.1:9/v1/metrics", &config).unwrap();
+        let exporter = &export.exporter;
+        for raw in [
+            &b"queue.wait:3|d"[..],
+            b"request.size:512:256|h",
+            b"request.time:10|ms",
+        ] {
+            exporter.record(&Metric::new(raw.to_vec()));
+        }
+
+        let requests = exporter.flush();
+        let request: Value = serde_json::from_slice(&requests[0]).unwrap();
+        let metrics = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        for (i, name, count, sum) in [
+            (0, "queue.wait", "1", 3.0),
+            (1, "request.size", "2", 768.0),
+            (2, "request.time", "1", 10.0),
+        ] {
+            assert_eq!(metrics[i]["name"], name);
+            let data_point = &metrics[i]["histogram"]["dataPoints"][0];
+            assert_eq!(data_point["count"], count);
+            assert_eq!(data_point["sum"], sum);
+        }
+        assert_eq!(metrics.as_array().unwrap().len(), 3);
+    }
+}
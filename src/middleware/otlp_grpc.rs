@@ -0,0 +1,201 @@
+//! Ingest of OTLP/gRPC metric export requests, translated into dogstatsd lines by
+//! `crate::middleware::otlp`.
+//!
+//! gRPC requires an async runtime, so the listener runs a single-threaded tokio runtime on its own
+//! thread, which submits the translated metrics like the other listener threads do.
+
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_server::{
+    MetricsService, MetricsServiceServer,
+};
+use opentelemetry_proto::tonic::collector::metrics::v1::{
+    ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+
+use crate::line_handler::LineHandler;
+use crate::middleware::otlp;
+use crate::middleware::shared::Shared;
+use crate::middleware::stream::Lines;
+use crate::middleware::Middleware;
+use crate::timers::Deadlines;
+
+// Matching the limit on HTTP request bodies.
+const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+pub struct OtlpGrpcListener {
+    listener: TcpListener,
+}
+
+impl OtlpGrpcListener {
+    pub fn bind(listen: &str) -> Result<Self, Error> {
+        let listener = TcpListener::bind(listen)
+            .map_err(|e| anyhow!("failed to listen on {}: {}", listen, e))?;
+        listener.set_nonblocking(true)?;
+        Ok(OtlpGrpcListener { listener })
+    }
+
+    /// Handle requests in a background thread until `stop` is set. The middlewares schedule their
+    /// deadlines in `deadlines`.
+    pub fn spawn<M>(
+        self,
+        middleware: Shared<M>,
+        deadlines: Deadlines,
+        line_handler: Arc<LineHandler>,
+        stop: Arc<AtomicBool>,
+    ) -> JoinHandle<()>
+    where
+        M: Middleware + Send + 'static,
+    {
+        thread::spawn(move || {
+            let _entered = deadlines.enter();
+            if let Err(e) = self.serve(middleware, line_handler, &stop) {
+                log::error!("OTLP/gRPC listener failed: {}", e);
+            }
+        })
+    }
+
+    fn serve<M>(
+        self,
+        middleware: Shared<M>,
+        line_handler: Arc<LineHandler>,
+        stop: &AtomicBool,
+    ) -> Result<(), Error>
+    where
+        M: Middleware + Send + 'static,
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(self.listener)?;
+            let incoming = TcpIncoming::from_listener(listener, true, None)
+                .map_err(|e| anyhow!("failed to accept connections: {}", e))?;
+            let service = MetricsServiceServer::new(Service {
+                middleware,
+                line_handler,
+            })
+            .max_decoding_message_size(MAX_MESSAGE_SIZE);
+            let stopped = async {
+                while !stop.load(Ordering::Relaxed) {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            };
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(incoming, stopped)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+struct Service<M> {
+    middleware: Shared<M>,
+    line_handler: Arc<LineHandler>,
+}
+
+#[tonic::async_trait]
+impl<M> MetricsService for Service<M>
+where
+    M: Middleware + Send + 'static,
+{
+    async fn export(
+        &self,
+        request: Request<ExportMetricsServiceRequest>,
+    ) -> Result<Response<ExportMetricsServiceResponse>, Status> {
+        let source = self
+            .line_handler
+            .source(request.remote_addr().map(|x| x.ip()));
+        let lines = Lines {
+            handler: &self.line_handler,
+            source: &source,
+        };
+        let export = otlp::ExportMetricsServiceRequest::from(request.into_inner());
+        let mut middleware = self.middleware.lock();
+        for line in export.to_lines() {
+            lines.submit(&mut *middleware, &line);
+        }
+        Ok(Response::new(ExportMetricsServiceResponse {
+            partial_success: None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_client::MetricsServiceClient;
+    use opentelemetry_proto::tonic::metrics::v1::{
+        metric, number_data_point, Gauge, Metric as OtlpMetric, NumberDataPoint, ResourceMetrics,
+        ScopeMetrics,
+    };
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::testutils::FnStep;
+    use crate::types::Metric;
+
+    #[test]
+    fn export() {
+        let results = Arc::new(Mutex::new(vec![]));
+        let middleware = Shared::new(FnStep({
+            let results = Arc::clone(&results);
+            move |metric: &mut Metric| results.lock().unwrap().push(metric.clone())
+        }));
+        let line_handler = Arc::new(LineHandler::new(&ServerConfig::default()).unwrap());
+        let stop = Arc::new(AtomicBool::new(false));
+        let listener = OtlpGrpcListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.listener.local_addr().unwrap();
+        let thread = listener.spawn(
+            middleware,
+            Deadlines::new(),
+            line_handler,
+            Arc::clone(&stop),
+        );
+
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                scope_metrics: vec![ScopeMetrics {
+                    metrics: vec![OtlpMetric {
+                        name: "memory".to_owned(),
+                        data: Some(metric::Data::Gauge(Gauge {
+                            data_points: vec![NumberDataPoint {
+                                value: Some(number_data_point::Value::AsDouble(1.5)),
+                                ..Default::default()
+                            }],
+                        })),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut client = MetricsServiceClient::connect(format!("http://{}", addr))
+                    .await
+                    .unwrap();
+                client.export(request).await.unwrap();
+            });
+
+        stop.store(true, Ordering::Relaxed);
+        thread.join().unwrap();
+        assert_eq!(
+            *results.lock().unwrap(),
+            [Metric::new(b"memory:1.5|g".to_vec())]
+        );
+    }
+}
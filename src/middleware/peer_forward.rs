@@ -0,0 +1,196 @@
+use anyhow::Error;
+
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::Metric;
+
+/// The tag marking metrics forwarded by a peer. They are processed by the receiving instance
+/// whether it owns them or not, so that instances disagreeing on the peers during a change of
+/// config cannot forward metrics back and forth.
+pub const FORWARDED_TAG: &[u8] = b"statsdproxy_forwarded";
+
+/// The position of the peer owning metrics named `name` in `peers`, by rendezvous hashing: every
+/// peer scores the name, and the highest score wins. Adding or removing a peer only moves the
+/// names it wins or won.
+fn owner(peers: &[String], name: &[u8]) -> usize {
+    let mut best = (0, 0);
+    for (i, peer) in peers.iter().enumerate() {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(peer.as_bytes());
+        hasher.update(name);
+        let score = hasher.finalize();
+        if i == 0 || score > best.1 {
+            best = (i, score);
+        }
+    }
+    best.0
+}
+
+/// Distributes metrics among the statsdproxy instances in a cluster by name, so that every metric
+/// name is processed by a single instance, e.g. to aggregate it into one series. Metrics owned by
+/// this instance, `local`, go to `next`, and all others to the owning peer, tagged with
+/// `FORWARDED_TAG`. Forwarded metrics received from peers go to `next` without the tag.
+pub struct PeerForward<P, M> {
+    names: Vec<String>,
+    local: usize,
+    // One sink per entry in `names`. The one at `local` is never used.
+    peers: Vec<P>,
+    // Metrics forwarded to every peer since the last poll.
+    forwarded: Vec<u64>,
+    next: M,
+}
+
+impl<P, M> PeerForward<P, M>
+where
+    P: Middleware,
+    M: Middleware,
+{
+    /// `peers` holds the names, e.g. listen addresses, of all instances in the same order on
+    /// every instance, with a sink sending to each of them. `local` is the position of this
+    /// instance.
+    pub fn new(peers: Vec<(String, P)>, local: usize, next: M) -> Self {
+        assert!(
+            local < peers.len(),
+            "the local peer must be one of the peers"
+        );
+        let (names, peers): (Vec<_>, Vec<_>) = peers.into_iter().unzip();
+        PeerForward {
+            forwarded: vec![0; names.len()],
+            names,
+            local,
+            peers,
+            next,
+        }
+    }
+}
+
+impl<P, M> Middleware for PeerForward<P, M>
+where
+    P: Middleware,
+    M: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        for peer in &mut self.peers {
+            peer.join()?;
+        }
+        self.next.join()
+    }
+
+    fn poll(&mut self) {
+        for (i, peer) in self.peers.iter_mut().enumerate() {
+            let forwarded = std::mem::take(&mut self.forwarded[i]);
+            if forwarded > 0 {
+                self_metrics::incr(
+                    "cluster.forwarded_metrics",
+                    &[("peer", &self.names[i])],
+                    forwarded,
+                );
+            }
+            peer.poll();
+        }
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if metric.tags_iter().any(|tag| tag.raw == FORWARDED_TAG) {
+            let tags: Vec<Vec<u8>> = metric
+                .tags_iter()
+                .filter(|tag| tag.raw != FORWARDED_TAG)
+                .map(|tag| tag.raw.to_vec())
+                .collect();
+            metric.set_tags(&tags.join(&b","[..]));
+            return self.next.submit(metric);
+        }
+
+        let owner = owner(&self.names, metric.name().unwrap_or(&metric.raw));
+        if owner == self.local {
+            return self.next.submit(metric);
+        }
+        metric.append_tags(FORWARDED_TAG);
+        self.forwarded[owner] += 1;
+        self.peers[owner].submit(metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn forward() {
+        let names: Vec<String> = (1..=3).map(|i| format!("10.0.0.{i}:8125")).collect();
+        let results = RefCell::new(vec![]);
+        let sink = |name: &str| {
+            let results = &results;
+            let name = name.to_owned();
+            FnStep(move |metric: &mut Metric| {
+                let raw = String::from_utf8(metric.raw.clone()).unwrap();
+                results.borrow_mut().push((name.clone(), raw));
+            })
+        };
+        let peers = names
+            .iter()
+            .map(|name| (name.clone(), sink(name)))
+            .collect();
+        let mut forward = PeerForward::new(peers, 0, sink("local"));
+
+        let metric_names: Vec<String> = (0..30).map(|i| format!("metric.{i}")).collect();
+        for name in &metric_names {
+            forward.submit(&mut Metric::new(format!("{name}:1|c|#a:b").into_bytes()));
+        }
+        for raw in [
+            "metric.0:1|c|#a:b,statsdproxy_forwarded",
+            "metric.1:1|c|#statsdproxy_forwarded",
+        ] {
+            forward.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        drop(forward);
+
+        let results = results.into_inner();
+        for (name, (sink, raw)) in metric_names.iter().zip(&results) {
+            match owner(&names, name.as_bytes()) {
+                0 => {
+                    assert_eq!(sink, "local");
+                    assert_eq!(*raw, format!("{name}:1|c|#a:b"));
+                }
+                i => {
+                    assert_eq!(*sink, names[i]);
+                    assert_eq!(*raw, format!("{name}:1|c|#a:b,statsdproxy_forwarded"));
+                }
+            }
+        }
+        // Names are spread over all peers.
+        for name in &names[1..] {
+            assert!(results.iter().any(|(sink, _)| sink == name));
+        }
+        // Forwarded metrics are never forwarded again.
+        assert_eq!(
+            results[metric_names.len()..],
+            [
+                ("local".to_owned(), "metric.0:1|c|#a:b".to_owned()),
+                ("local".to_owned(), "metric.1:1|c".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn stable_owners() {
+        let names: Vec<String> = (1..=4).map(|i| format!("peer{i}")).collect();
+        let moved = (0..1000)
+            .map(|i| format!("metric.{i}"))
+            .filter(|name| {
+                let before = &names[owner(&names, name.as_bytes())];
+                let after = &names[..3][owner(&names[..3], name.as_bytes())];
+                before != after
+            })
+            .count();
+        // Only the names owned by the removed peer move.
+        let owned_by_removed = (0..1000)
+            .filter(|i| owner(&names, format!("metric.{i}").as_bytes()) == 3)
+            .count();
+        assert_eq!(moved, owned_by_removed);
+    }
+}
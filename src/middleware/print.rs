@@ -0,0 +1,124 @@
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+
+use crate::config::PrintConfig;
+use crate::middleware::failover::SendErrors;
+use crate::middleware::Middleware;
+use crate::token_bucket::TokenBucket;
+use crate::types::Metric;
+
+// How often the number of metrics suppressed by the rate limit is logged.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Prints metrics to a writer like standard output, one line each, e.g. to check what the
+/// middlewares emit interactively. At most `max_lines_per_second` metrics are printed, if set,
+/// and the rest are only counted in the log.
+pub struct Print<W> {
+    writer: W,
+    prefix: Vec<u8>,
+    limit: Option<TokenBucket>,
+    suppressed: u64,
+    last_reported_at: Instant,
+    write_errors: u64,
+}
+
+impl<W> Print<W>
+where
+    W: Write,
+{
+    pub fn new(config: &PrintConfig, writer: W) -> Self {
+        let now = Instant::now();
+        Print {
+            writer,
+            prefix: config.prefix.as_bytes().to_vec(),
+            limit: config
+                .max_lines_per_second
+                .map(|rate| TokenBucket::new(rate as f64, rate as f64, now)),
+            suppressed: 0,
+            last_reported_at: now,
+            write_errors: 0,
+        }
+    }
+
+    fn report_suppressed(&mut self) {
+        self.last_reported_at = Instant::now();
+        if self.suppressed > 0 {
+            log::warn!(
+                "not printing {} metrics over max_lines_per_second",
+                self.suppressed
+            );
+            self.suppressed = 0;
+        }
+    }
+}
+
+impl<W> SendErrors for Print<W>
+where
+    W: Write,
+{
+    fn send_errors(&self) -> u64 {
+        self.write_errors
+    }
+}
+
+impl<W> Middleware for Print<W>
+where
+    W: Write,
+{
+    fn submit(&mut self, metric: &mut Metric) {
+        if let Some(limit) = &mut self.limit {
+            if !limit.try_take(1.0, Instant::now()) {
+                self.suppressed += 1;
+                return;
+            }
+        }
+        let result = self
+            .writer
+            .write_all(&self.prefix)
+            .and_then(|()| self.writer.write_all(&metric.raw))
+            .and_then(|()| self.writer.write_all(b"\n"));
+        if let Err(e) = result {
+            // Only logged once, e.g. if the output was closed.
+            if self.write_errors == 0 {
+                log::error!("failed to print metrics: {}", e);
+            }
+            self.write_errors += 1;
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.last_reported_at.elapsed() >= REPORT_INTERVAL {
+            self.report_suppressed();
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.report_suppressed();
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic() {
+        let config = PrintConfig {
+            prefix: "out: ".to_string(),
+            max_lines_per_second: Some(2),
+        };
+        let mut output = Vec::new();
+        let mut print = Print::new(&config, &mut output);
+        for raw in ["a:1|c", "b:1|c|#env:prod", "c:1|c"] {
+            print.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        assert_eq!(print.suppressed, 1);
+        print.join().unwrap();
+        drop(print);
+        assert_eq!(output, b"out: a:1|c\nout: b:1|c|#env:prod\n");
+    }
+}
@@ -0,0 +1,778 @@
+//! A QUIC transport between statsdproxy instances, for links that lose packets.
+//!
+//! `QuicUpstream` sends metrics to the `QuicListener` of another instance over a single
+//! connection, whose congestion control paces all of them. Metrics in reliable classes are written
+//! to a stream per class, on which lost packets are retransmitted, and all other metrics are sent
+//! as unreliable datagrams of newline-separated lines, like over UDP. While no connection can be
+//! established, e.g. because the upstream is a plain statsd server, metrics are sent over UDP
+//! instead.
+//!
+//! quinn requires an async runtime, so both ends run a single-threaded tokio runtime on a thread
+//! of their own.
+
+use std::collections::VecDeque;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinSet};
+
+use crate::config::{QuicClassConfig, QuicUpstreamConfig, TlsListenConfig};
+use crate::drops::{self, DropReason};
+use crate::health;
+use crate::line_buffer::LineBuffer;
+use crate::line_handler::LineHandler;
+use crate::middleware::failover::SendErrors;
+use crate::middleware::shared::Shared;
+use crate::middleware::stream::{self, Lines, MAX_LINE_LENGTH};
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::timers::Deadlines;
+use crate::types::Metric;
+
+// Negotiated by both ends, so that other QUIC servers reject the connection.
+const ALPN: &[u8] = b"statsdproxy";
+
+// How long a connection attempt may take. Metrics are held back during the first attempt, and
+// sent over UDP during later attempts once one failed.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The delay before reconnecting doubles with every failed attempt, up to the maximum.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// The most metrics sent at once, and how often to check on the connection while none arrive.
+const MAX_BATCH: usize = 1000;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The size of datagrams sent over UDP instead, the smallest one QUIC requires paths to support.
+const UDP_DATAGRAM_SIZE: usize = 1200;
+
+// How long to wait on shutdown for the upstream to receive what was written to the streams.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait between checks whether the server is shutting down.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A metric to send, with the index of its class if that is reliable.
+struct Message {
+    stream: Option<usize>,
+    line: Vec<u8>,
+}
+
+/// Forwards metrics to another statsdproxy instance over QUIC, see the module documentation.
+///
+/// Metrics are handed to a background thread through a queue of `max_buffered_lines`, and dropped
+/// while it is full, e.g. because the congestion window of the connection does not allow sending
+/// them any faster.
+pub struct QuicUpstream {
+    classes: Vec<QuicClassConfig>,
+    sender: Option<mpsc::Sender<Message>>,
+    thread: Option<JoinHandle<()>>,
+    send_errors: Arc<AtomicU64>,
+}
+
+impl QuicUpstream {
+    /// Connect to `upstream` in `host:port` format, verifying its certificate against the name
+    /// `server_name` in `config` or else `host`.
+    pub fn new(
+        upstream: &str,
+        host: &str,
+        config: &QuicUpstreamConfig,
+        max_buffered_lines: usize,
+    ) -> Result<Self, Error> {
+        use std::fs::File;
+        use std::io::BufReader;
+
+        let addr = upstream
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("could not resolve address"))?;
+
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(&config.ca_path)?)) {
+            roots.add(cert?)?;
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("no certificates found in {}", config.ca_path));
+        }
+        let mut tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let server_name = config.server_name.as_deref().unwrap_or(host);
+        rustls::pki_types::ServerName::try_from(server_name)
+            .map_err(|_| anyhow!("invalid TLS server name {}", server_name))?;
+
+        let bind: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let mut endpoint = {
+            let _entered = runtime.enter();
+            quinn::Endpoint::client(bind)?
+        };
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls)?,
+        )));
+
+        let send_errors = Arc::new(AtomicU64::new(0));
+        let link = Link {
+            upstream: addr,
+            server_name: server_name.to_owned(),
+            endpoint,
+            udp: UdpSocket::bind(bind)?,
+            priorities: config.classes.iter().map(|class| class.priority).collect(),
+            state: State::Idle {
+                retry_at: Instant::now(),
+            },
+            fallback: false,
+            pending: VecDeque::new(),
+            max_pending: max_buffered_lines.max(1),
+            backoff: MIN_BACKOFF,
+            send_errors: Arc::clone(&send_errors),
+        };
+        let (sender, receiver) = mpsc::channel(max_buffered_lines.max(1));
+        let thread = thread::spawn(move || runtime.block_on(link.run(receiver)));
+
+        Ok(QuicUpstream {
+            classes: config.classes.clone(),
+            sender: Some(sender),
+            thread: Some(thread),
+            send_errors,
+        })
+    }
+}
+
+impl Drop for QuicUpstream {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+impl SendErrors for QuicUpstream {
+    fn send_errors(&self) -> u64 {
+        self.send_errors.load(Ordering::Relaxed)
+    }
+}
+
+impl Middleware for QuicUpstream {
+    fn submit(&mut self, metric: &mut Metric) {
+        let name = metric.name().unwrap_or_default();
+        let stream = self
+            .classes
+            .iter()
+            .position(|class| {
+                class
+                    .prefixes
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix.as_bytes()))
+            })
+            .filter(|&i| self.classes[i].reliable);
+        let message = Message {
+            stream,
+            line: metric.raw.clone(),
+        };
+        match &self.sender {
+            Some(sender) if sender.try_send(message).is_ok() => {}
+            _ => drops::record(DropReason::UpstreamUnavailable, &metric.raw),
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        // Closing the queue makes the thread send the remaining metrics and close the connection.
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            thread
+                .join()
+                .map_err(|_| anyhow!("QUIC upstream thread panicked"))?;
+        }
+        Ok(())
+    }
+}
+
+enum State {
+    Connected {
+        connection: quinn::Connection,
+        // The stream of every reliable class, opened with its first metric.
+        streams: Vec<Option<quinn::SendStream>>,
+    },
+    Connecting(task::JoinHandle<Result<quinn::Connection, Error>>),
+    Idle {
+        retry_at: Instant,
+    },
+}
+
+/// The background half of `QuicUpstream`, owning the connection.
+struct Link {
+    upstream: SocketAddr,
+    server_name: String,
+    endpoint: quinn::Endpoint,
+    udp: UdpSocket,
+    // The priority of the stream of every class.
+    priorities: Vec<i32>,
+    state: State,
+    // Whether the last connection attempt failed, so that metrics are sent over UDP meanwhile.
+    fallback: bool,
+    // Metrics held back while connecting, oldest first.
+    pending: VecDeque<Message>,
+    max_pending: usize,
+    backoff: Duration,
+    send_errors: Arc<AtomicU64>,
+}
+
+impl Link {
+    async fn run(mut self, mut receiver: mpsc::Receiver<Message>) {
+        let mut batch = Vec::new();
+        loop {
+            let received =
+                tokio::time::timeout(POLL_INTERVAL, receiver.recv_many(&mut batch, MAX_BATCH));
+            if received.await == Ok(0) {
+                break;
+            }
+            self.update().await;
+            self.send(std::mem::take(&mut batch)).await;
+        }
+        self.close().await;
+    }
+
+    /// Notice a lost connection, and start or finish a connection attempt.
+    async fn update(&mut self) {
+        if let State::Connected { connection, .. } = &self.state {
+            if let Some(reason) = connection.close_reason() {
+                log::error!(
+                    "lost connection to QUIC upstream {}: {}",
+                    self.upstream,
+                    reason
+                );
+                self.error();
+                self.state = State::Idle {
+                    retry_at: Instant::now(),
+                };
+            }
+        }
+        if let State::Idle { retry_at } = self.state {
+            if Instant::now() >= retry_at {
+                let connecting = self.endpoint.connect(self.upstream, &self.server_name);
+                self.state = State::Connecting(tokio::spawn(async move {
+                    tokio::time::timeout(CONNECT_TIMEOUT, connecting?)
+                        .await
+                        .map_err(|_| anyhow!("timed out"))?
+                        .map_err(Error::from)
+                }));
+            }
+        }
+        if let State::Connecting(attempt) = &mut self.state {
+            if attempt.is_finished() {
+                let result = attempt.await.map_err(Error::from).and_then(|x| x);
+                self.connected(result);
+            }
+        }
+    }
+
+    fn connected(&mut self, result: Result<quinn::Connection, Error>) {
+        match result {
+            Ok(connection) => {
+                log::info!("connected to QUIC upstream {}", self.upstream);
+                self.state = State::Connected {
+                    connection,
+                    streams: self.priorities.iter().map(|_| None).collect(),
+                };
+                self.fallback = false;
+                self.backoff = MIN_BACKOFF;
+            }
+            Err(e) => {
+                log::error!(
+                    "failed to connect to QUIC upstream {}, sending over UDP and retrying in \
+                     {:?}: {}",
+                    self.upstream,
+                    self.backoff,
+                    e
+                );
+                self_metrics::incr("upstream.connect_errors", &[], 1);
+                self.error();
+                self.state = State::Idle {
+                    retry_at: Instant::now() + self.backoff,
+                };
+                self.fallback = true;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+
+    fn error(&self) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+        health::upstream_result(false);
+    }
+
+    /// Send `batch` and the metrics held back so far, or hold them back while connecting.
+    async fn send(&mut self, batch: Vec<Message>) {
+        self.pending.extend(batch);
+        if let State::Connected { .. } = self.state {
+            let messages = std::mem::take(&mut self.pending);
+            match self.send_quic(&messages).await {
+                Ok(()) => health::upstream_result(true),
+                Err(e) => {
+                    log::error!("failed to send to QUIC upstream {}: {}", self.upstream, e);
+                    self_metrics::incr("upstream.send_errors", &[], 1);
+                    self.error();
+                    self.state = State::Idle {
+                        retry_at: Instant::now(),
+                    };
+                    // They are sent again once reconnected. Metrics the upstream received already
+                    // are sent twice, which is better than losing the others.
+                    self.pending = messages;
+                }
+            }
+        } else if self.fallback {
+            let messages = std::mem::take(&mut self.pending);
+            self.send_udp(&messages);
+        }
+
+        while self.pending.len() > self.max_pending {
+            let message = self.pending.pop_front().expect("not empty");
+            drops::record(DropReason::UpstreamUnavailable, &message.line);
+        }
+    }
+
+    async fn send_quic(&mut self, messages: &VecDeque<Message>) -> Result<(), Error> {
+        let State::Connected {
+            connection,
+            streams,
+        } = &mut self.state
+        else {
+            unreachable!("not connected");
+        };
+        let max_datagram_size = connection
+            .max_datagram_size()
+            .ok_or_else(|| anyhow!("the upstream does not accept datagrams"))?;
+
+        let mut reliable = vec![Vec::new(); streams.len()];
+        let mut datagram = Vec::new();
+        for message in messages {
+            let Some(i) = message.stream else {
+                if message.line.len() > max_datagram_size {
+                    self_metrics::incr("upstream.oversized_metrics", &[("action", "dropped")], 1);
+                    drops::record(DropReason::Malformed, &message.line);
+                } else if append(&mut datagram, &message.line, max_datagram_size) {
+                    connection.send_datagram(std::mem::take(&mut datagram).into())?;
+                    datagram.extend(&message.line);
+                }
+                continue;
+            };
+            reliable[i].extend(&message.line);
+            reliable[i].push(b'\n');
+        }
+        if !datagram.is_empty() {
+            connection.send_datagram(datagram.into())?;
+        }
+
+        for (i, buf) in reliable.into_iter().enumerate() {
+            if buf.is_empty() {
+                continue;
+            }
+            let stream = match &mut streams[i] {
+                Some(stream) => stream,
+                None => {
+                    let stream = connection.open_uni().await?;
+                    stream.set_priority(self.priorities[i])?;
+                    streams[i].insert(stream)
+                }
+            };
+            // Waits while the congestion window is full, until the queue fills up.
+            stream.write_all(&buf).await?;
+        }
+        Ok(())
+    }
+
+    fn send_udp(&self, messages: &VecDeque<Message>) {
+        let mut datagram = Vec::new();
+        for message in messages {
+            if append(&mut datagram, &message.line, UDP_DATAGRAM_SIZE) {
+                self.send_udp_datagram(&datagram);
+                datagram.clear();
+                datagram.extend(&message.line);
+            }
+        }
+        if !datagram.is_empty() {
+            self.send_udp_datagram(&datagram);
+        }
+    }
+
+    fn send_udp_datagram(&self, datagram: &[u8]) {
+        match self.udp.send_to(datagram, self.upstream) {
+            Ok(_) => health::upstream_result(true),
+            Err(e) => {
+                log::error!("failed to send to UDP upstream {}: {}", self.upstream, e);
+                self_metrics::incr("upstream.send_errors", &[], 1);
+                self.error();
+                for line in datagram.split(|&x| x == b'\n') {
+                    drops::record(DropReason::UpstreamUnavailable, line);
+                }
+            }
+        }
+    }
+
+    /// Send the remaining metrics, and close the connection once the upstream received them.
+    async fn close(mut self) {
+        // Rather than sending metrics over UDP to an upstream that may accept QUIC.
+        if let State::Connecting(attempt) = &mut self.state {
+            let result = attempt.await.map_err(Error::from).and_then(|x| x);
+            self.connected(result);
+        }
+        self.send(Vec::new()).await;
+        if !self.pending.is_empty() {
+            log::error!(
+                "dropping {} metrics that could not be sent to QUIC upstream {}",
+                self.pending.len(),
+                self.upstream
+            );
+            for message in &self.pending {
+                drops::record(DropReason::UpstreamUnavailable, &message.line);
+            }
+        }
+
+        if let State::Connected {
+            connection,
+            streams,
+        } = &mut self.state
+        {
+            let mut stopped = Vec::new();
+            for stream in streams.iter_mut().flatten() {
+                let _ = stream.finish();
+                stopped.push(stream.stopped());
+            }
+            // Closing the connection discards whatever is still in flight.
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+                for stopped in stopped {
+                    let _ = stopped.await;
+                }
+            })
+            .await;
+            connection.close(0u32.into(), b"shutdown");
+        }
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, self.endpoint.wait_idle()).await;
+    }
+}
+
+/// Append `line` to the newline-separated lines in `datagram`, unless it would grow beyond
+/// `max_size`. Returns whether `datagram` is full, in which case it is left unchanged.
+fn append(datagram: &mut Vec<u8>, line: &[u8], max_size: usize) -> bool {
+    if datagram.is_empty() {
+        datagram.extend(line);
+        return false;
+    }
+    if datagram.len() + 1 + line.len() > max_size {
+        return true;
+    }
+    datagram.push(b'\n');
+    datagram.extend(line);
+    false
+}
+
+/// Accepts connections from the `QuicUpstream`s of other statsdproxy instances.
+pub struct QuicListener {
+    socket: UdpSocket,
+    server_config: quinn::ServerConfig,
+}
+
+impl QuicListener {
+    pub fn bind(config: &TlsListenConfig) -> Result<Self, Error> {
+        let mut tls = stream::server_tls_config(config)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let server_config =
+            quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+        let socket = UdpSocket::bind(&config.listen)
+            .map_err(|e| anyhow!("failed to listen on {}: {}", config.listen, e))?;
+        Ok(QuicListener {
+            socket,
+            server_config,
+        })
+    }
+
+    /// Handle connections in a background thread until `stop` is set. The middlewares schedule
+    /// their deadlines in `deadlines`.
+    pub fn spawn<M>(
+        self,
+        middleware: Shared<M>,
+        deadlines: Deadlines,
+        line_handler: Arc<LineHandler>,
+        stop: Arc<AtomicBool>,
+    ) -> JoinHandle<()>
+    where
+        M: Middleware + Send + 'static,
+    {
+        thread::spawn(move || {
+            let _entered = deadlines.enter();
+            if let Err(e) = self.serve(middleware, line_handler, &stop) {
+                log::error!("QUIC listener failed: {}", e);
+            }
+        })
+    }
+
+    fn serve<M>(
+        self,
+        middleware: Shared<M>,
+        line_handler: Arc<LineHandler>,
+        stop: &AtomicBool,
+    ) -> Result<(), Error>
+    where
+        M: Middleware + Send + 'static,
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            let endpoint = quinn::Endpoint::new(
+                quinn::EndpointConfig::default(),
+                Some(self.server_config),
+                self.socket,
+                Arc::new(quinn::TokioRuntime),
+            )?;
+            let mut connections = JoinSet::new();
+            while !stop.load(Ordering::Relaxed) {
+                let Ok(incoming) = tokio::time::timeout(ACCEPT_INTERVAL, endpoint.accept()).await
+                else {
+                    continue;
+                };
+                let Some(incoming) = incoming else {
+                    break;
+                };
+                connections.spawn(handle_connection(
+                    incoming,
+                    middleware.clone(),
+                    Arc::clone(&line_handler),
+                ));
+            }
+
+            endpoint.close(0u32.into(), b"shutdown");
+            while connections.join_next().await.is_some() {}
+            endpoint.wait_idle().await;
+            Ok(())
+        })
+    }
+}
+
+async fn handle_connection<M>(
+    incoming: quinn::Incoming,
+    middleware: Shared<M>,
+    line_handler: Arc<LineHandler>,
+) where
+    M: Middleware + Send + 'static,
+{
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            log::warn!("failed to accept QUIC connection: {}", e);
+            return;
+        }
+    };
+
+    let mut readers = JoinSet::new();
+    readers.spawn(read_datagrams(
+        connection.clone(),
+        middleware.clone(),
+        Arc::clone(&line_handler),
+    ));
+    let error = loop {
+        match connection.accept_uni().await {
+            Ok(stream) => {
+                readers.spawn(read_stream(
+                    stream,
+                    connection.remote_address(),
+                    middleware.clone(),
+                    Arc::clone(&line_handler),
+                ));
+            }
+            Err(e) => break e,
+        }
+    };
+    while readers.join_next().await.is_some() {}
+
+    match error {
+        quinn::ConnectionError::ApplicationClosed(_) | quinn::ConnectionError::LocallyClosed => {}
+        e => log::warn!(
+            "QUIC connection from {} failed: {}",
+            connection.remote_address(),
+            e
+        ),
+    }
+}
+
+async fn read_datagrams<M>(
+    connection: quinn::Connection,
+    middleware: Shared<M>,
+    line_handler: Arc<LineHandler>,
+) where
+    M: Middleware,
+{
+    let source = line_handler.source(Some(connection.remote_address().ip()));
+    let lines = Lines {
+        handler: &line_handler,
+        source: &source,
+    };
+    while let Ok(datagram) = connection.read_datagram().await {
+        lines.submit_all(&middleware, &datagram);
+    }
+}
+
+async fn read_stream<M>(
+    mut stream: quinn::RecvStream,
+    addr: SocketAddr,
+    middleware: Shared<M>,
+    line_handler: Arc<LineHandler>,
+) where
+    M: Middleware,
+{
+    let source = line_handler.source(Some(addr.ip()));
+    let lines = Lines {
+        handler: &line_handler,
+        source: &source,
+    };
+    let mut buffer = LineBuffer::new(MAX_LINE_LENGTH);
+    while let Ok(Some(chunk)) = stream.read_chunk(MAX_LINE_LENGTH, true).await {
+        let mut middleware = middleware.lock();
+        buffer.push(&chunk.bytes, |line| lines.submit(&mut *middleware, line));
+    }
+    let mut middleware = middleware.lock();
+    buffer.finish(|line| lines.submit(&mut *middleware, line));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::testutils::FnStep;
+
+    /// A self-signed certificate for `localhost`, written to files.
+    fn certificate(name: &str) -> TlsListenConfig {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!(
+            "statsdproxy-quic-{}-{}.pem",
+            name,
+            std::process::id()
+        ));
+        let key_path = dir.join(format!(
+            "statsdproxy-quic-{}-{}.key",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        TlsListenConfig {
+            listen: "127.0.0.1:0".to_owned(),
+            cert_path: cert_path.to_str().unwrap().to_owned(),
+            key_path: key_path.to_str().unwrap().to_owned(),
+        }
+    }
+
+    fn upstream_config(listen: &TlsListenConfig) -> QuicUpstreamConfig {
+        QuicUpstreamConfig {
+            ca_path: listen.cert_path.clone(),
+            server_name: None,
+            classes: vec![QuicClassConfig {
+                prefixes: vec!["billing.".to_owned()],
+                reliable: true,
+                priority: 1,
+            }],
+        }
+    }
+
+    #[test]
+    fn forward() {
+        let results = Arc::new(Mutex::new(vec![]));
+        let middleware = Shared::new(FnStep({
+            let results = Arc::clone(&results);
+            move |metric: &mut Metric| results.lock().unwrap().push(metric.clone())
+        }));
+        let line_handler = Arc::new(LineHandler::new(&ServerConfig::default()).unwrap());
+        let stop = Arc::new(AtomicBool::new(false));
+        let listen = certificate("forward");
+        let listener = QuicListener::bind(&listen).unwrap();
+        let addr = listener.socket.local_addr().unwrap();
+        let listener_thread = listener.spawn(
+            middleware,
+            Deadlines::new(),
+            line_handler,
+            Arc::clone(&stop),
+        );
+
+        let mut upstream = QuicUpstream::new(
+            &addr.to_string(),
+            "localhost",
+            &upstream_config(&listen),
+            100,
+        )
+        .unwrap();
+        for raw in ["billing.a:1|c", "requests:1|c", "billing.b:2|c"] {
+            upstream.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+
+        // Datagrams are only sent while connected, and may be lost when closing the connection
+        // right after, so wait for them to arrive.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while results.lock().unwrap().len() < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        upstream.join().unwrap();
+        stop.store(true, Ordering::Relaxed);
+        listener_thread.join().unwrap();
+
+        let mut received: Vec<_> = results
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|x| x.raw.clone())
+            .collect();
+        received.sort();
+        assert_eq!(
+            received,
+            [
+                b"billing.a:1|c".to_vec(),
+                b"billing.b:2|c".to_vec(),
+                b"requests:1|c".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn udp_fallback() {
+        // A plain statsd server, which does not answer the handshake.
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(CONNECT_TIMEOUT * 2)).unwrap();
+        let listen = certificate("fallback");
+        let mut upstream = QuicUpstream::new(
+            &socket.local_addr().unwrap().to_string(),
+            "localhost",
+            &upstream_config(&listen),
+            100,
+        )
+        .unwrap();
+
+        // Once the handshake failed, the metrics held back meanwhile and later ones are sent over
+        // UDP, regardless of their class.
+        let mut buf = [0; 1500];
+        loop {
+            upstream.submit(&mut Metric::new(b"billing.a:1|c".to_vec()));
+            upstream.submit(&mut Metric::new(b"requests:1|c".to_vec()));
+            let (n, _) = socket.recv_from(&mut buf).unwrap();
+            // Skip the packets of the QUIC handshake.
+            if buf[..n].starts_with(b"billing.a:1|c\nrequests:1|c") {
+                break;
+            }
+        }
+        assert!(upstream.send_errors() > 0);
+    }
+}
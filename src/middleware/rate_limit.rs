@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use anyhow::Error;
+use regex::bytes::RegexSet;
+
+use crate::config::{RateLimitConfig, RateLimitKey};
+use crate::drops::{self, DropReason};
+use crate::middleware::deny_metric::name_matcher;
+use crate::middleware::Middleware;
+use crate::token_bucket::TokenBucket;
+use crate::types::Metric;
+
+// Once there are this many buckets, forget about those that have been quiet long enough for
+// their bucket to be full again.
+const PRUNE_BUCKETS_AT: usize = 10000;
+
+/// Caps how many metrics per second every metric name, or every series, may pass, so that a
+/// single chatty metric cannot use up the whole budget of the upstream. The rate is that of the
+/// first rule matching the name, or the default rate.
+pub struct RateLimit<M> {
+    rules: Vec<(RegexSet, f64)>,
+    default_per_second: Option<f64>,
+    per: RateLimitKey,
+    buckets: HashMap<Vec<u8>, TokenBucket>,
+    prune_buckets_at: usize,
+    next: M,
+}
+
+impl<M> RateLimit<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: RateLimitConfig, next: M) -> Result<Self, Error> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| Ok((name_matcher(&rule.names, &rule.patterns)?, rule.per_second)))
+            .collect::<Result<_, Error>>()?;
+        Ok(RateLimit {
+            rules,
+            default_per_second: config.default_per_second,
+            per: config.per,
+            buckets: HashMap::new(),
+            prune_buckets_at: PRUNE_BUCKETS_AT,
+            next,
+        })
+    }
+
+    /// Whether the metric is within its limit at `now`.
+    fn allow(&mut self, metric: &Metric, now: Instant) -> bool {
+        let Some(name) = metric.name() else {
+            return true;
+        };
+        let rate = self
+            .rules
+            .iter()
+            .find(|(matcher, _)| matcher.is_match(name))
+            .map(|(_, rate)| *rate)
+            .or(self.default_per_second);
+        let Some(rate) = rate else {
+            return true;
+        };
+
+        let mut key = name.to_vec();
+        if self.per == RateLimitKey::Series {
+            key.push(b'|');
+            key.extend_from_slice(metric.ty().unwrap_or_default());
+            let mut tags: Vec<_> = metric.tags_iter().map(|tag| tag.raw).collect();
+            tags.sort_unstable();
+            key.extend_from_slice(b"|#");
+            key.extend_from_slice(&tags.join(&b","[..]));
+        }
+
+        if self.buckets.len() >= self.prune_buckets_at {
+            self.buckets.retain(|_, bucket| !bucket.is_full(now));
+            self.prune_buckets_at = (self.buckets.len() * 2).max(PRUNE_BUCKETS_AT);
+        }
+        // Up to a second worth of metrics may pass at once.
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(rate.max(1.0), rate, now))
+            .try_take(1.0, now)
+    }
+}
+
+impl<M> Middleware for RateLimit<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.allow(metric, Instant::now()) {
+            self.next.submit(metric)
+        } else {
+            drops::record(DropReason::MetricRateLimited, &metric.raw);
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::RateLimitRuleConfig;
+    use crate::testutils::FnStep;
+
+    /// How many of `count` metrics `raw` are allowed at `now`.
+    fn passed<M: Middleware>(
+        limit: &mut RateLimit<M>,
+        raw: &str,
+        count: usize,
+        now: Instant,
+    ) -> usize {
+        (0..count)
+            .filter(|_| limit.allow(&Metric::new(raw.as_bytes().to_vec()), now))
+            .count()
+    }
+
+    #[test]
+    fn rate_limit() {
+        let config = RateLimitConfig {
+            rules: vec![
+                RateLimitRuleConfig {
+                    names: vec!["debug.*".to_owned()],
+                    patterns: vec![],
+                    per_second: 2.0,
+                },
+                RateLimitRuleConfig {
+                    names: vec!["slo.*".to_owned()],
+                    patterns: vec![],
+                    per_second: 1000.0,
+                },
+            ],
+            default_per_second: Some(5.0),
+            per: RateLimitKey::Name,
+        };
+        let mut limit = RateLimit::new(config.clone(), FnStep(|_: &mut Metric| {})).unwrap();
+        let now = Instant::now();
+        assert_eq!(passed(&mut limit, "debug.cache:1|c|#a:1", 10, now), 2);
+        // The limit is per name, regardless of tags.
+        assert_eq!(passed(&mut limit, "debug.cache:1|c|#a:2", 10, now), 0);
+        assert_eq!(passed(&mut limit, "slo.requests:1|c", 10, now), 10);
+        assert_eq!(passed(&mut limit, "requests:1|c", 10, now), 5);
+        let later = now + Duration::from_millis(500);
+        assert_eq!(passed(&mut limit, "debug.cache:1|c|#a:1", 10, later), 1);
+
+        let mut limit = RateLimit::new(
+            RateLimitConfig {
+                per: RateLimitKey::Series,
+                ..config
+            },
+            FnStep(|_: &mut Metric| {}),
+        )
+        .unwrap();
+        assert_eq!(passed(&mut limit, "debug.cache:1|c|#a:1,b:1", 10, now), 2);
+        assert_eq!(passed(&mut limit, "debug.cache:1|c|#b:1,a:1", 10, now), 0);
+        assert_eq!(passed(&mut limit, "debug.cache:1|c|#a:2", 10, now), 2);
+    }
+
+    #[test]
+    fn drops() {
+        let config = RateLimitConfig {
+            rules: vec![],
+            default_per_second: Some(1.0),
+            per: RateLimitKey::Name,
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut limit = RateLimit::new(config, next).unwrap();
+        for _ in 0..3 {
+            limit.submit(&mut Metric::new(b"requests:1|c".to_vec()));
+        }
+        assert_eq!(results.borrow().len(), 1);
+    }
+}
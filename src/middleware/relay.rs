@@ -0,0 +1,117 @@
+use anyhow::Error;
+
+use crate::config::{AggregateMetricsConfig, RelayConfig};
+use crate::middleware::aggregate::AggregateMetrics;
+use crate::middleware::upstream::Upstream;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// An aggregating relay: counters, gauges and timers are aggregated and forwarded to the upstream
+/// in batched datagrams. Metrics that fail to send are spooled to disk if configured, and
+/// joining the pipeline flushes everything it holds.
+///
+/// This is meant to be the bottom of a middleware chain, in place of a plain `Upstream`.
+pub struct RelayPipeline<M = Upstream> {
+    aggregate: AggregateMetrics<M>,
+}
+
+impl RelayPipeline<Upstream> {
+    pub fn from_config(config: &RelayConfig, mut upstream: Upstream) -> Self {
+        if let Some(spool) = &config.spool {
+            upstream = upstream.with_spool(spool);
+        }
+        Self::with_next(config, upstream)
+    }
+}
+
+impl<M> RelayPipeline<M>
+where
+    M: Middleware,
+{
+    /// Like `from_config`, but forwards to any middleware instead of a UDP upstream. The spool
+    /// settings of `config` are ignored.
+    pub fn with_next(config: &RelayConfig, next: M) -> Self {
+        let aggregate = AggregateMetrics::new(
+            AggregateMetricsConfig {
+                aggregate_counters: true,
+                aggregate_gauges: true,
+                aggregate_timers: true,
+                aggregate_sets: false,
+                max_set_size: 10000,
+                flush_interval: config.flush_interval,
+                flush_offset: config.flush_offset,
+                max_map_size: None,
+                overrides: vec![],
+                staleness: None,
+                timer_summary: None,
+            },
+            next,
+        );
+        RelayPipeline { aggregate }
+    }
+}
+
+impl<M> Middleware for RelayPipeline<M>
+where
+    M: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        self.aggregate.join()
+    }
+
+    fn poll(&mut self) {
+        self.aggregate.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        self.aggregate.submit(metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn flush_on_join() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let config = RelayConfig {
+            flush_interval: 3600,
+            flush_offset: 0,
+            spool: None,
+        };
+        let mut relay = RelayPipeline::from_config(
+            &config,
+            Upstream::new(upstream.local_addr().unwrap()).unwrap(),
+        );
+
+        relay.poll();
+        for raw in [
+            "users.online:1|c|#country:china",
+            "users.online:2|c|#country:china",
+            "request.duration:12|ms",
+            "request.duration:30|ms",
+        ] {
+            relay.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        relay.join().unwrap();
+
+        let mut buf = [0; 1024];
+        let len = upstream.recv(&mut buf).unwrap();
+        let mut lines: Vec<_> = std::str::from_utf8(&buf[..len]).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "request.duration:12:30|ms",
+                "users.online:3|c|#country:china"
+            ]
+        );
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+use prost::Message;
+
+use crate::config::RemoteWriteConfig;
+use crate::drops::{self, DropReason};
+use crate::http_client::Sender;
+use crate::middleware::failover::SendErrors;
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::Metric;
+
+// Prometheus accepts larger requests, but rejects requests beyond a few MiB.
+const MAX_SAMPLES_PER_REQUEST: usize = 2000;
+// The marker Prometheus uses to end a series before it would become stale on its own.
+const STALE_NAN: u64 = 0x7ff0000000000002;
+
+const HEADERS: [(&str, &str); 3] = [
+    ("Content-Encoding", "snappy"),
+    ("Content-Type", "application/x-protobuf"),
+    ("X-Prometheus-Remote-Write-Version", "0.1.0"),
+];
+
+// The messages of the remote write protocol, from `prompb/remote.proto` and `prompb/types.proto` in
+// the Prometheus repository, reduced to the fields sent here.
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// Encode a `WriteRequest` of the remote write protocol, with one sample per series.
+fn encode_write_request<'a, I>(series: I, timestamp_ms: i64) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a [(String, String)], f64)>,
+{
+    let timeseries = series
+        .into_iter()
+        .map(|(labels, value)| TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(name, value)| Label {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            samples: vec![Sample {
+                value,
+                timestamp: timestamp_ms,
+            }],
+        })
+        .collect();
+    WriteRequest { timeseries }.encode_to_vec()
+}
+
+/// Replace characters not allowed in Prometheus metric or label names with underscores.
+fn sanitize_name(name: &[u8], allow_colon: bool) -> String {
+    let mut sanitized: String = name
+        .iter()
+        .map(|&x| match x {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' => x as char,
+            b':' if allow_colon => ':',
+            _ => '_',
+        })
+        .collect();
+    if sanitized.is_empty() || sanitized.starts_with(|x: char| x.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// The labels of a metric, sorted by name as Prometheus requires.
+fn labels(metric: &Metric, name: &[u8]) -> Vec<(String, String)> {
+    let mut labels = vec![("__name__".to_owned(), sanitize_name(name, true))];
+    for tag in metric.tags_iter() {
+        // Prometheus does not distinguish empty from missing labels.
+        let Some(value) = tag.value().filter(|value| !value.is_empty()) else {
+            continue;
+        };
+        let name = sanitize_name(tag.name(), false);
+        if name.starts_with("__") || labels.iter().any(|(x, _)| *x == name) {
+            continue;
+        }
+        labels.push((name, String::from_utf8_lossy(value).into_owned()));
+    }
+    labels.sort_unstable();
+    labels
+}
+
+struct Series {
+    value: f64,
+    updated_at: Instant,
+}
+
+struct State {
+    series: HashMap<Vec<(String, String)>, Series>,
+    last_flush_at: Instant,
+    warned_full: bool,
+}
+
+/// The series sent to one endpoint, shared by all chains sending to it, so that every series is
+/// sent once per flush even with worker threads.
+struct Exporter {
+    url: String,
+    config: RemoteWriteConfig,
+    state: Mutex<State>,
+    sender: Sender,
+}
+
+static EXPORTERS: Mutex<Vec<Weak<Exporter>>> = Mutex::new(Vec::new());
+
+impl Exporter {
+    fn shared(url: &str, config: &RemoteWriteConfig) -> Result<Arc<Self>, Error> {
+        let mut exporters = EXPORTERS.lock().unwrap();
+        exporters.retain(|exporter| exporter.strong_count() > 0);
+        if let Some(exporter) = exporters
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|exporter| exporter.url == url)
+        {
+            return Ok(exporter);
+        }
+
+        let exporter = Arc::new(Exporter {
+            url: url.to_owned(),
+            config: config.clone(),
+            state: Mutex::new(State {
+                series: HashMap::new(),
+                last_flush_at: Instant::now(),
+                warned_full: false,
+            }),
+            sender: Sender::new(url, HEADERS.to_vec(), Duration::from_secs(config.timeout))?,
+        });
+        exporters.push(Arc::downgrade(&exporter));
+        Ok(exporter)
+    }
+
+    fn record(&self, metric: &Metric, now: Instant) {
+        let (Some(name), Some(ty), Some(raw_value)) = (metric.name(), metric.ty(), metric.value())
+        else {
+            drops::record(DropReason::Malformed, &metric.raw);
+            return;
+        };
+        if ty != b"c" && ty != b"g" {
+            drops::record(DropReason::Unsupported, &metric.raw);
+            return;
+        }
+        let Some(value) = std::str::from_utf8(raw_value)
+            .ok()
+            .and_then(|x| x.parse::<f64>().ok())
+        else {
+            drops::record(DropReason::Malformed, &metric.raw);
+            return;
+        };
+
+        let labels = labels(metric, name);
+        let mut state = self.state.lock().unwrap();
+        if !state.series.contains_key(&labels) && state.series.len() >= self.config.max_series {
+            if !state.warned_full {
+                log::warn!(
+                    "remote write has {} series, dropping metrics of new series",
+                    state.series.len()
+                );
+                state.warned_full = true;
+            }
+            drops::record(DropReason::Cardinality, &metric.raw);
+            return;
+        }
+        let series = state.series.entry(labels).or_insert(Series {
+            value: 0.0,
+            updated_at: now,
+        });
+        series.updated_at = now;
+        if ty == b"c" {
+            // Prometheus counters are totals, so statsd counters are added up.
+            series.value += value / metric.sample_rate().unwrap_or(1.0);
+        } else if matches!(raw_value.first(), Some(b'+' | b'-')) {
+            series.value += value;
+        } else {
+            series.value = value;
+        }
+    }
+
+    /// The requests to send the current value of every series, ending series that were not
+    /// updated within `series_ttl`.
+    fn flush(&self, now: Instant) -> Vec<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.last_flush_at = now;
+        let ttl = Duration::from_secs(self.config.series_ttl);
+        let mut samples = Vec::with_capacity(state.series.len());
+        let mut expired = Vec::new();
+        for (labels, series) in &state.series {
+            if now.saturating_duration_since(series.updated_at) > ttl {
+                samples.push((&labels[..], f64::from_bits(STALE_NAN)));
+                expired.push(labels.clone());
+            } else {
+                samples.push((&labels[..], series.value));
+            }
+        }
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let requests = samples
+            .chunks(MAX_SAMPLES_PER_REQUEST)
+            .map(|chunk| {
+                let request = encode_write_request(chunk.iter().copied(), timestamp_ms);
+                snap::raw::Encoder::new()
+                    .compress_vec(&request)
+                    .expect("requests are far below the maximum snappy input size")
+            })
+            .collect();
+        for labels in expired {
+            state.series.remove(&labels);
+        }
+        if state.series.len() < self.config.max_series {
+            state.warned_full = false;
+        }
+        requests
+    }
+}
+
+/// Sends counters and gauges to a Prometheus remote write endpoint, e.g. Prometheus itself,
+/// Mimir or Thanos, meant to follow aggregation with `relay` or `aggregate-metrics`.
+///
+/// Every `flush_interval`, the current value of every series is sent: the last value of a gauge,
+/// and the total of all values of a counter, as Prometheus counters are totals rather than
+/// increments. Tags with values become labels. Other types of metrics are dropped, since they
+/// have no equivalent without aggregation into buckets.
+pub struct RemoteWrite {
+    exporter: Arc<Exporter>,
+}
+
+impl RemoteWrite {
+    pub fn new(url: &str, config: &RemoteWriteConfig) -> Result<Self, Error> {
+        Ok(RemoteWrite {
+            exporter: Exporter::shared(url, config)?,
+        })
+    }
+}
+
+impl SendErrors for RemoteWrite {
+    fn send_errors(&self) -> u64 {
+        self.exporter.sender.send_errors()
+    }
+}
+
+impl Middleware for RemoteWrite {
+    fn submit(&mut self, metric: &mut Metric) {
+        self.exporter.record(metric, Instant::now());
+    }
+
+    fn poll(&mut self) {
+        let exporter = &self.exporter;
+        let interval = Duration::from_secs(exporter.config.flush_interval);
+        if exporter.state.lock().unwrap().last_flush_at.elapsed() < interval {
+            return;
+        }
+        for body in exporter.flush(Instant::now()) {
+            if !exporter.sender.send(body) {
+                // Counters are totals, so the next flush catches up on them.
+                log::warn!("remote write endpoint is too slow, skipping a flush");
+                self_metrics::incr("upstream.remote_write_skipped_requests", &[], 1);
+            }
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        for body in self.exporter.flush(Instant::now()) {
+            self.exporter.sender.send_now(&body);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let series = [
+            ("__name__".to_owned(), "a".to_owned()),
+            ("env".to_owned(), "prod".to_owned()),
+        ];
+        #[rustfmt::skip]
+        let expected = [
+            // TimeSeries
+            10, 42,
+                // Label
+                10, 13, 10, 8, b'_', b'_', b'n', b'a', b'm', b'e', b'_', b'_', 18, 1, b'a',
+                // Label
+                10, 11, 10, 3, b'e', b'n', b'v', 18, 4, b'p', b'r', b'o', b'd',
+                // Sample
+                18, 12, 9, 0, 0, 0, 0, 0, 0, 0xf8, 0x3f, 16, 0xe8, 0x07,
+        ];
+        assert_eq!(encode_write_request([(&series[..], 1.5)], 1000), expected);
+
+        let metric =
+            Metric::new(b"http.requests-total:1|c|#2xx:yes,env:prod,env:dev,flag,__x:y".to_vec());
+        assert_eq!(
+            labels(&metric, metric.name().unwrap()),
+            [
+                ("_2xx".to_owned(), "yes".to_owned()),
+                ("__name__".to_owned(), "http_requests_total".to_owned()),
+                ("env".to_owned(), "prod".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn series() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/write", listener.local_addr().unwrap());
+        let config = RemoteWriteConfig {
+            series_ttl: 60,
+            max_series: 3,
+            ..Default::default()
+        };
+        let mut remote_write = RemoteWrite::new(&url, &config).unwrap();
+        // Chains sending to the same endpoint share their series.
+        let mut remote_write2 = RemoteWrite::new(&url, &config).unwrap();
+        let exporter = remote_write.exporter.clone();
+        assert!(Arc::ptr_eq(&exporter, &remote_write2.exporter));
+
+        let start = Instant::now();
+        for raw in [
+            &b"a:1|c"[..],
+            b"a:2|c|@0.5",
+            b"b:5|g|#env:prod",
+            b"b:+2|g|#env:prod",
+            // Dropped, as are histograms, distributions and sets.
+            b"c:10|ms",
+            b"c:10|h",
+            b"c:10|d",
+            b"c:a|s",
+        ] {
+            exporter.record(&Metric::new(raw.to_vec()), start);
+        }
+        remote_write2.submit(&mut Metric::new(b"c:3|g".to_vec()));
+        // Full.
+        remote_write2.submit(&mut Metric::new(b"d:3|g".to_vec()));
+
+        let values = |exporter: &Exporter| {
+            let mut values: Vec<_> = exporter
+                .state
+                .lock()
+                .unwrap()
+                .series
+                .iter()
+                .map(|(labels, series)| (labels[labels.len() - 1].1.clone(), series.value))
+                .collect();
+            values.sort_by(|a, b| a.0.cmp(&b.0));
+            values
+        };
+        assert_eq!(
+            values(&exporter),
+            [
+                ("a".to_owned(), 5.0),
+                ("c".to_owned(), 3.0),
+                ("prod".to_owned(), 7.0)
+            ]
+        );
+
+        // `a` and `b` expire and are sent once more as stale.
+        exporter.record(
+            &Metric::new(b"c:4|g".to_vec()),
+            start + Duration::from_secs(60),
+        );
+        let requests = exporter.flush(start + Duration::from_secs(61));
+        assert_eq!(requests.len(), 1);
+        assert_eq!(values(&exporter), [("c".to_owned(), 4.0)]);
+
+        // The final flush on shutdown is sent right away.
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Read the whole request before responding, up to the end of the body.
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let len: usize = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    if body.len() >= len {
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+        remote_write.join().unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v1/write HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Encoding: snappy\r\n"));
+        assert_eq!(remote_write.send_errors(), 0);
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::Error;
+use regex::bytes::Regex;
+
+use crate::config::RewriteNameConfig;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+struct Rule {
+    pattern: Regex,
+    replacement: Vec<u8>,
+    tags: Vec<Vec<u8>>,
+}
+
+/// Rewrites metric names with regular expressions, e.g. to collapse `api.users.<id>.latency` into
+/// `api.users.latency`. The first rule whose pattern matches the name replaces its first match,
+/// and adds tags, both of which may refer to capture groups like `$1` or `$id`. Metrics matching
+/// no rule are passed on unchanged.
+pub struct RewriteName<M> {
+    rules: Vec<Rule>,
+    next: M,
+}
+
+impl<M> RewriteName<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: RewriteNameConfig, next: M) -> Result<Self, Error> {
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| {
+                Ok(Rule {
+                    pattern: Regex::new(&rule.pattern)?,
+                    replacement: rule.replacement.into_bytes(),
+                    tags: rule.tags.into_iter().map(String::into_bytes).collect(),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(RewriteName { rules, next })
+    }
+
+    /// The metric with the name rewritten by the first matching rule, if any.
+    fn rewrite(&self, metric: &Metric) -> Option<Metric> {
+        let name = metric.name()?;
+        let (rule, captures) = self
+            .rules
+            .iter()
+            .find_map(|rule| Some((rule, rule.pattern.captures(name)?)))?;
+
+        let whole = captures.get(0).unwrap();
+        let mut raw = name[..whole.start()].to_vec();
+        captures.expand(&rule.replacement, &mut raw);
+        raw.extend_from_slice(&metric.raw[whole.end()..]);
+
+        let mut tags = Vec::new();
+        for template in &rule.tags {
+            let mut tag = Vec::new();
+            captures.expand(template, &mut tag);
+            if !tags.is_empty() {
+                tags.push(b',');
+            }
+            tags.extend(tag);
+        }
+
+        let mut rewritten = Metric::new(raw);
+        if !tags.is_empty() {
+            rewritten.append_tags(&tags);
+        }
+        Some(rewritten)
+    }
+}
+
+impl<M> Middleware for RewriteName<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        match self.rewrite(metric) {
+            Some(mut rewritten) => self.next.submit(&mut rewritten),
+            None => self.next.submit(metric),
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::config::RewriteRuleConfig;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn rewrite() {
+        let config = RewriteNameConfig {
+            rules: vec![
+                RewriteRuleConfig {
+                    pattern: r"^api\.users\.(?<id>\d+)\.".to_owned(),
+                    replacement: "api.users.".to_owned(),
+                    tags: vec!["user_id:$id".to_owned()],
+                },
+                RewriteRuleConfig {
+                    pattern: r"^legacy\.(\w+)$".to_owned(),
+                    replacement: "${1}_legacy".to_owned(),
+                    tags: vec![],
+                },
+            ],
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut rewrite = RewriteName::new(config, next).unwrap();
+
+        for raw in [
+            "api.users.1234.latency:12|ms|@0.5",
+            "api.users.1234.latency:12|ms|#env:prod|T1692653389",
+            "api.users.me.latency:12|ms",
+            "legacy.requests:1|c",
+        ] {
+            rewrite.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        assert_eq!(
+            *results.borrow(),
+            [
+                Metric::new(b"api.users.latency:12|ms|@0.5|#user_id:1234".to_vec()),
+                Metric::new(b"api.users.latency:12|ms|#env:prod,user_id:1234|T1692653389".to_vec()),
+                Metric::new(b"api.users.me.latency:12|ms".to_vec()),
+                Metric::new(b"requests_legacy:1|c".to_vec()),
+            ]
+        );
+    }
+}
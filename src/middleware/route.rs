@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Sends metrics to an upstream chosen by the value of a tag, e.g. a statsd server per team, and
+/// metrics without the tag or with an unknown value to `default`. The tag is kept.
+pub struct Route<M, N> {
+    tag: Vec<u8>,
+    routes: BTreeMap<Vec<u8>, M>,
+    default: N,
+}
+
+impl<M, N> Route<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    /// Route by the tag named `tag`, with `routes` by tag value.
+    pub fn new(tag: &str, routes: impl IntoIterator<Item = (String, M)>, default: N) -> Self {
+        Route {
+            tag: tag.trim_end_matches(':').as_bytes().to_vec(),
+            routes: routes
+                .into_iter()
+                .map(|(value, upstream)| (value.into_bytes(), upstream))
+                .collect(),
+            default,
+        }
+    }
+}
+
+impl<M, N> Middleware for Route<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        for upstream in self.routes.values_mut() {
+            upstream.join()?;
+        }
+        self.default.join()
+    }
+
+    fn poll(&mut self) {
+        for upstream in self.routes.values_mut() {
+            upstream.poll();
+        }
+        self.default.poll();
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let value = metric
+            .tags_iter()
+            .find(|tag| tag.name() == self.tag)
+            .and_then(|tag| tag.value().map(<[u8]>::to_vec));
+        let upstream = value.and_then(|value| self.routes.get_mut(&value));
+        match upstream {
+            Some(upstream) => upstream.submit(metric),
+            None => self.default.submit(metric),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn route() {
+        let results = RefCell::new(vec![]);
+        let upstream = |name: &'static str| {
+            let results = &results;
+            FnStep(move |metric: &mut Metric| {
+                let raw = String::from_utf8(metric.raw.clone()).unwrap();
+                results.borrow_mut().push((name, raw));
+            })
+        };
+        let mut route = Route::new(
+            "team:",
+            [
+                ("search".to_owned(), upstream("search")),
+                ("ingest".to_owned(), upstream("ingest")),
+            ],
+            upstream("default"),
+        );
+
+        for raw in [
+            "requests:1|c|#env:prod,team:ingest",
+            "requests:1|c|#team:search",
+            "requests:1|c|#team:billing",
+            "requests:1|c|#teams:search",
+            "requests:1|c",
+        ] {
+            route.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        assert_eq!(
+            *results.borrow(),
+            [
+                ("ingest", "requests:1|c|#env:prod,team:ingest".to_owned()),
+                ("search", "requests:1|c|#team:search".to_owned()),
+                ("default", "requests:1|c|#team:billing".to_owned()),
+                ("default", "requests:1|c|#teams:search".to_owned()),
+                ("default", "requests:1|c".to_owned()),
+            ]
+        );
+    }
+}
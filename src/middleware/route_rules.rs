@@ -0,0 +1,203 @@
+use anyhow::Error;
+use regex::bytes::RegexSet;
+
+use crate::config::RouteRuleConfig;
+use crate::middleware::deny_metric::name_matcher;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// Matches metrics by name, type and tags. Every configured criterion has to match.
+struct Rule {
+    names: Option<RegexSet>,
+    types: Vec<Vec<u8>>,
+    // Tags as `key` or `key:value`.
+    tags: Vec<Vec<u8>>,
+}
+
+impl Rule {
+    fn new(config: &RouteRuleConfig) -> Result<Self, Error> {
+        let names = if config.names.is_empty() && config.patterns.is_empty() {
+            None
+        } else {
+            Some(name_matcher(&config.names, &config.patterns)?)
+        };
+        Ok(Rule {
+            names,
+            types: config.types.iter().map(|x| x.as_bytes().to_vec()).collect(),
+            tags: config.tags.iter().map(|x| x.as_bytes().to_vec()).collect(),
+        })
+    }
+
+    fn matches(&self, metric: &Metric) -> bool {
+        if let Some(names) = &self.names {
+            if !metric.name().is_some_and(|name| names.is_match(name)) {
+                return false;
+            }
+        }
+        if !self.types.is_empty()
+            && !metric
+                .ty()
+                .is_some_and(|ty| self.types.iter().any(|x| x == ty))
+        {
+            return false;
+        }
+        self.tags.is_empty()
+            || metric
+                .tags_iter()
+                .any(|tag| self.tags.iter().any(|x| x == tag.raw || x == tag.name()))
+    }
+}
+
+/// Sends every metric through the middlewares of the first route whose rule matches it, and
+/// metrics matching no rule through `default`, e.g. to aggregate application counters while
+/// passing system timers straight through.
+///
+/// All routes are polled and joined, so they are expected to end in a handle from
+/// `Shared::submit_only` to where `default` ends, which is only driven through `default`.
+pub struct RouteRules<M, N> {
+    routes: Vec<(Rule, M)>,
+    default: N,
+}
+
+impl<M, N> RouteRules<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    pub fn new(
+        routes: impl IntoIterator<Item = (RouteRuleConfig, M)>,
+        default: N,
+    ) -> Result<Self, Error> {
+        Ok(RouteRules {
+            routes: routes
+                .into_iter()
+                .map(|(config, route)| Ok((Rule::new(&config)?, route)))
+                .collect::<Result<_, Error>>()?,
+            default,
+        })
+    }
+}
+
+impl<M, N> Middleware for RouteRules<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        // Routes flush into the tail of `default` before it is joined.
+        for (_, route) in &mut self.routes {
+            route.join()?;
+        }
+        self.default.join()
+    }
+
+    fn poll(&mut self) {
+        for (_, route) in &mut self.routes {
+            route.poll();
+        }
+        self.default.poll();
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        match self
+            .routes
+            .iter_mut()
+            .find(|(rule, _)| rule.matches(metric))
+        {
+            Some((_, route)) => route.submit(metric),
+            None => self.default.submit(metric),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::middleware::shared::Shared;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn route_rules() {
+        let results = RefCell::new(vec![]);
+        let route = |name: &'static str| {
+            let results = &results;
+            FnStep(move |metric: &mut Metric| {
+                let raw = String::from_utf8(metric.raw.clone()).unwrap();
+                results.borrow_mut().push((name, raw));
+            })
+        };
+        let rule = |names: &[&str], types: &[&str], tags: &[&str]| RouteRuleConfig {
+            names: names.iter().map(|x| x.to_string()).collect(),
+            patterns: vec![],
+            types: types.iter().map(|x| x.to_string()).collect(),
+            tags: tags.iter().map(|x| x.to_string()).collect(),
+            middlewares: vec![],
+        };
+        let mut router = RouteRules::new(
+            [
+                (rule(&["app.*"], &["c"], &[]), route("app")),
+                (rule(&[], &[], &["env:dev", "debug"]), route("dev")),
+            ],
+            route("default"),
+        )
+        .unwrap();
+
+        for raw in [
+            "app.requests:1|c|#env:dev",
+            "app.latency:1|ms|#env:dev",
+            "app.latency:1|ms|#debug:true",
+            "app.latency:1|ms|#env:prod",
+            "system.load:1|g",
+        ] {
+            router.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+
+        assert_eq!(
+            results.into_inner(),
+            [
+                ("app", "app.requests:1|c|#env:dev"),
+                ("dev", "app.latency:1|ms|#env:dev"),
+                ("dev", "app.latency:1|ms|#debug:true"),
+                ("default", "app.latency:1|ms|#env:prod"),
+                ("default", "system.load:1|g"),
+            ]
+            .map(|(name, raw)| (name, raw.to_string()))
+        );
+    }
+
+    #[test]
+    fn drives_next_once() {
+        #[derive(Default)]
+        struct Polls(usize);
+
+        impl Middleware for Polls {
+            fn poll(&mut self) {
+                self.0 += 1;
+            }
+
+            fn submit(&mut self, _metric: &mut Metric) {}
+        }
+
+        let rule = RouteRuleConfig {
+            names: vec!["app.*".to_string()],
+            patterns: vec![],
+            types: vec![],
+            tags: vec![],
+            middlewares: vec![],
+        };
+        let next = Shared::new(Polls::default());
+        let mut router = RouteRules::new(
+            [
+                (rule.clone(), next.submit_only()),
+                (rule, next.submit_only()),
+            ],
+            next.clone(),
+        )
+        .unwrap();
+        router.poll();
+        router.join().unwrap();
+        assert_eq!(next.lock().0, 1);
+    }
+}
@@ -2,21 +2,70 @@ use anyhow::Error;
 
 use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
+use regex::bytes::RegexSet;
 
-use crate::config::SampleConfig;
+use crate::config::{SampleConfig, SampleRuleConfig};
+use crate::drops::{self, DropReason};
+use crate::middleware::deny_metric::name_matcher;
 use crate::middleware::Middleware;
 use crate::types::Metric;
 
+struct Rule {
+    names: Option<RegexSet>,
+    types: Vec<Vec<u8>>,
+    sample_rate: f64,
+}
+
+impl Rule {
+    fn new(config: SampleRuleConfig) -> Result<Self, Error> {
+        let names = if config.names.is_empty() && config.patterns.is_empty() {
+            None
+        } else {
+            Some(name_matcher(&config.names, &config.patterns)?)
+        };
+        Ok(Rule {
+            names,
+            types: config.types.into_iter().map(String::into_bytes).collect(),
+            sample_rate: config.sample_rate,
+        })
+    }
+
+    fn matches(&self, metric: &Metric) -> bool {
+        let name_matches = match &self.names {
+            Some(names) => metric.name().is_some_and(|name| names.is_match(name)),
+            None => true,
+        };
+        name_matches
+            && (self.types.is_empty()
+                || metric
+                    .ty()
+                    .is_some_and(|ty| self.types.iter().any(|x| x == ty)))
+    }
+}
+
+/// Keeps a random `sample_rate` share of metrics, with the rate of the first matching rule, e.g.
+/// to keep all SLO counters while sampling noisy debug timers. The sample rate of kept counters,
+/// timers, histograms and distributions is multiplied by the rate, so that statsd servers scale
+/// them back up. Gauges and sets are sampled as they are, since servers ignore their sample rate.
 pub struct Sample<M> {
     next: M,
     rng: SmallRng,
-    config: SampleConfig,
+    sample_rate: f64,
+    rules: Vec<Rule>,
 }
 
 impl<M> Sample<M> {
-    pub fn new(config: SampleConfig, next: M) -> Self {
-        let rng = SmallRng::from_entropy();
-        Sample { next, config, rng }
+    pub fn new(config: SampleConfig, next: M) -> Result<Self, Error> {
+        Ok(Sample {
+            next,
+            rng: SmallRng::from_entropy(),
+            sample_rate: config.sample_rate,
+            rules: config
+                .rules
+                .into_iter()
+                .map(Rule::new)
+                .collect::<Result<_, _>>()?,
+        })
     }
 }
 
@@ -34,13 +83,106 @@ where
     }
 
     fn submit(&mut self, metric: &mut Metric) {
-        if self.config.sample_rate == 0.0 {
+        let sample_rate = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(metric))
+            .map_or(self.sample_rate, |rule| rule.sample_rate);
+        if sample_rate == 0.0 {
+            drops::record(DropReason::Sampled, &metric.raw);
             return;
         }
 
         let decision: f64 = self.rng.gen();
-        if decision < self.config.sample_rate {
+        if decision < sample_rate {
+            if sample_rate < 1.0 && matches!(metric.ty(), Some(b"c" | b"ms" | b"h" | b"d")) {
+                let rate = metric.sample_rate().unwrap_or(1.0) * sample_rate;
+                metric.set_sample_rate(rate);
+            }
             self.next.submit(metric);
+        } else {
+            drops::record(DropReason::Sampled, &metric.raw);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn rewrite_sample_rate() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let config = SampleConfig {
+            sample_rate: 0.5,
+            rules: vec![],
+        };
+        let mut sample = Sample::new(config, next).unwrap();
+
+        let metrics = [
+            ("requests:1|c", "requests:1|c|@0.5"),
+            ("requests:1|c|@0.5|#a:b", "requests:1|c|@0.25|#a:b"),
+            ("requests:1|c|@0.3", "requests:1|c|@0.15"),
+            ("request.duration:12|ms", "request.duration:12|ms|@0.5"),
+            ("request.size:1:2|h|#a:b", "request.size:1:2|h|@0.5|#a:b"),
+            ("request.size:100|d", "request.size:100|d|@0.5"),
+            ("users.online:3|g", "users.online:3|g"),
+            ("users.unique:abc|s", "users.unique:abc|s"),
+        ];
+        for _ in 0..100 {
+            for (raw, _) in metrics {
+                sample.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+            }
+        }
+
+        // Every metric is kept about half of the time, with the rate rewritten as expected.
+        let results = results.into_inner();
+        for (raw, expected) in metrics {
+            let kept = results
+                .iter()
+                .filter(|metric| metric.raw == expected.as_bytes())
+                .count();
+            assert!(kept > 0 && kept < 100, "{raw}");
+        }
+        assert!(results
+            .iter()
+            .all(|metric| metrics.iter().any(|(_, x)| metric.raw == x.as_bytes())));
+    }
+
+    #[test]
+    fn rules() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let rule = |names: &[&str], types: &[&str], sample_rate| SampleRuleConfig {
+            names: names.iter().map(|x| x.to_string()).collect(),
+            patterns: vec![],
+            types: types.iter().map(|x| x.to_string()).collect(),
+            sample_rate,
+        };
+        let config = SampleConfig {
+            sample_rate: 0.0,
+            rules: vec![rule(&["slo.*"], &[], 1.0), rule(&[], &["ms"], 0.5)],
+        };
+        let mut sample = Sample::new(config, next).unwrap();
+
+        for _ in 0..100 {
+            for raw in ["slo.requests:1|c", "debug.duration:1|ms", "debug.hits:1|c"] {
+                sample.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+            }
+        }
+
+        let results = results.into_inner();
+        let kept = |raw: &str| results.iter().filter(|x| x.raw == raw.as_bytes()).count();
+        assert_eq!(kept("slo.requests:1|c"), 100);
+        assert!((1..100).contains(&kept("debug.duration:1|ms|@0.5")));
+        assert_eq!(results.len(), 100 + kept("debug.duration:1|ms|@0.5"));
+    }
+}
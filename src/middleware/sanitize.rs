@@ -0,0 +1,172 @@
+use std::borrow::Cow;
+
+use anyhow::Error;
+
+use crate::config::{SanitizeConfig, SanitizeRuleConfig};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+const REPLACEMENT: char = '_';
+
+struct Rule {
+    allowed: Vec<char>,
+    max_length: Option<usize>,
+}
+
+impl Rule {
+    fn new(config: &SanitizeRuleConfig) -> Self {
+        Rule {
+            allowed: config.allowed.chars().collect(),
+            max_length: config.max_length,
+        }
+    }
+
+    fn is_allowed(&self, c: char) -> bool {
+        c.is_ascii_alphanumeric() || self.allowed.contains(&c)
+    }
+
+    /// `input` without invalid UTF-8, with disallowed characters replaced, and cut off after
+    /// `max_length` bytes.
+    fn apply<'a>(&self, input: &'a [u8]) -> Cow<'a, [u8]> {
+        let clean = input
+            .iter()
+            .all(|&x| x.is_ascii() && self.is_allowed(x as char))
+            && self.max_length.is_none_or(|x| input.len() <= x);
+        if clean {
+            return Cow::Borrowed(input);
+        }
+
+        let mut output = String::with_capacity(input.len());
+        for chunk in input.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                let c = if self.is_allowed(c) { c } else { REPLACEMENT };
+                if self
+                    .max_length
+                    .is_some_and(|x| output.len() + c.len_utf8() > x)
+                {
+                    return Cow::Owned(output.into_bytes());
+                }
+                output.push(c);
+            }
+        }
+        Cow::Owned(output.into_bytes())
+    }
+}
+
+/// Makes metric names and tag keys acceptable to backends that drop lines with illegal
+/// characters: invalid UTF-8 is removed, characters other than ASCII letters, digits and the
+/// allowed ones are replaced with `_`, and overly long names and keys are cut off. Tag values
+/// are left alone.
+pub struct Sanitize<M> {
+    name: Rule,
+    tag_keys: Rule,
+    next: M,
+}
+
+impl<M> Sanitize<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: SanitizeConfig, next: M) -> Self {
+        Sanitize {
+            name: Rule::new(&config.name),
+            tag_keys: Rule::new(&config.tag_keys),
+            next,
+        }
+    }
+
+    /// The sanitized metric, if anything had to change.
+    fn sanitize(&self, metric: &Metric) -> Option<Metric> {
+        let name = metric.name()?;
+        let new_name = self.name.apply(name);
+
+        let mut tags_changed = false;
+        let mut tags = Vec::new();
+        for tag in metric.tags_iter() {
+            if !tags.is_empty() {
+                tags.push(b',');
+            }
+            let key = self.tag_keys.apply(tag.name());
+            tags_changed |= matches!(key, Cow::Owned(_));
+            tags.extend_from_slice(&key);
+            tags.extend_from_slice(&tag.raw[tag.name().len()..]);
+        }
+
+        if matches!(new_name, Cow::Borrowed(_)) && !tags_changed {
+            return None;
+        }
+        let mut raw = new_name.into_owned();
+        raw.extend_from_slice(&metric.raw[name.len()..]);
+        let mut sanitized = Metric::new(raw);
+        if tags_changed {
+            sanitized.set_tags(&tags);
+        }
+        Some(sanitized)
+    }
+}
+
+impl<M> Middleware for Sanitize<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        match self.sanitize(metric) {
+            Some(mut sanitized) => self.next.submit(&mut sanitized),
+            None => self.next.submit(metric),
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn sanitize() {
+        let config = SanitizeConfig {
+            name: SanitizeRuleConfig {
+                allowed: "_.".to_owned(),
+                max_length: Some(12),
+            },
+            tag_keys: SanitizeRuleConfig {
+                allowed: "_".to_owned(),
+                max_length: None,
+            },
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.raw.clone());
+        });
+        let mut sanitize = Sanitize::new(config, next);
+
+        for raw in [
+            &b"users.online:1|c|#country:china"[..],
+            b"users online:1|c|#a-b:c d,e|T1692653389",
+            b"caf\xc3\xa9.\xff\xfesize:1|g",
+            b"request.duration.total:1|ms|#route:/home",
+        ] {
+            sanitize.submit(&mut Metric::new(raw.to_vec()));
+        }
+        drop(sanitize);
+        assert_eq!(
+            results.into_inner(),
+            [
+                &b"users.online:1|c|#country:china"[..],
+                b"users_online:1|c|#a_b:c d,e|T1692653389",
+                b"caf_.size:1|g",
+                b"request.dura:1|ms|#route:/home",
+            ]
+        );
+    }
+}
@@ -0,0 +1,232 @@
+#[cfg(test)]
+use std::sync::Mutex;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Error};
+
+use crate::config::{ScheduleConfig, TimeWindowConfig, Weekday};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+struct TimeWindow {
+    /// Bitmask of weekdays on which the window applies, with Monday as the lowest bit.
+    days: u8,
+    /// Start and end of the window in seconds since midnight. If `end` is before `start`, the
+    /// window wraps around midnight, and `days` refers to the day the window starts on.
+    start: u64,
+    end: u64,
+}
+
+pub(crate) fn parse_time_of_day(time: &str) -> Result<u64, Error> {
+    let (hours, minutes) = time
+        .split_once(':')
+        .ok_or_else(|| anyhow!("invalid time of day {:?}, expected HH:MM", time))?;
+    let hours: u64 = hours.parse()?;
+    let minutes: u64 = minutes.parse()?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes != 0) {
+        return Err(anyhow!("invalid time of day {:?}, expected HH:MM", time));
+    }
+    Ok(hours * 3600 + minutes * 60)
+}
+
+fn weekday_index(day: &Weekday) -> u64 {
+    match day {
+        Weekday::Mon => 0,
+        Weekday::Tue => 1,
+        Weekday::Wed => 2,
+        Weekday::Thu => 3,
+        Weekday::Fri => 4,
+        Weekday::Sat => 5,
+        Weekday::Sun => 6,
+    }
+}
+
+impl TryFrom<TimeWindowConfig> for TimeWindow {
+    type Error = Error;
+
+    fn try_from(config: TimeWindowConfig) -> Result<Self, Error> {
+        let days = if config.days.is_empty() {
+            0b111_1111
+        } else {
+            config
+                .days
+                .iter()
+                .fold(0, |mask, day| mask | (1 << weekday_index(day)))
+        };
+
+        Ok(TimeWindow {
+            days,
+            start: parse_time_of_day(&config.start)?,
+            end: parse_time_of_day(&config.end)?,
+        })
+    }
+}
+
+impl TimeWindow {
+    /// Whether `now` (a UNIX timestamp) falls into this window. Times are evaluated in UTC.
+    fn contains(&self, now: u64) -> bool {
+        let days_since_epoch = now / SECONDS_PER_DAY;
+        let time_of_day = now % SECONDS_PER_DAY;
+        // 1970-01-01 was a Thursday.
+        let weekday = (days_since_epoch + 3) % 7;
+        let previous_weekday = (weekday + 6) % 7;
+        let applies_on = |day: u64| self.days & (1 << day) != 0;
+
+        if self.start <= self.end {
+            applies_on(weekday) && self.start <= time_of_day && time_of_day < self.end
+        } else {
+            // The window wraps around midnight: it either started today, or yesterday.
+            (applies_on(weekday) && self.start <= time_of_day)
+                || (applies_on(previous_weekday) && time_of_day < self.end)
+        }
+    }
+}
+
+#[cfg(test)]
+static CURRENT_TIME: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Sends metrics through a nested middleware chain while inside of the configured time windows
+/// (or outside of them, if `invert` is set), and straight to `next` otherwise.
+///
+/// The nested chain is expected to end in (a handle to) `next`.
+pub struct Schedule<M, N> {
+    windows: Vec<TimeWindow>,
+    invert: bool,
+    inner: M,
+    next: N,
+}
+
+impl<M, N> Schedule<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    pub fn new(config: ScheduleConfig, inner: M, next: N) -> Result<Self, Error> {
+        let windows = config
+            .windows
+            .into_iter()
+            .map(TimeWindow::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(Schedule {
+            windows,
+            invert: config.invert,
+            inner,
+            next,
+        })
+    }
+
+    fn is_active(&self) -> bool {
+        #[cfg(test)]
+        let overwrite_now = *CURRENT_TIME.lock().unwrap();
+        #[cfg(not(test))]
+        let overwrite_now = None;
+
+        #[allow(clippy::unnecessary_literal_unwrap)]
+        let now = overwrite_now.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        });
+
+        self.windows.iter().any(|window| window.contains(now)) != self.invert
+    }
+}
+
+impl<M, N> Middleware for Schedule<M, N>
+where
+    M: Middleware,
+    N: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        // The nested chain joins `next` as well.
+        self.inner.join()
+    }
+
+    fn poll(&mut self) {
+        // Keep polling the nested chain outside of the time windows, so that it can flush any
+        // state it still holds.
+        self.inner.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.is_active() {
+            self.inner.submit(metric)
+        } else {
+            self.next.submit(metric)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    // 2023-08-21 (a Monday) at 00:00 UTC
+    const MONDAY: u64 = 1692576000;
+
+    fn window(days: Vec<Weekday>, start: &str, end: &str) -> TimeWindow {
+        TimeWindow::try_from(TimeWindowConfig {
+            days,
+            start: start.to_string(),
+            end: end.to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn time_window() {
+        let nightly = window(vec![], "02:00", "04:00");
+        assert!(!nightly.contains(MONDAY + 3600));
+        assert!(nightly.contains(MONDAY + 2 * 3600));
+        assert!(nightly.contains(MONDAY + 3 * 3600 + 59 * 60));
+        assert!(!nightly.contains(MONDAY + 4 * 3600));
+
+        let friday_night = window(vec![Weekday::Fri], "22:00", "02:00");
+        assert!(!friday_night.contains(MONDAY + 23 * 3600));
+        assert!(friday_night.contains(MONDAY + 4 * SECONDS_PER_DAY + 23 * 3600));
+        assert!(friday_night.contains(MONDAY + 5 * SECONDS_PER_DAY + 3600));
+        assert!(!friday_night.contains(MONDAY + 5 * SECONDS_PER_DAY + 3 * 3600));
+    }
+
+    #[test]
+    fn basic() {
+        let config = ScheduleConfig {
+            windows: vec![TimeWindowConfig {
+                days: vec![Weekday::Mon],
+                start: "02:00".to_string(),
+                end: "04:00".to_string(),
+            }],
+            invert: false,
+            middlewares: vec![],
+        };
+
+        let results = RefCell::new(vec![]);
+        let inner = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push((true, metric.clone()));
+        });
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push((false, metric.clone()));
+        });
+        let mut schedule = Schedule::new(config, inner, next).unwrap();
+
+        *CURRENT_TIME.lock().unwrap() = Some(MONDAY + 3 * 3600);
+        schedule.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+        *CURRENT_TIME.lock().unwrap() = Some(MONDAY + 5 * 3600);
+        schedule.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+
+        assert_eq!(
+            results.borrow().as_slice(),
+            &[
+                (true, Metric::new(b"users.online:1|c".to_vec())),
+                (false, Metric::new(b"users.online:1|c".to_vec())),
+            ]
+        );
+    }
+}
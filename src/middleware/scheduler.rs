@@ -0,0 +1,240 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+
+use crate::config::OutputSchedulerConfig;
+use crate::drops::{self, DropReason};
+use crate::middleware::failover::SendErrors;
+use crate::middleware::Middleware;
+use crate::timers;
+use crate::token_bucket::TokenBucket;
+use crate::types::Metric;
+
+thread_local! {
+    // Whether metrics submitted on this thread right now are flushed by a middleware, rather than
+    // passed through from a client.
+    static FLUSHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f`, in which a middleware submits the metrics it flushes, e.g. aggregated buckets. An
+/// `OutputScheduler` further down the same chain sends them after metrics passed through from
+/// clients.
+pub fn flushing<R>(f: impl FnOnce() -> R) -> R {
+    let previous = FLUSHING.replace(true);
+    let result = f();
+    FLUSHING.set(previous);
+    result
+}
+
+/// Merges metrics passed through from clients and metrics flushed by middlewares into batches of
+/// `batch_size` metrics for the upstream, optionally paced to `max_metrics_per_second`.
+///
+/// While pacing holds metrics back, passed through metrics go first, so that a large flush does
+/// not delay live traffic. A batch that is not full yet is forwarded after `max_delay_ms` at the
+/// latest. Once `max_queued` metrics are held back, the oldest flushed metrics are dropped first.
+pub struct OutputScheduler<M> {
+    next: M,
+    // Metrics held back by pacing, oldest first.
+    live: VecDeque<Metric>,
+    flushed: VecDeque<Metric>,
+    batch: Vec<Metric>,
+    batch_started_at: Instant,
+    batch_size: usize,
+    max_delay: Duration,
+    max_queued: usize,
+    pacing: Option<TokenBucket>,
+}
+
+impl<M> OutputScheduler<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: &OutputSchedulerConfig, next: M) -> Self {
+        let now = Instant::now();
+        OutputScheduler {
+            next,
+            live: VecDeque::new(),
+            flushed: VecDeque::new(),
+            batch: Vec::new(),
+            batch_started_at: now,
+            batch_size: config.batch_size.max(1),
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            max_queued: config.max_queued.max(1),
+            // Allow a burst of up to a second's worth of metrics.
+            pacing: config
+                .max_metrics_per_second
+                .map(|rate| TokenBucket::new(rate as f64, rate as f64, now)),
+        }
+    }
+
+    /// Move as many held back metrics into batches as pacing allows at `now`, or all of them with
+    /// `force`, and forward full batches and those older than `max_delay`.
+    fn release(&mut self, now: Instant, force: bool) {
+        loop {
+            if self.live.is_empty() && self.flushed.is_empty() {
+                break;
+            }
+            if !force {
+                if let Some(pacing) = &mut self.pacing {
+                    if !pacing.try_take(1.0, now) {
+                        break;
+                    }
+                }
+            }
+            let metric = match self.live.pop_front() {
+                Some(metric) => metric,
+                None => self.flushed.pop_front().expect("not empty"),
+            };
+            if self.batch.is_empty() {
+                self.batch_started_at = now;
+                timers::schedule(now + self.max_delay);
+            }
+            self.batch.push(metric);
+            if self.batch.len() >= self.batch_size {
+                self.send_batch();
+            }
+        }
+        if force || now.saturating_duration_since(self.batch_started_at) >= self.max_delay {
+            self.send_batch();
+        }
+    }
+
+    fn send_batch(&mut self) {
+        if !self.batch.is_empty() {
+            self.next.submit_batch(&mut self.batch);
+            self.batch.clear();
+        }
+    }
+}
+
+impl<M> SendErrors for OutputScheduler<M>
+where
+    M: SendErrors,
+{
+    fn send_errors(&self) -> u64 {
+        self.next.send_errors()
+    }
+}
+
+impl<M> Middleware for OutputScheduler<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.release(Instant::now(), false);
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.live.len() + self.flushed.len() >= self.max_queued {
+            if let Some(oldest) = self.flushed.pop_front().or_else(|| self.live.pop_front()) {
+                drops::record(DropReason::Overload, &oldest.raw);
+            }
+        }
+        let metric = metric.clone();
+        if FLUSHING.get() {
+            self.flushed.push_back(metric);
+        } else {
+            self.live.push_back(metric);
+        }
+        self.release(Instant::now(), false);
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.release(Instant::now(), true);
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::FnStep;
+    use std::cell::RefCell;
+
+    fn config() -> OutputSchedulerConfig {
+        OutputSchedulerConfig {
+            max_metrics_per_second: None,
+            batch_size: 2,
+            max_delay_ms: 100,
+            max_queued: 10,
+        }
+    }
+
+    #[test]
+    fn batches() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.raw.clone());
+        });
+        let mut scheduler = OutputScheduler::new(&config(), next);
+        let start = Instant::now();
+        for raw in ["a:1|c", "b:1|c", "c:1|c"] {
+            scheduler.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        assert_eq!(*results.borrow(), [b"a:1|c".to_vec(), b"b:1|c".to_vec()]);
+
+        // The partial batch is forwarded once it is old enough.
+        scheduler.release(start, false);
+        assert_eq!(results.borrow().len(), 2);
+        scheduler.release(start + Duration::from_millis(200), false);
+        assert_eq!(results.borrow().len(), 3);
+    }
+
+    #[test]
+    fn priorities() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results
+                .borrow_mut()
+                .push(String::from_utf8(metric.raw.clone()).unwrap());
+        });
+        let mut scheduler = OutputScheduler::new(
+            &OutputSchedulerConfig {
+                max_metrics_per_second: Some(2),
+                batch_size: 1,
+                max_queued: 4,
+                ..config()
+            },
+            next,
+        );
+        let start = Instant::now();
+        flushing(|| {
+            for raw in ["flushed.a:1|c", "flushed.b:1|c", "flushed.c:1|c"] {
+                scheduler.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+            }
+        });
+        for raw in ["live.a:1|c", "live.b:1|c"] {
+            scheduler.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        // The burst allowance is spent on the first two metrics, and the rest is held back.
+        assert_eq!(*results.borrow(), ["flushed.a:1|c", "flushed.b:1|c"]);
+
+        // Live metrics go first once pacing allows more.
+        scheduler.release(start + Duration::from_secs(2), false);
+        assert_eq!(
+            results.borrow()[2..],
+            ["live.a:1|c".to_owned(), "live.b:1|c".to_owned()]
+        );
+
+        // Over `max_queued`, the oldest flushed metric is dropped.
+        flushing(|| {
+            for raw in ["flushed.d:1|c", "flushed.e:1|c", "flushed.f:1|c"] {
+                scheduler.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+            }
+        });
+        scheduler.submit(&mut Metric::new(b"live.c:1|c".to_vec()));
+        scheduler.join().unwrap();
+        assert_eq!(
+            results.borrow()[4..],
+            [
+                "live.c:1|c".to_owned(),
+                "flushed.d:1|c".to_owned(),
+                "flushed.e:1|c".to_owned(),
+                "flushed.f:1|c".to_owned(),
+            ]
+        );
+    }
+}
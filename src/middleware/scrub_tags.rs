@@ -0,0 +1,197 @@
+use std::fmt::Write;
+use std::net::IpAddr;
+
+use anyhow::Error;
+use hmac::{Hmac, Mac};
+use regex::bytes::RegexSet;
+use sha2::Sha256;
+
+use crate::config::{PiiPattern, ScrubAction, ScrubTagsConfig};
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// The value replacing scrubbed values with `ScrubAction::Redact`.
+pub const REDACTED: &[u8] = b"redacted";
+
+// Hashed values are cut off after this many hex digits.
+const HASH_LENGTH: usize = 16;
+
+fn pattern_regex(pattern: PiiPattern) -> &'static str {
+    match pattern {
+        PiiPattern::Uuid => r"(?i)[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}",
+        PiiPattern::Email => r"[^@\s]+@[^@\s]+\.[A-Za-z]{2,}",
+        PiiPattern::Ip => r"\b(?:\d{1,3}\.){3}\d{1,3}\b",
+    }
+}
+
+/// Replaces the values of tags that may hold personal data, so that raw identifiers never leave
+/// the host: all values of the configured tags, and values of any tag matching a configured
+/// pattern. Values are replaced by a keyed hash, which still tells them apart, or redacted.
+pub struct ScrubTags<M> {
+    tags: Vec<Vec<u8>>,
+    values: RegexSet,
+    // Whether to check for IPv6 addresses, which are recognized by parsing instead of a pattern.
+    ipv6: bool,
+    key: Option<Hmac<Sha256>>,
+    next: M,
+}
+
+impl<M> ScrubTags<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: ScrubTagsConfig, next: M) -> Result<Self, Error> {
+        let values = RegexSet::new(
+            config
+                .patterns
+                .iter()
+                .map(|&pattern| pattern_regex(pattern).to_owned())
+                .chain(config.values.iter().cloned()),
+        )?;
+        let key = match config.action {
+            ScrubAction::Redact => None,
+            ScrubAction::Hash => {
+                let Some(key) = &config.key else {
+                    anyhow::bail!("scrub-tags requires a key to hash values");
+                };
+                Some(Hmac::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length"))
+            }
+        };
+        Ok(ScrubTags {
+            tags: config.tags.into_iter().map(String::into_bytes).collect(),
+            values,
+            ipv6: config.patterns.contains(&PiiPattern::Ip),
+            key,
+            next,
+        })
+    }
+
+    fn is_scrubbed(&self, name: &[u8], value: &[u8]) -> bool {
+        self.tags.iter().any(|x| x == name)
+            || self.values.is_match(value)
+            || (self.ipv6 && std::str::from_utf8(value).is_ok_and(|x| x.parse::<IpAddr>().is_ok()))
+    }
+
+    fn scrub(&self, value: &[u8]) -> Vec<u8> {
+        let Some(key) = &self.key else {
+            return REDACTED.to_vec();
+        };
+        let mut hex = String::with_capacity(HASH_LENGTH);
+        let mut mac = key.clone();
+        mac.update(value);
+        for byte in mac.finalize().into_bytes() {
+            if hex.len() >= HASH_LENGTH {
+                break;
+            }
+            write!(hex, "{:02x}", byte).unwrap();
+        }
+        hex.into_bytes()
+    }
+}
+
+impl<M> Middleware for ScrubTags<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let mut rewrite_tags = false;
+        let mut tags = Vec::new();
+        for tag in metric.tags_iter() {
+            if !tags.is_empty() {
+                tags.push(b',');
+            }
+            match tag.value() {
+                Some(value) if !value.is_empty() && self.is_scrubbed(tag.name(), value) => {
+                    tags.extend_from_slice(tag.name());
+                    tags.push(b':');
+                    tags.extend(self.scrub(value));
+                    rewrite_tags = true;
+                }
+                _ => tags.extend_from_slice(tag.raw),
+            }
+        }
+
+        if rewrite_tags {
+            metric.set_tags(&tags);
+        }
+        self.next.submit(metric)
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    fn scrubbed(config: ScrubTagsConfig, raw: &str) -> String {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut scrub = ScrubTags::new(config, next).unwrap();
+        scrub.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        drop(scrub);
+        String::from_utf8(results.into_inner().remove(0).raw).unwrap()
+    }
+
+    #[test]
+    fn redact() {
+        let config = ScrubTagsConfig {
+            tags: vec!["user_id".to_owned()],
+            patterns: vec![PiiPattern::Uuid, PiiPattern::Email, PiiPattern::Ip],
+            values: vec!["^secret-".to_owned()],
+            action: ScrubAction::Redact,
+            key: None,
+        };
+        assert_eq!(
+            scrubbed(
+                config.clone(),
+                "logins:1|c|#user_id:42,env:prod,contact:jane@example.com,client:10.0.0.1"
+            ),
+            "logins:1|c|#user_id:redacted,env:prod,contact:redacted,client:redacted"
+        );
+        assert_eq!(
+            scrubbed(
+                config.clone(),
+                "logins:1|c|#session:0b7b3a4e-1c2d-4e5f-8a9b-0c1d2e3f4a5b,peer:::1|T1692653389"
+            ),
+            "logins:1|c|#session:redacted,peer:redacted|T1692653389"
+        );
+        assert_eq!(
+            scrubbed(config.clone(), "logins:1|c|#token:secret-abc,version:1.2.3"),
+            "logins:1|c|#token:redacted,version:1.2.3"
+        );
+        assert_eq!(
+            scrubbed(config, "logins:1|c|#user_id"),
+            "logins:1|c|#user_id"
+        );
+    }
+
+    #[test]
+    fn hash() {
+        let config = ScrubTagsConfig {
+            tags: vec!["user_id".to_owned()],
+            patterns: vec![],
+            values: vec![],
+            action: ScrubAction::Hash,
+            key: Some("secret".to_owned()),
+        };
+        let a = scrubbed(config.clone(), "logins:1|c|#user_id:42");
+        let b = scrubbed(config.clone(), "logins:1|c|#user_id:43");
+        assert_eq!(a, scrubbed(config, "logins:1|c|#user_id:42"));
+        assert_ne!(a, b);
+        let hash = a.strip_prefix("logins:1|c|#user_id:").unwrap();
+        assert_eq!(hash.len(), HASH_LENGTH);
+        assert!(hash.bytes().all(|x| x.is_ascii_hexdigit()));
+    }
+}
@@ -1,72 +1,685 @@
-use std::io::ErrorKind;
-use std::net::UdpSocket;
+use std::net::TcpListener;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+#[cfg(not(all(feature = "tls", feature = "http", feature = "otlp", feature = "quic")))]
+use anyhow::anyhow;
 use anyhow::Error;
+use arc_swap::{ArcSwap, Guard};
 
+use crate::batch;
+use crate::config::{CanaryConfig, ServerConfig};
+use crate::health;
+use crate::ingest::socket::bind_tcp_listener;
+use crate::ingest::{Ingest, UdpIngest};
+use crate::line_handler::LineHandler;
+use crate::middleware::canary::Canary;
+use crate::middleware::shared::Shared;
+use crate::middleware::stream::{self, StreamKind};
 use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::timers::Deadlines;
 use crate::types::Metric;
 
+// How often to run the housekeeping of the source and check whether self metrics are due.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+// The receive timeout is only changed by at least this much, to save syscalls while a deadline
+// approaches under load. Polls may be late by up to this much.
+const TIMEOUT_GRANULARITY: Duration = Duration::from_millis(10);
+
+struct Housekeeping {
+    last_run_at: Instant,
+    last_self_metrics_at: Instant,
+}
+
+impl Housekeeping {
+    fn new() -> Self {
+        Housekeeping {
+            last_run_at: Instant::now(),
+            last_self_metrics_at: Instant::now(),
+        }
+    }
+}
+
+// Returns a new chain whenever the snapshot passed to `Server::with_snapshot` changed, and the
+// canary to roll it out with, starting at the given instant.
+type Rebuild<M> =
+    Box<dyn FnMut() -> Option<(Result<M, Error>, Option<(CanaryConfig, Instant)>)> + Send>;
+
+// The middlewares metrics are submitted to. After a reload with a canary, the new chain receives
+// a growing share of the timeseries and the previous chain the rest, until the ramp is done.
+enum Chain<M> {
+    Current(M),
+    Canary(Box<Canary<M, Chain<M>>>),
+    // Only while the chains are swapped.
+    Swapping,
+}
+
+impl<M> Middleware for Chain<M>
+where
+    M: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        match self {
+            Chain::Current(middleware) => middleware.join(),
+            Chain::Canary(canary) => canary.join(),
+            Chain::Swapping => Ok(()),
+        }
+    }
+
+    fn poll(&mut self) {
+        match self {
+            Chain::Current(middleware) => middleware.poll(),
+            Chain::Canary(canary) => canary.poll(),
+            Chain::Swapping => {}
+        }
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        match self {
+            Chain::Current(middleware) => middleware.submit(metric),
+            Chain::Canary(canary) => canary.submit(metric),
+            Chain::Swapping => {}
+        }
+    }
+
+    fn submit_batch(&mut self, metrics: &mut [Metric]) {
+        match self {
+            Chain::Current(middleware) => middleware.submit_batch(metrics),
+            Chain::Canary(canary) => canary.submit_batch(metrics),
+            Chain::Swapping => {}
+        }
+    }
+}
+
 pub struct Server<M> {
-    socket: UdpSocket,
-    middleware: M,
+    ingest: Box<dyn Ingest>,
+    stream_listeners: Vec<(TcpListener, StreamKind)>,
+    #[cfg(feature = "http")]
+    http_listener: Option<crate::middleware::http::HttpListener>,
+    #[cfg(feature = "otlp")]
+    otlp_grpc_listener: Option<crate::middleware::otlp_grpc::OtlpGrpcListener>,
+    #[cfg(feature = "quic")]
+    quic_listener: Option<crate::middleware::quic::QuicListener>,
+    config: ServerConfig,
+    // Shared with the threads handling stream connections and HTTP requests.
+    line_handler: Arc<LineHandler>,
+    // Shared with the threads handling stream connections.
+    middleware: Shared<Chain<M>>,
+    // The deadlines of the middlewares, also scheduled from the threads sharing them.
+    deadlines: Deadlines,
+    reload: Option<Box<dyn FnMut() -> Result<M, Error> + Send>>,
+    rebuild: Option<Rebuild<M>>,
 }
 
 impl<M> Server<M>
 where
-    M: Middleware,
+    M: Middleware + Send + 'static,
 {
-    pub fn new(listen: String, middleware: M) -> Result<Self, Error> {
-        let socket = UdpSocket::bind(listen)?;
-        // An acceptable balance between busyloop and responsiveness to signals.
-        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
-        Ok(Server { socket, middleware })
+    /// Receive metrics over UDP on `listen`.
+    pub fn new(listen: String, config: ServerConfig, middleware: M) -> Result<Self, Error> {
+        let ingest = UdpIngest::bind(&listen, &config)?;
+        Self::from_ingest(ingest, config, middleware)
     }
 
-    pub fn run(mut self) -> Result<(), Error> {
-        // if sending this large udp dataframes happens to work randomly, we should not be the
-        // one that breaks that setup.
-        let mut buf = [0; 65535];
+    /// Receive metrics from `ingest`. The TCP, TLS, HTTP, OTLP/gRPC and QUIC listeners in `config`
+    /// are started in addition to it, but its UDP socket settings are ignored.
+    pub fn from_ingest<I>(ingest: I, config: ServerConfig, middleware: M) -> Result<Self, Error>
+    where
+        I: Ingest + 'static,
+    {
+        let mut stream_listeners = Vec::new();
+        if let Some(tcp_listen) = &config.tcp_listen {
+            stream_listeners.push((bind_tcp_listener(tcp_listen, &config)?, StreamKind::Plain));
+        }
+        if let Some(tls) = &config.tls {
+            #[cfg(feature = "tls")]
+            stream_listeners.push((
+                bind_tcp_listener(&tls.listen, &config)?,
+                StreamKind::tls(tls)?,
+            ));
+            #[cfg(not(feature = "tls"))]
+            return Err(anyhow!(
+                "cannot listen on {}: statsdproxy was built without the tls feature",
+                tls.listen
+            ));
+        }
 
+        #[cfg(feature = "http")]
+        let http_listener = config
+            .http_listen
+            .as_deref()
+            .map(crate::middleware::http::HttpListener::bind)
+            .transpose()?;
+        #[cfg(not(feature = "http"))]
+        if let Some(http_listen) = &config.http_listen {
+            return Err(anyhow!(
+                "cannot listen on {}: statsdproxy was built without the http feature",
+                http_listen
+            ));
+        }
+
+        #[cfg(feature = "otlp")]
+        let otlp_grpc_listener = config
+            .otlp_grpc_listen
+            .as_deref()
+            .map(crate::middleware::otlp_grpc::OtlpGrpcListener::bind)
+            .transpose()?;
+        #[cfg(not(feature = "otlp"))]
+        if let Some(otlp_grpc_listen) = &config.otlp_grpc_listen {
+            return Err(anyhow!(
+                "cannot listen on {}: statsdproxy was built without the otlp feature",
+                otlp_grpc_listen
+            ));
+        }
+
+        #[cfg(feature = "quic")]
+        let quic_listener = config
+            .quic
+            .as_ref()
+            .map(crate::middleware::quic::QuicListener::bind)
+            .transpose()?;
+        #[cfg(not(feature = "quic"))]
+        if let Some(quic) = &config.quic {
+            return Err(anyhow!(
+                "cannot listen on {}: statsdproxy was built without the quic feature",
+                quic.listen
+            ));
+        }
+
+        let line_handler = Arc::new(LineHandler::new(&config)?);
+
+        Ok(Server {
+            ingest: Box::new(ingest),
+            stream_listeners,
+            #[cfg(feature = "http")]
+            http_listener,
+            #[cfg(feature = "otlp")]
+            otlp_grpc_listener,
+            #[cfg(feature = "quic")]
+            quic_listener,
+            config,
+            line_handler,
+            middleware: Shared::new(Chain::Current(middleware)),
+            deadlines: Deadlines::new(),
+            reload: None,
+            rebuild: None,
+        })
+    }
+
+    /// On SIGHUP, call `reload` to build a new middleware chain and swap it in. The old chain is
+    /// joined afterwards, so that it can flush any metrics it still holds. If `reload` fails, the
+    /// old chain is kept.
+    pub fn with_reload<F>(mut self, reload: F) -> Self
+    where
+        F: FnMut() -> Result<M, Error> + Send + 'static,
+    {
+        self.reload = Some(Box::new(reload));
+        self
+    }
+
+    /// Whenever a new value is stored in `snapshot`, call `build` with it to build a new
+    /// middleware chain and swap it in, like `with_reload` does on SIGHUP. This lets several
+    /// servers share one config, which is loaded once and applied by all of them.
+    pub fn with_snapshot<T, F>(self, snapshot: Arc<ArcSwap<T>>, build: F) -> Self
+    where
+        T: Send + Sync + 'static,
+        F: FnMut(&T) -> Result<M, Error> + Send + 'static,
+    {
+        self.with_canary_snapshot(snapshot, build, |_| None)
+    }
+
+    /// Like `with_snapshot`, but if `canary` returns a config for the new value, the new chain
+    /// only receives a percentage of the timeseries at first, and the previous chain keeps
+    /// receiving the rest until the percentage ramped up to 100. The ramp starts when the server
+    /// picks up the value, so servers sharing `snapshot` ramp up within a housekeeping interval of
+    /// each other.
+    pub fn with_canary_snapshot<T, F, C>(
+        mut self,
+        snapshot: Arc<ArcSwap<T>>,
+        mut build: F,
+        canary: C,
+    ) -> Self
+    where
+        T: Send + Sync + 'static,
+        F: FnMut(&T) -> Result<M, Error> + Send + 'static,
+        C: Fn(&T) -> Option<CanaryConfig> + Send + 'static,
+    {
+        // Kept alive, so that its address is not reused by a new value.
+        let mut current = snapshot.load_full();
+        self.rebuild = Some(Box::new(move || {
+            let value = snapshot.load();
+            if Arc::ptr_eq(&value, &current) {
+                return None;
+            }
+            current = Guard::into_inner(value);
+            let canary = canary(&current).map(|config| (config, Instant::now()));
+            Some((build(&current), canary))
+        }));
+        self
+    }
+
+    /// Receive metrics until SIGINT or SIGTERM is received. Then stop accepting metrics, wait for
+    /// the stream and HTTP listeners to finish, and join the middlewares so that they can flush
+    /// any metrics they still hold. A second signal exits immediately.
+    pub fn run(mut self) -> Result<(), Error> {
         let stop = Arc::new(AtomicBool::new(false));
+        let reload = Arc::new(AtomicBool::new(false));
+        let _entered = self.deadlines.enter();
 
         // This block is basically useless on windows. Would need to implement as a full fledged
         // service.
         #[cfg(not(windows))] // No SIGHUP on windows.
-        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&stop))?;
-        signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&stop))?;
-        signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&stop))?;
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload))?;
+        for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+            // Registered first, so that it only exits if `stop` was already set by an earlier
+            // signal, e.g. because joining the middlewares hangs.
+            signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop))?;
+            signal_hook::flag::register(signal, Arc::clone(&stop))?;
+        }
+
+        let mut ingest_threads = Vec::new();
+        for (listener, kind) in self.stream_listeners.drain(..) {
+            ingest_threads.push(stream::spawn_listener(
+                listener,
+                kind,
+                self.config.max_connections,
+                self.middleware.clone(),
+                self.deadlines.clone(),
+                Arc::clone(&self.line_handler),
+                Arc::clone(&stop),
+            )?);
+        }
+        #[cfg(feature = "http")]
+        if let Some(http_listener) = self.http_listener.take() {
+            ingest_threads.push(http_listener.spawn(
+                self.middleware.clone(),
+                self.deadlines.clone(),
+                Arc::clone(&self.line_handler),
+                Arc::clone(&stop),
+            ));
+        }
+        #[cfg(feature = "otlp")]
+        if let Some(otlp_grpc_listener) = self.otlp_grpc_listener.take() {
+            ingest_threads.push(otlp_grpc_listener.spawn(
+                self.middleware.clone(),
+                self.deadlines.clone(),
+                Arc::clone(&self.line_handler),
+                Arc::clone(&stop),
+            ));
+        }
+
+        #[cfg(feature = "quic")]
+        if let Some(quic_listener) = self.quic_listener.take() {
+            ingest_threads.push(quic_listener.spawn(
+                self.middleware.clone(),
+                self.deadlines.clone(),
+                Arc::clone(&self.line_handler),
+                Arc::clone(&stop),
+            ));
+        }
+
+        health::heartbeat();
+        let result = self.receive(&stop, &reload);
+        health::stopped();
 
-        let mut metric_data = Vec::new();
+        // Also stop the listeners if receiving failed.
+        stop.store(true, Ordering::Relaxed);
+        log::info!("shutting down, flushing middlewares");
+        for thread in ingest_threads {
+            if thread.join().is_err() {
+                log::error!("a listener thread panicked");
+            }
+        }
+        self.emit_self_metrics();
+        let mut middleware = self.middleware.lock();
+        middleware.poll();
+        middleware.join()?;
+        result
+    }
+
+    /// Receive metrics from the source until `stop` is set or the source ends, and submit all
+    /// metrics received at once as one batch.
+    fn receive(&mut self, stop: &AtomicBool, reload: &AtomicBool) -> Result<(), Error> {
+        let mut metrics = Vec::new();
+        // Buffers of already submitted metrics, reused to avoid allocations.
+        let mut spare_data: Vec<Vec<u8>> = Vec::new();
+
+        let mut housekeeping = Housekeeping::new();
+        // The timeout the source waits for metrics with, which it starts out with.
+        let mut timeout = HOUSEKEEPING_INTERVAL;
         while !stop.load(Ordering::Relaxed) {
-            let (num_bytes, _app_socket) = match self.socket.recv_from(buf.as_mut_slice()) {
-                Err(err) => match err.kind() {
-                    // Different timeout errors might be raised depending on platform.
-                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
-                        // Allow the middlewares to do any needed bookkeeping.
-                        self.middleware.poll();
-                        continue;
+            if reload.swap(false, Ordering::Relaxed) {
+                self.reload();
+            }
+            if let Some((new_middleware, canary)) =
+                self.rebuild.as_mut().and_then(|rebuild| rebuild())
+            {
+                self.swap_middleware(new_middleware, canary);
+            }
+            self.housekeeping(&mut housekeeping);
+
+            // Wake up for the next deadline of the middlewares.
+            let next_timeout = self
+                .deadlines
+                .timeout(Instant::now(), HOUSEKEEPING_INTERVAL)
+                .max(Duration::from_millis(1));
+            if next_timeout.abs_diff(timeout) >= TIMEOUT_GRANULARITY {
+                timeout = next_timeout;
+                self.ingest.set_timeout(timeout);
+            }
+
+            let line_handler = &self.line_handler;
+            let accept_batches = self.config.accept_batches;
+            let open = self.ingest.recv(&mut |source, payload| {
+                let source = line_handler.source(source);
+                let decompressed;
+                let payload = if accept_batches && batch::is_compressed(payload) {
+                    match batch::decompress(payload) {
+                        Ok(batch) => {
+                            decompressed = batch;
+                            &decompressed[..]
+                        }
+                        Err(e) => {
+                            log::warn!("received an invalid compressed batch: {}", e);
+                            self_metrics::incr("server.invalid_batches", &[], 1);
+                            return;
+                        }
                     }
-                    _ => return Err(Error::from(err)),
-                },
-                Ok(s) => s,
-            };
-            for raw in buf[..num_bytes].split(|&x| x == b'\n') {
-                if raw.is_empty() {
-                    continue;
+                } else {
+                    payload
+                };
+                if accept_batches && batch::is_batch(payload) {
+                    let result = batch::decode(payload, |mut metric| {
+                        if line_handler.prepare(&mut metric, &source) {
+                            metrics.push(metric);
+                        }
+                    });
+                    if let Err(e) = result {
+                        log::warn!("received an invalid batch: {}", e);
+                        self_metrics::incr("server.invalid_batches", &[], 1);
+                    }
+                    return;
                 }
 
-                metric_data.extend(raw);
-                let mut metric = Metric::new(metric_data);
+                for raw in payload.split(|&x| x == b'\n') {
+                    if raw.is_empty() {
+                        continue;
+                    }
+
+                    let mut metric_data = spare_data.pop().unwrap_or_default();
+                    metric_data.extend(raw);
+                    let mut metric = Metric::new(metric_data);
+                    if line_handler.prepare(&mut metric, &source) {
+                        metrics.push(metric);
+                    } else {
+                        let mut metric_data = metric.take();
+                        metric_data.clear();
+                        spare_data.push(metric_data);
+                    }
+                }
+            })?;
 
-                self.middleware.poll();
-                self.middleware.submit(&mut metric);
-                metric_data = metric.take();
+            // Also allows the middlewares to do any needed bookkeeping if nothing was received.
+            let mut middleware = self.middleware.lock();
+            middleware.poll();
+            if !metrics.is_empty() {
+                middleware.submit_batch(&mut metrics);
+            }
+            drop(middleware);
+            spare_data.extend(metrics.drain(..).map(|metric| {
+                let mut metric_data = metric.take();
                 metric_data.clear();
+                metric_data
+            }));
+
+            if !open {
+                log::info!("the source of metrics ended");
+                break;
             }
         }
         Ok(())
     }
+
+    fn reload(&mut self) {
+        let Some(reload) = &mut self.reload else {
+            // With a snapshot, whoever stores new values handles SIGHUP.
+            if self.rebuild.is_none() {
+                log::warn!("received SIGHUP, but reloading is not supported");
+            }
+            return;
+        };
+        let new_middleware = reload();
+        self.swap_middleware(new_middleware, None);
+    }
+
+    fn swap_middleware(
+        &mut self,
+        new_middleware: Result<M, Error>,
+        canary: Option<(CanaryConfig, Instant)>,
+    ) {
+        let new_middleware = match new_middleware {
+            Ok(middleware) => middleware,
+            Err(e) => {
+                log::error!("failed to reload, keeping the current middlewares: {}", e);
+                return;
+            }
+        };
+
+        // Metrics keep arriving in the socket's receive buffer while the chains are swapped, and
+        // are received by the new chain.
+        let mut chain = self.middleware.lock();
+        if let Some((config, started_at)) = canary {
+            // The previous chain keeps receiving the timeseries not yet moved to the new one.
+            let baseline = std::mem::replace(&mut *chain, Chain::Swapping);
+            let canary = Canary::new(&config, started_at, new_middleware, baseline);
+            *chain = Chain::Canary(Box::new(canary));
+            log::info!("reloaded middlewares, rolling them out gradually");
+            return;
+        }
+        let mut old_middleware = std::mem::replace(&mut *chain, Chain::Current(new_middleware));
+        drop(chain);
+        if let Err(e) = old_middleware.join() {
+            log::error!("failed to join the previous middlewares: {}", e);
+        }
+        log::info!("reloaded middlewares");
+    }
+
+    // Once the new chain receives all timeseries, join the previous one.
+    fn finish_canary(&mut self) {
+        let mut chain = self.middleware.lock();
+        if !matches!(&*chain, Chain::Canary(canary) if canary.is_done()) {
+            return;
+        }
+        let Chain::Canary(canary) = std::mem::replace(&mut *chain, Chain::Swapping) else {
+            unreachable!();
+        };
+        let (candidate, mut baseline) = canary.into_parts();
+        *chain = Chain::Current(candidate);
+        drop(chain);
+        if let Err(e) = baseline.join() {
+            log::error!("failed to join the previous middlewares: {}", e);
+        }
+        log::info!("rolled out reloaded middlewares");
+    }
+
+    fn housekeeping(&mut self, state: &mut Housekeeping) {
+        if state.last_run_at.elapsed() < HOUSEKEEPING_INTERVAL {
+            return;
+        }
+        state.last_run_at = Instant::now();
+        health::heartbeat();
+        self.finish_canary();
+        self.ingest.housekeeping();
+
+        if let Some(config) = &self.config.self_metrics {
+            if state.last_self_metrics_at.elapsed() >= Duration::from_secs(config.interval) {
+                state.last_self_metrics_at = Instant::now();
+                self.emit_self_metrics();
+            }
+        }
+    }
+
+    fn emit_self_metrics(&self) {
+        let Some(config) = &self.config.self_metrics else {
+            return;
+        };
+        let mut middleware = self.middleware.lock();
+        middleware.poll();
+        for mut metric in self_metrics::take(&config.prefix) {
+            middleware.submit(&mut metric);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::config::ClientTagConfig;
+    use crate::testutils::FnStep;
+
+    struct Payloads(Vec<&'static [u8]>);
+
+    impl Ingest for Payloads {
+        fn recv(
+            &mut self,
+            on_payload: &mut dyn FnMut(Option<IpAddr>, &[u8]),
+        ) -> Result<bool, Error> {
+            if self.0.is_empty() {
+                return Ok(false);
+            }
+            on_payload(Some("10.0.3.4".parse().unwrap()), self.0.remove(0));
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn custom_ingest() {
+        let results = Arc::new(Mutex::new(vec![]));
+        let next = FnStep({
+            let results = Arc::clone(&results);
+            move |metric: &mut Metric| results.lock().unwrap().push(metric.clone())
+        });
+        let config = ServerConfig {
+            client_tag: Some(ClientTagConfig::default()),
+            ..Default::default()
+        };
+        let ingest = Payloads(vec![b"users.online:1|c\nservers.online:1|c|#a:b", b"c:1|c"]);
+
+        // Stops once the source ends.
+        Server::from_ingest(ingest, config, next)
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!(
+            *results.lock().unwrap(),
+            [
+                Metric::new(b"users.online:1|c|#client:10.0.3.4".to_vec()),
+                Metric::new(b"servers.online:1|c|#a:b,client:10.0.3.4".to_vec()),
+                Metric::new(b"c:1|c|#client:10.0.3.4".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn batches() {
+        let mut batch = batch::MAGIC.to_vec();
+        batch::encode(&Metric::new(b"users.online:1|c".to_vec()), &mut batch);
+        let batch: &'static [u8] = Box::leak(batch.into_boxed_slice());
+
+        // Batches are only decoded if enabled, and parsed as text otherwise.
+        for accept_batches in [false, true] {
+            let results = Arc::new(Mutex::new(vec![]));
+            let next = FnStep({
+                let results = Arc::clone(&results);
+                move |metric: &mut Metric| results.lock().unwrap().push(metric.clone())
+            });
+            let config = ServerConfig {
+                accept_batches,
+                ..Default::default()
+            };
+            Server::from_ingest(Payloads(vec![batch]), config, next)
+                .unwrap()
+                .run()
+                .unwrap();
+            let decoded = *results.lock().unwrap() == [Metric::new(b"users.online:1|c".to_vec())];
+            assert_eq!(decoded, accept_batches);
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        type Results = Arc<Mutex<Vec<(&'static str, Metric)>>>;
+        fn chain(results: &Results, name: &'static str) -> FnStep<impl FnMut(&mut Metric)> {
+            let results = Arc::clone(results);
+            FnStep(move |metric: &mut Metric| results.lock().unwrap().push((name, metric.clone())))
+        }
+
+        // Stores a new name for the chain after the first payload.
+        struct Reloading(Payloads, Arc<ArcSwap<&'static str>>);
+
+        impl Ingest for Reloading {
+            fn recv(
+                &mut self,
+                on_payload: &mut dyn FnMut(Option<IpAddr>, &[u8]),
+            ) -> Result<bool, Error> {
+                let open = self.0.recv(on_payload)?;
+                self.1.store(Arc::new("new"));
+                Ok(open)
+            }
+        }
+
+        let results = Results::default();
+        let snapshot = Arc::new(ArcSwap::from_pointee("old"));
+        let ingest = Reloading(Payloads(vec![b"a:1|c", b"b:1|c"]), Arc::clone(&snapshot));
+        Server::from_ingest(ingest, ServerConfig::default(), chain(&results, "old"))
+            .unwrap()
+            .with_snapshot(snapshot, {
+                let results = Arc::clone(&results);
+                move |name| Ok(chain(&results, name))
+            })
+            .run()
+            .unwrap();
+        assert_eq!(
+            *results.lock().unwrap(),
+            [
+                ("old", Metric::new(b"a:1|c".to_vec())),
+                ("new", Metric::new(b"b:1|c".to_vec())),
+            ]
+        );
+
+        // With a canary at 0% that does not ramp up, the previous chain keeps all timeseries.
+        let results = Results::default();
+        let snapshot = Arc::new(ArcSwap::from_pointee("old"));
+        let ingest = Reloading(Payloads(vec![b"a:1|c", b"b:1|c"]), Arc::clone(&snapshot));
+        Server::from_ingest(ingest, ServerConfig::default(), chain(&results, "old"))
+            .unwrap()
+            .with_canary_snapshot(
+                snapshot,
+                {
+                    let results = Arc::clone(&results);
+                    move |name| Ok(chain(&results, name))
+                },
+                |_| {
+                    Some(CanaryConfig {
+                        percentage: 0.0,
+                        ramp_duration: 0,
+                    })
+                },
+            )
+            .run()
+            .unwrap();
+        assert_eq!(
+            *results.lock().unwrap(),
+            [
+                ("old", Metric::new(b"a:1|c".to_vec())),
+                ("old", Metric::new(b"b:1|c".to_vec())),
+            ]
+        );
+    }
 }
@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+
+use crate::bounded_queue::{BoundedQueue, Pop};
+use crate::config::OverloadPolicy;
+use crate::drops::{self, DropReason};
+use crate::health;
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::timers::Deadlines;
+use crate::types::Metric;
+
+// How often idle workers poll their middlewares, unless they have an earlier deadline.
+const WORKER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Worker {
+    queue: Arc<BoundedQueue<Metric>>,
+    thread: JoinHandle<Result<(), Error>>,
+}
+
+/// Distributes metrics over worker threads, each owning its own middleware chain. Metrics are
+/// assigned to workers by hashing their name, so all metrics of a timeseries are processed by
+/// the same worker in the order they were submitted.
+///
+/// Every worker has a bounded queue. If it is full, `overload_policy` decides whether `submit`
+/// blocks until the worker catches up, or which metric is dropped. Dropped metrics are counted in
+/// the `server.overload_drops` self metric.
+///
+/// With a single worker, this decouples receiving metrics from processing them.
+pub struct Sharded {
+    workers: Vec<Worker>,
+    overload_policy: OverloadPolicy,
+}
+
+impl Sharded {
+    pub fn new<M>(chains: Vec<M>, queue_size: usize, overload_policy: OverloadPolicy) -> Self
+    where
+        M: Middleware + Send + 'static,
+    {
+        assert!(
+            !chains.is_empty(),
+            "at least one middleware chain is required"
+        );
+        let workers = chains
+            .into_iter()
+            .map(|mut chain| {
+                let queue = Arc::new(BoundedQueue::new(queue_size));
+                let worker_queue = Arc::clone(&queue);
+                let thread = thread::spawn(move || {
+                    let deadlines = Deadlines::new();
+                    let _entered = deadlines.enter();
+                    loop {
+                        let timeout = deadlines.timeout(Instant::now(), WORKER_POLL_INTERVAL);
+                        match worker_queue.pop(timeout) {
+                            Pop::Item(mut metric) => {
+                                chain.poll();
+                                chain.submit(&mut metric);
+                            }
+                            Pop::Timeout => chain.poll(),
+                            Pop::Closed => break,
+                        }
+                    }
+                    chain.join()
+                });
+                Worker { queue, thread }
+            })
+            .collect();
+        Sharded {
+            workers,
+            overload_policy,
+        }
+    }
+
+    fn worker_index(&self, metric: &Metric) -> usize {
+        let key = metric.name().unwrap_or(&metric.raw);
+        crc32fast::hash(key) as usize % self.workers.len()
+    }
+}
+
+impl Middleware for Sharded {
+    fn join(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for worker in self.workers.drain(..) {
+            // Closing the queue makes the worker join its chain once the queue is drained.
+            worker.queue.close();
+            let worker_result = worker
+                .thread
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("worker thread panicked")));
+            if let Err(e) = worker_result {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.workers.is_empty() {
+            log::error!("sharded: dropping metric submitted after join");
+            return;
+        }
+        let index = self.worker_index(metric);
+        if self.workers[index].thread.is_finished() {
+            log::error!("sharded: worker {} exited, dropping metric", index);
+            return;
+        }
+        let metric = std::mem::replace(metric, Metric::new(Vec::new()));
+        if let Some(dropped) = self.workers[index].queue.push(metric, self.overload_policy) {
+            self_metrics::incr("server.overload_drops", &[], 1);
+            health::overloaded();
+            drops::record(DropReason::Overload, &dropped.raw);
+        }
+    }
+}
+
+impl Drop for Sharded {
+    fn drop(&mut self) {
+        if let Err(e) = self.join() {
+            log::error!("failed to join worker: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    struct Collect {
+        worker: usize,
+        results: Arc<Mutex<Vec<(usize, Metric)>>>,
+    }
+
+    impl Middleware for Collect {
+        fn submit(&mut self, metric: &mut Metric) {
+            self.results
+                .lock()
+                .unwrap()
+                .push((self.worker, metric.clone()));
+        }
+    }
+
+    #[test]
+    fn ordering_per_name() {
+        let results = Arc::new(Mutex::new(vec![]));
+        let chains = (0..4)
+            .map(|worker| Collect {
+                worker,
+                results: Arc::clone(&results),
+            })
+            .collect();
+        let mut sharded = Sharded::new(chains, 2, OverloadPolicy::Block);
+
+        for i in 0..100 {
+            for name in ["a", "b", "c", "d", "e"] {
+                sharded.submit(&mut Metric::new(format!("{}:{}|c", name, i).into_bytes()));
+            }
+        }
+        sharded.join().unwrap();
+
+        let results = results.lock().unwrap();
+        assert_eq!(results.len(), 500);
+        for name in ["a", "b", "c", "d", "e"] {
+            let metrics: Vec<_> = results
+                .iter()
+                .filter(|(_, metric)| metric.name() == Some(name.as_bytes()))
+                .collect();
+            // Every name is handled by one worker, in order.
+            assert!(metrics.iter().all(|(worker, _)| *worker == metrics[0].0));
+            let values: Vec<_> = metrics
+                .iter()
+                .map(|(_, metric)| metric.value().unwrap().to_vec())
+                .collect();
+            let expected: Vec<_> = (0..100).map(|i| i.to_string().into_bytes()).collect();
+            assert_eq!(values, expected);
+        }
+    }
+}
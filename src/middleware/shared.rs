@@ -0,0 +1,83 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::Error;
+
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+/// A handle to a middleware that can be cloned, so that several middleware chains can submit
+/// into the same next middleware. This is used to build nested chains that merge back into the
+/// main chain.
+pub struct Shared<M> {
+    inner: Arc<Mutex<M>>,
+}
+
+impl<M> Shared<M> {
+    pub fn new(middleware: M) -> Self {
+        Shared {
+            inner: Arc::new(Mutex::new(middleware)),
+        }
+    }
+
+    /// Lock the middleware for exclusive use, e.g. to submit several metrics in a row.
+    pub fn lock(&self) -> MutexGuard<'_, M> {
+        self.inner.lock().unwrap()
+    }
+
+    /// A handle that only submits into the middleware, for nested chains whose parent polls and
+    /// joins it through another handle, so that it is driven once rather than once per chain.
+    pub fn submit_only(&self) -> SubmitOnly<M> {
+        SubmitOnly(self.clone())
+    }
+}
+
+/// Returned by [`Shared::submit_only`]. Polling and joining it does nothing.
+pub struct SubmitOnly<M>(Shared<M>);
+
+impl<M> Middleware for SubmitOnly<M>
+where
+    M: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn poll(&mut self) {}
+
+    fn submit(&mut self, metric: &mut Metric) {
+        self.0.submit(metric)
+    }
+
+    fn submit_batch(&mut self, metrics: &mut [Metric]) {
+        self.0.submit_batch(metrics)
+    }
+}
+
+impl<M> Clone for Shared<M> {
+    fn clone(&self) -> Self {
+        Shared {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<M> Middleware for Shared<M>
+where
+    M: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        self.inner.lock().unwrap().join()
+    }
+
+    fn poll(&mut self) {
+        self.inner.lock().unwrap().poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        self.inner.lock().unwrap().submit(metric)
+    }
+
+    fn submit_batch(&mut self, metrics: &mut [Metric]) {
+        self.inner.lock().unwrap().submit_batch(metrics)
+    }
+}
@@ -0,0 +1,282 @@
+//! Ingest of newline-separated metrics over stream sockets (TCP, optionally wrapped in TLS).
+
+use std::io::{ErrorKind, Read};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[cfg(any(feature = "tls", feature = "quic"))]
+use anyhow::anyhow;
+use anyhow::Error;
+
+use crate::line_buffer::LineBuffer;
+use crate::line_handler::{LineHandler, Source};
+use crate::middleware::shared::Shared;
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::timers::Deadlines;
+use crate::types::Metric;
+
+// The maximum length of a line, matching the maximum size of a UDP datagram.
+pub(crate) const MAX_LINE_LENGTH: usize = 65535;
+
+// How long to wait between attempts to accept connections while there are none.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub enum StreamKind {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls(Arc<rustls::ServerConfig>),
+}
+
+impl StreamKind {
+    #[cfg(feature = "tls")]
+    pub fn tls(config: &crate::config::TlsListenConfig) -> Result<Self, Error> {
+        Ok(StreamKind::Tls(Arc::new(server_tls_config(config)?)))
+    }
+}
+
+/// The TLS config of a listener presenting the certificate in `config`.
+#[cfg(any(feature = "tls", feature = "quic"))]
+pub(crate) fn server_tls_config(
+    config: &crate::config::TlsListenConfig,
+) -> Result<rustls::ServerConfig, Error> {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&config.cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&config.key_path)?))?
+        .ok_or_else(|| anyhow!("no private key found in {}", config.key_path))?;
+    Ok(rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?)
+}
+
+/// Accept connections on `listener` in a background thread, and submit every line received on
+/// them to `middleware`. Each connection is handled by its own thread, and connections beyond
+/// `max_connections` are closed right away. The middlewares schedule their deadlines in
+/// `deadlines`. Once `stop` is set, the thread stops accepting connections and finishes after all
+/// connections have been handled.
+pub fn spawn_listener<M>(
+    listener: TcpListener,
+    kind: StreamKind,
+    max_connections: usize,
+    middleware: Shared<M>,
+    deadlines: Deadlines,
+    line_handler: Arc<LineHandler>,
+    stop: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>, Error>
+where
+    M: Middleware + Send + 'static,
+{
+    // Accept without blocking, so that `stop` is noticed even if no client connects.
+    listener.set_nonblocking(true)?;
+
+    Ok(thread::spawn(move || {
+        let mut connections: Vec<JoinHandle<()>> = Vec::new();
+        while !stop.load(Ordering::Relaxed) {
+            let (stream, addr) = match listener.accept() {
+                Ok(x) => x,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            connections.retain(|connection| !connection.is_finished());
+            if connections.len() >= max_connections {
+                log::warn!(
+                    "closing connection from {}: {} connections are open already",
+                    addr,
+                    connections.len()
+                );
+                self_metrics::incr("server.rejected_connections", &[], 1);
+                drop(stream);
+                continue;
+            }
+
+            let kind = kind.clone();
+            let middleware = middleware.clone();
+            let deadlines = deadlines.clone();
+            let line_handler = Arc::clone(&line_handler);
+            let stop = Arc::clone(&stop);
+            connections.push(thread::spawn(move || {
+                let _entered = deadlines.enter();
+                let source = line_handler.source(Some(addr.ip()));
+                let lines = Lines {
+                    handler: &line_handler,
+                    source: &source,
+                };
+                if let Err(e) = handle_connection(stream, kind, middleware, lines, &stop) {
+                    log::warn!("failed to handle connection: {}", e);
+                }
+            }));
+        }
+
+        for connection in connections {
+            let _ = connection.join();
+        }
+    }))
+}
+
+fn handle_connection<M>(
+    stream: TcpStream,
+    kind: StreamKind,
+    middleware: Shared<M>,
+    lines: Lines<'_>,
+    stop: &AtomicBool,
+) -> Result<(), Error>
+where
+    M: Middleware,
+{
+    // Accepted sockets may inherit non-blocking mode from the listener on some platforms.
+    stream.set_nonblocking(false)?;
+    // Wake up regularly to check whether the server is shutting down.
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    match kind {
+        StreamKind::Plain => read_lines(stream, middleware, lines, stop),
+        #[cfg(feature = "tls")]
+        StreamKind::Tls(config) => {
+            let connection = rustls::ServerConnection::new(config)?;
+            read_lines(
+                rustls::StreamOwned::new(connection, stream),
+                middleware,
+                lines,
+                stop,
+            )
+        }
+    }
+}
+
+fn read_lines<S, M>(
+    mut stream: S,
+    middleware: Shared<M>,
+    lines: Lines<'_>,
+    stop: &AtomicBool,
+) -> Result<(), Error>
+where
+    S: Read,
+    M: Middleware,
+{
+    let mut buf = [0; 65535];
+    let mut buffer = LineBuffer::new(MAX_LINE_LENGTH);
+
+    while !stop.load(Ordering::Relaxed) {
+        let num_bytes = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => continue,
+                // TLS clients commonly close the connection without a close_notify alert.
+                ErrorKind::UnexpectedEof => break,
+                _ => return Err(Error::from(e)),
+            },
+        };
+        let mut middleware = middleware.lock();
+        buffer.push(&buf[..num_bytes], |line| {
+            lines.submit(&mut *middleware, line)
+        });
+    }
+
+    let mut middleware = middleware.lock();
+    buffer.finish(|line| lines.submit(&mut *middleware, line));
+    Ok(())
+}
+
+/// How lines from one client are turned into metrics.
+#[derive(Clone, Copy)]
+pub(crate) struct Lines<'a> {
+    pub handler: &'a LineHandler,
+    pub source: &'a Source,
+}
+
+impl Lines<'_> {
+    /// Submit a single line to `middleware`.
+    pub fn submit<M>(&self, middleware: &mut M, raw: &[u8])
+    where
+        M: Middleware,
+    {
+        let mut metric = Metric::new(raw.to_vec());
+        if self.handler.prepare(&mut metric, self.source) {
+            middleware.poll();
+            middleware.submit(&mut metric);
+        }
+    }
+
+    /// Submit newline-separated metrics to `middleware`.
+    #[cfg(any(feature = "http", feature = "quic"))]
+    pub fn submit_all<M>(&self, middleware: &Shared<M>, lines: &[u8])
+    where
+        M: Middleware,
+    {
+        let mut middleware = middleware.lock();
+        for raw in lines.split(|&x| x == b'\n') {
+            if !raw.is_empty() {
+                self.submit(&mut *middleware, raw);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn max_connections() {
+        let results = Arc::new(Mutex::new(vec![]));
+        let middleware = Shared::new(FnStep({
+            let results = Arc::clone(&results);
+            move |metric: &mut Metric| results.lock().unwrap().push(metric.clone())
+        }));
+        let line_handler = Arc::new(LineHandler::new(&ServerConfig::default()).unwrap());
+        let stop = Arc::new(AtomicBool::new(false));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thread = spawn_listener(
+            listener,
+            StreamKind::Plain,
+            1,
+            middleware,
+            Deadlines::new(),
+            line_handler,
+            Arc::clone(&stop),
+        )
+        .unwrap();
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        first.write_all(b"users.online:1|c\n").unwrap();
+        while results.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Closed by the listener without reading from it.
+        let mut second = TcpStream::connect(addr).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(second.read(&mut [0; 1]).unwrap(), 0);
+
+        drop(first);
+        stop.store(true, Ordering::Relaxed);
+        thread.join().unwrap();
+        assert_eq!(
+            *results.lock().unwrap(),
+            [Metric::new(b"users.online:1|c".to_vec())]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+use anyhow::Error;
+
+use crate::drops;
+use crate::middleware::failover::SendErrors;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+// Distinct timeseries and tag values beyond this many per prefix or tag key are not counted, and
+// prefixes and tag keys beyond this many are counted under `OTHER`, to bound the memory used.
+const MAX_DISTINCT: usize = 100_000;
+const MAX_KEYS: usize = 1000;
+const OTHER: &str = "other";
+
+// Tag keys with at least this many distinct values are suggested for `deny-tag`.
+const DENY_TAG_MIN_VALUES: usize = 1000;
+// The window and headroom of the suggested `cardinality-limit`.
+const LIMIT_WINDOW: u64 = 3600;
+const LIMIT_HEADROOM: u64 = 2;
+// The flush interval of the suggested `aggregate-metrics`, which is suggested if counters or
+// gauges arrive at least `AGGREGATE_MIN_LINES` times per timeseries and flush interval.
+const FLUSH_INTERVAL: u64 = 10;
+const AGGREGATE_MIN_LINES: f64 = 2.0;
+// The number of prefixes and tag keys listed in the comments of a suggestion.
+const TOP: usize = 10;
+
+#[derive(Default)]
+struct PrefixStats {
+    series: HashSet<u64>,
+    // Counters and gauges, which `aggregate-metrics` folds into one line per flush.
+    aggregatable_lines: u64,
+    aggregatable_series: HashSet<u64>,
+}
+
+#[derive(Default)]
+struct TagStats {
+    metrics: u64,
+    values: HashSet<u64>,
+}
+
+/// The traffic observed by all `Suggest` instances.
+struct Profile {
+    started_at: Instant,
+    metrics: u64,
+    prefixes: HashMap<String, PrefixStats>,
+    tags: HashMap<Vec<u8>, TagStats>,
+    // The number of instances not joined yet. The last one to be joined prints the suggestions.
+    open: usize,
+}
+
+// Shared by every `Suggest`, e.g. in worker threads or after a reload, so that one draft covers
+// all traffic.
+static PROFILE: Mutex<Weak<Mutex<Profile>>> = Mutex::new(Weak::new());
+
+fn hash(parts: &[&[u8]]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Insert into a set of hashes, unless it is full.
+fn insert(set: &mut HashSet<u64>, value: u64) {
+    if set.len() < MAX_DISTINCT {
+        set.insert(value);
+    }
+}
+
+/// Round up to two significant digits, e.g. 2437 to 2500.
+fn round_up(value: u64) -> u64 {
+    let mut step = 1;
+    while value / step >= 100 {
+        step *= 10;
+    }
+    value.div_ceil(step) * step
+}
+
+/// Format a count of distinct values, which stops at `MAX_DISTINCT`.
+fn distinct(count: usize) -> String {
+    if count >= MAX_DISTINCT {
+        format!("at least {}", count)
+    } else {
+        count.to_string()
+    }
+}
+
+impl Profile {
+    fn record(&mut self, metric: &Metric) {
+        let (Some(name), Some(ty)) = (metric.name(), metric.ty()) else {
+            return;
+        };
+        self.metrics += 1;
+
+        let mut tags: Vec<&[u8]> = metric.tags_iter().map(|tag| tag.raw).collect();
+        tags.sort_unstable();
+        let series = hash(&[&[name, ty], &tags[..]].concat());
+
+        let mut prefix = drops::prefix(name);
+        if self.prefixes.len() >= MAX_KEYS && !self.prefixes.contains_key(&prefix) {
+            prefix = OTHER.to_owned();
+        }
+        let stats = self.prefixes.entry(prefix).or_default();
+        insert(&mut stats.series, series);
+        if matches!(ty, b"c" | b"g") {
+            stats.aggregatable_lines += 1;
+            insert(&mut stats.aggregatable_series, series);
+        }
+
+        for tag in metric.tags_iter() {
+            let Some(value) = tag.value() else {
+                continue;
+            };
+            let key = if self.tags.len() < MAX_KEYS || self.tags.contains_key(tag.name()) {
+                tag.name()
+            } else {
+                OTHER.as_bytes()
+            };
+            if !self.tags.contains_key(key) {
+                self.tags.insert(key.to_vec(), TagStats::default());
+            }
+            let stats = self.tags.get_mut(key).unwrap();
+            stats.metrics += 1;
+            insert(&mut stats.values, hash(&[value]));
+        }
+    }
+
+    /// A draft config with the suggested middlewares, as YAML with explanatory comments.
+    fn render(&self, elapsed_secs: u64) -> String {
+        let mut output = String::new();
+        writeln!(
+            output,
+            "# Draft config suggested by statsdproxy from {} metrics observed over {}s.\n\
+             # Review every suggestion before adopting it.\n\
+             middlewares:",
+            self.metrics, elapsed_secs
+        )
+        .unwrap();
+        let mut empty = true;
+
+        let mut tags: Vec<_> = self
+            .tags
+            .iter()
+            .filter(|(key, stats)| {
+                stats.values.len() >= DENY_TAG_MIN_VALUES && key.as_slice() != OTHER.as_bytes()
+            })
+            .map(|(key, stats)| (String::from_utf8_lossy(key), stats))
+            .collect();
+        tags.sort_unstable_by(|a, b| b.1.values.len().cmp(&a.1.values.len()).then(a.0.cmp(&b.0)));
+        if !tags.is_empty() {
+            empty = false;
+            output.push_str(
+                "\n  # Tags with many distinct values, which multiply the number of timeseries:\n",
+            );
+            for (key, stats) in &tags {
+                writeln!(
+                    output,
+                    "  #   {}: {} values in {} metrics",
+                    key,
+                    distinct(stats.values.len()),
+                    stats.metrics
+                )
+                .unwrap();
+            }
+            let keys: Vec<_> = tags.iter().map(|(key, _)| key.as_ref()).collect();
+            writeln!(
+                output,
+                "  - type: deny-tag\n    tags: [{}]",
+                keys.join(", ")
+            )
+            .unwrap();
+        }
+
+        let mut prefixes: Vec<_> = self.prefixes.iter().collect();
+        prefixes
+            .sort_unstable_by(|a, b| b.1.series.len().cmp(&a.1.series.len()).then(a.0.cmp(b.0)));
+        let series: usize = prefixes.iter().map(|(_, stats)| stats.series.len()).sum();
+        if series > 0 {
+            empty = false;
+            output.push_str("\n  # Timeseries by metric name prefix:\n");
+            for (prefix, stats) in prefixes.iter().take(TOP) {
+                writeln!(output, "  #   {}: {}", prefix, distinct(stats.series.len())).unwrap();
+            }
+            writeln!(
+                output,
+                "  # The limit allows {} times the {} timeseries observed, for all metrics\n  \
+                 # together. Raise it if the traffic observed is not representative.\n  \
+                 - type: cardinality-limit\n    limits:\n      - window: {}\n        limit: {}\n        \
+                 warn_percent: 80",
+                LIMIT_HEADROOM,
+                series,
+                LIMIT_WINDOW,
+                round_up(series as u64 * LIMIT_HEADROOM)
+            )
+            .unwrap();
+        }
+
+        // The number of counter and gauge lines per timeseries and flush interval.
+        let lines_per_flush = |stats: &PrefixStats| {
+            stats.aggregatable_lines as f64 / stats.aggregatable_series.len().max(1) as f64
+                * FLUSH_INTERVAL as f64
+                / elapsed_secs.max(1) as f64
+        };
+        let mut aggregatable: Vec<_> = prefixes
+            .iter()
+            .filter(|(_, stats)| lines_per_flush(stats) >= AGGREGATE_MIN_LINES)
+            .collect();
+        aggregatable.sort_by(|a, b| lines_per_flush(b.1).total_cmp(&lines_per_flush(a.1)));
+        if !aggregatable.is_empty() {
+            empty = false;
+            writeln!(
+                output,
+                "\n  # Counters and gauges that arrive several times per timeseries within {}s,\n  \
+                 # in lines per timeseries and flush:",
+                FLUSH_INTERVAL
+            )
+            .unwrap();
+            for (prefix, stats) in aggregatable.iter().take(TOP) {
+                writeln!(output, "  #   {}: {:.1}", prefix, lines_per_flush(stats)).unwrap();
+            }
+            writeln!(
+                output,
+                "  - type: aggregate-metrics\n    flush_interval: {}",
+                FLUSH_INTERVAL
+            )
+            .unwrap();
+        }
+
+        if empty {
+            output.truncate(output.len() - 1);
+            output.push_str(" []\n");
+        }
+        output
+    }
+}
+
+/// Profiles the traffic instead of forwarding it, and prints a draft config with suggested
+/// `deny-tag`, `cardinality-limit` and `aggregate-metrics` middlewares once all instances are
+/// joined, e.g. on shutdown or at the end of a replay from standard input.
+pub struct Suggest<W> {
+    profile: Arc<Mutex<Profile>>,
+    writer: W,
+    joined: bool,
+}
+
+impl<W> Suggest<W>
+where
+    W: Write,
+{
+    pub fn new(writer: W) -> Self {
+        let mut shared = PROFILE.lock().unwrap();
+        let profile = shared.upgrade().unwrap_or_else(|| {
+            let profile = Arc::new(Mutex::new(Profile {
+                started_at: Instant::now(),
+                metrics: 0,
+                prefixes: HashMap::new(),
+                tags: HashMap::new(),
+                open: 0,
+            }));
+            *shared = Arc::downgrade(&profile);
+            profile
+        });
+        profile.lock().unwrap().open += 1;
+        Suggest {
+            profile,
+            writer,
+            joined: false,
+        }
+    }
+}
+
+impl<W> SendErrors for Suggest<W>
+where
+    W: Write,
+{
+    fn send_errors(&self) -> u64 {
+        0
+    }
+}
+
+impl<W> Middleware for Suggest<W>
+where
+    W: Write,
+{
+    fn submit(&mut self, metric: &mut Metric) {
+        self.profile.lock().unwrap().record(metric);
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        if std::mem::replace(&mut self.joined, true) {
+            return Ok(());
+        }
+        let mut profile = self.profile.lock().unwrap();
+        profile.open -= 1;
+        if profile.open == 0 {
+            let output = profile.render(profile.started_at.elapsed().as_secs());
+            self.writer.write_all(output.as_bytes())?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(metrics: impl IntoIterator<Item = String>) -> Profile {
+        let mut profile = Profile {
+            started_at: Instant::now(),
+            metrics: 0,
+            prefixes: HashMap::new(),
+            tags: HashMap::new(),
+            open: 0,
+        };
+        for raw in metrics {
+            profile.record(&Metric::new(raw.into_bytes()));
+        }
+        profile
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(round_up(0), 0);
+        assert_eq!(round_up(7), 7);
+        assert_eq!(round_up(99), 99);
+        assert_eq!(round_up(101), 110);
+        assert_eq!(round_up(2437), 2500);
+        assert_eq!(round_up(2400), 2400);
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(
+            profile([]).render(60),
+            "# Draft config suggested by statsdproxy from 0 metrics observed over 60s.\n\
+             # Review every suggestion before adopting it.\n\
+             middlewares: []\n"
+        );
+
+        let metrics = (0..1200)
+            .map(|i| format!("api.requests:1|c|#request_id:{},env:prod", i))
+            .chain((0..100).map(|i| format!("users.online:{}|g|#env:prod", i)))
+            .chain((0..10).map(|i| format!("db.query:{}|ms", i)));
+        let output = profile(metrics).render(60);
+        assert_eq!(
+            output,
+            "# Draft config suggested by statsdproxy from 1310 metrics observed over 60s.
+# Review every suggestion before adopting it.
+middlewares:
+
+  # Tags with many distinct values, which multiply the number of timeseries:
+  #   request_id: 1200 values in 1200 metrics
+  - type: deny-tag
+    tags: [request_id]
+
+  # Timeseries by metric name prefix:
+  #   api: 1200
+  #   db: 1
+  #   users: 1
+  # The limit allows 2 times the 1202 timeseries observed, for all metrics
+  # together. Raise it if the traffic observed is not representative.
+  - type: cardinality-limit
+    limits:
+      - window: 3600
+        limit: 2500
+        warn_percent: 80
+
+  # Counters and gauges that arrive several times per timeseries within 10s,
+  # in lines per timeseries and flush:
+  #   users: 16.7
+  - type: aggregate-metrics
+    flush_interval: 10
+"
+        );
+        #[cfg(feature = "cli")]
+        {
+            let config: crate::config::Config = serde_yaml::from_str(&output).unwrap();
+            assert_eq!(config.middlewares.len(), 3);
+        }
+    }
+
+    #[test]
+    fn suggest() {
+        let mut output = Vec::new();
+        let mut first = Suggest::new(&mut output);
+        let mut second = Suggest::new(Vec::new());
+        first.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+        second.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+        // Only the last instance to be joined prints the suggestions for both.
+        first.join().unwrap();
+        first.join().unwrap();
+        let second_output = {
+            second.join().unwrap();
+            String::from_utf8(second.writer).unwrap()
+        };
+        drop(first);
+        assert!(output.is_empty());
+        assert!(second_output.contains("from 2 metrics"));
+    }
+}
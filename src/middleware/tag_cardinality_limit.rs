@@ -1,15 +1,24 @@
 use crate::config::{TagCardinalityLimitConfig, TagLimitConfig};
+use crate::contributors::{self, Contributors};
 use crate::middleware::Middleware;
+use crate::self_metrics;
 use crate::types::Metric;
 use anyhow::Error;
 use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+// How often new tag values over a warning threshold are logged.
+const WARN_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Clone, Debug)]
 struct Quota {
     // Currently this supports wildcard (*) or exact match on tag key
     tag: String,
     limit: u64,
+    // The number of values from which on new values are kept with a warning.
+    warn_at: Option<usize>,
     values_seen: HashSet<Vec<u8>>,
+    contributors: Contributors,
 }
 
 impl From<TagLimitConfig> for Quota {
@@ -17,7 +26,14 @@ impl From<TagLimitConfig> for Quota {
         Quota {
             tag: config.tag,
             limit: config.limit,
+            warn_at: config.warn_percent.map(|percent| {
+                config
+                    .limit
+                    .saturating_mul(u64::from(percent))
+                    .div_ceil(100) as usize
+            }),
             values_seen: HashSet::new(),
+            contributors: Contributors::new(WARN_INTERVAL),
         }
     }
 }
@@ -25,6 +41,7 @@ impl From<TagLimitConfig> for Quota {
 pub struct TagCardinalityLimit<M> {
     next: M,
     quotas: Vec<Quota>,
+    overflow_value: Option<Vec<u8>>,
 }
 
 impl<M> TagCardinalityLimit<M>
@@ -35,6 +52,7 @@ where
         Self {
             next,
             quotas: config.limits.into_iter().map(Quota::from).collect(),
+            overflow_value: config.overflow_value.map(String::into_bytes),
         }
     }
 }
@@ -50,7 +68,8 @@ where
     fn submit(&mut self, metric: &mut Metric) {
         let mut rewritten_metric = metric.clone();
 
-        rewritten_metric.set_tags_from_iter(metric.tags_iter().filter(|tag| {
+        let mut tags: Vec<Vec<u8>> = Vec::new();
+        'tags: for tag in metric.tags_iter() {
             let tag_name = tag.name();
 
             if let Some(tag_value) = tag.value() {
@@ -60,20 +79,29 @@ where
                         && (quota.values_seen.len() >= quota.limit as usize
                             && !quota.values_seen.contains(tag_value))
                     {
-                        // Drop the tags that don't fit in quota
+                        // Drop the tags that don't fit in quota, or replace their value
                         log::debug!(
                             "tag_cardinality_limit: Dropping tag {:?} with value {:?}",
                             tag_name,
                             tag_value
                         );
-                        return false;
+                        contributors::rejected(
+                            format_args!("tag-cardinality-limit tag={}", quota.tag),
+                            contributors::DEFAULT_WINDOW,
+                            &metric.raw,
+                        );
+                        if let Some(overflow_value) = &self.overflow_value {
+                            tags.push([tag_name, b":", overflow_value].concat());
+                        }
+                        continue 'tags;
                     }
                 }
             }
 
             // Tag fits in quota, or has no value -- keep it
-            true
-        }));
+            tags.push(tag.raw.to_vec());
+        }
+        rewritten_metric.set_tags(&tags.join(&b','));
 
         self.next.submit(&mut rewritten_metric.clone());
 
@@ -82,7 +110,34 @@ where
             for quota in self.quotas.iter_mut() {
                 if quota.tag == "*" || quota.tag.as_bytes() == tag.name() {
                     if let Some(tag_value) = tag.value() {
-                        quota.values_seen.insert(tag_value.to_vec());
+                        if self.overflow_value.as_deref() == Some(tag_value)
+                            || !quota.values_seen.insert(tag_value.to_vec())
+                        {
+                            continue;
+                        }
+
+                        if quota
+                            .warn_at
+                            .is_some_and(|warn_at| quota.values_seen.len() >= warn_at)
+                        {
+                            self_metrics::incr(
+                                "tag_cardinality_limit.warnings",
+                                &[("tag", &quota.tag)],
+                                1,
+                            );
+                            quota
+                                .contributors
+                                .record(rewritten_metric.name().unwrap_or_default());
+                            if let Some(top) = quota.contributors.report(Instant::now()) {
+                                log::warn!(
+                                    "tag_cardinality_limit: Tag {:?} has {} of {} values, new values by metric name: {}",
+                                    quota.tag,
+                                    quota.values_seen.len(),
+                                    quota.limit,
+                                    top
+                                );
+                            }
+                        }
 
                         if quota.values_seen.len() == quota.limit as usize {
                             log::info!(
@@ -114,7 +169,9 @@ mod tests {
             limits: vec![TagLimitConfig {
                 tag: "env".to_string(),
                 limit: 1,
+                warn_percent: None,
             }],
+            overflow_value: None,
         };
         let results = RefCell::new(vec![]);
         let next = FnStep(|metric: &mut Metric| {
@@ -141,4 +198,72 @@ mod tests {
             Metric::new(b"users.online:1|c|#env".to_vec())
         );
     }
+
+    #[test]
+    fn warning() {
+        let config = TagCardinalityLimitConfig {
+            limits: vec![TagLimitConfig {
+                tag: "env".to_string(),
+                limit: 4,
+                warn_percent: Some(50),
+            }],
+            overflow_value: None,
+        };
+        let mut limiter = TagCardinalityLimit::new(config, FnStep(|_: &mut Metric| {}));
+        for metric in [
+            "users.online:1|c|#env:a",
+            "users.online:1|c|#env:b",
+            "users.online:1|c|#env:b",
+            "servers.online:1|c|#env:c",
+            "users.online:1|c|#env:d",
+            "users.online:1|c|#env:d",
+        ] {
+            limiter.submit(&mut Metric::new(metric.as_bytes().to_vec()));
+        }
+        // The first warning at the second value is logged right away, the next are counted.
+        assert_eq!(
+            limiter.quotas[0].contributors.top(5),
+            [
+                ("servers.online".to_owned(), 1),
+                ("users.online".to_owned(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn overflow_value() {
+        let config = TagCardinalityLimitConfig {
+            limits: vec![TagLimitConfig {
+                tag: "env".to_string(),
+                limit: 1,
+                warn_percent: None,
+            }],
+            overflow_value: Some("__other__".to_string()),
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+
+        let mut limiter = TagCardinalityLimit::new(config, next);
+        for metric in [
+            "users.online:1|c|#env:prod,host:a",
+            "users.online:1|c|#env:dev,host:a",
+            "users.online:1|c|#env:__other__",
+            "users.online:1|c|#env:prod",
+        ] {
+            limiter.submit(&mut Metric::new(metric.as_bytes().to_vec()));
+        }
+
+        assert_eq!(
+            results.into_inner(),
+            [
+                "users.online:1|c|#env:prod,host:a",
+                "users.online:1|c|#env:__other__,host:a",
+                "users.online:1|c|#env:__other__",
+                "users.online:1|c|#env:prod",
+            ]
+            .map(|raw| Metric::new(raw.as_bytes().to_vec()))
+        );
+    }
 }
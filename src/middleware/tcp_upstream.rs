@@ -0,0 +1,444 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+
+use crate::config::SpoolConfig;
+use crate::drops::{self, DropReason};
+use crate::health;
+use crate::middleware::failover::SendErrors;
+use crate::middleware::Middleware;
+use crate::resolve::{self, Resolved};
+use crate::self_metrics;
+use crate::spool::Spool;
+use crate::types::Metric;
+
+// Write buffered lines once this many have accumulated, or after `FLUSH_INTERVAL`.
+const FLUSH_LINES: usize = 100;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long connecting and writing may block the thread submitting metrics.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The delay before reconnecting doubles with every failed attempt, up to the maximum.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A connection to the upstream, optionally wrapped in TLS.
+enum Connection {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    /// Write all of `buf`, and with TLS also the records rustls still buffers, so that nothing is
+    /// left unsent once this returns.
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.write_all(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => {
+                stream.write_all(buf)?;
+                stream.flush()
+            }
+        }
+    }
+}
+
+/// Forwards metrics to a statsd server over a persistent TCP connection, one metric per line,
+/// optionally wrapped in TLS.
+///
+/// If the connection fails, it is re-established with exponential backoff. In the meantime up to
+/// `max_buffered_lines` metrics are buffered, and the oldest metrics are dropped once the buffer
+/// is full, or written to a spool on disk if configured.
+pub struct TcpUpstream {
+    upstream: SocketAddr,
+    stream: Option<Connection>,
+    #[cfg(feature = "tls")]
+    tls: Option<(
+        Arc<rustls::ClientConfig>,
+        rustls::pki_types::ServerName<'static>,
+    )>,
+    // Metrics not yet written, oldest first.
+    buffered: VecDeque<Vec<u8>>,
+    max_buffered_lines: usize,
+    backoff: Duration,
+    next_connect_at: Instant,
+    last_flush_at: Instant,
+    send_errors: u64,
+    // New addresses of the upstream, if its name is resolved periodically.
+    resolved: Option<Resolved>,
+    spool: Option<Spool>,
+    // Whether the spool may hold metrics to write once connected.
+    spooled: bool,
+}
+
+impl TcpUpstream {
+    pub fn new<A>(upstream: A, max_buffered_lines: usize) -> Result<Self, Error>
+    where
+        A: ToSocketAddrs,
+    {
+        let upstream = upstream
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("could not resolve address"))?;
+        Ok(TcpUpstream {
+            upstream,
+            stream: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            buffered: VecDeque::new(),
+            max_buffered_lines: max_buffered_lines.max(1),
+            backoff: MIN_BACKOFF,
+            next_connect_at: Instant::now(),
+            last_flush_at: Instant::now(),
+            send_errors: 0,
+            resolved: None,
+            spool: None,
+            spooled: false,
+        })
+    }
+
+    /// Resolve `name`, the name the upstream was created with, again every `interval` in the
+    /// background, and reconnect to the new address whenever it changes.
+    pub fn with_resolve_interval(mut self, name: &str, interval: Duration) -> Self {
+        self.resolved = Some(resolve::spawn(name, interval, self.upstream));
+        self
+    }
+
+    /// Write metrics that do not fit into the buffer anymore, or are still buffered on shutdown,
+    /// to a spool file on disk, and write them once connected again. Metrics spooled by a previous
+    /// process are written as well.
+    pub fn with_spool(mut self, config: &SpoolConfig) -> Self {
+        self.spool = Some(Spool::new(config));
+        self.spooled = true;
+        self
+    }
+
+    /// Wrap the connection in TLS, verifying the upstream's certificate against the name
+    /// `server_name` or else `host`, and presenting a client certificate if configured.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(
+        mut self,
+        config: &crate::config::TlsUpstreamConfig,
+        host: &str,
+    ) -> Result<Self, Error> {
+        use std::fs::File;
+        use std::io::BufReader;
+
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(&config.ca_path)?)) {
+            roots.add(cert?)?;
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("no certificates found in {}", config.ca_path));
+        }
+        let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+        let client_config = match (&config.cert_path, &config.key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+                    .ok_or_else(|| anyhow!("no private key found in {}", key_path))?;
+                builder.with_client_auth_cert(certs, key)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(anyhow!("cert_path and key_path must be given together")),
+        };
+        let server_name = config.server_name.as_deref().unwrap_or(host);
+        let server_name = rustls::pki_types::ServerName::try_from(server_name.to_owned())
+            .map_err(|_| anyhow!("invalid TLS server name {}", server_name))?;
+        self.tls = Some((Arc::new(client_config), server_name));
+        Ok(self)
+    }
+
+    /// Spool `line` if there is a spool, and drop it otherwise or if the spool is full.
+    fn spool_line(&mut self, line: &[u8]) {
+        match &self.spool {
+            Some(spool) if spool.write(line) => self.spooled = true,
+            _ => drops::record(DropReason::UpstreamUnavailable, line),
+        }
+    }
+
+    /// Connect unless connected already, or still backing off after a failed attempt. Returns
+    /// whether there is a connection.
+    fn connect(&mut self) -> bool {
+        if self.stream.is_some() {
+            return true;
+        }
+        let now = Instant::now();
+        if now < self.next_connect_at {
+            return false;
+        }
+
+        let result =
+            TcpStream::connect_timeout(&self.upstream, CONNECT_TIMEOUT).and_then(|stream| {
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                self.wrap(stream)
+            });
+        match result {
+            Ok(connection) => {
+                log::info!("connected to TCP upstream {}", self.upstream);
+                self.stream = Some(connection);
+                self.backoff = MIN_BACKOFF;
+                true
+            }
+            Err(e) => {
+                log::error!(
+                    "failed to connect to TCP upstream {}, retrying in {:?}: {}",
+                    self.upstream,
+                    self.backoff,
+                    e
+                );
+                self_metrics::incr("upstream.connect_errors", &[], 1);
+                self.send_errors += 1;
+                health::upstream_result(false);
+                self.next_connect_at = now + self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                false
+            }
+        }
+    }
+
+    /// Wrap `stream` in TLS if configured. The handshake happens with the first write.
+    fn wrap(&self, stream: TcpStream) -> io::Result<Connection> {
+        #[cfg(feature = "tls")]
+        if let Some((config, server_name)) = &self.tls {
+            let connection = rustls::ClientConnection::new(config.clone(), server_name.clone())
+                .map_err(io::Error::other)?;
+            return Ok(Connection::Tls(Box::new(rustls::StreamOwned::new(
+                connection, stream,
+            ))));
+        }
+        Ok(Connection::Plain(stream))
+    }
+
+    /// Write all buffered and spooled metrics, unless there is no connection.
+    fn flush(&mut self) {
+        self.last_flush_at = Instant::now();
+        if (self.buffered.is_empty() && !self.spooled) || !self.connect() {
+            return;
+        }
+
+        let mut buf = Vec::new();
+        for line in &self.buffered {
+            buf.extend(line);
+            buf.push(b'\n');
+        }
+        if self.write(&buf) {
+            self.buffered.clear();
+            self.replay_spool();
+        }
+    }
+
+    /// Write everything in the spool. Metrics that fail to write are spooled again.
+    fn replay_spool(&mut self) {
+        let Some(spool) = &self.spool else {
+            return;
+        };
+        if !self.spooled {
+            return;
+        }
+        self.spooled = false;
+        let data = match spool.take() {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("failed to read spool: {}", e);
+                return;
+            }
+        };
+        if !data.is_empty() && !self.write(&data) {
+            self.spool_line(data.strip_suffix(b"\n").unwrap_or(&data));
+        }
+    }
+
+    /// Write newline-terminated lines to the connection, returning whether that succeeded.
+    fn write(&mut self, buf: &[u8]) -> bool {
+        let stream = self.stream.as_mut().expect("connected");
+        match stream.write_all(buf) {
+            Ok(()) => {
+                health::upstream_result(true);
+                true
+            }
+            Err(e) => {
+                // The caller keeps the lines to write them again on the next connection. Lines the
+                // server already received are sent twice, which is better than losing a partially
+                // written one.
+                log::error!("failed to write to TCP upstream {}: {}", self.upstream, e);
+                self_metrics::incr("upstream.send_errors", &[], 1);
+                self.send_errors += 1;
+                health::upstream_result(false);
+                self.stream = None;
+                false
+            }
+        }
+    }
+}
+
+impl Drop for TcpUpstream {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl SendErrors for TcpUpstream {
+    fn send_errors(&self) -> u64 {
+        self.send_errors
+    }
+}
+
+impl Middleware for TcpUpstream {
+    fn submit(&mut self, metric: &mut Metric) {
+        if self.buffered.len() >= self.max_buffered_lines {
+            if let Some(oldest) = self.buffered.pop_front() {
+                self.spool_line(&oldest);
+            }
+        }
+        self.buffered.push_back(metric.raw.clone());
+        if self.buffered.len() >= FLUSH_LINES {
+            self.flush();
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        // Make a last attempt regardless of the backoff.
+        self.next_connect_at = Instant::now();
+        self.flush();
+        if !self.buffered.is_empty() {
+            log::error!(
+                "{} {} metrics that could not be sent to TCP upstream {}",
+                if self.spool.is_some() {
+                    "spooling"
+                } else {
+                    "dropping"
+                },
+                self.buffered.len(),
+                self.upstream
+            );
+            for line in std::mem::take(&mut self.buffered) {
+                self.spool_line(&line);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) {
+        if let Some(addr) = self.resolved.as_mut().and_then(|x| x.changed()) {
+            // Lines written so far go to the old address.
+            self.flush();
+            self.upstream = addr;
+            self.stream = None;
+            self.next_connect_at = Instant::now();
+            self.backoff = MIN_BACKOFF;
+        }
+        if self.last_flush_at.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn reconnect() {
+        // Find a free port, and close it again so that connecting fails at first.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut upstream = TcpUpstream::new(addr, 2).unwrap();
+
+        for raw in ["a:1|c", "b:1|c", "c:1|c"] {
+            upstream.poll();
+            upstream.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        upstream.flush();
+        assert!(upstream.stream.is_none());
+
+        // Buffered metrics are sent once the upstream is back, except the oldest one, which did
+        // not fit into the buffer.
+        let listener = TcpListener::bind(addr).unwrap();
+        thread::sleep(MIN_BACKOFF);
+        upstream.flush();
+        let (mut stream, _) = listener.accept().unwrap();
+        upstream.submit(&mut Metric::new(b"d:1|c".to_vec()));
+        upstream.join().unwrap();
+        drop(upstream);
+
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "b:1|c\nc:1|c\nd:1|c\n");
+    }
+
+    #[test]
+    fn spool() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("statsdproxy-tcp-spool-{}", std::process::id()));
+        let config = SpoolConfig {
+            path: path.to_str().unwrap().to_owned(),
+            max_bytes: 1024,
+        };
+
+        // Metrics that do not fit into the buffer and those left on shutdown are spooled.
+        let mut upstream = TcpUpstream::new(addr, 1).unwrap().with_spool(&config);
+        for raw in ["a:1|c", "b:1|c", "c:1|c"] {
+            upstream.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        upstream.join().unwrap();
+        drop(upstream);
+        assert_eq!(std::fs::read(&path).unwrap(), b"a:1|c\nb:1|c\nc:1|c\n");
+
+        // And written by the next process once connected.
+        let listener = TcpListener::bind(addr).unwrap();
+        let mut upstream = TcpUpstream::new(addr, 1).unwrap().with_spool(&config);
+        upstream.submit(&mut Metric::new(b"d:1|c".to_vec()));
+        upstream.join().unwrap();
+        drop(upstream);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "d:1|c\na:1|c\nb:1|c\nc:1|c\n");
+        assert!(!path.exists());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_config() {
+        use crate::config::TlsUpstreamConfig;
+
+        let ca_path =
+            std::env::temp_dir().join(format!("statsdproxy-tls-ca-{}", std::process::id()));
+        std::fs::write(&ca_path, "").unwrap();
+        let config = TlsUpstreamConfig {
+            ca_path: ca_path.to_str().unwrap().to_owned(),
+            cert_path: None,
+            key_path: None,
+            server_name: None,
+        };
+        let upstream = TcpUpstream::new("127.0.0.1:8125", 1).unwrap();
+        let error = upstream.with_tls(&config, "localhost").err().unwrap();
+        assert!(error.to_string().starts_with("no certificates found"));
+        std::fs::remove_file(&ca_path).unwrap();
+
+        let upstream = TcpUpstream::new("127.0.0.1:8125", 1).unwrap();
+        assert!(upstream.with_tls(&config, "localhost").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::borrow::Cow;
+
+use anyhow::Error;
+
+use crate::config::TruncateConfig;
+use crate::middleware::Middleware;
+use crate::types::Metric;
+
+// The length of the suffix `_` and 8 hex digits of the CRC32 of the whole input.
+const HASH_SUFFIX_LENGTH: usize = 9;
+
+/// `input` cut off to at most `max_length` bytes at a character boundary, ending in a hash of
+/// all of `input` with `hash_suffix`.
+fn truncate(input: &[u8], max_length: Option<usize>, hash_suffix: bool) -> Cow<'_, [u8]> {
+    let Some(max_length) = max_length.filter(|&x| input.len() > x) else {
+        return Cow::Borrowed(input);
+    };
+    let mut end = if hash_suffix {
+        max_length.saturating_sub(HASH_SUFFIX_LENGTH)
+    } else {
+        max_length
+    };
+    // Don't cut UTF-8 characters in half.
+    while end > 0 && input[end] & 0xc0 == 0x80 {
+        end -= 1;
+    }
+    let mut output = input[..end].to_vec();
+    if hash_suffix {
+        output.extend(format!("_{:08x}", crc32fast::hash(input)).into_bytes());
+    }
+    Cow::Owned(output)
+}
+
+/// Cuts off metric names, tag keys and tag values that are longer than the configured number of
+/// bytes, for backends that drop lines over a length limit. With `hash_suffix`, truncated names,
+/// keys and values end in a hash of the original, so that they stay distinct.
+pub struct Truncate<M> {
+    max_name_length: Option<usize>,
+    max_tag_key_length: Option<usize>,
+    max_tag_value_length: Option<usize>,
+    hash_suffix: bool,
+    next: M,
+}
+
+impl<M> Truncate<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: TruncateConfig, next: M) -> Self {
+        Truncate {
+            max_name_length: config.max_name_length,
+            max_tag_key_length: config.max_tag_key_length,
+            max_tag_value_length: config.max_tag_value_length,
+            hash_suffix: config.hash_suffix,
+            next,
+        }
+    }
+
+    /// The truncated metric, if anything had to change.
+    fn truncate(&self, metric: &Metric) -> Option<Metric> {
+        let name = metric.name()?;
+        let new_name = truncate(name, self.max_name_length, self.hash_suffix);
+
+        let mut tags_changed = false;
+        let mut tags = Vec::new();
+        for tag in metric.tags_iter() {
+            if !tags.is_empty() {
+                tags.push(b',');
+            }
+            let key = truncate(tag.name(), self.max_tag_key_length, self.hash_suffix);
+            tags_changed |= matches!(key, Cow::Owned(_));
+            tags.extend_from_slice(&key);
+            if let Some(value) = tag.value() {
+                let value = truncate(value, self.max_tag_value_length, self.hash_suffix);
+                tags_changed |= matches!(value, Cow::Owned(_));
+                tags.push(b':');
+                tags.extend_from_slice(&value);
+            }
+        }
+
+        if matches!(new_name, Cow::Borrowed(_)) && !tags_changed {
+            return None;
+        }
+        let mut raw = new_name.into_owned();
+        raw.extend_from_slice(&metric.raw[name.len()..]);
+        let mut truncated = Metric::new(raw);
+        if tags_changed {
+            truncated.set_tags(&tags);
+        }
+        Some(truncated)
+    }
+}
+
+impl<M> Middleware for Truncate<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        match self.truncate(metric) {
+            Some(mut truncated) => self.next.submit(&mut truncated),
+            None => self.next.submit(metric),
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn truncate() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.raw.clone());
+        });
+        let config = TruncateConfig {
+            max_name_length: Some(12),
+            max_tag_key_length: Some(4),
+            max_tag_value_length: Some(5),
+            hash_suffix: false,
+        };
+        let mut truncate = Truncate::new(config, next);
+
+        for raw in [
+            &b"users.online:1|c|#env:prod"[..],
+            b"request.duration.total:1|ms|#route:/home/user,flag|T1692653389",
+            "caf\u{e9}s.visited:1|c|#city:z\u{fc}rich".as_bytes(),
+        ] {
+            truncate.submit(&mut Metric::new(raw.to_vec()));
+        }
+        assert_eq!(
+            results.into_inner(),
+            [
+                &b"users.online:1|c|#env:prod"[..],
+                b"request.dura:1|ms|#rout:/home,flag|T1692653389",
+                "caf\u{e9}s.visit:1|c|#city:z\u{fc}ri".as_bytes(),
+            ]
+        );
+    }
+
+    #[test]
+    fn hash_suffix() {
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.raw.clone());
+        });
+        let config = TruncateConfig {
+            max_name_length: None,
+            max_tag_key_length: None,
+            max_tag_value_length: Some(12),
+            hash_suffix: true,
+        };
+        let mut truncate = Truncate::new(config, next);
+
+        for raw in [
+            "requests:1|c|#url:/users/123456",
+            "requests:1|c|#url:/users/567890",
+        ] {
+            truncate.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        let results = results.into_inner();
+        for raw in &results {
+            assert_eq!(raw.len(), "requests:1|c|#url:".len() + 12);
+            assert!(raw.starts_with(b"requests:1|c|#url:/us_"));
+        }
+        assert_ne!(results[0], results[1]);
+    }
+}
@@ -1,21 +1,160 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io;
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 
+use crate::batch;
+use crate::circuit_breaker::{CircuitBreaker, Transition};
+use crate::config::{
+    CircuitBreakerConfig, OversizedDatagramPolicy, SpoolConfig, UpstreamFormat,
+    UpstreamRateLimitConfig,
+};
+use crate::drops::{self, DropReason};
+use crate::health;
+use crate::middleware::failover::SendErrors;
 use crate::middleware::Middleware;
-use crate::types::Metric;
+use crate::resolve::{self, Resolved};
+use crate::self_metrics;
+use crate::spool::Spool;
+use crate::timers;
+use crate::types::{Metric, MetricTag};
+use crate::upstream_limiter::{Admission, UpstreamLimiter};
 
 // hoisted from cadence crate -- we saw that with larger buffer size 8192, we were losing metrics
-const BUFSIZE: usize = 512;
+const DEFAULT_MAX_DATAGRAM_SIZE: usize = 512;
+
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(1);
+
+// The largest payload of a UDP datagram over IPv4.
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+// How often the number and duration of sends are reported in self metrics.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// The maximum datagram size chosen for each upstream address, for `/stats`.
+static DATAGRAM_SIZES: Mutex<BTreeMap<SocketAddr, usize>> = Mutex::new(BTreeMap::new());
+
+/// The maximum datagram size chosen for each upstream address created so far.
+pub fn datagram_sizes() -> Vec<(SocketAddr, usize)> {
+    let sizes = DATAGRAM_SIZES.lock().unwrap();
+    sizes.iter().map(|(addr, size)| (*addr, *size)).collect()
+}
+
+/// The largest UDP payload that fits into the MTU of the route to `upstream`, as known to the
+/// kernel: the MTU of the outgoing interface, or a smaller path MTU learned from ICMP.
+#[cfg(target_os = "linux")]
+fn discover_datagram_size(upstream: SocketAddr) -> Result<usize, Error> {
+    use std::os::fd::AsRawFd;
+
+    let bind: SocketAddr = if upstream.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(upstream)?;
+    // IP and UDP headers.
+    let (level, name, overhead) = if upstream.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_MTU, 28)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU, 48)
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&mut mtu as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok((mtu as usize).saturating_sub(overhead).min(MAX_UDP_PAYLOAD))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn discover_datagram_size(_upstream: SocketAddr) -> Result<usize, Error> {
+    Err(anyhow!("MTU discovery is only supported on Linux"))
+}
+
+// Sockets bound to a fixed port, shared by all upstreams binding that address. Several chains
+// can exist at once, e.g. with worker threads or while reloading.
+static FIXED_PORT_SOCKETS: Mutex<Vec<(SocketAddr, Weak<UdpSocket>)>> = Mutex::new(Vec::new());
+
+fn bind_socket(bind: SocketAddr) -> Result<Arc<UdpSocket>, Error> {
+    if bind.port() == 0 {
+        return Ok(Arc::new(bind_nonblocking(bind)?));
+    }
+
+    let mut sockets = FIXED_PORT_SOCKETS.lock().unwrap();
+    sockets.retain(|(_, socket)| socket.strong_count() > 0);
+    if let Some(socket) = sockets
+        .iter()
+        .find(|(addr, _)| *addr == bind)
+        .and_then(|(_, socket)| socket.upgrade())
+    {
+        return Ok(socket);
+    }
+    let socket = Arc::new(bind_nonblocking(bind)?);
+    sockets.push((bind, Arc::downgrade(&socket)));
+    Ok(socket)
+}
+
+fn bind_nonblocking(bind: SocketAddr) -> Result<UdpSocket, Error> {
+    let socket = UdpSocket::bind(bind)
+        .map_err(|e| anyhow!("failed to bind upstream socket to {}: {}", bind, e))?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn resolve<A>(addr: A) -> Result<SocketAddr, Error>
+where
+    A: ToSocketAddrs,
+{
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("could not resolve address"))
+}
 
 pub struct Upstream {
     socket: Arc<UdpSocket>,
     upstream: SocketAddr,
-    buffer: [u8; BUFSIZE],
+    buffer: Vec<u8>,
     buf_used: usize,
+    format: UpstreamFormat,
+    oversized_policy: OversizedDatagramPolicy,
+    // Tag names kept longest when truncating tags, most important first.
+    tag_priority: Vec<Vec<u8>>,
+    // Reused to encode metrics in the batch format.
+    encoded: Vec<u8>,
     last_sent_at: SystemTime,
+    // A partially filled datagram is sent once this long has passed since the last send.
+    max_delay: Duration,
+    spool: Option<Spool>,
+    // Whether the last attempt to send a datagram failed, in which case the spool is not
+    // replayed yet.
+    send_failed: bool,
+    send_errors: Cell<u64>,
+    // New addresses of the upstream, if its name is resolved periodically.
+    resolved: Option<Resolved>,
+    breaker: Option<RefCell<CircuitBreaker>>,
+    limiter: Option<RefCell<UpstreamLimiter>>,
+    // Full datagrams are sent with a single syscall once this many are waiting in `outgoing`.
+    send_batch_size: usize,
+    outgoing: RefCell<Vec<Vec<u8>>>,
+    // Attempted sends and the time spent in them since they were last reported.
+    sends: Cell<u64>,
+    send_time: Cell<Duration>,
+    last_reported_at: Instant,
 }
 
 impl Upstream {
@@ -23,47 +162,477 @@ impl Upstream {
     where
         A: ToSocketAddrs,
     {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_nonblocking(true).unwrap();
-        Ok(Upstream {
-            socket: Arc::new(socket),
-            upstream: upstream.to_socket_addrs()?.next().unwrap(),
-            buffer: [0; BUFSIZE],
+        let upstream = resolve(upstream)?;
+        let bind: SocketAddr = if upstream.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        Self::with_bind_address(upstream, bind)
+    }
+
+    /// Send from the local address `bind` instead of an ephemeral port on all interfaces, e.g.
+    /// for firewall rules or to route metrics over a dedicated interface. Upstreams binding the
+    /// same fixed port share one socket.
+    pub fn with_bind_address<A, B>(upstream: A, bind: B) -> Result<Self, Error>
+    where
+        A: ToSocketAddrs,
+        B: ToSocketAddrs,
+    {
+        let mut upstream = Upstream {
+            socket: bind_socket(resolve(bind)?)?,
+            upstream: resolve(upstream)?,
+            buffer: Vec::new(),
             buf_used: 0,
+            format: UpstreamFormat::Text,
+            oversized_policy: OversizedDatagramPolicy::Send,
+            tag_priority: Vec::new(),
+            encoded: Vec::new(),
             last_sent_at: UNIX_EPOCH,
-        })
+            max_delay: DEFAULT_MAX_DELAY,
+            spool: None,
+            send_failed: false,
+            send_errors: Cell::new(0),
+            resolved: None,
+            breaker: None,
+            limiter: None,
+            send_batch_size: 1,
+            outgoing: RefCell::new(Vec::new()),
+            sends: Cell::new(0),
+            send_time: Cell::new(Duration::ZERO),
+            last_reported_at: Instant::now(),
+        };
+        upstream.set_max_datagram_size(DEFAULT_MAX_DATAGRAM_SIZE);
+        Ok(upstream)
+    }
+
+    /// Send metrics in `format`. The batch formats are only understood by other statsdproxy
+    /// instances.
+    pub fn with_format(mut self, format: UpstreamFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Send datagrams of up to `size` bytes. Metrics that do not fit into a datagram on their own
+    /// are handled according to `policy`. With `OversizedDatagramPolicy::TruncateTags`, tags not
+    /// in `tag_priority` are removed first, then those in it from the end.
+    pub fn with_max_datagram_size(
+        mut self,
+        size: usize,
+        policy: OversizedDatagramPolicy,
+        tag_priority: &[String],
+    ) -> Self {
+        self.set_max_datagram_size(size);
+        self.oversized_policy = policy;
+        self.tag_priority = tag_priority.iter().map(|x| x.as_bytes().to_vec()).collect();
+        self
+    }
+
+    /// Send a partially filled datagram once `max_delay` has passed since the last send, instead
+    /// of a second.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Send datagrams as large as the MTU of the route to the upstream allows, see
+    /// `discover_datagram_size`. Keeps the current maximum datagram size if that fails, e.g. on
+    /// platforms other than Linux.
+    pub fn with_mtu_discovery(mut self) -> Self {
+        match discover_datagram_size(self.upstream) {
+            Ok(size) => {
+                log::info!(
+                    "sending datagrams of up to {} bytes to {}",
+                    size,
+                    self.upstream
+                );
+                self.set_max_datagram_size(size);
+            }
+            Err(e) => log::warn!(
+                "MTU discovery for {} failed, sending datagrams of up to {} bytes: {}",
+                self.upstream,
+                self.buffer.len(),
+                e
+            ),
+        }
+        self
+    }
+
+    fn set_max_datagram_size(&mut self, size: usize) {
+        self.buffer = vec![0; size];
+        DATAGRAM_SIZES.lock().unwrap().insert(self.upstream, size);
+    }
+
+    /// Resolve `name`, the name the upstream was created with, again every `interval` in the
+    /// background, and send to the new address whenever it changes.
+    pub fn with_resolve_interval(mut self, name: &str, interval: Duration) -> Self {
+        self.resolved = Some(resolve::spawn(name, interval, self.upstream));
+        self
+    }
+
+    /// Write metrics that fail to send to a spool file on disk, and send them again once sending
+    /// succeeds. Metrics spooled by a previous process are sent as well.
+    pub fn with_spool(mut self, config: &SpoolConfig) -> Self {
+        self.spool = Some(Spool::new(config));
+        self
     }
 
-    fn send_buffer(&self, buf: &[u8]) {
-        match self.socket.send_to(buf, self.upstream) {
+    /// Stop sending once the share of failed sends reaches a threshold, see `CircuitBreaker`.
+    /// Datagrams not sent while the circuit is open are spooled if there is a spool, and dropped
+    /// otherwise.
+    pub fn with_circuit_breaker(mut self, config: &CircuitBreakerConfig) -> Self {
+        self.breaker = Some(RefCell::new(CircuitBreaker::new(config)));
+        self
+    }
+
+    /// Send at most as many datagrams and bytes per second as `config` allows. Datagrams over the
+    /// limit are queued and sent once the limit allows, or dropped, see `UpstreamLimiter`.
+    pub fn with_rate_limit(mut self, config: &UpstreamRateLimitConfig) -> Self {
+        self.limiter = Some(RefCell::new(UpstreamLimiter::new(config, Instant::now())));
+        self
+    }
+
+    /// Send up to `size` full datagrams with a single `sendmmsg` syscall on Linux. Datagrams wait
+    /// until `size` of them are full, the end of a batch of metrics, or the next poll. Ignored on
+    /// other platforms.
+    pub fn with_send_batch_size(mut self, size: usize) -> Self {
+        if cfg!(target_os = "linux") {
+            self.send_batch_size = size.max(1);
+        }
+        self
+    }
+
+    fn send_buffer(&self, buf: &[u8]) -> bool {
+        let Some(limiter) = &self.limiter else {
+            return self.send_now(buf);
+        };
+        let admission = limiter.borrow_mut().admit(buf, Instant::now());
+        match admission {
+            Admission::Send => self.send_now(buf),
+            Admission::Queued { evicted } => {
+                self_metrics::incr(
+                    "upstream.rate_limited_datagrams",
+                    &[("action", "queued")],
+                    1,
+                );
+                for datagram in evicted {
+                    self.shed(&datagram);
+                }
+                true
+            }
+            Admission::Shed => {
+                self.shed(buf);
+                true
+            }
+        }
+    }
+
+    /// Drop a datagram over the rate limit.
+    fn shed(&self, buf: &[u8]) {
+        self_metrics::incr(
+            "upstream.rate_limited_datagrams",
+            &[("action", "dropped")],
+            1,
+        );
+        for line in lines(buf).split(|&x| x == b'\n') {
+            drops::record(DropReason::UpstreamRateLimited, line);
+        }
+    }
+
+    /// Send the datagrams queued by the rate limit that it allows by now.
+    fn send_queued(&mut self) {
+        let Some(limiter) = &self.limiter else {
+            return;
+        };
+        loop {
+            let Some(datagram) = limiter.borrow_mut().pop_ready(Instant::now()) else {
+                break;
+            };
+            self.send_failed = !self.send_now(&datagram);
+        }
+    }
+
+    fn send_now(&self, buf: &[u8]) -> bool {
+        let now = Instant::now();
+        if let Some(breaker) = &self.breaker {
+            if !breaker.borrow().allow(now) {
+                self_metrics::incr("upstream.circuit_rejected_datagrams", &[], 1);
+                match &self.spool {
+                    Some(spool) => {
+                        spool.write(&lines(buf));
+                    }
+                    None => {
+                        for line in lines(buf).split(|&x| x == b'\n') {
+                            drops::record(DropReason::UpstreamUnavailable, line);
+                        }
+                    }
+                }
+                return false;
+            }
+        }
+        if self.send_batch_size > 1 {
+            let mut outgoing = self.outgoing.borrow_mut();
+            outgoing.push(buf.to_vec());
+            if outgoing.len() < self.send_batch_size {
+                return true;
+            }
+            drop(outgoing);
+            return self.send_outgoing();
+        }
+        let ok = self.send_datagram(buf);
+        self.sends.set(self.sends.get() + 1);
+        self.send_time.set(self.send_time.get() + now.elapsed());
+        self.record(ok, now);
+        ok
+    }
+
+    /// Send all datagrams waiting in `outgoing` at once. Returns false if any of them failed.
+    fn send_outgoing(&self) -> bool {
+        let outgoing = std::mem::take(&mut *self.outgoing.borrow_mut());
+        if outgoing.is_empty() {
+            return true;
+        }
+        let now = Instant::now();
+        let datagrams: Vec<_> = outgoing.iter().map(|buf| self.encode(buf)).collect();
+        let results = sendmmsg::send_to(
+            &self.socket,
+            &datagrams.iter().map(|x| x.as_ref()).collect::<Vec<_>>(),
+            self.upstream,
+        );
+        self.sends.set(self.sends.get() + outgoing.len() as u64);
+        self.send_time.set(self.send_time.get() + now.elapsed());
+        let mut all_ok = true;
+        for ((buf, datagram), result) in outgoing.iter().zip(&datagrams).zip(results) {
+            let ok = self.sent(buf, datagram.len(), result);
+            self.record(ok, now);
+            all_ok &= ok;
+        }
+        all_ok
+    }
+
+    /// Record the result of a send in the circuit breaker, if any.
+    fn record(&self, ok: bool, now: Instant) {
+        if let Some(breaker) = &self.breaker {
+            let mut breaker = breaker.borrow_mut();
+            match breaker.record(ok, now) {
+                Some(Transition::Opened { errors, sends }) => {
+                    log::error!(
+                        "{} of {} sends to UDP upstream {} failed, not sending for {:?}",
+                        errors,
+                        sends,
+                        self.upstream,
+                        breaker.open_for()
+                    );
+                    self_metrics::incr("upstream.circuit_opened", &[], 1);
+                }
+                Some(Transition::Closed) => {
+                    log::info!("sending to UDP upstream {} succeeded again", self.upstream);
+                }
+                Some(Transition::Reopened) | None => {}
+            }
+        }
+    }
+
+    /// The datagram to send for `buf`, compressed if needed.
+    fn encode<'a>(&self, buf: &'a [u8]) -> Cow<'a, [u8]> {
+        #[cfg(feature = "zstd")]
+        if self.format == UpstreamFormat::CompressedBatch {
+            return Cow::Owned(batch::compress(buf));
+        }
+        Cow::Borrowed(buf)
+    }
+
+    fn send_datagram(&self, buf: &[u8]) -> bool {
+        let datagram = self.encode(buf);
+        let result = self.socket.send_to(&datagram, self.upstream);
+        self.sent(buf, datagram.len(), result)
+    }
+
+    /// Handle the `result` of sending `buf` as a datagram of `len` bytes.
+    fn sent(&self, buf: &[u8], len: usize, result: io::Result<usize>) -> bool {
+        match result {
             Ok(bytes) => {
-                if bytes != buf.len() {
+                if bytes != len {
                     // UDP, so this should never happen, but...
-                    log::error!("tried to send {} bytes but only sent {}.", buf.len(), bytes);
+                    log::error!("tried to send {} bytes but only sent {}.", len, bytes);
                 }
+                health::upstream_result(true);
+                true
             }
             Err(e) => {
                 log::error!("failed to send to UDP upstream: {}", e);
+                health::upstream_result(false);
+                self_metrics::incr("upstream.send_errors", &[], 1);
+                self.send_errors.set(self.send_errors.get() + 1);
+                self.spool_buffer(buf);
+                false
             }
         }
     }
 
+    fn spool_buffer(&self, buf: &[u8]) {
+        if let Some(spool) = &self.spool {
+            spool.write(&lines(buf));
+        }
+    }
+
+    /// Report the number and duration of sends, and whether the circuit is open, at most every
+    /// `REPORT_INTERVAL`.
+    fn report(&mut self) {
+        if self.last_reported_at.elapsed() < REPORT_INTERVAL {
+            return;
+        }
+        self.last_reported_at = Instant::now();
+        let sends = self.sends.replace(0);
+        let send_time = self.send_time.replace(Duration::ZERO);
+        if sends > 0 {
+            self_metrics::incr("upstream.sends", &[], sends);
+            self_metrics::incr(
+                "upstream.send_time_us",
+                &[],
+                send_time.as_micros().try_into().unwrap_or(u64::MAX),
+            );
+        }
+        if let Some(breaker) = &self.breaker {
+            let open = breaker.borrow().is_open();
+            self_metrics::gauge("upstream.circuit_open", &[], u8::from(open).into());
+        }
+    }
+
+    /// Send everything in the spool again.
+    fn replay_spool(&mut self) {
+        let Some(spool) = &self.spool else {
+            return;
+        };
+        let data = match spool.take() {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("failed to read spool: {}", e);
+                return;
+            }
+        };
+        for raw in data.split(|&x| x == b'\n') {
+            if !raw.is_empty() {
+                self.submit(&mut Metric::new(raw.to_vec()));
+            }
+        }
+        self.flush();
+    }
+
     fn flush(&mut self) {
         if self.buf_used > 0 {
-            self.send_buffer(&self.buffer[..self.buf_used]);
+            self.send_failed = !self.send_buffer(&self.buffer[..self.buf_used]);
             self.buf_used = 0;
         }
         self.last_sent_at = SystemTime::now(); // Annoyingly superfluous call to now().
     }
 
+    /// Add an encoded metric to the buffer, flushing the buffer first if it does not fit.
+    fn buffer_entry(&mut self, entry: &[u8]) {
+        // Text datagrams separate metrics by newlines, batches start with a header.
+        let (header, separator): (&[u8], &[u8]) = match self.format {
+            UpstreamFormat::Text => (b"", b"\n"),
+            UpstreamFormat::Batch | UpstreamFormat::CompressedBatch => (batch::MAGIC, b""),
+        };
+        let max_size = self.buffer.len();
+        if self.buf_used > 0 && separator.len() + entry.len() > max_size - self.buf_used {
+            // Message bigger than space left in buffer. Flush the buffer.
+            self.flush();
+        }
+        if header.len() + entry.len() > max_size {
+            // Message too big for the entire buffer, send it and pray.
+            self.send_failed = !self.send_buffer(&[header, entry].concat());
+            return;
+        }
+        // Put the message in the buffer, separating it from the previous message if any.
+        let prefix = if self.buf_used == 0 {
+            // Flushed by `timed_flush` at the latest.
+            timers::schedule(Instant::now() + self.max_delay);
+            header
+        } else {
+            separator
+        };
+        for bytes in [prefix, entry] {
+            self.buffer[self.buf_used..self.buf_used + bytes.len()].copy_from_slice(bytes);
+            self.buf_used += bytes.len();
+        }
+    }
+
+    /// The size of a datagram containing only `metric`. In the batch format, this leaves the
+    /// encoded metric in `encoded` for `buffer_metric`.
+    fn datagram_size(&mut self, metric: &Metric) -> usize {
+        match self.format {
+            UpstreamFormat::Text => metric.raw.len(),
+            UpstreamFormat::Batch | UpstreamFormat::CompressedBatch => {
+                self.encoded.clear();
+                batch::encode(metric, &mut self.encoded);
+                batch::MAGIC.len() + self.encoded.len()
+            }
+        }
+    }
+
+    /// Add `metric` to the buffer. Must directly follow `datagram_size` for the same metric.
+    fn buffer_metric(&mut self, metric: &Metric) {
+        match self.format {
+            UpstreamFormat::Text => self.buffer_entry(&metric.raw),
+            UpstreamFormat::Batch | UpstreamFormat::CompressedBatch => {
+                let encoded = std::mem::take(&mut self.encoded);
+                self.buffer_entry(&encoded);
+                self.encoded = encoded;
+            }
+        }
+    }
+
+    /// Remove tags from `metric`, least important first, until it fits into a datagram. Returns
+    /// false if it does not fit without any tags.
+    fn truncate_tags(&mut self, metric: &mut Metric) -> bool {
+        let mut tags: Vec<Vec<u8>> = metric.tags_iter().map(|x| x.raw.to_vec()).collect();
+        while self.datagram_size(metric) > self.buffer.len() {
+            // The last of the tags with the lowest priority.
+            let Some((i, _)) = tags
+                .iter()
+                .enumerate()
+                .max_by_key(|(i, tag)| (self.tag_priority(tag), *i))
+            else {
+                return false;
+            };
+            tags.remove(i);
+            metric.set_tags(&tags.join(&b","[..]));
+        }
+        true
+    }
+
+    /// The position of `tag` in `tag_priority`, or after all of them if it is not listed.
+    fn tag_priority(&self, tag: &[u8]) -> usize {
+        let tag = MetricTag::new(tag);
+        self.tag_priority
+            .iter()
+            .position(|x| x == tag.name())
+            .unwrap_or(self.tag_priority.len())
+    }
+
+    fn drop_oversized(&self, metric: &Metric) {
+        self_metrics::incr("upstream.oversized_metrics", &[("action", "dropped")], 1);
+        drops::record(DropReason::Malformed, &metric.raw);
+    }
+
+    fn send_pending(&mut self) {
+        if !self.outgoing.borrow().is_empty() {
+            self.send_failed = !self.send_outgoing();
+        }
+    }
+
     fn timed_flush(&mut self) {
         let now = SystemTime::now();
         if now
             .duration_since(self.last_sent_at)
-            .map_or(true, |x| x > Duration::from_secs(1))
+            .map_or(true, |x| x > self.max_delay)
         {
             // We have not sent any metrics in a while. Flush the buffer.
             self.flush();
+            if !self.send_failed {
+                self.replay_spool();
+            }
         }
     }
 }
@@ -71,33 +640,433 @@ impl Upstream {
 impl Drop for Upstream {
     fn drop(&mut self) {
         self.flush();
+        self.send_pending();
+    }
+}
+
+impl SendErrors for Upstream {
+    fn send_errors(&self) -> u64 {
+        self.send_errors.get()
     }
 }
 
 impl Middleware for Upstream {
     fn submit(&mut self, metric: &mut Metric) {
-        let metric_len = metric.raw.len();
-        if metric_len + 1 > BUFSIZE - self.buf_used {
-            // Message bigger than space left in buffer. Flush the buffer.
-            self.flush();
+        let max_size = self.buffer.len();
+        if self.datagram_size(metric) <= max_size {
+            self.buffer_metric(metric);
+            return;
         }
-        if metric_len > BUFSIZE {
-            // Message too big for the entire buffer, send it and pray.
-            self.send_buffer(&metric.raw);
-        } else {
-            // Put the message in the buffer, separating it from the previous message if any.
-            if self.buf_used > 0 {
-                self.buffer[self.buf_used] = b'\n';
-                self.buf_used += 1;
+
+        match self.oversized_policy {
+            OversizedDatagramPolicy::Send => {
+                self_metrics::incr("upstream.oversized_metrics", &[("action", "sent")], 1);
+                self.buffer_metric(metric);
+            }
+            OversizedDatagramPolicy::Drop => self.drop_oversized(metric),
+            OversizedDatagramPolicy::TruncateTags => {
+                let mut truncated = metric.clone();
+                if self.truncate_tags(&mut truncated) {
+                    self_metrics::incr("upstream.oversized_metrics", &[("action", "truncated")], 1);
+                    self.buffer_metric(&truncated);
+                } else {
+                    self.drop_oversized(metric);
+                }
             }
-            self.buffer[self.buf_used..self.buf_used + metric_len].copy_from_slice(&metric.raw);
-            self.buf_used += metric_len;
         }
         // poll gets called before submit, so if the buffer needed to be flushed for time reasons,
         // it already was.
     }
 
+    fn submit_batch(&mut self, metrics: &mut [Metric]) {
+        for metric in metrics {
+            self.submit(metric);
+        }
+        self.send_pending();
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.flush();
+        self.send_queued();
+        self.send_pending();
+        // Datagrams the rate limit holds back are spooled, or dropped without a spool.
+        if let Some(limiter) = &self.limiter {
+            let queued = limiter.borrow_mut().take_queued();
+            for datagram in queued {
+                match &self.spool {
+                    Some(_) => self.spool_buffer(&datagram),
+                    None => self.shed(&datagram),
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn poll(&mut self) {
+        if let Some(addr) = self.resolved.as_mut().and_then(|x| x.changed()) {
+            self.upstream = addr;
+        }
+        self.send_queued();
         self.timed_flush();
+        self.send_pending();
+        self.report();
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sendmmsg {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::fd::AsRawFd;
+    use std::{io, mem, ptr};
+
+    fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: sockaddr_storage is a plain C struct for which all zeroes is a valid value.
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let storage_ptr: *mut libc::sockaddr_storage = &mut storage;
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let address = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: addr.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from(*addr.ip()).to_be(),
+                    },
+                    sin_zero: [0; 8],
+                };
+                // SAFETY: sockaddr_storage is large enough and suitably aligned for all address
+                // families.
+                unsafe { ptr::write(storage_ptr.cast(), address) };
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let address = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: addr.port().to_be(),
+                    sin6_flowinfo: addr.flowinfo(),
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    },
+                    sin6_scope_id: addr.scope_id(),
+                };
+                // SAFETY: as above.
+                unsafe { ptr::write(storage_ptr.cast(), address) };
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    /// Send `datagrams` to `addr` with as few `sendmmsg` syscalls as possible. Returns the result
+    /// of sending each datagram, like `UdpSocket::send_to`.
+    pub fn send_to(
+        socket: &UdpSocket,
+        datagrams: &[&[u8]],
+        addr: SocketAddr,
+    ) -> Vec<io::Result<usize>> {
+        let (mut address, address_len) = sockaddr(addr);
+        let address_ptr: *mut libc::sockaddr_storage = &mut address;
+        let mut iovecs: Vec<libc::iovec> = datagrams
+            .iter()
+            .map(|datagram| libc::iovec {
+                iov_base: datagram.as_ptr() as *mut _,
+                iov_len: datagram.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iovec| {
+                // SAFETY: mmsghdr is a plain C struct for which all zeroes is a valid value.
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = address_ptr.cast();
+                header.msg_hdr.msg_namelen = address_len;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+
+        // The kernel stops at the first datagram that fails to send, and the next call reports
+        // its error.
+        let mut results = Vec::with_capacity(headers.len());
+        while results.len() < headers.len() {
+            let remaining = &mut headers[results.len()..];
+            // SAFETY: the headers point to `iovecs` and `address`, and the iovecs to
+            // `datagrams`, all of which outlive the call. The kernel only reads the datagrams.
+            let sent = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    remaining.as_mut_ptr(),
+                    remaining.len() as _,
+                    0,
+                )
+            };
+            match sent {
+                ..0 => results.push(Err(io::Error::last_os_error())),
+                0 => results.push(Err(io::ErrorKind::WriteZero.into())),
+                sent => results.extend(
+                    remaining[..sent as usize]
+                        .iter()
+                        .map(|header| Ok(header.msg_len as usize)),
+                ),
+            }
+        }
+        results
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sendmmsg {
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+
+    pub fn send_to(
+        socket: &UdpSocket,
+        datagrams: &[&[u8]],
+        addr: SocketAddr,
+    ) -> Vec<io::Result<usize>> {
+        datagrams
+            .iter()
+            .map(|datagram| socket.send_to(datagram, addr))
+            .collect()
+    }
+}
+
+/// The metrics in a datagram as newline-separated text lines, whatever its format.
+fn lines(buf: &[u8]) -> Cow<'_, [u8]> {
+    if !batch::is_batch(buf) {
+        return Cow::Borrowed(buf);
+    }
+    let mut lines = Vec::new();
+    let result = batch::decode(buf, |metric| {
+        if !lines.is_empty() {
+            lines.push(b'\n');
+        }
+        lines.extend(metric.raw);
+    });
+    debug_assert!(result.is_ok());
+    Cow::Owned(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_source_port() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let bind = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut first = Upstream::with_bind_address(upstream.local_addr().unwrap(), bind).unwrap();
+        // Binding the same port again shares the socket instead of failing.
+        let mut second = Upstream::with_bind_address(upstream.local_addr().unwrap(), bind).unwrap();
+        assert!(Arc::ptr_eq(&first.socket, &second.socket));
+
+        first.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+        first.join().unwrap();
+        second.join().unwrap();
+
+        let mut buf = [0; 1024];
+        let (len, source) = upstream.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"users.online:1|c");
+        assert_eq!(source, bind);
+    }
+
+    #[test]
+    fn batch_format() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client = Upstream::new(upstream.local_addr().unwrap())
+            .unwrap()
+            .with_format(UpstreamFormat::Batch);
+
+        client.submit(&mut Metric::new(
+            b"users.online:1|c|#country:china".to_vec(),
+        ));
+        client.submit(&mut Metric::new(b"servers.online:1|c".to_vec()));
+        client.join().unwrap();
+
+        let mut buf = [0; 1024];
+        let len = upstream.recv(&mut buf).unwrap();
+        let mut metrics = Vec::new();
+        batch::decode(&buf[..len], |metric| metrics.push(metric)).unwrap();
+        assert_eq!(
+            metrics,
+            [
+                Metric::new(b"users.online:1|c|#country:china".to_vec()),
+                Metric::new(b"servers.online:1|c".to_vec()),
+            ]
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_batch_format() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client = Upstream::new(upstream.local_addr().unwrap())
+            .unwrap()
+            .with_format(UpstreamFormat::CompressedBatch);
+        for _ in 0..10 {
+            client.submit(&mut Metric::new(
+                b"users.online:1|c|#country:china".to_vec(),
+            ));
+        }
+        client.join().unwrap();
+
+        let mut buf = [0; 1024];
+        let len = upstream.recv(&mut buf).unwrap();
+        assert!(batch::is_compressed(&buf[..len]));
+        let mut metrics = Vec::new();
+        batch::decode(&batch::decompress(&buf[..len]).unwrap(), |metric| {
+            metrics.push(metric)
+        })
+        .unwrap();
+        assert_eq!(
+            metrics,
+            vec![Metric::new(b"users.online:1|c|#country:china".to_vec()); 10]
+        );
+    }
+
+    #[test]
+    fn oversized_metrics() {
+        let send = |policy, raw: &[u8]| {
+            let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+            upstream
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            let mut client = Upstream::new(upstream.local_addr().unwrap())
+                .unwrap()
+                .with_max_datagram_size(28, policy, &["env".to_string(), "a".to_string()]);
+            client.submit(&mut Metric::new(raw.to_vec()));
+            client.join().unwrap();
+
+            let mut buf = [0; 1024];
+            let len = upstream.recv(&mut buf).ok()?;
+            Some(buf[..len].to_vec())
+        };
+
+        let raw = b"users.online:1|c|#a:1,env:prod,b:2";
+        assert_eq!(send(OversizedDatagramPolicy::Send, raw), Some(raw.to_vec()));
+        assert_eq!(send(OversizedDatagramPolicy::Drop, raw), None);
+        assert_eq!(
+            send(OversizedDatagramPolicy::TruncateTags, raw),
+            Some(b"users.online:1|c|#env:prod".to_vec())
+        );
+        assert_eq!(
+            send(
+                OversizedDatagramPolicy::TruncateTags,
+                b"users.online.in.all.countries:1|c"
+            ),
+            None
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mtu_discovery() {
+        // The loopback interface has an MTU of 64 KiB.
+        let upstream = Upstream::new("127.0.0.1:8125")
+            .unwrap()
+            .with_mtu_discovery();
+        assert_eq!(upstream.buffer.len(), MAX_UDP_PAYLOAD);
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        // Sending from an IPv6 socket to an IPv4 address fails.
+        let config = CircuitBreakerConfig {
+            error_rate: 0.5,
+            min_sends: 2,
+            window: 10,
+            open_for: 60,
+        };
+        let mut client = Upstream::with_bind_address("127.0.0.1:8125", "[::1]:0")
+            .unwrap()
+            .with_circuit_breaker(&config);
+        for _ in 0..5 {
+            client.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+            client.flush();
+        }
+
+        // Only the sends until the circuit opened were attempted.
+        assert_eq!(client.send_errors(), 2);
+        assert_eq!(client.sends.get(), 2);
+        assert!(client.breaker.as_ref().unwrap().borrow().is_open());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut client = Upstream::new(upstream.local_addr().unwrap())
+            .unwrap()
+            .with_rate_limit(&UpstreamRateLimitConfig {
+                packets_per_second: Some(1),
+                ..Default::default()
+            });
+        for raw in ["a:1|c", "b:1|c"] {
+            client.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+            client.flush();
+        }
+        client.poll();
+        client.join().unwrap();
+
+        // The second datagram is held back, and dropped on shutdown.
+        let mut buf = [0; 512];
+        let len = upstream.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"a:1|c");
+        assert!(upstream.recv(&mut buf).is_err());
+        assert!(client
+            .limiter
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .take_queued()
+            .is_empty());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn send_batch() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut client = Upstream::new(upstream.local_addr().unwrap())
+            .unwrap()
+            .with_max_datagram_size(5, OversizedDatagramPolicy::Send, &[])
+            .with_send_batch_size(3);
+        let mut buf = [0; 512];
+
+        // Every metric fills a datagram, which is sent once three are full.
+        for raw in ["a:1|c", "b:1|c", "c:1|c"] {
+            client.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        assert!(upstream.recv(&mut buf).is_err());
+        client.submit(&mut Metric::new(b"d:1|c".to_vec()));
+        for expected in [b"a:1|c", b"b:1|c", b"c:1|c"] {
+            let len = upstream.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], expected);
+        }
+
+        // Datagrams of a batch of metrics are sent at its end.
+        client.submit_batch(&mut [Metric::new(b"e:1|c".to_vec())]);
+        let len = upstream.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"d:1|c");
+        client.join().unwrap();
+        let len = upstream.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"e:1|c");
     }
 }
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use crc32fast::Hasher;
+
+use crate::config::UsageConfig;
+use crate::middleware::{scheduler, Middleware};
+use crate::timers::Timer;
+use crate::types::Metric;
+
+#[derive(Default)]
+struct Usage {
+    lines: u64,
+    bytes: u64,
+    series: HashSet<u32>,
+}
+
+/// Counts lines, bytes and distinct timeseries per value of a tag, and periodically emits the
+/// counts as metrics of their own, e.g. for internal chargeback. Metrics pass through unchanged.
+pub struct UsageAccounting<M> {
+    tag: Vec<u8>,
+    metric_prefix: String,
+    flush_interval: Duration,
+    flush_timer: Timer,
+    // Keyed by tag value. Metrics without the tag are accounted for under `None`.
+    usage: HashMap<Option<Vec<u8>>, Usage>,
+    next: M,
+}
+
+impl<M> UsageAccounting<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: UsageConfig, next: M) -> Self {
+        UsageAccounting {
+            tag: config.tag.into_bytes(),
+            metric_prefix: config.metric_prefix,
+            flush_interval: Duration::from_secs(config.flush_interval),
+            flush_timer: Timer::new(),
+            usage: HashMap::new(),
+            next,
+        }
+    }
+
+    fn hash_metric(metric: &Metric) -> u32 {
+        let mut hasher = Hasher::new();
+        if let Some(name) = metric.name() {
+            hasher.update(name);
+        }
+        if let Some(tags) = metric.tags() {
+            hasher.update(tags);
+        }
+        hasher.finalize()
+    }
+
+    fn flush(&mut self) {
+        let tag = String::from_utf8_lossy(&self.tag).into_owned();
+        scheduler::flushing(|| {
+            for (value, usage) in self.usage.drain() {
+                let tags = match value {
+                    Some(value) => format!("|#{}:{}", tag, String::from_utf8_lossy(&value)),
+                    None => String::new(),
+                };
+                for (name, value, ty) in [
+                    ("lines", usage.lines, "c"),
+                    ("bytes", usage.bytes, "c"),
+                    ("series", usage.series.len() as u64, "g"),
+                ] {
+                    let raw = format!("{}.{}:{}|{}{}", self.metric_prefix, name, value, ty, tags);
+                    self.next.submit(&mut Metric::new(raw.into_bytes()));
+                }
+            }
+        });
+    }
+}
+
+impl<M> Middleware for UsageAccounting<M>
+where
+    M: Middleware,
+{
+    fn join(&mut self) -> Result<(), Error> {
+        self.flush();
+        self.next.join()
+    }
+
+    fn poll(&mut self) {
+        let now = Instant::now();
+        let is_due = self.flush_timer.is_due(now);
+        if is_due {
+            self.flush();
+        }
+        if is_due || !self.flush_timer.is_set() {
+            self.flush_timer.set(now + self.flush_interval);
+        }
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        let value = metric
+            .tags_iter()
+            .find(|tag| tag.name() == self.tag.as_slice())
+            .and_then(|tag| tag.value().map(<[u8]>::to_vec));
+        let hash = Self::hash_metric(metric);
+
+        let usage = self.usage.entry(value).or_default();
+        usage.lines += 1;
+        usage.bytes += metric.raw.len() as u64;
+        usage.series.insert(hash);
+
+        self.next.submit(metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn basic() {
+        let config = UsageConfig {
+            tag: "team".to_string(),
+            metric_prefix: "usage".to_string(),
+            flush_interval: 60,
+        };
+
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results.borrow_mut().push(metric.clone());
+        });
+        let mut usage = UsageAccounting::new(config, next);
+
+        usage.submit(&mut Metric::new(b"a:1|c|#team:web".to_vec()));
+        usage.submit(&mut Metric::new(b"a:1|c|#team:web".to_vec()));
+        usage.submit(&mut Metric::new(b"b:1|c|#team:web".to_vec()));
+        assert_eq!(results.borrow().len(), 3);
+
+        usage.join().unwrap();
+        let mut reports: Vec<_> = results.borrow()[3..]
+            .iter()
+            .map(|metric| String::from_utf8(metric.raw.clone()).unwrap())
+            .collect();
+        reports.sort();
+        assert_eq!(
+            reports,
+            [
+                "usage.bytes:45|c|#team:web",
+                "usage.lines:3|c|#team:web",
+                "usage.series:2|g|#team:web",
+            ]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+
+use crate::config::{OutOfRangePolicy, ValidateValuesConfig, ValueRangeConfig};
+use crate::drops::{self, DropReason};
+use crate::middleware::Middleware;
+use crate::self_metrics;
+use crate::types::Metric;
+
+/// Drops metrics whose values are not finite numbers, e.g. `NaN` gauges, and drops or clamps
+/// values outside the range configured for their type, e.g. negative counters. Values of sets
+/// are not numbers and are passed on unchecked. Dropped metrics are counted with the
+/// `invalid_value` reason, and clamped ones in the `validate_values.clamped` self metric.
+pub struct ValidateValues<M> {
+    ranges: BTreeMap<Vec<u8>, ValueRangeConfig>,
+    out_of_range: OutOfRangePolicy,
+    next: M,
+}
+
+impl<M> ValidateValues<M>
+where
+    M: Middleware,
+{
+    pub fn new(config: ValidateValuesConfig, next: M) -> Self {
+        ValidateValues {
+            ranges: config
+                .ranges
+                .into_iter()
+                .map(|(ty, range)| (ty.into_bytes(), range))
+                .collect(),
+            out_of_range: config.out_of_range,
+            next,
+        }
+    }
+
+    /// The metric with clamped values if any were out of range, or `Err` if it must be dropped.
+    fn validate(&self, metric: &Metric) -> Result<Option<Metric>, ()> {
+        let (Some(name), Some(ty), Some(name_and_value)) =
+            (metric.name(), metric.ty(), metric.name_and_value())
+        else {
+            return Err(());
+        };
+        if ty == b"s" {
+            return Ok(None);
+        }
+        let raw_values = name_and_value.get(name.len() + 1..).unwrap_or_default();
+        let values = raw_values
+            .split(|&x| x == b':')
+            .map(|x| std::str::from_utf8(x).ok()?.parse::<f64>().ok())
+            .collect::<Option<Vec<f64>>>()
+            .filter(|values| values.iter().all(|x| x.is_finite()))
+            .ok_or(())?;
+
+        let Some(range) = self.ranges.get(ty) else {
+            return Ok(None);
+        };
+        let min = range.min.unwrap_or(f64::NEG_INFINITY);
+        let max = range.max.unwrap_or(f64::INFINITY);
+        if values.iter().all(|x| (min..=max).contains(x)) {
+            return Ok(None);
+        }
+        if self.out_of_range == OutOfRangePolicy::Drop {
+            return Err(());
+        }
+
+        let mut raw = name.to_vec();
+        for value in values {
+            raw.push(b':');
+            raw.extend(value.clamp(min, max).to_string().bytes());
+        }
+        raw.extend_from_slice(&metric.raw[name_and_value.len()..]);
+        Ok(Some(Metric::new(raw)))
+    }
+}
+
+impl<M> Middleware for ValidateValues<M>
+where
+    M: Middleware,
+{
+    fn poll(&mut self) {
+        self.next.poll()
+    }
+
+    fn submit(&mut self, metric: &mut Metric) {
+        match self.validate(metric) {
+            Ok(None) => self.next.submit(metric),
+            Ok(Some(mut clamped)) => {
+                self_metrics::incr("validate_values.clamped", &[], 1);
+                self.next.submit(&mut clamped)
+            }
+            Err(()) => drops::record(DropReason::InvalidValue, &metric.raw),
+        }
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.next.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testutils::FnStep;
+
+    #[test]
+    fn validate() {
+        let metrics = [
+            "requests:1|c|#a:b",
+            "requests:-1|c|#a:b",
+            "users.online:NaN|g",
+            "users.online:abc|g",
+            "temperature:-300|g|@0.5",
+            "request.duration:12:-1:inf|ms",
+            "request.duration:12:-1|ms",
+            "users.unique:abc|s",
+            "invalid",
+        ];
+        for (out_of_range, expected) in [
+            (
+                OutOfRangePolicy::Drop,
+                &["requests:1|c|#a:b", "users.unique:abc|s"][..],
+            ),
+            (
+                OutOfRangePolicy::Clamp,
+                &[
+                    "requests:1|c|#a:b",
+                    "requests:0|c|#a:b",
+                    "temperature:-273.15|g|@0.5",
+                    "request.duration:12:0|ms",
+                    "users.unique:abc|s",
+                ][..],
+            ),
+        ] {
+            let config = ValidateValuesConfig {
+                ranges: BTreeMap::from([
+                    (
+                        "c".to_owned(),
+                        ValueRangeConfig {
+                            min: Some(0.0),
+                            max: None,
+                        },
+                    ),
+                    (
+                        "g".to_owned(),
+                        ValueRangeConfig {
+                            min: Some(-273.15),
+                            max: Some(1000.0),
+                        },
+                    ),
+                    (
+                        "ms".to_owned(),
+                        ValueRangeConfig {
+                            min: Some(0.0),
+                            max: None,
+                        },
+                    ),
+                ]),
+                out_of_range,
+            };
+            let results = RefCell::new(vec![]);
+            let next = FnStep(|metric: &mut Metric| {
+                results
+                    .borrow_mut()
+                    .push(String::from_utf8(metric.raw.clone()).unwrap());
+            });
+            let mut validate = ValidateValues::new(config, next);
+            for raw in metrics {
+                validate.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+            }
+            drop(validate);
+            assert_eq!(results.into_inner(), expected);
+        }
+    }
+
+    #[test]
+    fn histograms() {
+        let config = ValidateValuesConfig {
+            ranges: BTreeMap::from([(
+                "h".to_owned(),
+                ValueRangeConfig {
+                    min: Some(0.0),
+                    max: None,
+                },
+            )]),
+            out_of_range: OutOfRangePolicy::Drop,
+        };
+        let results = RefCell::new(vec![]);
+        let next = FnStep(|metric: &mut Metric| {
+            results
+                .borrow_mut()
+                .push(String::from_utf8(metric.raw.clone()).unwrap());
+        });
+        let mut validate = ValidateValues::new(config, next);
+        for raw in [
+            "request.size:512:256|h",
+            "request.size:512:-1|h",
+            "request.size:NaN|h",
+            // The range of `h` does not apply to distributions.
+            "queue.wait:-1|d",
+            "queue.wait:inf|d",
+        ] {
+            validate.submit(&mut Metric::new(raw.as_bytes().to_vec()));
+        }
+        assert_eq!(
+            results.into_inner(),
+            ["request.size:512:256|h", "queue.wait:-1|d"]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::config::PacketRateLimitConfig;
+use crate::token_bucket::TokenBucket;
+
+// Once there are this many sources, forget about those that have been quiet long enough for their
+// bucket to be full again.
+const PRUNE_SOURCES_AT: usize = 10000;
+
+/// Limits the rate of received packets, either for all sources together or for each source.
+pub struct PacketLimiter {
+    config: PacketRateLimitConfig,
+    global: TokenBucket,
+    sources: HashMap<IpAddr, TokenBucket>,
+    prune_sources_at: usize,
+}
+
+impl PacketLimiter {
+    pub fn new(config: PacketRateLimitConfig, now: Instant) -> Self {
+        let global = TokenBucket::new(config.capacity(), config.packets_per_second as f64, now);
+        PacketLimiter {
+            config,
+            global,
+            sources: HashMap::new(),
+            prune_sources_at: PRUNE_SOURCES_AT,
+        }
+    }
+
+    /// Returns whether a packet from `source` is within the limit.
+    pub fn allow(&mut self, source: Option<IpAddr>, now: Instant) -> bool {
+        if !self.config.per_source {
+            return self.global.try_take(1.0, now);
+        }
+        let Some(source) = source else {
+            return true;
+        };
+
+        if self.sources.len() >= self.prune_sources_at {
+            self.sources.retain(|_, bucket| !bucket.is_full(now));
+            self.prune_sources_at = (self.sources.len() * 2).max(PRUNE_SOURCES_AT);
+        }
+        let capacity = self.config.capacity();
+        let rate = self.config.packets_per_second as f64;
+        self.sources
+            .entry(source)
+            .or_insert_with(|| TokenBucket::new(capacity, rate, now))
+            .try_take(1.0, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn global() {
+        let config = PacketRateLimitConfig {
+            packets_per_second: 2,
+            burst: None,
+            per_source: false,
+        };
+        let start = Instant::now();
+        let mut limiter = PacketLimiter::new(config, start);
+        let a = Some("10.0.3.4".parse().unwrap());
+        let b = Some("10.0.3.5".parse().unwrap());
+
+        assert!(limiter.allow(a, start));
+        assert!(limiter.allow(b, start));
+        assert!(!limiter.allow(b, start));
+        assert!(limiter.allow(b, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn per_source() {
+        let config = PacketRateLimitConfig {
+            packets_per_second: 1,
+            burst: Some(2),
+            per_source: true,
+        };
+        let start = Instant::now();
+        let mut limiter = PacketLimiter::new(config, start);
+        let a = Some("10.0.3.4".parse().unwrap());
+        let b = Some("10.0.3.5".parse().unwrap());
+
+        assert!(limiter.allow(a, start));
+        assert!(limiter.allow(a, start));
+        assert!(!limiter.allow(a, start));
+        // A flooding client does not affect others.
+        assert!(limiter.allow(b, start));
+        assert!(limiter.allow(None, start));
+        assert!(limiter.allow(a, start + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,41 @@
+//! The stable API of statsdproxy as a library: the types needed to build a chain of middlewares,
+//! write custom middlewares and run the server. Breaking changes to anything exported here only
+//! happen in major releases, and `tests/prelude.rs` fails when one is made by accident. Other
+//! modules are public for the bundled binary, hidden from the documentation, and may change in any
+//! release.
+//!
+//! The config types are non-exhaustive, so that new settings are not breaking changes. Start from
+//! their `Default` and set the fields you need.
+//!
+//! ```
+//! use statsdproxy::prelude::*;
+//!
+//! struct Count(usize);
+//!
+//! impl Middleware for Count {
+//!     fn submit(&mut self, _metric: &mut Metric) {
+//!         self.0 += 1;
+//!     }
+//! }
+//!
+//! let mut count = Count(0);
+//! count.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+//! assert_eq!(count.0, 1);
+//! ```
+
+// Taken by `Server::with_snapshot`, so that library users do not need to depend on arc-swap.
+pub use arc_swap::ArcSwap;
+
+#[cfg(feature = "cadence")]
+pub use crate::cadence::StatsdProxyMetricSink;
+pub use crate::config::{
+    AggregateMetricsConfig, Config, MiddlewareConfig, RelayConfig, ServerConfig, UpstreamConfig,
+};
+#[cfg(feature = "cli")]
+pub use crate::ingest::Ingest;
+pub use crate::middleware::relay::RelayPipeline;
+#[cfg(feature = "cli")]
+pub use crate::middleware::server::Server;
+pub use crate::middleware::upstream::Upstream;
+pub use crate::middleware::Middleware;
+pub use crate::types::{Metric, MetricTag};
@@ -0,0 +1,116 @@
+//! Resolving upstream hostnames again periodically, since the addresses behind them may change,
+//! e.g. when the upstream is redeployed.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+
+// Resolvers by name, interval and address family, shared by all upstreams resolving the same name.
+// Several chains can exist at once, e.g. with worker threads or while reloading. The thread of a
+// resolver stops once no upstream uses it anymore.
+type Resolvers = Vec<((String, Duration, bool), Weak<ArcSwap<SocketAddr>>)>;
+static RESOLVERS: Mutex<Resolvers> = Mutex::new(Vec::new());
+
+/// Resolve `name` every `interval` in a background thread, starting from `current`. Only
+/// addresses of the same family as `current` are used, since the socket sending to them is bound
+/// to that family. The returned [`Resolved`] reports every new address.
+pub(crate) fn spawn(name: &str, interval: Duration, current: SocketAddr) -> Resolved {
+    let key = (name.to_string(), interval, current.is_ipv4());
+    let mut resolvers = RESOLVERS.lock().unwrap();
+    resolvers.retain(|(_, addr)| addr.strong_count() > 0);
+    if let Some(addr) = resolvers
+        .iter()
+        .find(|(x, _)| *x == key)
+        .and_then(|(_, addr)| addr.upgrade())
+    {
+        return Resolved::new(addr);
+    }
+
+    let addr = Arc::new(ArcSwap::from_pointee(current));
+    let weak = Arc::downgrade(&addr);
+    resolvers.push((key, weak.clone()));
+    let name = name.to_string();
+    thread::spawn(move || {
+        let mut current = current;
+        loop {
+            thread::sleep(interval);
+            let Some(shared) = weak.upgrade() else {
+                break;
+            };
+            // With several addresses, keep the current one as long as it is among them.
+            let resolved = name.to_socket_addrs().map(|addrs| {
+                let addrs: Vec<_> = addrs
+                    .filter(|addr| addr.is_ipv4() == current.is_ipv4())
+                    .collect();
+                addrs
+                    .contains(&current)
+                    .then_some(current)
+                    .or_else(|| addrs.first().copied())
+            });
+            match resolved {
+                Ok(Some(addr)) if addr != current => {
+                    log::info!("{} now resolves to {}, was {}", name, addr, current);
+                    current = addr;
+                    shared.store(Arc::new(addr));
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => log::warn!("{} did not resolve to a usable address", name),
+                Err(e) => log::warn!("failed to resolve {}: {}", name, e),
+            }
+        }
+    });
+    Resolved::new(addr)
+}
+
+/// An upstream's view of the addresses its name resolves to.
+pub(crate) struct Resolved {
+    shared: Arc<ArcSwap<SocketAddr>>,
+    current: SocketAddr,
+}
+
+impl Resolved {
+    // Only addresses resolved afterwards are reported as changed.
+    fn new(shared: Arc<ArcSwap<SocketAddr>>) -> Self {
+        let current = **shared.load();
+        Resolved { shared, current }
+    }
+
+    /// The address if it changed since the last call. Several changes in between are reported
+    /// once, with the latest address.
+    pub(crate) fn changed(&mut self) -> Option<SocketAddr> {
+        let addr = **self.shared.load();
+        if addr == self.current {
+            return None;
+        }
+        self.current = addr;
+        Some(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn changed() {
+        let mut reader = spawn(
+            "127.0.0.1:8125",
+            Duration::from_millis(10),
+            "127.0.0.2:8125".parse().unwrap(),
+        );
+        let started_at = Instant::now();
+        loop {
+            if let Some(addr) = reader.changed() {
+                assert_eq!(addr, "127.0.0.1:8125".parse().unwrap());
+                break;
+            }
+            assert!(started_at.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
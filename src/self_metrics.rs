@@ -0,0 +1,82 @@
+//! Metrics about statsdproxy itself. Any component can record them, and the server periodically
+//! emits them through the middleware chain if configured to.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::types::Metric;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Counter(u64),
+    Gauge(f64),
+}
+
+// Keyed by metric name and formatted tags.
+static METRICS: Mutex<BTreeMap<(String, String), Value>> = Mutex::new(BTreeMap::new());
+
+fn format_tags(tags: &[(&str, &str)]) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}:{}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Increment a counter.
+pub fn incr(name: &str, tags: &[(&str, &str)], value: u64) {
+    let mut metrics = METRICS.lock().unwrap();
+    let entry = metrics
+        .entry((name.to_string(), format_tags(tags)))
+        .or_insert(Value::Counter(0));
+    if let Value::Counter(counter) = entry {
+        *counter += value;
+    }
+}
+
+/// Set a gauge.
+pub fn gauge(name: &str, tags: &[(&str, &str)], value: f64) {
+    METRICS
+        .lock()
+        .unwrap()
+        .insert((name.to_string(), format_tags(tags)), Value::Gauge(value));
+}
+
+/// Take all metrics recorded since the last call, with `prefix` prepended to their names.
+pub fn take(prefix: &str) -> Vec<Metric> {
+    let metrics = std::mem::take(&mut *METRICS.lock().unwrap());
+    metrics
+        .into_iter()
+        .map(|((name, tags), value)| {
+            let mut raw = match value {
+                Value::Counter(x) => format!("{}.{}:{}|c", prefix, name, x),
+                Value::Gauge(x) => format!("{}.{}:{}|g", prefix, name, x),
+            };
+            if !tags.is_empty() {
+                raw.push_str("|#");
+                raw.push_str(&tags);
+            }
+            Metric::new(raw.into_bytes())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic() {
+        incr("test.counter", &[("reason", "a")], 1);
+        incr("test.counter", &[("reason", "a")], 2);
+        gauge("test.gauge", &[], 1.5);
+
+        let metrics = take("statsdproxy");
+        assert!(metrics.contains(&Metric::new(
+            b"statsdproxy.test.counter:3|c|#reason:a".to_vec()
+        )));
+        assert!(metrics.contains(&Metric::new(b"statsdproxy.test.gauge:1.5|g".to_vec())));
+        assert!(!take("statsdproxy")
+            .iter()
+            .any(|metric| metric.name() == Some(b"statsdproxy.test.counter")));
+    }
+}
@@ -0,0 +1,95 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::config::SpoolConfig;
+use crate::self_metrics;
+
+/// A file on disk holding newline-separated metrics that could not be sent yet.
+#[derive(Debug)]
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl Spool {
+    pub fn new(config: &SpoolConfig) -> Self {
+        Spool {
+            path: PathBuf::from(&config.path),
+            max_bytes: config.max_bytes,
+        }
+    }
+
+    /// Append newline-separated metrics to the spool, returning false if they were not written
+    /// because the spool is full.
+    pub fn append(&self, data: &[u8]) -> io::Result<bool> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if file.metadata()?.len() + data.len() as u64 + 1 > self.max_bytes {
+            return Ok(false);
+        }
+        let mut line = Vec::with_capacity(data.len() + 1);
+        line.extend(data);
+        line.push(b'\n');
+        file.write_all(&line)?;
+        Ok(true)
+    }
+
+    /// Like `append`, but logs failures and counts the bytes in the `upstream.spooled_bytes` and
+    /// `upstream.spool_dropped_bytes` self metrics. Returns whether the metrics were written.
+    pub fn write(&self, data: &[u8]) -> bool {
+        match self.append(data) {
+            Ok(true) => {
+                self_metrics::incr("upstream.spooled_bytes", &[], data.len() as u64);
+                true
+            }
+            Ok(false) => {
+                log::error!("spool is full, dropping {} bytes", data.len());
+                self_metrics::incr("upstream.spool_dropped_bytes", &[], data.len() as u64);
+                false
+            }
+            Err(e) => {
+                log::error!("failed to write to spool: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Remove and return everything in the spool.
+    pub fn take(&self) -> io::Result<Vec<u8>> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                fs::remove_file(&self.path)?;
+                Ok(data)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_and_take() {
+        let path = std::env::temp_dir().join(format!("statsdproxy-spool-{}", std::process::id()));
+        let spool = Spool::new(&SpoolConfig {
+            path: path.to_str().unwrap().to_owned(),
+            max_bytes: 20,
+        });
+
+        assert_eq!(spool.take().unwrap(), b"");
+        assert!(spool.append(b"a:1|c\nb:1|c").unwrap());
+        // Does not fit anymore.
+        assert!(!spool.append(b"c:1|c\nd:1|c").unwrap());
+        assert!(spool.append(b"e:1|c").unwrap());
+
+        assert_eq!(spool.take().unwrap(), b"a:1|c\nb:1|c\ne:1|c\n");
+        assert_eq!(spool.take().unwrap(), b"");
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,95 @@
+//! Waiting for dependencies before listening, so that the first metrics after a deploy are not
+//! dropped or spooled because the upstream is not reachable yet.
+
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+
+use crate::config::{StartupConfig, StartupTimeoutPolicy};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+fn connect(address: &str) -> Result<(), Error> {
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("could not resolve address"))?;
+    TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    Ok(())
+}
+
+/// Wait until every address in `wait_for` and `addresses` accepts TCP connections, or the timeout
+/// in `config` passes.
+pub fn wait_for(config: &StartupConfig, addresses: &[&str]) -> Result<(), Error> {
+    let deadline = Instant::now() + Duration::from_secs(config.timeout);
+    let addresses = config
+        .wait_for
+        .iter()
+        .map(String::as_str)
+        .chain(addresses.iter().copied());
+    for address in addresses {
+        log::info!("waiting for {} to accept connections", address);
+        loop {
+            let error = match connect(address) {
+                Ok(()) => break,
+                Err(e) => e,
+            };
+            if Instant::now() >= deadline {
+                match config.on_timeout {
+                    StartupTimeoutPolicy::Listen => {
+                        log::warn!(
+                            "{} did not accept connections within {}s, listening anyway: {}",
+                            address,
+                            config.timeout,
+                            error
+                        );
+                        return Ok(());
+                    }
+                    StartupTimeoutPolicy::Exit => {
+                        return Err(anyhow!(
+                            "{} did not accept connections within {}s: {}",
+                            address,
+                            config.timeout,
+                            error
+                        ));
+                    }
+                }
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn wait() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().to_string();
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let config = StartupConfig {
+            wait_for: vec![open],
+            timeout: 0,
+            on_timeout: StartupTimeoutPolicy::Exit,
+        };
+        wait_for(&config, &[]).unwrap();
+        assert!(wait_for(&config, &[&closed]).is_err());
+        let config = StartupConfig {
+            on_timeout: StartupTimeoutPolicy::Listen,
+            ..config
+        };
+        wait_for(&config, &[&closed]).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! Deadlines of middlewares, e.g. the next flush of aggregated metrics. The loop running a chain
+//! waits for metrics until the earliest deadline at the latest, so that middlewares are polled
+//! when something is due instead of up to a second late, without waking up more often.
+//!
+//! Deadlines are kept per chain in [`Deadlines`], which the loop running the chain owns. A chain
+//! can be driven from several threads, e.g. the server's receiver thread and the threads handling
+//! its stream connections, so every thread driving a chain enters the chain's deadlines, and
+//! middlewares schedule into whatever deadlines the current thread entered. Deadlines scheduled
+//! on other threads only wake up the loop once it waits again, so they may be late by up to the
+//! loop's maximum timeout.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Deadlines beyond this many are dropped, latest first. They can only pile up if middlewares
+// schedule deadlines far in the future over and over again.
+const MAX_DEADLINES: usize = 1024;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Deadlines>> = const { RefCell::new(None) };
+}
+
+/// The deadlines of the middlewares of one chain.
+#[derive(Clone, Default)]
+pub struct Deadlines {
+    inner: Arc<Mutex<BTreeSet<Instant>>>,
+}
+
+impl Deadlines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `schedule` add to these deadlines on the current thread, until the returned guard is
+    /// dropped.
+    pub fn enter(&self) -> Entered {
+        let previous = CURRENT.with_borrow_mut(|current| current.replace(self.clone()));
+        Entered { previous }
+    }
+
+    fn insert(&self, deadline: Instant) {
+        let mut deadlines = self.inner.lock().unwrap();
+        deadlines.insert(deadline);
+        if deadlines.len() > MAX_DEADLINES {
+            deadlines.pop_last();
+        }
+    }
+
+    /// How long to wait at `now` before polling the chain: until the earliest deadline, but at
+    /// most `max`. Deadlines that passed are removed, since the caller polls right after.
+    pub fn timeout(&self, now: Instant, max: Duration) -> Duration {
+        let mut deadlines = self.inner.lock().unwrap();
+        while deadlines.first().is_some_and(|&deadline| deadline <= now) {
+            deadlines.pop_first();
+        }
+        deadlines
+            .first()
+            .map_or(max, |&deadline| (deadline - now).min(max))
+    }
+}
+
+/// Returned by [`Deadlines::enter`]. Restores the deadlines entered before when dropped.
+pub struct Entered {
+    previous: Option<Deadlines>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with_borrow_mut(|current| *current = previous);
+    }
+}
+
+/// Poll the chain driven by this thread at `deadline`, or soon after. Middlewares are still
+/// polled regularly without any deadline, and on threads that did not enter any deadlines.
+pub fn schedule(deadline: Instant) {
+    CURRENT.with_borrow(|current| {
+        if let Some(deadlines) = current {
+            deadlines.insert(deadline);
+        }
+    });
+}
+
+/// A deadline of a middleware, like its next flush. Setting it schedules a poll for it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timer {
+    deadline: Option<Instant>,
+}
+
+impl Timer {
+    pub const fn new() -> Self {
+        Timer { deadline: None }
+    }
+
+    pub fn set(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+        schedule(deadline);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    /// Whether the timer is set and its deadline passed at `now`.
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn deadlines() {
+        let deadlines = Deadlines::new();
+        let now = Instant::now();
+        let max = Duration::from_secs(1);
+        assert_eq!(deadlines.timeout(now, max), max);
+
+        let entered = deadlines.enter();
+        schedule(now + Duration::from_millis(300));
+        schedule(now + Duration::from_millis(100));
+        schedule(now + Duration::from_secs(5));
+        drop(entered);
+        // Not entered anymore.
+        schedule(now + Duration::from_millis(50));
+
+        assert_eq!(deadlines.timeout(now, max), Duration::from_millis(100));
+        assert_eq!(
+            deadlines.timeout(now + Duration::from_millis(100), max),
+            Duration::from_millis(200)
+        );
+        assert_eq!(
+            deadlines.timeout(now + Duration::from_millis(300), max),
+            max
+        );
+        assert_eq!(
+            deadlines.timeout(now + Duration::from_millis(4500), max),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn other_threads() {
+        let deadlines = Deadlines::new();
+        let now = Instant::now();
+        thread::spawn({
+            let deadlines = deadlines.clone();
+            move || {
+                let _entered = deadlines.enter();
+                schedule(now + Duration::from_millis(100));
+            }
+        })
+        .join()
+        .unwrap();
+        assert_eq!(
+            deadlines.timeout(now, Duration::from_secs(1)),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn timer() {
+        let now = Instant::now();
+        let mut timer = Timer::new();
+        assert!(!timer.is_set());
+        assert!(!timer.is_due(now));
+
+        timer.set(now + Duration::from_secs(1));
+        assert!(timer.is_set());
+        assert!(!timer.is_due(now));
+        assert!(timer.is_due(now + Duration::from_secs(1)));
+    }
+}
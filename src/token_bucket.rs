@@ -0,0 +1,72 @@
+use std::time::Instant;
+
+/// A token bucket, refilled continuously at `rate` tokens per second up to `capacity` tokens.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    pub fn new(capacity: f64, rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            last_refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.last_refilled_at = now;
+    }
+
+    /// Whether the bucket is full, i.e. indistinguishable from a new one.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    /// Whether `amount` tokens are available, without taking them.
+    pub fn has(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= amount
+    }
+
+    /// Take `amount` tokens if that many are available, returning whether they were taken.
+    pub fn try_take(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn basic() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 5.0, start);
+
+        assert!(bucket.try_take(6.0, start));
+        assert!(!bucket.try_take(6.0, start));
+        assert!(bucket.try_take(6.0, start + Duration::from_millis(400)));
+        // Never refills beyond the capacity.
+        assert!(!bucket.try_take(11.0, start + Duration::from_secs(60)));
+        assert!(bucket.try_take(10.0, start + Duration::from_secs(60)));
+    }
+}
@@ -40,7 +40,7 @@ pub struct MetricTag<'a> {
 }
 
 impl<'a> MetricTag<'a> {
-    pub fn new(bytes: &[u8]) -> MetricTag {
+    pub fn new(bytes: &[u8]) -> MetricTag<'_> {
         MetricTag {
             raw: bytes,
             name_value_sep_pos: bytes.iter().position(|&b| b == b':'),
@@ -85,7 +85,7 @@ impl<'a> Iterator for MetricTagIterator<'a> {
         let mut tag_pos_iter = remaining_tags.iter();
         let next_tag_sep_pos = tag_pos_iter.position(|&b| b == b',');
 
-        return if let Some(tag_sep_pos) = next_tag_sep_pos {
+        if let Some(tag_sep_pos) = next_tag_sep_pos {
             // Got a tag and more tags remain
             let tag = MetricTag::new(&remaining_tags[..tag_sep_pos]);
             self.remaining_tags = Some(&remaining_tags[tag_sep_pos + 1..]);
@@ -96,7 +96,7 @@ impl<'a> Iterator for MetricTagIterator<'a> {
             let tag = MetricTag::new(remaining_tags);
             self.remaining_tags = None;
             Some(tag)
-        };
+        }
     }
 }
 
@@ -115,6 +115,12 @@ impl Metric {
         Metric { raw, tags_pos }
     }
 
+    /// Like `new`, for a line whose tags are already known to be at `tags_pos`.
+    pub(crate) fn from_raw_parts(raw: Vec<u8>, tags_pos: Option<(usize, usize)>) -> Self {
+        debug_assert_eq!(Metric::new(raw.clone()).tags_pos, tags_pos);
+        Metric { raw, tags_pos }
+    }
+
     pub fn name_and_value(&self) -> Option<&[u8]> {
         self.raw.split(|&x| x == b'|').next()
     }
@@ -131,11 +137,47 @@ impl Metric {
         self.raw.split(|&x| x == b'|').nth(1)
     }
 
+    /// Whether this is a DogStatsD event, like `_e{5,4}:title|text`, rather than a metric.
+    pub fn is_event(&self) -> bool {
+        self.raw.starts_with(b"_e{")
+    }
+
+    /// Whether this is a DogStatsD service check, like `_sc|name|0`, rather than a metric.
+    pub fn is_service_check(&self) -> bool {
+        self.raw.starts_with(b"_sc|")
+    }
+
+    /// The sample rate, e.g. 0.5 for `users.online:1|c|@0.5`, if the metric has a valid one.
+    pub fn sample_rate(&self) -> Option<f64> {
+        self.raw
+            .split(|&x| x == b'|')
+            .skip(2)
+            .find_map(|field| field.strip_prefix(b"@"))
+            .and_then(|rate| std::str::from_utf8(rate).ok()?.parse::<f64>().ok())
+            .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+    }
+
+    /// Replace the sample rate with `rate`, or add it right after the type if the metric has none.
+    /// Metrics without a type are left unchanged.
+    ///
+    /// The rate is written with single precision, so that products of rates like `0.1 * 0.1` are
+    /// written as `@0.01` rather than `@0.010000000000000002`.
+    pub fn set_sample_rate(&mut self, rate: f64) {
+        let rate = format!("@{}", rate as f32);
+        let mut sections: Vec<&[u8]> = self.raw.split(|&x| x == b'|').collect();
+        match sections.iter().skip(2).position(|x| x.starts_with(b"@")) {
+            Some(i) => sections[i + 2] = rate.as_bytes(),
+            None if sections.len() >= 2 => sections.insert(2, rate.as_bytes()),
+            None => return,
+        }
+        *self = Metric::new(sections.join(&b'|'));
+    }
+
     pub fn tags(&self) -> Option<&[u8]> {
         self.tags_pos.map(|(i, j)| &self.raw[i..j])
     }
 
-    pub fn tags_iter(&self) -> MetricTagIterator {
+    pub fn tags_iter(&self) -> MetricTagIterator<'_> {
         MetricTagIterator {
             remaining_tags: self.tags(),
         }
@@ -163,6 +205,19 @@ impl Metric {
         }
     }
 
+    /// Add comma-separated `tags` after any existing tags.
+    pub fn append_tags(&mut self, tags: &[u8]) {
+        match self.tags() {
+            Some(existing) if !existing.is_empty() => {
+                let mut tag_buffer = existing.to_vec();
+                tag_buffer.push(b',');
+                tag_buffer.extend(tags);
+                self.set_tags(&tag_buffer);
+            }
+            _ => self.set_tags(tags),
+        }
+    }
+
     pub fn set_tags_from_iter<'a, M: Iterator<Item = MetricTag<'a>>>(&mut self, tag_iter: M) {
         let tag_bytes = tag_iter.map(|t| t.raw);
         let mut tag_buffer = Vec::new();
@@ -190,6 +245,7 @@ mod tests {
         let metric = Metric::new(b"users.online:1|c|@0.5".to_vec());
         assert_eq!(metric.ty().unwrap(), b"c");
         assert_eq!(metric.value().unwrap(), b"1");
+        assert_eq!(metric.sample_rate(), Some(0.5));
         assert_eq!(metric.tags(), None);
         assert_eq!(metric.tags_iter().collect::<Vec<MetricTag>>(), []);
         assert_eq!(metric.name().unwrap(), b"users.online");
@@ -215,12 +271,37 @@ mod tests {
         );
         assert_eq!(metric.tags().unwrap(), b"instance:foobar,country:china");
         assert_eq!(metric.name().unwrap(), b"users.online");
+        assert_eq!(metric.sample_rate(), Some(0.5));
         assert_eq!(
             metric.raw,
             b"users.online:1|c|@0.5|#instance:foobar,country:china|T1692653389"
         );
     }
 
+    #[test]
+    fn set_sample_rate() {
+        let mut metric = Metric::new(b"users.online:1|c|@0.5|#country:china".to_vec());
+        metric.set_sample_rate(0.25);
+        assert_eq!(metric.raw, b"users.online:1|c|@0.25|#country:china");
+        assert_eq!(metric.tags().unwrap(), b"country:china");
+
+        let mut metric = Metric::new(b"request.size:1:2|h|#route:home|T1692653389".to_vec());
+        metric.set_sample_rate(0.1);
+        assert_eq!(
+            metric.raw,
+            b"request.size:1:2|h|@0.1|#route:home|T1692653389"
+        );
+        assert_eq!(metric.tags().unwrap(), b"route:home");
+
+        let mut metric = Metric::new(b"requests:1|c|@0.1".to_vec());
+        metric.set_sample_rate(metric.sample_rate().unwrap() * 0.1);
+        assert_eq!(metric.raw, b"requests:1|c|@0.01");
+
+        let mut metric = Metric::new(b"invalid".to_vec());
+        metric.set_sample_rate(0.1);
+        assert_eq!(metric.raw, b"invalid");
+    }
+
     #[test]
     fn add_none_tags_to_none() {
         let mut metric = Metric::new(b"users.online:1|c|@0.5".to_vec());
@@ -241,6 +322,20 @@ mod tests {
         assert_eq!(metric.raw, b"users.online:1|c|@0.5|#country:japan");
     }
 
+    #[test]
+    fn append_tags() {
+        let mut metric = Metric::new(b"users.online:1|c|@0.5".to_vec());
+        metric.append_tags(b"country:japan");
+        assert_eq!(metric.raw, b"users.online:1|c|@0.5|#country:japan");
+
+        metric.append_tags(b"client:10.0.3.4");
+        assert_eq!(metric.tags().unwrap(), b"country:japan,client:10.0.3.4");
+        assert_eq!(
+            metric.raw,
+            b"users.online:1|c|@0.5|#country:japan,client:10.0.3.4"
+        );
+    }
+
     #[test]
     fn remove_tags_end() {
         let mut metric =
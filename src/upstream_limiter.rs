@@ -0,0 +1,170 @@
+//! A limit on the datagrams and bytes per second sent to an upstream, e.g. a hosted statsd
+//! endpoint that rejects traffic over a rate.
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::config::{RateLimitPolicy, UpstreamRateLimitConfig};
+use crate::token_bucket::TokenBucket;
+
+// The largest payload of a UDP datagram over IPv4. The byte limit allows bursts of at least this
+// much, so that every datagram can be sent eventually.
+const MAX_UDP_PAYLOAD: u64 = 65507;
+
+/// What to do with a datagram.
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    /// Send it right away.
+    Send,
+    /// It was queued, pushing out the oldest queued datagrams in `evicted`.
+    Queued { evicted: Vec<Vec<u8>> },
+    /// Drop it.
+    Shed,
+}
+
+/// Allows bursts of up to a second's worth of datagrams and bytes. Datagrams over the limit are
+/// queued or shed according to the policy, see `RateLimitPolicy`.
+#[derive(Debug)]
+pub struct UpstreamLimiter {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    policy: RateLimitPolicy,
+    max_queued_bytes: usize,
+    // Datagrams held back, oldest first.
+    queued: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+}
+
+impl UpstreamLimiter {
+    pub fn new(config: &UpstreamRateLimitConfig, now: Instant) -> Self {
+        UpstreamLimiter {
+            packets: config
+                .packets_per_second
+                .map(|rate| TokenBucket::new(rate as f64, rate as f64, now)),
+            bytes: config
+                .bytes_per_second
+                .map(|rate| TokenBucket::new(rate.max(MAX_UDP_PAYLOAD) as f64, rate as f64, now)),
+            policy: config.policy,
+            max_queued_bytes: config.max_queued_bytes,
+            queued: VecDeque::new(),
+            queued_bytes: 0,
+        }
+    }
+
+    /// Take the tokens to send a datagram of `len` bytes at `now`, if there are enough.
+    fn try_take(&mut self, len: usize, now: Instant) -> bool {
+        let packets_ok = self.packets.as_mut().is_none_or(|x| x.has(1.0, now));
+        let bytes_ok = self.bytes.as_mut().is_none_or(|x| x.has(len as f64, now));
+        if !(packets_ok && bytes_ok) {
+            return false;
+        }
+        if let Some(packets) = &mut self.packets {
+            packets.try_take(1.0, now);
+        }
+        if let Some(bytes) = &mut self.bytes {
+            bytes.try_take(len as f64, now);
+        }
+        true
+    }
+
+    /// Decide what to do with `datagram` at `now`. Queued datagrams are sent first, so a datagram
+    /// is only sent right away if none are queued.
+    pub fn admit(&mut self, datagram: &[u8], now: Instant) -> Admission {
+        if self.queued.is_empty() && self.try_take(datagram.len(), now) {
+            return Admission::Send;
+        }
+        if self.policy == RateLimitPolicy::Shed {
+            return Admission::Shed;
+        }
+        self.queued.push_back(datagram.to_vec());
+        self.queued_bytes += datagram.len();
+        let mut evicted = Vec::new();
+        while self.queued_bytes > self.max_queued_bytes {
+            let Some(oldest) = self.queued.pop_front() else {
+                break;
+            };
+            self.queued_bytes -= oldest.len();
+            evicted.push(oldest);
+        }
+        Admission::Queued { evicted }
+    }
+
+    /// The oldest queued datagram, if the limit allows sending it at `now`.
+    pub fn pop_ready(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let len = self.queued.front()?.len();
+        if !self.try_take(len, now) {
+            return None;
+        }
+        self.queued_bytes -= len;
+        self.queued.pop_front()
+    }
+
+    /// Remove all queued datagrams, e.g. on shutdown.
+    pub fn take_queued(&mut self) -> VecDeque<Vec<u8>> {
+        self.queued_bytes = 0;
+        std::mem::take(&mut self.queued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn queue() {
+        let start = Instant::now();
+        let mut limiter = UpstreamLimiter::new(
+            &UpstreamRateLimitConfig {
+                packets_per_second: Some(2),
+                max_queued_bytes: 4,
+                ..Default::default()
+            },
+            start,
+        );
+        assert_eq!(limiter.admit(b"a", start), Admission::Send);
+        assert_eq!(limiter.admit(b"b", start), Admission::Send);
+        assert_eq!(
+            limiter.admit(b"cc", start),
+            Admission::Queued { evicted: vec![] }
+        );
+        assert_eq!(
+            limiter.admit(b"ddd", start),
+            Admission::Queued {
+                evicted: vec![b"cc".to_vec()]
+            }
+        );
+        assert_eq!(limiter.pop_ready(start), None);
+
+        // Queued datagrams go first.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            limiter.admit(b"e", later),
+            Admission::Queued { evicted: vec![] }
+        );
+        assert_eq!(limiter.pop_ready(later), Some(b"ddd".to_vec()));
+        assert_eq!(limiter.pop_ready(later), Some(b"e".to_vec()));
+        assert_eq!(limiter.pop_ready(later), None);
+    }
+
+    #[test]
+    fn shed() {
+        let start = Instant::now();
+        let mut limiter = UpstreamLimiter::new(
+            &UpstreamRateLimitConfig {
+                bytes_per_second: Some(1000),
+                policy: RateLimitPolicy::Shed,
+                ..Default::default()
+            },
+            start,
+        );
+        // Bursts of a full datagram are allowed regardless of the rate.
+        assert_eq!(limiter.admit(&[0; 60000], start), Admission::Send);
+        assert_eq!(limiter.admit(&[0; 6000], start), Admission::Shed);
+        assert_eq!(
+            limiter.admit(&[0; 6000], start + Duration::from_secs(1)),
+            Admission::Send
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Breaks on changes to the stable API in `statsdproxy::prelude`: the signatures below are what
+//! library users rely on, and must only change in a major release.
+
+use std::sync::Arc;
+
+use anyhow::Error;
+use statsdproxy::prelude::*;
+
+#[derive(Default)]
+struct Collect(Vec<Vec<u8>>);
+
+impl Middleware for Collect {
+    fn join(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn poll(&mut self) {}
+
+    fn submit(&mut self, metric: &mut Metric) {
+        self.0.push(metric.raw.clone());
+    }
+
+    fn submit_batch(&mut self, metrics: &mut [Metric]) {
+        for metric in metrics {
+            self.submit(metric);
+        }
+    }
+}
+
+#[test]
+fn metric() {
+    let mut metric = Metric::new(b"users.online:1|c|@0.5|#country:china".to_vec());
+    let _: Option<&[u8]> = metric.name();
+    let _: Option<&[u8]> = metric.value();
+    let _: Option<&[u8]> = metric.ty();
+    let _: Option<f64> = metric.sample_rate();
+    let _: Option<&[u8]> = metric.tags();
+    let tags: Vec<MetricTag> = metric.tags_iter().collect();
+    let _: (&[u8], Option<&[u8]>) = (tags[0].name(), tags[0].value());
+    metric.set_tags(b"country:china");
+    metric.append_tags(b"env:prod");
+    assert_eq!(
+        metric.take(),
+        b"users.online:1|c|@0.5|#country:china,env:prod"
+    );
+}
+
+#[test]
+fn middlewares() {
+    let mut config = RelayConfig::default();
+    config.flush_interval = 10;
+    config.flush_offset = 0;
+    config.spool = None;
+    let mut relay = RelayPipeline::with_next(&config, Collect::default());
+    relay.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+    relay.submit(&mut Metric::new(b"users.online:1|c".to_vec()));
+    relay.join().unwrap();
+
+    let _: fn(&RelayConfig, Upstream) -> RelayPipeline = RelayPipeline::from_config;
+    let _: fn(String) -> Result<Upstream, Error> = Upstream::new;
+    let _: Box<dyn Middleware> = Box::new(Collect::default());
+}
+
+#[test]
+fn config() {
+    let config = Config::default();
+    let _: &Vec<MiddlewareConfig> = &config.middlewares;
+    let _: &ServerConfig = &config.server;
+    let _: &UpstreamConfig = &config.upstream;
+    let mut aggregate = AggregateMetricsConfig::default();
+    aggregate.aggregate_counters = true;
+    aggregate.aggregate_gauges = true;
+    aggregate.aggregate_timers = false;
+    aggregate.flush_interval = 10;
+    aggregate.flush_offset = 0;
+    aggregate.max_map_size = None;
+    aggregate.overrides = vec![];
+    aggregate.staleness = None;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(config.clone()));
+    snapshot.store(Arc::new(config));
+    let _: Arc<Config> = snapshot.load_full();
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn server() {
+    struct Empty;
+
+    impl Ingest for Empty {
+        fn recv(
+            &mut self,
+            _on_payload: &mut dyn FnMut(Option<std::net::IpAddr>, &[u8]),
+        ) -> Result<bool, Error> {
+            Ok(false)
+        }
+    }
+
+    let _: fn(String, ServerConfig, Collect) -> Result<Server<Collect>, Error> = Server::new;
+    let server = Server::from_ingest(Empty, ServerConfig::default(), Collect::default()).unwrap();
+    server.run().unwrap();
+}
+
+/// The items exported from the prelude, with the feature each requires. Removing or renaming one is
+/// a breaking change, so only update the snapshot for that in a major release. This only guards the
+/// list of exported names, parsed from the `pub use` statements: the signatures behind them are
+/// covered by the tests above.
+#[test]
+fn public_api() {
+    let mut items = Vec::new();
+    let mut feature = None;
+    let mut statement = String::new();
+    for line in include_str!("../src/prelude.rs").lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix("#[cfg(feature = \"")
+            .and_then(|x| x.strip_suffix("\")]"))
+        {
+            feature = Some(name);
+            continue;
+        }
+        if statement.is_empty() && !line.starts_with("pub use ") {
+            continue;
+        }
+        statement.push_str(line);
+        let Some(path) = statement
+            .strip_prefix("pub use ")
+            .and_then(|x| x.strip_suffix(';'))
+        else {
+            continue;
+        };
+        let (module, names) = match path.split_once("::{") {
+            Some((module, names)) => (module, names.trim_end_matches(['}', ','])),
+            None => path.rsplit_once("::").unwrap(),
+        };
+        for name in names.split(',').map(str::trim) {
+            let required = feature.map_or(String::new(), |x| format!(" (feature {})", x));
+            items.push(format!("{}::{}{}", module, name, required));
+        }
+        feature = None;
+        statement.clear();
+    }
+    items.sort();
+    insta::assert_snapshot!(items.join("\n"));
+}